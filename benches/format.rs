@@ -0,0 +1,79 @@
+// Run with `cargo bench`. Compares ASCII-only input, which takes the fast path
+// in format_text, with the same input plus a single fullwidth character, which
+// forces the regex pass over the whole string. Then compares one pass per
+// rule with the single automaton pass for growing rule sets, which is where
+// DEFAULT_AUTOMATON_THRESHOLD comes from.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use kill_zen_all::{format_text, format_text_with_options, FormatOptions, Replacement};
+
+const ITERATIONS: u32 = 2000;
+
+fn measure(label: &str, mut run: impl FnMut()) -> Duration {
+    // Warm up so the first measurement is not penalized
+    for _ in 0..ITERATIONS / 10 {
+        run();
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        run();
+    }
+    let per_call = start.elapsed() / ITERATIONS;
+    println!("{:<24} {:>10.2?} per call", label, per_call);
+    per_call
+}
+
+fn time(label: &str, text: &str) -> Duration {
+    measure(label, || {
+        black_box(format_text(black_box(text), &[], &[]).unwrap());
+    })
+}
+
+// Single-character rules (like a variant-kanji table), which the automaton
+// can take over
+fn char_rules(count: u32) -> Vec<Replacement> {
+    (0..count)
+        .map(|i| Replacement {
+            original: char::from_u32(0x4E00 + i).unwrap().to_string(),
+            replacement: char::from_u32(0x8000 + i).unwrap().to_string(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+fn main() {
+    let ascii = "The quick brown fox jumps over https://example.com/?q=1 ".repeat(200);
+    let mixed = format!("{}Ａ", ascii);
+
+    let fast = time("ascii-only (fast path)", &ascii);
+    let slow = time("one fullwidth char", &mixed);
+    println!(
+        "speedup: {:.1}x",
+        slow.as_secs_f64() / fast.as_secs_f64().max(f64::EPSILON)
+    );
+
+    let text: String = (0..2000)
+        .map(|i| char::from_u32(0x4E00 + i % 256).unwrap())
+        .collect();
+    for count in [4, 8, 16, 32, 64, 128] {
+        let rules = char_rules(count);
+        let per_rule = FormatOptions {
+            automaton_threshold: 0,
+            ..Default::default()
+        };
+        let automaton = FormatOptions {
+            automaton_threshold: 1,
+            ..Default::default()
+        };
+        let run = |options: &FormatOptions| {
+            black_box(format_text_with_options(black_box(&text), &rules, &[], options).unwrap());
+        };
+        let sequential = measure(&format!("{} rules, per rule", count), || run(&per_rule));
+        let single = measure(&format!("{} rules, automaton", count), || run(&automaton));
+        println!(
+            "automaton speedup: {:.1}x",
+            sequential.as_secs_f64() / single.as_secs_f64().max(f64::EPSILON)
+        );
+    }
+}
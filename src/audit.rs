@@ -0,0 +1,115 @@
+// `audit_log`: an append-only JSON Lines record of every conversion, with the
+// SHA-256 of the text before and after but never the text itself. Each record
+// also carries the hash of the line before it, so removing or editing a record
+// breaks the chain from there on.
+//
+// The hashes are plain SHA-256, neither salted nor keyed: anyone who can read
+// the log can check a guess, so short or predictable text (a PIN, a known
+// password) is as good as recorded. The log shows that a conversion happened;
+// it does not keep guessable text secret.
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+pub fn sha256_hex(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// One line of the log, without the newline. `previous` is the hash of the
+// line before it, or None for the first record.
+pub fn audit_record(
+    timestamp: u64,
+    original: &str,
+    converted: &str,
+    previous: Option<&str>,
+) -> String {
+    serde_json::json!({
+        "timestamp": timestamp,
+        "original_sha256": sha256_hex(original),
+        "converted_sha256": sha256_hex(converted),
+        "previous_sha256": previous,
+    })
+    .to_string()
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    // Hash of the last line written, read back from the file at startup
+    previous: Option<String>,
+}
+
+impl AuditLog {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let previous = match fs::read_to_string(&path) {
+            Ok(log) => log.lines().rfind(|line| !line.is_empty()).map(sha256_hex),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        Ok(AuditLog { path, previous })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&mut self, timestamp: u64, original: &str, converted: &str) -> Result<()> {
+        let line = audit_record(timestamp, original, converted, self.previous.as_deref());
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        writeln!(file, "{}", line)
+            .with_context(|| format!("Failed to write to {}", self.path.display()))?;
+        self.previous = Some(sha256_hex(&line));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_audit_record_holds_hashes_only() {
+        let record = audit_record(1_700_000_000, "ＡＢＣ", "ABC", None);
+        let value: serde_json::Value = serde_json::from_str(&record).unwrap();
+        assert_eq!(value["timestamp"], 1_700_000_000);
+        assert_eq!(
+            value["converted_sha256"],
+            "b5d4045c3f466fa91fe2cc6abe79232a1a57cdf104f7a26e716e0a1e2789df78"
+        );
+        assert_eq!(value["original_sha256"], sha256_hex("ＡＢＣ"));
+        assert!(value["previous_sha256"].is_null());
+        assert!(!record.contains("ＡＢＣ"));
+        assert!(!record.contains("\"ABC\""));
+    }
+
+    #[test]
+    fn test_records_chain_across_restarts() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("audit.jsonl");
+        let mut log = AuditLog::open(path.clone()).unwrap();
+        log.record(1, "ｓｅｃｒｅｔ", "secret").unwrap();
+        let mut log = AuditLog::open(path.clone()).unwrap();
+        log.record(2, "ｐａｓｓ", "pass").unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let second: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(second["previous_sha256"], sha256_hex(lines[0]));
+        for text in ["ｓｅｃｒｅｔ", "secret", "ｐａｓｓ", "pass"] {
+            assert!(!contents.contains(text));
+        }
+    }
+}
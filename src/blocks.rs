@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+
+use difference::{Changeset, Difference};
+
+use crate::diff::MAX_DIFF_INPUT_CHARS;
+
+// Rough Unicode blocks for the per-session statistics; the fullwidth forms
+// are split the way users think of them
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum UnicodeBlock {
+    FullwidthDigits,
+    FullwidthLetters,
+    FullwidthSymbols,
+    IdeographicSpace,
+    HalfwidthKatakana,
+    // ￠￡￢ and the rest of U+FFE0..U+FFEE
+    FullwidthSigns,
+    Other,
+}
+
+impl UnicodeBlock {
+    pub fn of(c: char) -> Self {
+        match c {
+            '０'..='９' => UnicodeBlock::FullwidthDigits,
+            'Ａ'..='Ｚ' | 'ａ'..='ｚ' => UnicodeBlock::FullwidthLetters,
+            '！'..='～' | '｟' | '｠' => UnicodeBlock::FullwidthSymbols,
+            '　' => UnicodeBlock::IdeographicSpace,
+            '\u{FF61}'..='\u{FF9F}' => UnicodeBlock::HalfwidthKatakana,
+            '\u{FFE0}'..='\u{FFEE}' => UnicodeBlock::FullwidthSigns,
+            _ => UnicodeBlock::Other,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(transparent)]
+pub struct BlockTally(BTreeMap<UnicodeBlock, u64>);
+
+impl BlockTally {
+    // Counts the characters of `before` that did not survive into `after`
+    pub fn record(&mut self, before: &str, after: &str) {
+        converted_chars(before, after)
+            .into_iter()
+            .for_each(|c| self.add(c));
+    }
+
+    fn add(&mut self, c: char) {
+        *self.0.entry(UnicodeBlock::of(c)).or_default() += 1;
+    }
+
+    pub fn get(&self, block: UnicodeBlock) -> u64 {
+        self.0.get(&block).copied().unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// The characters of `before` that did not survive into `after`
+pub fn converted_chars(before: &str, after: &str) -> Vec<char> {
+    let before_chars: Vec<char> = before.chars().collect();
+    let after_chars: Vec<char> = after.chars().collect();
+    let prefix = before_chars
+        .iter()
+        .zip(&after_chars)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = before_chars[prefix..]
+        .iter()
+        .rev()
+        .zip(after_chars[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let before_span = &before_chars[prefix..before_chars.len() - suffix];
+    let after_span = &after_chars[prefix..after_chars.len() - suffix];
+    // The Changeset is quadratic; a huge span is counted as a whole instead
+    if before_span.len().max(after_span.len()) > MAX_DIFF_INPUT_CHARS {
+        return before_span.to_vec();
+    }
+    let before_span: String = before_span.iter().collect();
+    let after_span: String = after_span.iter().collect();
+    Changeset::new(&before_span, &after_span, "")
+        .diffs
+        .into_iter()
+        .filter_map(|diff| match diff {
+            Difference::Rem(removed) => Some(removed),
+            _ => None,
+        })
+        .flat_map(|removed| removed.chars().collect::<Vec<_>>())
+        .collect()
+}
+
+// e.g. "fullwidth_digits 3, fullwidth_letters 2"
+impl std::fmt::Display for BlockTally {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, (block, count)) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            let name = serde_json::to_value(block).map_err(|_| std::fmt::Error)?;
+            write!(f, "{} {}", name.as_str().unwrap_or_default(), count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_of() {
+        assert_eq!(UnicodeBlock::of('５'), UnicodeBlock::FullwidthDigits);
+        assert_eq!(UnicodeBlock::of('ｚ'), UnicodeBlock::FullwidthLetters);
+        assert_eq!(UnicodeBlock::of('！'), UnicodeBlock::FullwidthSymbols);
+        assert_eq!(UnicodeBlock::of('ｶ'), UnicodeBlock::HalfwidthKatakana);
+        assert_eq!(UnicodeBlock::of('￥'), UnicodeBlock::FullwidthSigns);
+        assert_eq!(UnicodeBlock::of('あ'), UnicodeBlock::Other);
+    }
+
+    #[test]
+    fn test_tally_mixed_blocks() {
+        let mut tally = BlockTally::default();
+        tally.record("ＡＢ １２３ ｶﾀｶﾅ！ あ", "AB 123 カタカナ! あ");
+        assert_eq!(tally.get(UnicodeBlock::FullwidthLetters), 2);
+        assert_eq!(tally.get(UnicodeBlock::FullwidthDigits), 3);
+        assert_eq!(tally.get(UnicodeBlock::HalfwidthKatakana), 4);
+        assert_eq!(tally.get(UnicodeBlock::FullwidthSymbols), 1);
+        // Unchanged text is not counted
+        assert_eq!(tally.get(UnicodeBlock::Other), 0);
+
+        tally.record("７", "7");
+        assert_eq!(tally.get(UnicodeBlock::FullwidthDigits), 4);
+        assert_eq!(
+            tally.to_string(),
+            "fullwidth_digits 4, fullwidth_letters 2, fullwidth_symbols 1, halfwidth_katakana 4"
+        );
+    }
+
+    #[test]
+    fn test_tally_unchanged() {
+        let mut tally = BlockTally::default();
+        tally.record("same", "same");
+        assert!(tally.is_empty());
+    }
+}
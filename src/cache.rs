@@ -0,0 +1,84 @@
+use lru::LruCache;
+use std::num::NonZeroUsize;
+
+pub const DEFAULT_CACHE_SIZE: usize = 64;
+
+// Conversion results keyed by the input text itself, as two inputs sharing a
+// hash must never be handed each other's result. Results depend on the loaded
+// rules, so the owner must clear it whenever the config is reloaded.
+pub struct ConversionCache {
+    entries: LruCache<String, String>,
+}
+
+impl ConversionCache {
+    // Returns None for a capacity of 0, i.e. caching disabled
+    pub fn new(capacity: usize) -> Option<Self> {
+        NonZeroUsize::new(capacity).map(|capacity| ConversionCache {
+            entries: LruCache::new(capacity),
+        })
+    }
+
+    pub fn get_or_try_insert<E>(
+        &mut self,
+        input: &str,
+        convert: impl FnOnce() -> Result<String, E>,
+    ) -> Result<String, E> {
+        if let Some(cached) = self.entries.get(input) {
+            return Ok(cached.clone());
+        }
+        let converted = convert()?;
+        self.entries.put(input.to_string(), converted.clone());
+        Ok(converted)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_hit_returns_cached_result() {
+        let mut cache = ConversionCache::new(2).unwrap();
+        let first: Result<_, ()> = cache.get_or_try_insert("ＡＢＣ", || Ok("ABC".to_string()));
+        assert_eq!(first.unwrap(), "ABC");
+
+        let second: Result<_, ()> =
+            cache.get_or_try_insert("ＡＢＣ", || panic!("should have been cached"));
+        assert_eq!(second.unwrap(), "ABC");
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_cache_does_not_store_errors() {
+        let mut cache = ConversionCache::new(2).unwrap();
+        assert!(cache.get_or_try_insert("foo", || Err(())).is_err());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let mut cache = ConversionCache::new(1).unwrap();
+        let _: Result<_, ()> = cache.get_or_try_insert("a", || Ok("a".to_string()));
+        let _: Result<_, ()> = cache.get_or_try_insert("b", || Ok("b".to_string()));
+        assert_eq!(cache.len(), 1);
+        let again: Result<_, ()> = cache.get_or_try_insert("a", || Ok("recomputed".to_string()));
+        assert_eq!(again.unwrap(), "recomputed");
+    }
+
+    #[test]
+    fn test_zero_capacity_disables_cache() {
+        assert!(ConversionCache::new(0).is_none());
+    }
+}
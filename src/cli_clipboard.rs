@@ -0,0 +1,240 @@
+// Last-resort clipboard access through the platform's command-line tools,
+// for setups where the linked clipboard library is broken but the tools work
+use anyhow::{bail, Context, Result};
+use std::env;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// Targets a password manager adds to an entry so that clipboard tools do not
+// record it (KeePassXC and KDE's own tools use this one)
+const SENSITIVE_TARGETS: &[&str] = &["x-kde-passwordManagerHint"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CliTool {
+    // pbpaste / pbcopy (macOS)
+    Pasteboard,
+    // wl-paste / wl-copy (Wayland)
+    WlClipboard,
+    // xclip (X11)
+    Xclip,
+}
+
+impl CliTool {
+    fn read_command(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            CliTool::Pasteboard => ("pbpaste", &[]),
+            CliTool::WlClipboard => ("wl-paste", &["--no-newline"]),
+            CliTool::Xclip => ("xclip", &["-selection", "clipboard", "-o"]),
+        }
+    }
+
+    // pbpaste cannot list the types on the pasteboard
+    fn targets_command(self) -> Option<(&'static str, &'static [&'static str])> {
+        match self {
+            CliTool::Pasteboard => None,
+            CliTool::WlClipboard => Some(("wl-paste", &["--list-types"])),
+            CliTool::Xclip => Some(("xclip", &["-selection", "clipboard", "-o", "-t", "TARGETS"])),
+        }
+    }
+
+    fn write_command(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            CliTool::Pasteboard => ("pbcopy", &[]),
+            CliTool::WlClipboard => ("wl-copy", &[]),
+            CliTool::Xclip => ("xclip", &["-selection", "clipboard", "-i"]),
+        }
+    }
+
+    // pbcopy only writes plain text, under the targets macOS chooses
+    pub fn can_choose_target(self) -> bool {
+        self != CliTool::Pasteboard
+    }
+
+    fn write_args(self, target: Option<&'static str>) -> Vec<&'static str> {
+        let (_, args) = self.write_command();
+        let mut args = args.to_vec();
+        match (self, target) {
+            (CliTool::WlClipboard, Some(target)) => args.extend(["--type", target]),
+            (CliTool::Xclip, Some(target)) => args.extend(["-t", target]),
+            _ => {}
+        }
+        args
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CliTool::Pasteboard => "pbpaste/pbcopy",
+            CliTool::WlClipboard => "wl-paste/wl-copy",
+            CliTool::Xclip => "xclip",
+        }
+    }
+
+    pub fn read(self) -> Result<Vec<u8>> {
+        let (program, args) = self.read_command();
+        run(program, args)
+    }
+
+    // Whether the current entry carries a password manager's marker. A tool
+    // that cannot tell, or fails to, reports false.
+    pub fn is_marked_sensitive(self) -> bool {
+        let Some((program, args)) = self.targets_command() else {
+            return false;
+        };
+        run(program, args).is_ok_and(|targets| has_sensitive_target(&targets))
+    }
+
+    pub fn write(self, text: &str) -> Result<()> {
+        self.write_as(text, None)
+    }
+
+    // Offers the text as `target` (e.g. UTF8_STRING), where the tool can choose
+    pub fn write_as(self, text: &str, target: Option<&'static str>) -> Result<()> {
+        let (program, _) = self.write_command();
+        let args = self.write_args(target);
+        // wl-copy and xclip stay in the background to serve the selection, so
+        // their output is not captured: a pipe would be held open by them
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to start '{}'", program))?;
+        let mut stdin = child.stdin.take().context("stdin is not piped")?;
+        stdin.write_all(text.as_bytes())?;
+        drop(stdin);
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("'{}' exited with {}", program, status);
+        }
+        Ok(())
+    }
+}
+
+// One target per line, as both xclip and wl-paste list them
+fn has_sensitive_target(targets: &[u8]) -> bool {
+    String::from_utf8_lossy(targets)
+        .lines()
+        .any(|target| SENSITIVE_TARGETS.contains(&target.trim()))
+}
+
+fn run(program: &str, args: &[&str]) -> Result<Vec<u8>> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run '{}'", program))?;
+    if !output.status.success() {
+        bail!("'{}' exited with {}", program, output.status);
+    }
+    Ok(output.stdout)
+}
+
+// The tool to fall back to, given which programs are installed. A tool needs
+// both its read and its write program. Wayland tools only work in a Wayland
+// session, where xclip still works through XWayland.
+pub fn choose_cli_tool(wayland: bool, is_present: impl Fn(&str) -> bool) -> Option<CliTool> {
+    [CliTool::Pasteboard, CliTool::WlClipboard, CliTool::Xclip]
+        .into_iter()
+        .filter(|&tool| wayland || tool != CliTool::WlClipboard)
+        .find(|tool| is_present(tool.read_command().0) && is_present(tool.write_command().0))
+}
+
+pub fn detect_cli_tool() -> Option<CliTool> {
+    choose_cli_tool(env::var_os("WAYLAND_DISPLAY").is_some(), on_path)
+}
+
+fn on_path(program: &str) -> bool {
+    let Some(path) = env::var_os("PATH") else {
+        return false;
+    };
+    env::split_paths(&path).any(|dir| is_executable(&dir.join(program)))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file() || path.with_extension("exe").is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn present(programs: &'static [&'static str]) -> impl Fn(&str) -> bool {
+        move |program| programs.contains(&program)
+    }
+
+    #[test]
+    fn test_choose_cli_tool() {
+        assert_eq!(
+            choose_cli_tool(false, present(&["pbpaste", "pbcopy"])),
+            Some(CliTool::Pasteboard)
+        );
+        assert_eq!(
+            choose_cli_tool(true, present(&["wl-paste", "wl-copy", "xclip"])),
+            Some(CliTool::WlClipboard)
+        );
+        assert_eq!(
+            choose_cli_tool(false, present(&["xclip"])),
+            Some(CliTool::Xclip)
+        );
+        assert_eq!(choose_cli_tool(false, present(&[])), None);
+    }
+
+    #[test]
+    fn test_has_sensitive_target() {
+        assert!(has_sensitive_target(
+            b"TIMESTAMP\nTARGETS\nUTF8_STRING\nx-kde-passwordManagerHint\n"
+        ));
+        assert!(has_sensitive_target(
+            b"text/plain\r\nx-kde-passwordManagerHint"
+        ));
+        assert!(!has_sensitive_target(b"TARGETS\nUTF8_STRING\n"));
+        assert!(!has_sensitive_target(b""));
+    }
+
+    #[test]
+    fn test_write_args_choose_the_target() {
+        assert_eq!(
+            CliTool::Xclip.write_args(Some("UTF8_STRING")),
+            ["-selection", "clipboard", "-i", "-t", "UTF8_STRING"]
+        );
+        assert_eq!(
+            CliTool::WlClipboard.write_args(Some("text/plain;charset=utf-8")),
+            ["--type", "text/plain;charset=utf-8"]
+        );
+        assert!(CliTool::WlClipboard.write_args(None).is_empty());
+        assert!(!CliTool::Pasteboard.can_choose_target());
+        assert!(CliTool::Pasteboard
+            .write_args(Some("UTF8_STRING"))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_choose_cli_tool_needs_a_usable_pair() {
+        // Outside Wayland, wl-copy cannot reach the clipboard
+        assert_eq!(
+            choose_cli_tool(false, present(&["wl-paste", "wl-copy", "xclip"])),
+            Some(CliTool::Xclip)
+        );
+        assert_eq!(
+            choose_cli_tool(false, present(&["wl-paste", "wl-copy"])),
+            None
+        );
+        // Half of a pair is not enough
+        assert_eq!(choose_cli_tool(true, present(&["wl-paste"])), None);
+        assert_eq!(
+            choose_cli_tool(true, present(&["pbpaste", "xclip"])),
+            Some(CliTool::Xclip)
+        );
+    }
+}
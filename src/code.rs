@@ -0,0 +1,118 @@
+use std::ops::Range;
+
+// Comment syntax of the pasted code, for `code_aware`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CodeLanguage {
+    // `//` and `/* */` comments: C, C++, Java, JavaScript, Rust, ...
+    #[default]
+    CLike,
+    // `#` comments: Python, Ruby, shell scripts, YAML, ...
+    Hash,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CodeAware {
+    #[default]
+    Off,
+    // Convert only inside string literals and comments
+    Literals,
+    // Convert only outside them, i.e. identifiers, keywords and operators
+    Code,
+}
+
+// Byte ranges of string literals ('...' and "...") and comments. A simple
+// scanner rather than a parser: backslash escapes are honored, a literal
+// ends at the end of its line at the latest, and an unterminated block
+// comment runs to the end of the text.
+pub fn literal_ranges(text: &str, language: CodeLanguage) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, next)| next);
+        let end = match (c, next, language) {
+            ('"' | '\'', _, _) => {
+                let mut end = text.len();
+                while let Some((i, inner)) = chars.next() {
+                    if inner == '\\' {
+                        chars.next_if(|&(_, escaped)| escaped != '\n');
+                    } else if inner == c {
+                        end = i + 1;
+                        break;
+                    } else if inner == '\n' {
+                        end = i;
+                        break;
+                    }
+                }
+                end
+            }
+            ('/', Some('/'), CodeLanguage::CLike) | ('#', _, CodeLanguage::Hash) => {
+                text[start..].find('\n').map_or(text.len(), |i| start + i)
+            }
+            ('/', Some('*'), CodeLanguage::CLike) => text[start + 2..]
+                .find("*/")
+                .map_or(text.len(), |i| start + 2 + i + 2),
+            _ => continue,
+        };
+        while chars.next_if(|&(i, _)| i < end).is_some() {}
+        ranges.push(start..end);
+    }
+    ranges
+}
+
+// Splits `text` into (segment, in_scope) pieces in order, where in_scope
+// tells whether `code_aware` allows converting the segment
+pub fn scoped_segments(
+    text: &str,
+    code_aware: CodeAware,
+    language: CodeLanguage,
+) -> Vec<(&str, bool)> {
+    if code_aware == CodeAware::Off {
+        return vec![(text, true)];
+    }
+    let literals_in_scope = code_aware == CodeAware::Literals;
+    let mut segments = Vec::new();
+    let mut position = 0;
+    for range in literal_ranges(text, language) {
+        if position < range.start {
+            segments.push((&text[position..range.start], !literals_in_scope));
+        }
+        segments.push((&text[range.clone()], literals_in_scope));
+        position = range.end;
+    }
+    if position < text.len() {
+        segments.push((&text[position..], !literals_in_scope));
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_literal_ranges_c_like() {
+        let text = r#"a = "x\"y"; b = 'z' // c "d"
+/* e
+f */ g"#;
+        let literals: Vec<&str> = literal_ranges(text, CodeLanguage::CLike)
+            .into_iter()
+            .map(|range| &text[range])
+            .collect();
+        assert_eq!(
+            literals,
+            vec![r#""x\"y""#, "'z'", r#"// c "d""#, "/* e\nf */"]
+        );
+    }
+
+    #[test]
+    fn test_literal_ranges_hash_and_unterminated() {
+        let text = "x = 'open\n# note // not c\ny = \"end";
+        let literals: Vec<&str> = literal_ranges(text, CodeLanguage::Hash)
+            .into_iter()
+            .map(|range| &text[range])
+            .collect();
+        assert_eq!(literals, vec!["'open", "# note // not c", "\"end"]);
+    }
+}
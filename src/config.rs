@@ -0,0 +1,2668 @@
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cache::DEFAULT_CACHE_SIZE;
+use crate::error::ConfigError;
+use crate::feedback::{ConvertFeedback, DEFAULT_NOTIFY_PREVIEW_CHARS};
+use crate::format::{
+    check_rule_patterns, ContextRegexes, ExclusionStage, FormatOptions, StageExclusions,
+};
+use crate::history::ReloadRecord;
+use crate::ipc::DEFAULT_IPC_PORT;
+use crate::migrate::{write_defaults_version, DEFAULTS_VERSION};
+use crate::post::DEFAULT_POST_COMMAND_TIMEOUT_MS;
+use crate::quiet::QuietWindow;
+use crate::reload::ReloadHashStrategy;
+use crate::remote_rules::DEFAULT_RULES_URL_INTERVAL_SECS;
+use crate::selection::Modifier;
+use crate::snapshots::DEFAULT_SNAPSHOT_LIMIT;
+
+const DEFAULT_REPLACEMENTS: &str = include_str!("default_replacements.json");
+const DEFAULT_EXCLUSIONS: &str = include_str!("default_exclusions.json");
+pub const REPLACEMENTS_FILE_NAME: &str = "replacements.json";
+pub const EXCLUSIONS_FILE_NAME: &str = "exclusions.json";
+pub const SETTINGS_FILE_NAME: &str = "settings.json";
+pub const REPLACEMENTS_FILE_STEM: &str = "replacements";
+pub const EXCLUSIONS_FILE_STEM: &str = "exclusions";
+pub const COMBINED_CONFIG_FILE_STEM: &str = "config";
+pub const PROJECT_CONFIG_DIR_NAME: &str = ".kill-zen-all";
+pub const REPLACEMENTS_ENV_VAR: &str = "KZA_REPLACEMENTS";
+pub const DEFAULT_CLIPBOARD_TIMEOUT_MS: u64 = 2000;
+// An editor caught mid-write can leave the file truncated, half-written or with
+// a multi-byte character cut in half for a moment, so an empty file, invalid
+// UTF-8 or a parse error is read again before it counts
+const MID_WRITE_RETRIES: u32 = 3;
+const MID_WRITE_RETRY_DELAY: Duration = Duration::from_millis(20);
+// Exclusions are looked up one by one, so a range is kept to about the size
+// of a Unicode block rather than, say, all of U+0000..U+10FFFF
+pub const MAX_EXCLUDE_RANGE_CHARS: u32 = 4096;
+// The settings format this build understands; bumped when keys are renamed or removed
+pub const CONFIG_VERSION: u32 = 1;
+// Settings keys that no longer have any effect, and what to use instead. None yet.
+pub const DEPRECATED_SETTINGS: &[(&str, &str)] = &[];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    #[default]
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ConfigFormat::Json => "json",
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Yaml => "yaml",
+        }
+    }
+
+    // Anything that is not TOML or YAML is read as JSON, as before
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    fn serialize<T: serde::Serialize>(&self, value: &T) -> Result<String, ConfigError> {
+        let serialized = match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::to_string_pretty(value).map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+        };
+        serialized.map_err(|message| ConfigError::Serialize {
+            format: self.extension().to_string(),
+            message,
+        })
+    }
+}
+
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplacementAction {
+    #[default]
+    Replace,
+    Delete,
+    // `original` is a regex; fullwidth characters inside each match are
+    // halved even if they are listed in the exclusions
+    ConvertWidth,
+    // `original` is a set of characters; any run of two or more of them
+    // becomes `replacement`
+    CollapseRuns,
+}
+
+// Which matches of a rule are replaced, counted after the context check
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Occurrence {
+    #[default]
+    All,
+    First,
+    Last,
+}
+
+impl Occurrence {
+    pub fn select<T>(self, matches: &[T]) -> &[T] {
+        match self {
+            Occurrence::All => matches,
+            Occurrence::First => &matches[..matches.len().min(1)],
+            Occurrence::Last => &matches[matches.len().saturating_sub(1)..],
+        }
+    }
+
+    fn is_all(&self) -> bool {
+        *self == Occurrence::All
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, Hash)]
+#[serde(try_from = "RawReplacement")]
+pub struct Replacement {
+    pub original: String,
+    pub replacement: String,
+    pub action: ReplacementAction,
+    // Regexes that must match right before / after `original` for the rule to apply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_after: Option<String>,
+    #[serde(skip_serializing_if = "Occurrence::is_all")]
+    pub occurrence: Occurrence,
+    // The regex in `original` is matched literally, as if every special
+    // character were escaped
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub literal_escape: bool,
+    // Only the first N regex matches are looked at, so adversarial input
+    // cannot make one rule do unbounded work
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_matches: Option<usize>,
+    // What the rule inserts is kept as it is by the later rules and stages,
+    // e.g. a fullwidth ！ is not then converted to !
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub protect_output: bool,
+    // context_before/context_after, compiled the first time the rule runs
+    #[serde(skip)]
+    pub context_regexes: ContextRegexes,
+}
+
+impl Replacement {
+    // Regex source for rule kinds that are matched with a regex
+    pub fn pattern(&self) -> Option<String> {
+        match self.action {
+            ReplacementAction::ConvertWidth if self.literal_escape => {
+                Some(regex::escape(&self.original))
+            }
+            ReplacementAction::ConvertWidth => Some(self.original.clone()),
+            ReplacementAction::CollapseRuns => {
+                let class: String = self
+                    .original
+                    .chars()
+                    .map(|c| regex::escape(&c.to_string()))
+                    .collect();
+                Some(format!("[{}]{{2,}}", class))
+            }
+            ReplacementAction::Replace | ReplacementAction::Delete => None,
+        }
+    }
+
+    // Replaces `original` with itself. With protect_output the same rule
+    // keeps the text as it is, which is not a no-op.
+    pub fn is_noop(&self) -> bool {
+        self.action == ReplacementAction::Replace
+            && self.original == self.replacement
+            && !self.protect_output
+    }
+
+    // The output contains the input again, e.g. "a" -> "xa", so anything that
+    // applies the rules more than once keeps growing the text
+    pub fn is_self_containing(&self) -> bool {
+        self.action == ReplacementAction::Replace
+            && self.replacement != self.original
+            && self.replacement.contains(&self.original)
+    }
+
+    pub fn is_deletion(&self) -> bool {
+        match self.action {
+            ReplacementAction::Replace => self.replacement.is_empty(),
+            ReplacementAction::Delete => true,
+            ReplacementAction::ConvertWidth => false,
+            ReplacementAction::CollapseRuns => self.replacement.is_empty(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct RawReplacement {
+    original: String,
+    replacement: Option<String>,
+    #[serde(default)]
+    action: ReplacementAction,
+    context_before: Option<String>,
+    context_after: Option<String>,
+    #[serde(default)]
+    occurrence: Occurrence,
+    #[serde(default)]
+    literal_escape: bool,
+    max_matches: Option<usize>,
+    #[serde(default)]
+    protect_output: bool,
+}
+
+impl TryFrom<RawReplacement> for Replacement {
+    type Error = String;
+
+    fn try_from(raw: RawReplacement) -> Result<Self, Self::Error> {
+        let replacement = match (raw.action, raw.replacement) {
+            (ReplacementAction::Replace | ReplacementAction::CollapseRuns, Some(replacement)) => {
+                replacement
+            }
+            (ReplacementAction::CollapseRuns, None) => {
+                return Err(format!(
+                    "missing `replacement` for collapse_runs rule '{}'",
+                    raw.original
+                ))
+            }
+            (ReplacementAction::Replace, None) => {
+                return Err(format!(
+                    "missing `replacement` for '{}' (use \"action\": \"delete\" to delete it)",
+                    raw.original
+                ))
+            }
+            (ReplacementAction::Delete | ReplacementAction::ConvertWidth, None) => String::new(),
+            (ReplacementAction::Delete | ReplacementAction::ConvertWidth, Some(replacement))
+                if replacement.is_empty() =>
+            {
+                replacement
+            }
+            (action, Some(_)) => {
+                return Err(format!(
+                    "`replacement` must be empty for {:?} rule '{}'",
+                    action, raw.original
+                ))
+            }
+        };
+        let rule = Replacement {
+            original: raw.original,
+            replacement,
+            action: raw.action,
+            context_before: raw.context_before,
+            context_after: raw.context_after,
+            occurrence: raw.occurrence,
+            literal_escape: raw.literal_escape,
+            max_matches: raw.max_matches,
+            protect_output: raw.protect_output,
+            context_regexes: ContextRegexes::default(),
+        };
+        // Other rules match `original` literally already
+        if rule.literal_escape && rule.action != ReplacementAction::ConvertWidth {
+            return Err(format!(
+                "`literal_escape` only applies to convert_width rules, not '{}'",
+                rule.original
+            ));
+        }
+        if rule.max_matches.is_some() && rule.pattern().is_none() {
+            return Err(format!(
+                "`max_matches` only applies to convert_width and collapse_runs rules, not '{}'",
+                rule.original
+            ));
+        }
+        // A rule that may never match is almost certainly a mistake
+        if rule.max_matches == Some(0) {
+            return Err(format!(
+                "`max_matches` must be at least 1, not 0, in '{}'",
+                rule.original
+            ));
+        }
+        if rule.pattern().is_some() {
+            if rule.context_before.is_some() || rule.context_after.is_some() {
+                return Err(format!(
+                    "{:?} rule '{}' cannot have a context; put it in the pattern",
+                    rule.action, rule.original
+                ));
+            }
+            if rule.original.is_empty() {
+                return Err(format!("{:?} rule must have an `original`", rule.action));
+            }
+        }
+        // Patterns that do not compile are checked once the file is read, so
+        // that only their rules are dropped
+        Ok(rule)
+    }
+}
+
+// TOML files must be a table at the top level, so replacements go under a key there
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+struct ReplacementTable {
+    #[serde(default)]
+    replacements: Vec<Replacement>,
+}
+
+#[derive(serde::Serialize)]
+struct ExclusionsOut<'a> {
+    exclude: &'a [char],
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct Exclusions {
+    #[serde(default)]
+    exclude: Vec<ExcludedChar>,
+    // Whole blocks, e.g. ["U+FF10", "U+FF19"], added to `exclude`
+    #[serde(default)]
+    exclude_ranges: Vec<ExcludeRange>,
+    // Removes entries of the global list from a project's exclusions, or of
+    // the bundled list with `extend_defaults`
+    #[serde(default)]
+    unexclude: Vec<ExcludedChar>,
+    // The bundled exclusions always apply and this file adds to them, so
+    // exclusions added in later releases are not missed
+    #[serde(default)]
+    extend_defaults: bool,
+}
+
+// config.json: the replacements, exclusions and settings in one file, which
+// takes precedence over the split files in the same directory
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+struct CombinedConfig {
+    replacements: Vec<Replacement>,
+    // `exclude` of exclusions.json
+    exclusions: Vec<ExcludedChar>,
+    exclude_ranges: Vec<ExcludeRange>,
+    unexclude: Vec<ExcludedChar>,
+    extend_default_exclusions: bool,
+    #[serde(flatten)]
+    settings: Settings,
+}
+
+fn is_combined_config(path: &Path) -> bool {
+    path.file_stem().and_then(|stem| stem.to_str()) == Some(COMBINED_CONFIG_FILE_STEM)
+}
+
+// Either a plain entry or `{"char": ..., "reason": ...}` documenting why it is excluded
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum RawExcludedChar {
+    Plain(String),
+    Documented {
+        char: String,
+        #[serde(default)]
+        reason: Option<String>,
+        #[serde(default)]
+        stages: Vec<ExclusionStage>,
+    },
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(try_from = "RawExcludedChar")]
+struct ExcludedChar {
+    c: char,
+    reason: Option<String>,
+    // Empty for every stage
+    stages: Vec<ExclusionStage>,
+}
+
+impl TryFrom<RawExcludedChar> for ExcludedChar {
+    type Error = String;
+
+    fn try_from(raw: RawExcludedChar) -> Result<Self, Self::Error> {
+        let (entry, reason, stages) = match raw {
+            RawExcludedChar::Plain(entry) => (entry, None, Vec::new()),
+            RawExcludedChar::Documented {
+                char,
+                reason,
+                stages,
+            } => (char, reason, stages),
+        };
+        Ok(ExcludedChar {
+            c: parse_excluded_char(&entry)?,
+            reason,
+            stages,
+        })
+    }
+}
+
+// Either a literal character ("！") or a code point ("U+FF01")
+fn parse_excluded_char(entry: &str) -> Result<char, String> {
+    let mut chars = entry.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(c);
+    }
+    entry
+        .strip_prefix("U+")
+        .or_else(|| entry.strip_prefix("u+"))
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .and_then(char::from_u32)
+        .ok_or_else(|| {
+            format!(
+                "invalid exclusion '{}': expected a single character or U+XXXX",
+                entry
+            )
+        })
+}
+
+// Both ends included, each written like an exclusion entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "(String, String)")]
+pub struct ExcludeRange {
+    pub start: char,
+    pub end: char,
+}
+
+impl TryFrom<(String, String)> for ExcludeRange {
+    type Error = String;
+
+    fn try_from((start, end): (String, String)) -> Result<Self, Self::Error> {
+        Ok(ExcludeRange {
+            start: parse_excluded_char(&start)?,
+            end: parse_excluded_char(&end)?,
+        })
+    }
+}
+
+impl std::fmt::Display for ExcludeRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "U+{:04X}..U+{:04X}",
+            u32::from(self.start),
+            u32::from(self.end)
+        )
+    }
+}
+
+impl ExcludeRange {
+    fn len(&self) -> u32 {
+        u32::from(self.end) - u32::from(self.start) + 1
+    }
+}
+
+// The ranges with inverted ones swapped around and oversized ones left out,
+// and what was wrong with them. Overlapping ranges still load, but are
+// reported since one of them is usually not what was meant.
+pub fn check_exclude_ranges(ranges: &[ExcludeRange]) -> (Vec<ExcludeRange>, Vec<String>) {
+    let mut warnings = Vec::new();
+    let mut checked = Vec::new();
+    for &range in ranges {
+        let range = if range.start <= range.end {
+            range
+        } else {
+            let swapped = ExcludeRange {
+                start: range.end,
+                end: range.start,
+            };
+            warnings.push(format!(
+                "exclude_ranges: {} starts after it ends; using {}",
+                range, swapped
+            ));
+            swapped
+        };
+        if range.len() > MAX_EXCLUDE_RANGE_CHARS {
+            warnings.push(format!(
+                "exclude_ranges: {} has more than {} characters; ignoring it",
+                range, MAX_EXCLUDE_RANGE_CHARS
+            ));
+            continue;
+        }
+        checked.push(range);
+    }
+    for (index, first) in checked.iter().enumerate() {
+        for second in &checked[index + 1..] {
+            if first.start <= second.end && second.start <= first.end {
+                warnings.push(format!("exclude_ranges: {} overlaps {}", first, second));
+            }
+        }
+    }
+    (checked, warnings)
+}
+
+// The rule set as the converter actually sees it, for --dump-config
+#[derive(Debug, serde::Serialize)]
+pub struct EffectiveConfig {
+    pub replacements: Vec<Replacement>,
+    pub exclusions: Vec<char>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub exclusion_reasons: BTreeMap<char, String>,
+    // Characters excluded from some stages only
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub stage_exclusions: StageExclusions,
+    // The daemon's on-disk reload history, with `reload_history` enabled
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reload_history: Vec<ReloadRecord>,
+}
+
+impl EffectiveConfig {
+    // Sorted, so that two dumps can be diffed
+    pub fn new(
+        mut replacements: Vec<Replacement>,
+        mut exclusions: Vec<char>,
+        exclusion_reasons: BTreeMap<char, String>,
+        stage_exclusions: StageExclusions,
+    ) -> Self {
+        replacements.sort_by(|a, b| {
+            (&a.original, &a.replacement, a.action).cmp(&(&b.original, &b.replacement, b.action))
+        });
+        exclusions.sort_unstable();
+        exclusions.dedup();
+        EffectiveConfig {
+            replacements,
+            exclusions,
+            exclusion_reasons,
+            stage_exclusions,
+            reload_history: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerMode {
+    #[default]
+    Continuous,
+    Manual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    #[default]
+    Replace,
+    Append,
+    Prepend,
+}
+
+impl OutputMode {
+    pub fn compose(&self, original: &str, converted: &str, separator: &str) -> String {
+        match self {
+            OutputMode::Replace => converted.to_string(),
+            OutputMode::Append => format!("{}{}{}", original, separator, converted),
+            OutputMode::Prepend => format!("{}{}{}", converted, separator, original),
+        }
+    }
+}
+
+// What happens to clipboard text longer than `max_input_bytes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LargeInputPolicy {
+    // Leave the clipboard alone
+    #[default]
+    Skip,
+    // Convert the first `max_input_bytes` and keep the rest as is
+    Prefix,
+}
+
+impl LargeInputPolicy {
+    // The part to convert and the part to keep verbatim, or None to skip.
+    // The split never falls inside a character.
+    pub fn split(self, text: &str, max_bytes: usize) -> Option<(&str, &str)> {
+        if text.len() <= max_bytes {
+            return Some((text, ""));
+        }
+        match self {
+            LargeInputPolicy::Skip => None,
+            LargeInputPolicy::Prefix => {
+                let mut boundary = max_bytes;
+                while !text.is_char_boundary(boundary) {
+                    boundary -= 1;
+                }
+                Some(text.split_at(boundary))
+            }
+        }
+    }
+}
+
+// The target the converted text is offered as. Some applications paste
+// differently depending on which one they pick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextFlavor {
+    // Whatever the clipboard backend writes
+    #[default]
+    Default,
+    Utf8String,
+    TextPlainUtf8,
+}
+
+impl TextFlavor {
+    pub fn target(self) -> Option<&'static str> {
+        match self {
+            TextFlavor::Default => None,
+            TextFlavor::Utf8String => Some("UTF8_STRING"),
+            TextFlavor::TextPlainUtf8 => Some("text/plain;charset=utf-8"),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub trigger_mode: TriggerMode,
+    pub ipc_enabled: bool,
+    pub ipc_port: u16,
+    pub secret_patterns: Vec<String>,
+    pub reload_hash: ReloadHashStrategy,
+    // Keep the compiled rules in the cache directory for faster startup
+    pub rule_cache: bool,
+    pub learn_mode: bool,
+    pub learn_window_secs: u64,
+    pub skip_whitespace_only: bool,
+    pub interval_convert_secs: Option<u64>,
+    // WM_CLASS names of apps whose gaining focus triggers a conversion
+    pub focus_convert_apps: Vec<String>,
+    pub output_mode: OutputMode,
+    pub output_separator: String,
+    pub safe_mode: bool,
+    pub cache_size: usize,
+    pub primary_selection: bool,
+    pub primary_modifier: Modifier,
+    // Copies made while this is held are not converted
+    pub skip_modifier: Option<Modifier>,
+    // Copies made while the focused window's title matches one of these globs are not converted
+    pub skip_window_titles: Vec<String>,
+    pub on_convert_feedback: ConvertFeedback,
+    pub notify_preview_chars: usize,
+    pub usage_counter: bool,
+    pub min_changed_chars: usize,
+    pub max_input_bytes: Option<usize>,
+    pub large_input_policy: LargeInputPolicy,
+    pub clipboard_timeout_ms: u64,
+    pub disabled_defaults: Vec<String>,
+    // Program and arguments the converted text is piped through; empty disables it
+    pub post_command: Vec<String>,
+    pub post_command_timeout_ms: u64,
+    pub text_flavor: TextFlavor,
+    // Use pbcopy/wl-copy/xclip when the native clipboard keeps failing
+    pub clipboard_cli_fallback: bool,
+    // Write the clipboard with OSC 52 escape sequences through the terminal
+    pub osc52: bool,
+    pub idle_pause_secs: Option<u64>,
+    pub quiet_hours: Vec<QuietWindow>,
+    // Also append each loaded config version to reload_history.jsonl
+    pub reload_history: bool,
+    // Conversions kept for `kill-zen-all history`; 0 keeps none
+    pub snapshot_limit: usize,
+    // Also keep them in snapshots.json across restarts
+    pub persist_snapshots: bool,
+    // Hashes of every conversion are appended here, without the text
+    pub audit_log: Option<PathBuf>,
+    // Shared libraries run after the conversion, with the `plugins` feature
+    pub plugins: Vec<PathBuf>,
+    // Shared replacements fetched periodically, with the `rules-url` feature
+    pub rules_url: Option<String>,
+    pub rules_url_interval_secs: u64,
+    // Convert what is already on the clipboard at startup
+    pub process_existing: bool,
+    // Loop iterations logged at debug level after an error; 0 disables
+    pub verbose_after_error: u32,
+    // A new clipboard value must stay this long before it is converted, so a
+    // burst of copies only converts the last one; 0 converts right away
+    pub coalesce_ms: u64,
+    // Log a warning when a replacement rule changes part of a URL or email address
+    pub warn_on_link_change: bool,
+    // Convert sample text twice at startup and warn if the second pass changes it
+    pub check_idempotent: bool,
+    // Keep the clipboard at its converted fixed point: every copy that differs
+    // from it is converted, whatever min_changed_chars, output_mode or
+    // process_existing say
+    pub enforce_canonical: bool,
+    // CONFIG_VERSION the file was written for
+    pub config_version: Option<u32>,
+    #[serde(flatten)]
+    pub format: FormatOptions,
+    // Whatever neither Settings nor FormatOptions took, for compatibility_warnings.
+    // Must stay after `format`, which takes its keys first.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, serde::de::IgnoredAny>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            trigger_mode: TriggerMode::default(),
+            ipc_enabled: false,
+            ipc_port: DEFAULT_IPC_PORT,
+            secret_patterns: Vec::new(),
+            reload_hash: ReloadHashStrategy::default(),
+            rule_cache: false,
+            learn_mode: false,
+            learn_window_secs: 10,
+            skip_whitespace_only: true,
+            interval_convert_secs: None,
+            focus_convert_apps: Vec::new(),
+            output_mode: OutputMode::default(),
+            output_separator: "\n".to_string(),
+            safe_mode: false,
+            cache_size: DEFAULT_CACHE_SIZE,
+            primary_selection: false,
+            primary_modifier: Modifier::default(),
+            skip_modifier: None,
+            skip_window_titles: Vec::new(),
+            on_convert_feedback: ConvertFeedback::default(),
+            notify_preview_chars: DEFAULT_NOTIFY_PREVIEW_CHARS,
+            usage_counter: false,
+            min_changed_chars: 1,
+            max_input_bytes: None,
+            large_input_policy: LargeInputPolicy::default(),
+            clipboard_timeout_ms: DEFAULT_CLIPBOARD_TIMEOUT_MS,
+            disabled_defaults: Vec::new(),
+            post_command: Vec::new(),
+            post_command_timeout_ms: DEFAULT_POST_COMMAND_TIMEOUT_MS,
+            text_flavor: TextFlavor::default(),
+            clipboard_cli_fallback: true,
+            osc52: false,
+            idle_pause_secs: None,
+            quiet_hours: Vec::new(),
+            reload_history: false,
+            snapshot_limit: DEFAULT_SNAPSHOT_LIMIT,
+            persist_snapshots: false,
+            audit_log: None,
+            plugins: Vec::new(),
+            rules_url: None,
+            rules_url_interval_secs: DEFAULT_RULES_URL_INTERVAL_SECS,
+            process_existing: false,
+            verbose_after_error: 10,
+            coalesce_ms: 0,
+            warn_on_link_change: false,
+            check_idempotent: false,
+            enforce_canonical: false,
+            config_version: None,
+            format: FormatOptions::default(),
+            unknown: BTreeMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn interval_convert(&self) -> Option<Duration> {
+        self.interval_convert_secs
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn idle_pause(&self) -> Option<Duration> {
+        self.idle_pause_secs
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    // 0 waits on the clipboard indefinitely
+    pub fn clipboard_timeout(&self) -> Option<Duration> {
+        (self.clipboard_timeout_ms > 0).then(|| Duration::from_millis(self.clipboard_timeout_ms))
+    }
+
+    // Only the continuous loop sees bursts; a manual trigger converts at once
+    pub fn coalesce_window(&self) -> Option<Duration> {
+        (self.coalesce_ms > 0 && self.trigger_mode == TriggerMode::Continuous)
+            .then(|| Duration::from_millis(self.coalesce_ms))
+    }
+
+    pub fn ipc_required(&self) -> bool {
+        self.ipc_enabled || self.trigger_mode == TriggerMode::Manual
+    }
+}
+
+pub fn get_config_dir() -> Result<PathBuf, ConfigError> {
+    resolve_config_base(env::var_os("XDG_CONFIG_HOME"), dirs::config_dir())
+        .map(|base| base.join("kill-zen-all"))
+        .ok_or(ConfigError::NoConfigDir)
+}
+
+// A shared fallback such as the temp dir would let other users plant settings
+// (plugins, post_command) there, so no config dir is an error
+fn resolve_config_base(
+    xdg_config_home: Option<OsString>,
+    platform_dir: Option<PathBuf>,
+) -> Option<PathBuf> {
+    xdg_config_home.map(PathBuf::from).or(platform_dir)
+}
+
+fn write_default_file(path: &PathBuf, content: &str) -> Result<(), ConfigError> {
+    fs::write(path, content).map_err(|source| ConfigError::Io {
+        path: path.display().to_string(),
+        source,
+    })
+}
+
+// The existing replacements/exclusions file in any supported format, or the
+// JSON path when there is none yet
+pub fn find_config_file(config_dir: &Path, stem: &str) -> PathBuf {
+    // TSV only has room for plain two-column replacement rules
+    let extensions: &[&str] = if stem == REPLACEMENTS_FILE_STEM {
+        &["json", "toml", "yaml", "yml", "tsv"]
+    } else {
+        &["json", "toml", "yaml", "yml"]
+    };
+    extensions
+        .iter()
+        .map(|ext| config_dir.join(format!("{}.{}", stem, ext)))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| config_dir.join(format!("{}.json", stem)))
+}
+
+pub fn find_combined_config(config_dir: &Path) -> Option<PathBuf> {
+    ["json", "toml", "yaml", "yml"]
+        .iter()
+        .map(|ext| config_dir.join(format!("{}.{}", COMBINED_CONFIG_FILE_STEM, ext)))
+        .find(|path| path.exists())
+}
+
+// config.json when there is one, settings.json otherwise
+pub fn resolve_settings_file(config_dir: &Path) -> PathBuf {
+    find_combined_config(config_dir).unwrap_or_else(|| config_dir.join(SETTINGS_FILE_NAME))
+}
+
+// Nearest `.kill-zen-all/` directory at or above `start`, like .git discovery
+pub fn find_project_config_dir(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_DIR_NAME))
+        .find(|dir| dir.is_dir())
+}
+
+// Prefers the project's file and falls back to the global one per file, so a
+// project can override just its replacements and keep the global exclusions.
+// Exclusions are layered instead, see `project_exclusions_file`.
+// A combined config.json counts as the file in its directory.
+pub fn resolve_config_file(project_dir: Option<&Path>, global_dir: &Path, stem: &str) -> PathBuf {
+    project_dir
+        .map(|dir| find_combined_config(dir).unwrap_or_else(|| find_config_file(dir, stem)))
+        .filter(|path| path.exists())
+        .or_else(|| find_combined_config(global_dir))
+        .unwrap_or_else(|| find_config_file(global_dir, stem))
+}
+
+// KZA_REPLACEMENTS (a PATH-style list, `:`-separated on Unix) takes precedence
+// over the project and global files and bypasses the config dir entirely.
+// Rules are merged in list order.
+pub fn resolve_replacement_files(
+    env_value: Option<&OsStr>,
+    project_dir: Option<&Path>,
+    global_dir: &Path,
+) -> Vec<PathBuf> {
+    let from_env: Vec<PathBuf> = env_value
+        .map(|value| {
+            env::split_paths(value)
+                .filter(|path| !path.as_os_str().is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    if from_env.is_empty() {
+        vec![resolve_config_file(
+            project_dir,
+            global_dir,
+            REPLACEMENTS_FILE_STEM,
+        )]
+    } else {
+        from_env
+    }
+}
+
+pub fn load_replacement_files(paths: &[PathBuf]) -> Result<Vec<Replacement>, ConfigError> {
+    let mut replacements = Vec::new();
+    for path in paths {
+        let path = path
+            .to_str()
+            .ok_or_else(|| ConfigError::InvalidPath(path.display().to_string()))?;
+        replacements.extend(load_replacements(path)?);
+    }
+    Ok(replacements)
+}
+
+pub fn project_exclusions_file(project_dir: Option<&Path>) -> Option<PathBuf> {
+    project_dir
+        .map(|dir| {
+            find_combined_config(dir).unwrap_or_else(|| find_config_file(dir, EXCLUSIONS_FILE_STEM))
+        })
+        .filter(|path| path.exists())
+}
+
+pub fn create_default_config() -> Result<(), ConfigError> {
+    create_default_config_in(ConfigFormat::Json)
+}
+
+pub fn create_default_config_in(format: ConfigFormat) -> Result<(), ConfigError> {
+    write_default_config(&get_config_dir()?, format)
+}
+
+pub(crate) fn write_default_config(
+    config_dir: &Path,
+    format: ConfigFormat,
+) -> Result<(), ConfigError> {
+    if !config_dir.exists() {
+        fs::create_dir_all(config_dir).map_err(|source| ConfigError::Io {
+            path: config_dir.display().to_string(),
+            source,
+        })?;
+    }
+    if find_combined_config(config_dir).is_some() {
+        return Ok(());
+    }
+    if !find_config_file(config_dir, REPLACEMENTS_FILE_STEM).exists() {
+        let replacement_path =
+            config_dir.join(format!("{}.{}", REPLACEMENTS_FILE_STEM, format.extension()));
+        let content = match format {
+            // Keep the hand-formatted JSON as is
+            ConfigFormat::Json => DEFAULT_REPLACEMENTS.to_string(),
+            ConfigFormat::Toml => format.serialize(&ReplacementTable {
+                replacements: default_replacements(),
+            })?,
+            ConfigFormat::Yaml => format.serialize(&default_replacements())?,
+        };
+        write_default_file(&replacement_path, &content)?;
+        write_defaults_version(config_dir, DEFAULTS_VERSION)?;
+        info!(
+            "Created default replacements file: {}",
+            replacement_path.display()
+        );
+    }
+    if !find_config_file(config_dir, EXCLUSIONS_FILE_STEM).exists() {
+        let exclusion_path =
+            config_dir.join(format!("{}.{}", EXCLUSIONS_FILE_STEM, format.extension()));
+        let content = match format {
+            ConfigFormat::Json => DEFAULT_EXCLUSIONS.to_string(),
+            ConfigFormat::Toml | ConfigFormat::Yaml => format.serialize(&ExclusionsOut {
+                exclude: &default_exclusions(),
+            })?,
+        };
+        write_default_file(&exclusion_path, &content)?;
+        info!(
+            "Created default exclusions file: {}",
+            exclusion_path.display()
+        );
+    }
+    Ok(())
+}
+
+// For writing a generated exclusion list in the file's own format
+pub(crate) fn serialize_exclusions(path: &Path, exclude: &[char]) -> Result<String, ConfigError> {
+    ConfigFormat::from_path(path).serialize(&ExclusionsOut { exclude })
+}
+
+// For writing rules back to an existing file in its own format
+pub(crate) fn serialize_replacements(
+    path: &Path,
+    replacements: &[Replacement],
+) -> Result<String, ConfigError> {
+    if path.extension().and_then(|ext| ext.to_str()) == Some("tsv") {
+        return Err(ConfigError::Serialize {
+            format: "TSV".to_string(),
+            message: "rules cannot be written back to a TSV file".to_string(),
+        });
+    }
+    match ConfigFormat::from_path(path) {
+        ConfigFormat::Toml => ConfigFormat::Toml.serialize(&ReplacementTable {
+            replacements: replacements.to_vec(),
+        }),
+        format @ (ConfigFormat::Json | ConfigFormat::Yaml) => format.serialize(&replacements),
+    }
+}
+
+// The embedded defaults are known-good, so failing to parse them is a build bug
+fn default_replacements() -> Vec<Replacement> {
+    serde_json::from_str(DEFAULT_REPLACEMENTS).expect("default replacements are valid")
+}
+
+fn bundled_exclusions() -> Exclusions {
+    serde_json::from_str(DEFAULT_EXCLUSIONS).expect("default exclusions are valid")
+}
+
+fn default_exclusions() -> Vec<char> {
+    bundled_exclusions()
+        .exclude
+        .into_iter()
+        .map(|entry| entry.c)
+        .collect()
+}
+
+fn read_config_text(file_path: &str) -> Result<String, ConfigError> {
+    fs::read_to_string(file_path).map_err(|source| ConfigError::Io {
+        path: file_path.to_string(),
+        source,
+    })
+}
+
+// Ok(None) is an empty file. Read errors other than a cut character are not retried.
+fn retry_mid_write<T>(
+    mut read: impl FnMut() -> Result<Option<T>, ConfigError>,
+) -> Result<Option<T>, ConfigError> {
+    let mut attempts = 0;
+    loop {
+        match read() {
+            Ok(None) if attempts < MID_WRITE_RETRIES => {
+                debug!("Read an empty file, possibly mid-write. Reading again...");
+            }
+            Err(e) if is_mid_write_error(&e) && attempts < MID_WRITE_RETRIES => {
+                debug!("{}, possibly mid-write. Reading again...", e);
+            }
+            result => return result,
+        }
+        attempts += 1;
+        std::thread::sleep(MID_WRITE_RETRY_DELAY);
+    }
+}
+
+fn is_mid_write_error(error: &ConfigError) -> bool {
+    match error {
+        ConfigError::Io { source, .. } => source.kind() == std::io::ErrorKind::InvalidData,
+        ConfigError::Parse { .. }
+        | ConfigError::ParseToml { .. }
+        | ConfigError::ParseYaml { .. }
+        | ConfigError::ParseTsv { .. } => true,
+        _ => false,
+    }
+}
+
+// Reads and parses the file again while it looks mid-write; only a file that
+// stays empty means "no entries"
+fn load_config_text<T>(
+    mut read: impl FnMut() -> Result<String, ConfigError>,
+    parse: impl Fn(&str) -> Result<T, ConfigError>,
+) -> Result<Option<T>, ConfigError> {
+    retry_mid_write(|| {
+        let data = read()?;
+        if data.trim().is_empty() {
+            return Ok(None);
+        }
+        parse(&data).map(Some)
+    })
+}
+
+fn parse_config_str<T>(file_path: &str, data: &str) -> Result<T, ConfigError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let path = file_path.to_string();
+    match ConfigFormat::from_path(Path::new(file_path)) {
+        ConfigFormat::Json => {
+            serde_json::from_str(data).map_err(|source| ConfigError::Parse { path, source })
+        }
+        ConfigFormat::Toml => {
+            toml::from_str(data).map_err(|source| ConfigError::ParseToml { path, source })
+        }
+        ConfigFormat::Yaml => {
+            serde_yaml::from_str(data).map_err(|source| ConfigError::ParseYaml { path, source })
+        }
+    }
+}
+
+fn load_config_file<T>(file_path: &str) -> Result<T, ConfigError>
+where
+    T: serde::de::DeserializeOwned + Default,
+{
+    let parsed = load_config_text(
+        || read_config_text(file_path),
+        |data| parse_config_str(file_path, data),
+    )?;
+    Ok(parsed.unwrap_or_else(|| {
+        debug!("{} is empty; using no entries.", file_path);
+        T::default()
+    }))
+}
+
+pub fn load_replacements(file_path: &str) -> Result<Vec<Replacement>, ConfigError> {
+    let mut warnings = Vec::new();
+    let replacements = load_checked_replacements(file_path, &mut warnings)?;
+    log_warnings(&warnings);
+    Ok(replacements)
+}
+
+// load_replacements with its warnings collected rather than logged, for
+// callers that keep them, like the rule cache
+pub fn load_checked_replacements(
+    file_path: &str,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<Replacement>, ConfigError> {
+    Ok(check_rules(
+        file_path,
+        read_replacements(file_path)?,
+        warnings,
+    ))
+}
+
+fn log_warnings(warnings: &[String]) {
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+}
+
+// No-op rules and rules whose patterns do not compile are dropped;
+// self-containing ones are kept but flagged
+fn check_rules(
+    file_path: &str,
+    replacements: Vec<Replacement>,
+    warnings: &mut Vec<String>,
+) -> Vec<Replacement> {
+    replacements
+        .into_iter()
+        .enumerate()
+        .filter(|(index, rule)| {
+            if let Err(e) = check_rule_patterns(*index, rule) {
+                let reason = std::error::Error::source(&e)
+                    .map_or_else(|| e.to_string(), ToString::to_string);
+                warnings.push(format!(
+                    "{}: ignoring rule {} ('{}'), which cannot run: {}",
+                    file_path,
+                    index + 1,
+                    rule.original,
+                    reason
+                ));
+                return false;
+            }
+            if rule.is_noop() {
+                warnings.push(format!(
+                    "{}: ignoring the rule '{}' -> '{}', which changes nothing.",
+                    file_path, rule.original, rule.replacement
+                ));
+                return false;
+            }
+            if rule.is_self_containing() {
+                warnings.push(format!(
+                    "{}: the rule '{}' -> '{}' puts '{}' back into its output, so the text grows each time it is converted again.",
+                    file_path, rule.original, rule.replacement, rule.original
+                ));
+            }
+            true
+        })
+        .map(|(_, rule)| rule)
+        .collect()
+}
+
+// The rules as written, no-ops included, for tools that write the file back
+pub(crate) fn read_replacements(file_path: &str) -> Result<Vec<Replacement>, ConfigError> {
+    if is_combined_config(Path::new(file_path)) {
+        return Ok(load_config_file::<CombinedConfig>(file_path)?.replacements);
+    }
+    if Path::new(file_path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        == Some("tsv")
+    {
+        let parsed = load_config_text(
+            || read_config_text(file_path),
+            |data| {
+                parse_tsv_replacements(data).map_err(|(line, message)| ConfigError::ParseTsv {
+                    path: file_path.to_string(),
+                    line,
+                    message,
+                })
+            },
+        )?;
+        return Ok(parsed.unwrap_or_default());
+    }
+    match ConfigFormat::from_path(Path::new(file_path)) {
+        ConfigFormat::Toml => Ok(load_config_file::<ReplacementTable>(file_path)?.replacements),
+        ConfigFormat::Json | ConfigFormat::Yaml => load_config_file(file_path),
+    }
+}
+
+// One `original<TAB>replacement` rule per line. Blank lines and lines starting
+// with `#` are skipped; `\t`, `\n`, `\r` and `\\` escape characters that
+// cannot appear literally. Errors carry the 1-based line number.
+fn parse_tsv_replacements(data: &str) -> Result<Vec<Replacement>, (usize, String)> {
+    let mut replacements = Vec::new();
+    for (index, line) in data.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut columns = line.split('\t');
+        let (Some(original), Some(replacement), None) =
+            (columns.next(), columns.next(), columns.next())
+        else {
+            return Err((
+                line_number,
+                "expected exactly two tab-separated columns".to_string(),
+            ));
+        };
+        let original = unescape_tsv(original).map_err(|e| (line_number, e))?;
+        if original.is_empty() {
+            return Err((line_number, "`original` must not be empty".to_string()));
+        }
+        replacements.push(Replacement {
+            original,
+            replacement: unescape_tsv(replacement).map_err(|e| (line_number, e))?,
+            ..Default::default()
+        });
+    }
+    Ok(replacements)
+}
+
+fn unescape_tsv(field: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => return Err(format!("unknown escape '\\{}'", other)),
+            None => return Err("trailing backslash".to_string()),
+        }
+    }
+    Ok(unescaped)
+}
+
+pub fn load_exclusion_list(file_path: &str) -> Result<Vec<char>, ConfigError> {
+    let mut warnings = Vec::new();
+    let exclusions = layer_on_defaults(load_exclusions_file(file_path, &mut warnings)?);
+    log_warnings(&warnings);
+    Ok(exclusions
+        .exclude
+        .into_iter()
+        .filter(|entry| entry.stages.is_empty())
+        .map(|entry| entry.c)
+        .collect())
+}
+
+// The global exclusions plus the layer's `exclude`, minus its `unexclude`
+pub fn load_layered_exclusions(
+    base_path: &str,
+    layer_path: Option<&str>,
+) -> Result<Vec<char>, ConfigError> {
+    let exclusions = load_layered_entries(base_path, layer_path)?;
+    Ok(exclusions
+        .into_iter()
+        .filter(|entry| entry.stages.is_empty())
+        .map(|entry| entry.c)
+        .collect())
+}
+
+// load_layered_exclusions and load_stage_exclusions from one read, with the
+// warnings collected rather than logged
+pub fn load_checked_exclusions(
+    base_path: &str,
+    layer_path: Option<&str>,
+    warnings: &mut Vec<String>,
+) -> Result<(Vec<char>, StageExclusions), ConfigError> {
+    let (exclusion_list, stage_exclusions): (Vec<ExcludedChar>, Vec<ExcludedChar>) =
+        read_layered_entries(base_path, layer_path, warnings)?
+            .into_iter()
+            .partition(|entry| entry.stages.is_empty());
+    Ok((
+        exclusion_list.into_iter().map(|entry| entry.c).collect(),
+        stage_exclusions
+            .into_iter()
+            .map(|entry| (entry.c, entry.stages))
+            .collect(),
+    ))
+}
+
+// The entries limited to some stages, layered like load_layered_exclusions
+pub fn load_stage_exclusions(
+    base_path: &str,
+    layer_path: Option<&str>,
+) -> Result<StageExclusions, ConfigError> {
+    let exclusions = load_layered_entries(base_path, layer_path)?;
+    Ok(exclusions
+        .into_iter()
+        .filter(|entry| !entry.stages.is_empty())
+        .map(|entry| (entry.c, entry.stages))
+        .collect())
+}
+
+// Reasons given for the effective exclusions; a layer's reason wins over the global one
+pub fn load_exclusion_reasons(
+    base_path: &str,
+    layer_path: Option<&str>,
+) -> Result<BTreeMap<char, String>, ConfigError> {
+    let exclusions = load_layered_entries(base_path, layer_path)?;
+    Ok(exclusions
+        .into_iter()
+        .filter_map(|entry| Some((entry.c, entry.reason?)))
+        .collect())
+}
+
+fn load_layered_entries(
+    base_path: &str,
+    layer_path: Option<&str>,
+) -> Result<Vec<ExcludedChar>, ConfigError> {
+    let mut warnings = Vec::new();
+    let exclusions = read_layered_entries(base_path, layer_path, &mut warnings)?;
+    log_warnings(&warnings);
+    Ok(exclusions)
+}
+
+fn read_layered_entries(
+    base_path: &str,
+    layer_path: Option<&str>,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<ExcludedChar>, ConfigError> {
+    let mut exclusions = layer_on_defaults(load_exclusions_file(base_path, warnings)?).exclude;
+    if let Some(layer_path) = layer_path {
+        let layer = load_exclusions_file(layer_path, warnings)?;
+        merge_exclusions(&mut exclusions, layer);
+    }
+    Ok(exclusions)
+}
+
+fn load_exclusions_file(
+    file_path: &str,
+    warnings: &mut Vec<String>,
+) -> Result<Exclusions, ConfigError> {
+    let mut exclusions = if is_combined_config(Path::new(file_path)) {
+        let combined: CombinedConfig = load_config_file(file_path)?;
+        Exclusions {
+            exclude: combined.exclusions,
+            exclude_ranges: combined.exclude_ranges,
+            unexclude: combined.unexclude,
+            extend_defaults: combined.extend_default_exclusions,
+        }
+    } else {
+        load_config_file(file_path)?
+    };
+    add_ranges(file_path, &mut exclusions, warnings);
+    Ok(exclusions)
+}
+
+// `exclude_ranges` into `exclude`, after warning about the ranges
+fn add_ranges(label: &str, exclusions: &mut Exclusions, warnings: &mut Vec<String>) {
+    let (ranges, range_warnings) = check_exclude_ranges(&exclusions.exclude_ranges);
+    warnings.extend(
+        range_warnings
+            .into_iter()
+            .map(|warning| format!("{}: {}", label, warning)),
+    );
+    let mut listed: HashSet<char> = exclusions.exclude.iter().map(|entry| entry.c).collect();
+    for range in ranges {
+        for c in range.start..=range.end {
+            if listed.insert(c) {
+                exclusions.exclude.push(ExcludedChar {
+                    c,
+                    reason: None,
+                    stages: Vec::new(),
+                });
+            }
+        }
+    }
+}
+
+// load_replacements over JSON already in memory, e.g. from the browser
+// playground; `label` stands in for the path in errors and warnings
+pub fn parse_replacements(label: &str, json: &str) -> Result<Vec<Replacement>, ConfigError> {
+    if json.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let replacements = serde_json::from_str(json).map_err(|source| ConfigError::Parse {
+        path: label.to_string(),
+        source,
+    })?;
+    let mut warnings = Vec::new();
+    let replacements = check_rules(label, replacements, &mut warnings);
+    log_warnings(&warnings);
+    Ok(replacements)
+}
+
+// load_exclusion_list over JSON already in memory
+pub fn parse_exclusion_list(label: &str, json: &str) -> Result<Vec<char>, ConfigError> {
+    let mut exclusions = if json.trim().is_empty() {
+        Exclusions::default()
+    } else {
+        serde_json::from_str(json).map_err(|source| ConfigError::Parse {
+            path: label.to_string(),
+            source,
+        })?
+    };
+    let mut warnings = Vec::new();
+    add_ranges(label, &mut exclusions, &mut warnings);
+    log_warnings(&warnings);
+    Ok(layer_on_defaults(exclusions)
+        .exclude
+        .into_iter()
+        .filter(|entry| entry.stages.is_empty())
+        .map(|entry| entry.c)
+        .collect())
+}
+
+// Only the global file can extend the defaults; a project layers on the global list
+fn layer_on_defaults(exclusions: Exclusions) -> Exclusions {
+    if !exclusions.extend_defaults {
+        return exclusions;
+    }
+    let mut layered = bundled_exclusions();
+    merge_exclusions(&mut layered.exclude, exclusions);
+    layered
+}
+
+fn merge_exclusions(exclusions: &mut Vec<ExcludedChar>, layer: Exclusions) {
+    for entry in layer.exclude {
+        match exclusions.iter_mut().find(|existing| existing.c == entry.c) {
+            Some(existing) => {
+                if entry.reason.is_some() {
+                    existing.reason = entry.reason;
+                }
+                // The layer decides which stages its character is excluded from
+                existing.stages = entry.stages;
+            }
+            None => exclusions.push(entry),
+        }
+    }
+    let removed: Vec<char> = layer.unexclude.into_iter().map(|entry| entry.c).collect();
+    exclusions.retain(|entry| !removed.contains(&entry.c));
+}
+
+// Excluding fullwidth letters is almost always a mistake (e.g. OCR output like
+// ｈｅｌｌｏ then stays fullwidth), so these are reported at startup
+pub fn fullwidth_latin_exclusions(exclusions: &[char]) -> Vec<char> {
+    let mut letters: Vec<char> = exclusions
+        .iter()
+        .copied()
+        .filter(|c| matches!(c, 'Ａ'..='Ｚ' | 'ａ'..='ｚ'))
+        .collect();
+    letters.sort_unstable();
+    letters.dedup();
+    letters
+}
+
+pub fn load_settings(file_path: &str) -> Result<Settings, ConfigError> {
+    if !PathBuf::from(file_path).exists() {
+        return Ok(Settings::default());
+    }
+    let settings = if is_combined_config(Path::new(file_path)) {
+        load_config_file::<CombinedConfig>(file_path)?.settings
+    } else {
+        load_config_file(file_path)?
+    };
+    for warning in compatibility_warnings(&settings, DEPRECATED_SETTINGS) {
+        warn!("{}: {}", file_path, warning);
+    }
+    Ok(settings)
+}
+
+// Unknown and deprecated keys are ignored rather than rejected, so a settings
+// file shared between versions still loads; these say what was ignored
+pub fn compatibility_warnings(settings: &Settings, deprecated: &[(&str, &str)]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if let Some(version) = settings.config_version.filter(|&v| v > CONFIG_VERSION) {
+        warnings.push(format!(
+            "written for config_version {}, but this kill-zen-all understands {}. Newer settings are ignored.",
+            version, CONFIG_VERSION
+        ));
+    }
+    for key in settings.unknown.keys() {
+        match deprecated.iter().find(|(name, _)| name == key) {
+            Some((_, advice)) => {
+                warnings.push(format!("`{}` is deprecated and ignored. {}", key, advice))
+            }
+            None => warnings.push(format!(
+                "unknown setting `{}` is ignored. Check the spelling, or update kill-zen-all.",
+                key
+            )),
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Aggressiveness;
+    use crate::reload::calculate_hash;
+
+    // Test for get_config_dir
+    use std::env;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_config_dir_fallback() {
+        let xdg = tempdir().unwrap();
+        let platform = PathBuf::from("/platform/config");
+        assert_eq!(
+            resolve_config_base(Some(xdg.path().into()), Some(platform.clone())),
+            Some(xdg.path().to_path_buf())
+        );
+        assert_eq!(
+            resolve_config_base(None, Some(platform.clone())),
+            Some(platform)
+        );
+        // No platform dir: refuse rather than share the temp dir with other users
+        assert_eq!(resolve_config_base(None, None), None);
+    }
+
+    #[test]
+    fn test_create_default_config() {
+        // 一時ディレクトリを作成
+        let temp_dir = tempdir().unwrap();
+        let temp_path = temp_dir.path().to_path_buf();
+
+        // 一時ディレクトリをXDG_CONFIG_HOMEに設定
+        env::set_var("XDG_CONFIG_HOME", &temp_path);
+
+        // create_default_config()を呼び出す
+        create_default_config().unwrap();
+
+        // 設定ファイルが正しい場所に作成されたかを確認
+        let replacements_path = temp_path.join("kill-zen-all").join("replacements.json");
+        let exclusions_path = temp_path.join("kill-zen-all").join("exclusions.json");
+
+        assert!(
+            replacements_path.exists(),
+            "replacements.json が存在しません"
+        );
+        assert!(exclusions_path.exists(), "exclusions.json が存在しません");
+
+        // replacements.json の内容を検証
+        let replacements_content =
+            fs::read_to_string(&replacements_path).expect("Failed to read replacements.json");
+        let expected_replacements_content = r#"[
+  { "original": "，", "replacement": ", " },
+  { "original": "．", "replacement": ". " },
+  { "original": "CRLF", "replacement": "。" },
+  { "original": "頚", "replacement": "頸" }
+]"#
+        .trim(); // テスト用に改行とインデントを除去
+
+        assert_eq!(replacements_content.trim(), expected_replacements_content);
+
+        // exclusions.json の内容を検証
+        let exclusions_content =
+            fs::read_to_string(&exclusions_path).expect("Failed to read exclusions.json");
+        let expected_exclusions_content = r#"{
+  "exclude": ["　", "！", "？", "〜", "～"]
+}"#
+        .trim(); // テスト用に改行とインデントを除去
+
+        assert_eq!(exclusions_content.trim(), expected_exclusions_content);
+
+        // 環境変数のクリーンアップ
+        env::remove_var("XDG_CONFIG_HOME");
+    }
+    // Test for load_replacements
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn test_load_replacements() {
+        let test_data = r#"
+        [
+            {"original": "foo", "replacement": "bar"},
+            {"original": "baz", "replacement": "qux"}
+        ]
+        "#;
+
+        // Create a test file
+        let file_path = "test_replacements.json";
+        let mut file = File::create(file_path).unwrap();
+        file.write_all(test_data.as_bytes()).unwrap();
+
+        let replacements = load_replacements(file_path).unwrap();
+        assert_eq!(replacements.len(), 2);
+        assert_eq!(replacements[0].original, "foo");
+        assert_eq!(replacements[0].replacement, "bar");
+        assert_eq!(replacements[1].original, "baz");
+        assert_eq!(replacements[1].replacement, "qux");
+
+        // Remove the test file
+        fs::remove_file(file_path).unwrap();
+    }
+
+    // Test for reading a file caught mid-write
+    #[test]
+    fn test_mid_write_read_is_retried() {
+        let mut reads = 0;
+        let result = retry_mid_write(|| {
+            reads += 1;
+            if reads == 1 {
+                // What read_to_string reports for a cut multi-byte character
+                return Err(ConfigError::Io {
+                    path: "replacements.json".to_string(),
+                    source: std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "stream did not contain valid UTF-8",
+                    ),
+                });
+            }
+            Ok(Some("[]".to_string()))
+        });
+        assert_eq!(result.unwrap().unwrap(), "[]");
+        assert_eq!(reads, 2);
+    }
+
+    #[test]
+    fn test_persistent_invalid_utf8_still_fails() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(&file_path, b"[{\"original\": \"\xef\xbc").unwrap();
+        let result = load_replacements(file_path.to_str().unwrap());
+        assert!(matches!(result, Err(ConfigError::Io { .. })));
+
+        // Other errors are not retried
+        let mut reads = 0;
+        let result = retry_mid_write::<String>(|| {
+            reads += 1;
+            Err(ConfigError::Io {
+                path: "replacements.json".to_string(),
+                source: std::io::Error::from(std::io::ErrorKind::NotFound),
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(reads, 1);
+    }
+
+    #[test]
+    fn test_truncated_file_is_read_again() {
+        // An editor that truncates before writing shows an empty file first
+        let mut reads = 0;
+        let result = retry_mid_write(|| {
+            reads += 1;
+            Ok((reads > 1).then_some(1))
+        });
+        assert_eq!(result.unwrap(), Some(1));
+        assert_eq!(reads, 2);
+    }
+
+    #[test]
+    fn test_file_completed_during_retry_loads() {
+        // What a reader can see while an editor truncates and rewrites the file
+        let snapshots = [
+            "",
+            r#"[{"original": "ｆ", "repla"#,
+            r#"[{"original": "ｆ", "replacement": "f"}]"#,
+        ];
+        let mut reads = snapshots.iter();
+        let replacements: Vec<Replacement> = load_config_text(
+            || Ok(reads.next().unwrap().to_string()),
+            |data| parse_config_str("replacements.json", data),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(replacements[0].original, "ｆ");
+        assert!(reads.next().is_none());
+    }
+
+    // Test for load_replacements with nonexistent file
+    #[test]
+    fn test_load_replacements_no_file() {
+        let file_path = "nonexistent.json";
+        let result = load_replacements(file_path);
+        assert!(result.is_err());
+    }
+
+    // Test for load_replacements with invalid JSON
+    #[test]
+    fn test_load_replacements_invalid_json() {
+        let test_data = r#"
+        [
+            {"original": "foo", "replacement": "bar"},
+            {"original": "baz", "replacement": "qux"}
+        "#;
+
+        // Create a test file
+        let file_path = "test_invalid_replacements.json";
+        let mut file = File::create(file_path).unwrap();
+        file.write_all(test_data.as_bytes()).unwrap();
+
+        let result = load_replacements(file_path);
+        println!("{:?}", result);
+        assert!(result.is_err());
+
+        // Remove the test file
+        fs::remove_file(file_path).unwrap();
+    }
+
+    // Test for load_exclusion_list
+    #[test]
+    fn test_load_exclusion_list() {
+        let test_data = r#"
+        {
+            "exclude": ["！", "？"]
+        }
+        "#;
+
+        // Create a test file
+        let file_path = "test_exclusions.json";
+        let mut file = File::create(file_path).unwrap();
+        file.write_all(test_data.as_bytes()).unwrap();
+
+        let exclusions = load_exclusion_list(file_path).unwrap();
+        assert_eq!(exclusions.len(), 2);
+        assert_eq!(exclusions[0], '！');
+        assert_eq!(exclusions[1], '？');
+
+        // Remove the test file
+        fs::remove_file(file_path).unwrap();
+    }
+
+    // Test for load_exclusion_list with nonexistent file
+    #[test]
+    fn test_load_exclusion_list_no_file() {
+        let file_path = "nonexistent.json";
+        let result = load_exclusion_list(file_path);
+        assert!(result.is_err());
+    }
+
+    // Test for load_exclusion_list with invalid JSON
+    #[test]
+    fn test_load_exclusion_list_invalid_json() {
+        let test_data = r#"
+        {
+            "exclude": ["！", "？
+        }
+        "#;
+
+        // Create a test file
+        let file_path = "test_invalid_exclusions.json";
+        let mut file = File::create(file_path).unwrap();
+        file.write_all(test_data.as_bytes()).unwrap();
+
+        let result = load_exclusion_list(file_path);
+        assert!(result.is_err());
+
+        // Remove the test file
+        fs::remove_file(file_path).unwrap();
+    }
+
+    // Test for delete action
+    #[test]
+    fn test_load_replacements_delete_action() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &file_path,
+            r#"[
+                {"original": "foo", "action": "delete"},
+                {"original": "bar", "replacement": ""}
+            ]"#,
+        )
+        .unwrap();
+
+        let replacements = load_replacements(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(replacements[0].action, ReplacementAction::Delete);
+        assert_eq!(replacements[0].replacement, "");
+        assert!(replacements[0].is_deletion());
+        assert_eq!(replacements[1].action, ReplacementAction::Replace);
+        assert!(replacements[1].is_deletion());
+    }
+
+    // Test for self-loop checks
+    #[test]
+    fn test_load_replacements_drops_noop_rules() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &file_path,
+            r#"[
+                {"original": "foo", "replacement": "foo"},
+                {"original": "a", "replacement": "xa"},
+                {"original": "ｂａｒ", "replacement": "bar"},
+                {"original": "ＡＢＣ", "replacement": "ＡＢＣ", "protect_output": true}
+            ]"#,
+        )
+        .unwrap();
+
+        let replacements = load_replacements(file_path.to_str().unwrap()).unwrap();
+        let originals: Vec<&str> = replacements.iter().map(|r| r.original.as_str()).collect();
+        assert_eq!(originals, vec!["a", "ｂａｒ", "ＡＢＣ"]);
+    }
+
+    #[test]
+    fn test_load_replacements_drops_rules_that_cannot_run() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &file_path,
+            r#"[
+                {"original": "(", "action": "convert_width"},
+                {"original": "foo", "replacement": "bar"},
+                {"original": "x", "replacement": "y", "context_after": "["}
+            ]"#,
+        )
+        .unwrap();
+
+        let mut warnings = Vec::new();
+        let replacements =
+            load_checked_replacements(file_path.to_str().unwrap(), &mut warnings).unwrap();
+        let originals: Vec<&str> = replacements.iter().map(|r| r.original.as_str()).collect();
+        assert_eq!(originals, vec!["foo"]);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("ignoring rule 1 ('(')"));
+        assert!(warnings[1].contains("ignoring rule 3 ('x')"));
+    }
+
+    #[test]
+    fn test_self_containing_rules_are_flagged() {
+        let rule = |original: &str, replacement: &str| Replacement {
+            original: original.to_string(),
+            replacement: replacement.to_string(),
+            ..Default::default()
+        };
+        assert!(rule("a", "xa").is_self_containing());
+        assert!(rule("ab", "abab").is_self_containing());
+        assert!(!rule("foo", "foo").is_self_containing());
+        assert!(rule("foo", "foo").is_noop());
+        assert!(!rule("ａ", "a").is_self_containing());
+        // Only plain replacements; a collapse_runs set like " " -> " " is useful
+        let collapse = Replacement {
+            action: ReplacementAction::CollapseRuns,
+            ..rule(" ", " ")
+        };
+        assert!(!collapse.is_noop());
+        // A keep-as-is rule
+        let keep = Replacement {
+            protect_output: true,
+            ..rule("ＡＢＣ", "ＡＢＣ")
+        };
+        assert!(!keep.is_noop());
+    }
+
+    #[test]
+    fn test_load_replacements_missing_replacement_is_rejected() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(&file_path, r#"[{"original": "foo"}]"#).unwrap();
+
+        let result = load_replacements(file_path.to_str().unwrap());
+        assert!(matches!(result, Err(ConfigError::Parse { .. })));
+
+        // The same text is valid once escaped
+        fs::write(
+            &file_path,
+            r#"[{"original": "(unclosed", "action": "convert_width", "literal_escape": true}]"#,
+        )
+        .unwrap();
+        let replacements = load_replacements(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(replacements[0].pattern().unwrap(), r"\(unclosed");
+
+        fs::write(
+            &file_path,
+            r#"[{"original": "a", "replacement": "b", "literal_escape": true}]"#,
+        )
+        .unwrap();
+        assert!(load_replacements(file_path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_replacements_max_matches() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &file_path,
+            r#"[{"original": "\\d+", "action": "convert_width", "max_matches": 2}]"#,
+        )
+        .unwrap();
+        let replacements = load_replacements(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(replacements[0].max_matches, Some(2));
+
+        // Plain rules have no regex to bound
+        fs::write(
+            &file_path,
+            r#"[{"original": "a", "replacement": "b", "max_matches": 2}]"#,
+        )
+        .unwrap();
+        assert!(load_replacements(file_path.to_str().unwrap()).is_err());
+
+        // 0 would silently turn the rule off
+        fs::write(
+            &file_path,
+            r#"[{"original": "\d+", "action": "convert_width", "max_matches": 0}]"#,
+        )
+        .unwrap();
+        assert!(load_replacements(file_path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_replacements_delete_with_replacement_is_rejected() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &file_path,
+            r#"[{"original": "foo", "replacement": "bar", "action": "delete"}]"#,
+        )
+        .unwrap();
+
+        let result = load_replacements(file_path.to_str().unwrap());
+        assert!(matches!(result, Err(ConfigError::Parse { .. })));
+    }
+
+    // Test for error variants
+    #[test]
+    fn test_load_replacements_no_file_is_io_error() {
+        let result = load_replacements("nonexistent.json");
+        assert!(matches!(result, Err(ConfigError::Io { .. })));
+    }
+
+    #[test]
+    fn test_load_replacements_invalid_json_is_parse_error() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(&file_path, r#"[{"original": "foo""#).unwrap();
+
+        let result = load_replacements(file_path.to_str().unwrap());
+        assert!(matches!(result, Err(ConfigError::Parse { .. })));
+    }
+
+    #[test]
+    fn test_load_exclusion_list_error_variants() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("exclusions.json");
+        fs::write(&file_path, r#"{"exclude": "！"}"#).unwrap();
+
+        assert!(matches!(
+            load_exclusion_list("nonexistent.json"),
+            Err(ConfigError::Io { .. })
+        ));
+        assert!(matches!(
+            load_exclusion_list(file_path.to_str().unwrap()),
+            Err(ConfigError::Parse { .. })
+        ));
+    }
+
+    // Test for load_settings
+    #[test]
+    fn test_load_settings_defaults_when_missing() {
+        let settings = load_settings("nonexistent_settings.json").unwrap();
+        assert_eq!(settings.trigger_mode, TriggerMode::Continuous);
+        assert!(!settings.ipc_required());
+    }
+
+    #[test]
+    fn test_load_settings_manual_trigger_mode() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("settings.json");
+        fs::write(&file_path, r#"{ "trigger_mode": "manual" }"#).unwrap();
+
+        let settings = load_settings(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(settings.trigger_mode, TriggerMode::Manual);
+        assert_eq!(settings.ipc_port, DEFAULT_IPC_PORT);
+        assert!(settings.ipc_required());
+        assert!(!settings.format.smart_spacing);
+    }
+
+    #[test]
+    fn test_load_settings_format_options() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("settings.json");
+        fs::write(&file_path, r#"{ "smart_spacing": true }"#).unwrap();
+
+        let settings = load_settings(file_path.to_str().unwrap()).unwrap();
+        assert!(settings.format.smart_spacing);
+    }
+
+    // Test for compatibility_warnings
+    #[test]
+    fn test_unknown_settings_warn() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join(SETTINGS_FILE_NAME);
+        fs::write(
+            &file_path,
+            r#"{"ipc_enabeld": true, "aggressiveness": "high", "safe_mode": true}"#,
+        )
+        .unwrap();
+        let settings = load_settings(file_path.to_str().unwrap()).unwrap();
+        // Known keys, including the flattened format options, still load
+        assert!(settings.safe_mode);
+        assert_eq!(settings.format.aggressiveness, Aggressiveness::High);
+        let warnings = compatibility_warnings(&settings, &[]);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("`ipc_enabeld`"));
+    }
+
+    #[test]
+    fn test_deprecated_and_newer_settings_warn() {
+        let settings: Settings =
+            serde_json::from_str(r#"{"config_version": 99, "old_key": 1, "ipc_enabled": true}"#)
+                .unwrap();
+        let warnings = compatibility_warnings(&settings, &[("old_key", "Use `new_key` instead.")]);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("config_version 99"));
+        assert_eq!(
+            warnings[1],
+            "`old_key` is deprecated and ignored. Use `new_key` instead."
+        );
+
+        let current: Settings =
+            serde_json::from_str(&format!(r#"{{"config_version": {}}}"#, CONFIG_VERSION)).unwrap();
+        assert!(compatibility_warnings(&current, DEPRECATED_SETTINGS).is_empty());
+    }
+
+    // Test for EffectiveConfig::new
+    #[test]
+    fn test_effective_config_is_sorted() {
+        let temp_dir = tempdir().unwrap();
+        let replacement_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &replacement_path,
+            r#"[
+                {"original": "（注）", "action": "delete"},
+                {"original": "頚", "replacement": "頸"}
+            ]"#,
+        )
+        .unwrap();
+
+        let config = EffectiveConfig::new(
+            load_replacements(replacement_path.to_str().unwrap()).unwrap(),
+            vec!['？', '！', '？'],
+            BTreeMap::new(),
+            StageExclusions::new(),
+        );
+        let dumped = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            dumped,
+            serde_json::json!({
+                "replacements": [
+                    {"original": "頚", "replacement": "頸", "action": "replace"},
+                    {"original": "（注）", "replacement": "", "action": "delete"}
+                ],
+                "exclusions": ["！", "？"]
+            })
+        );
+    }
+
+    // Test for convert_width action
+    #[test]
+    fn test_load_replacements_convert_width_action() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &file_path,
+            r#"[{"original": "〒\\d{3}－\\d{4}", "action": "convert_width"}]"#,
+        )
+        .unwrap();
+        let replacements = load_replacements(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(replacements[0].action, ReplacementAction::ConvertWidth);
+        assert!(!replacements[0].is_deletion());
+
+        fs::write(
+            &file_path,
+            r#"[{"original": "(unclosed", "action": "convert_width"}]"#,
+        )
+        .unwrap();
+        // A pattern that does not compile drops its rule only
+        assert!(load_replacements(file_path.to_str().unwrap())
+            .unwrap()
+            .is_empty());
+    }
+
+    // Test for empty config files
+    #[test]
+    fn test_load_empty_config_files() {
+        let temp_dir = tempdir().unwrap();
+        let replacement_path = temp_dir.path().join("replacements.json");
+        let exclusion_path = temp_dir.path().join("exclusions.json");
+        let replacement_path = replacement_path.to_str().unwrap();
+        let exclusion_path = exclusion_path.to_str().unwrap();
+
+        for content in ["[]", "", "  \n"] {
+            fs::write(replacement_path, content).unwrap();
+            assert!(load_replacements(replacement_path).unwrap().is_empty());
+        }
+        for content in [r#"{"exclude": []}"#, "{}", ""] {
+            fs::write(exclusion_path, content).unwrap();
+            assert!(load_exclusion_list(exclusion_path).unwrap().is_empty());
+        }
+    }
+
+    // Test for output_mode
+    #[test]
+    fn test_output_mode_compose() {
+        assert_eq!(OutputMode::Replace.compose("ＡＢ", "AB", "\n"), "AB");
+        assert_eq!(OutputMode::Append.compose("ＡＢ", "AB", "\n"), "ＡＢ\nAB");
+        assert_eq!(
+            OutputMode::Prepend.compose("ＡＢ", "AB", " / "),
+            "AB / ＡＢ"
+        );
+    }
+
+    // Test for context_before / context_after
+    #[test]
+    fn test_load_replacements_with_context() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &file_path,
+            r#"[{"original": "・", "replacement": ",", "context_before": "\\d", "context_after": "\\d"}]"#,
+        )
+        .unwrap();
+        let replacements = load_replacements(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(replacements[0].context_before.as_deref(), Some(r"\d"));
+        assert_eq!(replacements[0].context_after.as_deref(), Some(r"\d"));
+
+        fs::write(
+            &file_path,
+            r#"[{"original": "・", "replacement": ",", "context_before": "(unclosed"}]"#,
+        )
+        .unwrap();
+        assert!(load_replacements(file_path.to_str().unwrap())
+            .unwrap()
+            .is_empty());
+    }
+
+    // Test for occurrence
+    #[test]
+    fn test_load_replacements_with_occurrence() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &file_path,
+            r#"[{"original": "a", "replacement": "b", "occurrence": "first"}, {"original": "a", "replacement": "b"}]"#,
+        )
+        .unwrap();
+        let replacements = load_replacements(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(replacements[0].occurrence, Occurrence::First);
+        assert_eq!(replacements[1].occurrence, Occurrence::All);
+        // The reload hash must notice a change of occurrence alone
+        assert_ne!(
+            calculate_hash(&replacements[0]),
+            calculate_hash(&replacements[1])
+        );
+        assert!(!serde_json::to_string(&replacements[1])
+            .unwrap()
+            .contains("occurrence"));
+    }
+
+    // Test for documented exclusions
+    #[test]
+    fn test_documented_exclusions_parse_into_same_set() {
+        let temp_dir = tempdir().unwrap();
+        let plain_path = temp_dir.path().join("plain.json");
+        let documented_path = temp_dir.path().join("documented.json");
+        fs::write(&plain_path, r#"{"exclude": ["～", "U+3000"]}"#).unwrap();
+        fs::write(
+            &documented_path,
+            r#"{"exclude": [{"char": "～", "reason": "wave dash in titles"}, {"char": "U+3000"}]}"#,
+        )
+        .unwrap();
+        let plain_path = plain_path.to_str().unwrap();
+        let documented_path = documented_path.to_str().unwrap();
+
+        assert_eq!(
+            load_exclusion_list(plain_path).unwrap(),
+            load_exclusion_list(documented_path).unwrap()
+        );
+        assert!(load_exclusion_reasons(plain_path, None).unwrap().is_empty());
+        assert_eq!(
+            load_exclusion_reasons(documented_path, None).unwrap(),
+            BTreeMap::from([('～', "wave dash in titles".to_string())])
+        );
+
+        fs::write(documented_path, r#"{"exclude": [{"char": "ab"}]}"#).unwrap();
+        assert!(load_exclusion_list(documented_path).is_err());
+    }
+
+    #[test]
+    fn test_stage_exclusions_are_kept_apart() {
+        let temp_dir = tempdir().unwrap();
+        let base_path = temp_dir.path().join("exclusions.json");
+        let layer_path = temp_dir.path().join("layer.json");
+        fs::write(
+            &base_path,
+            r#"{"exclude": ["！", {"char": "～", "stages": ["width"]}, {"char": "ｱ", "stages": ["katakana", "nfkc"]}]}"#,
+        )
+        .unwrap();
+        let base_path = base_path.to_str().unwrap();
+        assert_eq!(
+            load_layered_exclusions(base_path, None).unwrap(),
+            vec!['！']
+        );
+        assert_eq!(
+            load_stage_exclusions(base_path, None).unwrap(),
+            StageExclusions::from([
+                ('～', vec![ExclusionStage::Width]),
+                ('ｱ', vec![ExclusionStage::Katakana, ExclusionStage::Nfkc]),
+            ])
+        );
+
+        // A layer's entry decides the stages of its character
+        fs::write(&layer_path, r#"{"exclude": ["～"]}"#).unwrap();
+        let layer_path = layer_path.to_str().unwrap();
+        assert_eq!(
+            load_layered_exclusions(base_path, Some(layer_path)).unwrap(),
+            vec!['！', '～']
+        );
+        assert_eq!(
+            load_stage_exclusions(base_path, Some(layer_path))
+                .unwrap()
+                .len(),
+            1
+        );
+
+        fs::write(
+            base_path,
+            r#"{"exclude": [{"char": "～", "stages": ["sideways"]}]}"#,
+        )
+        .unwrap();
+        assert!(load_stage_exclusions(base_path, None).is_err());
+    }
+
+    // Test for code point exclusions
+    #[test]
+    fn test_load_exclusion_list_code_points() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("exclusions.json");
+        fs::write(&file_path, r#"{"exclude": ["U+FF01", "！", "u+ff5e"]}"#).unwrap();
+
+        let exclusions = load_exclusion_list(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(exclusions, vec!['！', '！', '～']);
+
+        for invalid in ["U+", "U+ZZZZ", "U+D800", "ab"] {
+            fs::write(&file_path, format!(r#"{{"exclude": ["{}"]}}"#, invalid)).unwrap();
+            let result = load_exclusion_list(file_path.to_str().unwrap());
+            assert!(
+                matches!(result, Err(ConfigError::Parse { .. })),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    // Test for exclude_ranges
+    #[test]
+    fn test_exclude_ranges_expand_and_correct() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("exclusions.json");
+        fs::write(
+            &file_path,
+            r#"{"exclude": ["！"], "exclude_ranges": [["U+FF13", "U+FF11"], ["！", "＂"]]}"#,
+        )
+        .unwrap();
+
+        let exclusions = load_exclusion_list(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(exclusions, vec!['！', '１', '２', '３', '＂']);
+    }
+
+    #[test]
+    fn test_check_exclude_ranges() {
+        let range = |start, end| ExcludeRange { start, end };
+        let (ranges, warnings) = check_exclude_ranges(&[range('９', '０')]);
+        assert_eq!(ranges, vec![range('０', '９')]);
+        assert_eq!(
+            warnings,
+            vec!["exclude_ranges: U+FF19..U+FF10 starts after it ends; using U+FF10..U+FF19"]
+        );
+
+        let (ranges, warnings) =
+            check_exclude_ranges(&[range('０', '５'), range('Ａ', 'Ｚ'), range('５', '９')]);
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(
+            warnings,
+            vec!["exclude_ranges: U+FF10..U+FF15 overlaps U+FF15..U+FF19"]
+        );
+        let (ranges, warnings) = check_exclude_ranges(&[range('\0', '\u{10FFFF}')]);
+        assert!(ranges.is_empty());
+        assert_eq!(
+            warnings,
+            vec!["exclude_ranges: U+0000..U+10FFFF has more than 4096 characters; ignoring it"]
+        );
+        assert!(
+            check_exclude_ranges(&[range('０', '４'), range('５', '９')])
+                .1
+                .is_empty()
+        );
+    }
+
+    // Test for collapse_runs action
+    #[test]
+    fn test_load_replacements_collapse_runs_action() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &file_path,
+            r#"[{"original": "ー〜-", "replacement": "—", "action": "collapse_runs"}]"#,
+        )
+        .unwrap();
+        let replacements = load_replacements(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(replacements[0].action, ReplacementAction::CollapseRuns);
+        assert_eq!(replacements[0].pattern().unwrap(), r"[ー〜\-]{2,}");
+
+        fs::write(
+            &file_path,
+            r#"[{"original": "ー", "action": "collapse_runs"}]"#,
+        )
+        .unwrap();
+        let result = load_replacements(file_path.to_str().unwrap());
+        assert!(matches!(result, Err(ConfigError::Parse { .. })));
+    }
+
+    // Test for write_default_config
+    #[test]
+    fn test_default_config_round_trips_in_each_format() {
+        for format in [ConfigFormat::Json, ConfigFormat::Toml, ConfigFormat::Yaml] {
+            let temp_dir = tempdir().unwrap();
+            let config_dir = temp_dir.path().join("kill-zen-all");
+            write_default_config(&config_dir, format).unwrap();
+
+            let replacement_path = find_config_file(&config_dir, REPLACEMENTS_FILE_STEM);
+            let exclusion_path = find_config_file(&config_dir, EXCLUSIONS_FILE_STEM);
+            assert_eq!(ConfigFormat::from_path(&replacement_path), format);
+            assert_eq!(ConfigFormat::from_path(&exclusion_path), format);
+            assert_eq!(
+                load_replacements(replacement_path.to_str().unwrap()).unwrap(),
+                default_replacements(),
+                "{:?}",
+                format
+            );
+            assert_eq!(
+                load_exclusion_list(exclusion_path.to_str().unwrap()).unwrap(),
+                default_exclusions(),
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    // Test for rule order
+    #[test]
+    fn test_load_replacements_keeps_file_order() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &file_path,
+            r#"[
+                {"original": "b", "replacement": "c"},
+                {"original": "a", "replacement": "b"},
+                {"original": "c", "replacement": "a"}
+            ]"#,
+        )
+        .unwrap();
+        let originals: Vec<String> = load_replacements(file_path.to_str().unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|rule| rule.original)
+            .collect();
+        assert_eq!(originals, vec!["b", "a", "c"]);
+    }
+
+    // Test for project config discovery
+    #[test]
+    fn test_find_project_config_dir_from_nested_dir() {
+        let temp_dir = tempdir().unwrap();
+        let project_config = temp_dir.path().join(PROJECT_CONFIG_DIR_NAME);
+        let nested = temp_dir.path().join("src").join("deeply").join("nested");
+        fs::create_dir_all(&project_config).unwrap();
+        fs::create_dir_all(&nested).unwrap();
+
+        assert_eq!(
+            find_project_config_dir(&nested),
+            Some(project_config.clone())
+        );
+        assert_eq!(
+            find_project_config_dir(temp_dir.path()),
+            Some(project_config)
+        );
+    }
+
+    #[test]
+    fn test_resolve_config_file_falls_back_to_global() {
+        let temp_dir = tempdir().unwrap();
+        let global_dir = temp_dir.path().join("global");
+        let project_dir = temp_dir
+            .path()
+            .join("project")
+            .join(PROJECT_CONFIG_DIR_NAME);
+        fs::create_dir_all(&global_dir).unwrap();
+        fs::create_dir_all(&project_dir).unwrap();
+        fs::write(project_dir.join("replacements.json"), "[]").unwrap();
+
+        assert_eq!(
+            resolve_config_file(Some(&project_dir), &global_dir, REPLACEMENTS_FILE_STEM),
+            project_dir.join("replacements.json")
+        );
+        assert_eq!(
+            resolve_config_file(Some(&project_dir), &global_dir, EXCLUSIONS_FILE_STEM),
+            global_dir.join("exclusions.json")
+        );
+        assert_eq!(
+            resolve_config_file(None, &global_dir, REPLACEMENTS_FILE_STEM),
+            global_dir.join("replacements.json")
+        );
+    }
+
+    // Test for the combined config.json
+    #[test]
+    fn test_combined_config_loads_lists_and_settings() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("config.json");
+        fs::write(
+            &path,
+            r#"{
+                "replacements": [{"original": "foo", "replacement": "bar"}],
+                "exclusions": ["！", {"char": "？", "stages": ["width"]}],
+                "min_changed_chars": 3,
+                "aggressiveness": "high"
+            }"#,
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let replacements = load_replacements(path).unwrap();
+        assert_eq!(replacements.len(), 1);
+        assert_eq!(replacements[0].replacement, "bar");
+        assert_eq!(load_exclusion_list(path).unwrap(), vec!['！']);
+        assert_eq!(load_stage_exclusions(path, None).unwrap().len(), 1);
+        let settings = load_settings(path).unwrap();
+        assert_eq!(settings.min_changed_chars, 3);
+        assert_eq!(settings.format.aggressiveness, Aggressiveness::High);
+        // The lists are not reported as unknown settings
+        assert!(compatibility_warnings(&settings, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_combined_config_takes_precedence() {
+        let temp_dir = tempdir().unwrap();
+        let global_dir = temp_dir.path();
+        fs::write(global_dir.join("replacements.json"), "[]").unwrap();
+        fs::write(global_dir.join("exclusions.json"), r#"{"exclude": []}"#).unwrap();
+        fs::write(global_dir.join(SETTINGS_FILE_NAME), "{}").unwrap();
+        assert_eq!(
+            resolve_settings_file(global_dir),
+            global_dir.join(SETTINGS_FILE_NAME)
+        );
+
+        fs::write(global_dir.join("config.toml"), "min_changed_chars = 2\n").unwrap();
+        for stem in [REPLACEMENTS_FILE_STEM, EXCLUSIONS_FILE_STEM] {
+            assert_eq!(
+                resolve_config_file(None, global_dir, stem),
+                global_dir.join("config.toml")
+            );
+        }
+        let settings_path = resolve_settings_file(global_dir);
+        assert_eq!(settings_path, global_dir.join("config.toml"));
+        let settings = load_settings(settings_path.to_str().unwrap()).unwrap();
+        assert_eq!(settings.min_changed_chars, 2);
+
+        // Nor are the split files created next to it
+        fs::remove_file(global_dir.join("replacements.json")).unwrap();
+        write_default_config(global_dir, ConfigFormat::Json).unwrap();
+        assert!(!global_dir.join("replacements.json").exists());
+    }
+
+    // Test for the fullwidth latin exclusion diagnostic
+    #[test]
+    fn test_fullwidth_latin_exclusions() {
+        let exclusions = ['ａ', '　', 'Ｚ', '！', 'ａ', '０', 'ｚ', 'A'];
+        assert_eq!(
+            fullwidth_latin_exclusions(&exclusions),
+            vec!['Ｚ', 'ａ', 'ｚ']
+        );
+        assert!(fullwidth_latin_exclusions(&default_exclusions()).is_empty());
+    }
+
+    // Test for TSV replacements
+    #[test]
+    fn test_load_tsv_replacements() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("replacements.tsv");
+        fs::write(
+            &path,
+            "# sed-style rules\n頚\t頸\r\n\nfoo\\tbar\tfoo bar\n改行\\n\t\\\\\n削除\t\n",
+        )
+        .unwrap();
+
+        let replacements = load_replacements(path.to_str().unwrap()).unwrap();
+        let pairs: Vec<(&str, &str)> = replacements
+            .iter()
+            .map(|r| (r.original.as_str(), r.replacement.as_str()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("頚", "頸"),
+                ("foo\tbar", "foo bar"),
+                ("改行\n", "\\"),
+                ("削除", ""),
+            ]
+        );
+        assert!(replacements
+            .iter()
+            .all(|r| r.action == ReplacementAction::Replace));
+    }
+
+    #[test]
+    fn test_tsv_errors_report_line() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("replacements.tsv");
+        fs::write(&path, "a\tb\nno tab here\n").unwrap();
+        let err = load_replacements(path.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ConfigError::ParseTsv { line: 2, .. }));
+
+        fs::write(&path, "a\tb\tc\n").unwrap();
+        assert!(load_replacements(path.to_str().unwrap()).is_err());
+        fs::write(&path, "a\\x\tb\n").unwrap();
+        assert!(load_replacements(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_find_config_file_tsv_only_for_replacements() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("replacements.tsv"), "").unwrap();
+        fs::write(temp_dir.path().join("exclusions.tsv"), "").unwrap();
+        assert_eq!(
+            find_config_file(temp_dir.path(), REPLACEMENTS_FILE_STEM),
+            temp_dir.path().join("replacements.tsv")
+        );
+        assert_eq!(
+            find_config_file(temp_dir.path(), EXCLUSIONS_FILE_STEM),
+            temp_dir.path().join("exclusions.json")
+        );
+    }
+
+    // Test for layered project exclusions
+    #[test]
+    fn test_layered_exclusions_merge() {
+        let temp_dir = tempdir().unwrap();
+        let base_path = temp_dir.path().join("exclusions.json");
+        let layer_path = temp_dir.path().join("project.json");
+        fs::write(&base_path, r#"{"exclude": ["！", "？"]}"#).unwrap();
+        fs::write(&layer_path, r#"{"exclude": ["？", "＃"]}"#).unwrap();
+
+        let exclusions = load_layered_exclusions(
+            base_path.to_str().unwrap(),
+            Some(layer_path.to_str().unwrap()),
+        )
+        .unwrap();
+        assert_eq!(exclusions, vec!['！', '？', '＃']);
+
+        let base_only = load_layered_exclusions(base_path.to_str().unwrap(), None).unwrap();
+        assert_eq!(base_only, vec!['！', '？']);
+    }
+
+    #[test]
+    fn test_layered_exclusions_unexclude() {
+        let temp_dir = tempdir().unwrap();
+        let base_path = temp_dir.path().join("exclusions.json");
+        let layer_path = temp_dir.path().join("project.json");
+        fs::write(&base_path, r#"{"exclude": ["！", "？"]}"#).unwrap();
+        fs::write(
+            &layer_path,
+            r#"{"exclude": ["＃"], "unexclude": ["U+FF01"]}"#,
+        )
+        .unwrap();
+
+        let exclusions = load_layered_exclusions(
+            base_path.to_str().unwrap(),
+            Some(layer_path.to_str().unwrap()),
+        )
+        .unwrap();
+        assert_eq!(exclusions, vec!['？', '＃']);
+    }
+
+    // Test for extend_defaults
+    #[test]
+    fn test_extend_defaults_keeps_bundled_exclusions() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("exclusions.json");
+        fs::write(&path, r#"{"extend_defaults": true, "exclude": ["＃"]}"#).unwrap();
+
+        let mut expected = default_exclusions();
+        expected.push('＃');
+        assert_eq!(
+            load_exclusion_list(path.to_str().unwrap()).unwrap(),
+            expected
+        );
+
+        // Without it the file replaces the defaults, as before
+        fs::write(&path, r#"{"exclude": ["＃"]}"#).unwrap();
+        assert_eq!(
+            load_exclusion_list(path.to_str().unwrap()).unwrap(),
+            vec!['＃']
+        );
+    }
+
+    #[test]
+    fn test_extend_defaults_unexclude_removes_a_default() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("exclusions.json");
+        fs::write(
+            &path,
+            r#"{"extend_defaults": true, "exclude": ["＃"], "unexclude": ["！"]}"#,
+        )
+        .unwrap();
+        let layer_path = temp_dir.path().join("project.json");
+        fs::write(&layer_path, r#"{"unexclude": ["？"]}"#).unwrap();
+
+        let exclusions = load_layered_exclusions(path.to_str().unwrap(), None).unwrap();
+        assert!(!exclusions.contains(&'！'));
+        assert!(exclusions.contains(&'？'));
+        assert!(exclusions.contains(&'＃'));
+        // A project still layers on top of the extended list
+        let exclusions =
+            load_layered_exclusions(path.to_str().unwrap(), Some(layer_path.to_str().unwrap()))
+                .unwrap();
+        assert!(!exclusions.contains(&'！'));
+        assert!(!exclusions.contains(&'？'));
+        assert!(exclusions.contains(&'＃'));
+    }
+
+    // Test for large_input_policy
+    #[test]
+    fn test_large_input_policy_split() {
+        assert_eq!(LargeInputPolicy::Skip.split("ＡＢ", 6), Some(("ＡＢ", "")));
+        assert_eq!(LargeInputPolicy::Skip.split("ＡＢ", 5), None);
+        // 5 bytes falls inside Ｂ, so the prefix stops after Ａ
+        assert_eq!(
+            LargeInputPolicy::Prefix.split("ＡＢＣ", 5),
+            Some(("Ａ", "ＢＣ"))
+        );
+        assert_eq!(
+            LargeInputPolicy::Prefix.split("ＡＢＣ", 6),
+            Some(("ＡＢ", "Ｃ"))
+        );
+        assert_eq!(LargeInputPolicy::Prefix.split("Ａ", 0), Some(("", "Ａ")));
+    }
+
+    // Test for KZA_REPLACEMENTS
+    #[test]
+    fn test_replacement_files_from_env() {
+        let temp_dir = tempdir().unwrap();
+        let global_dir = temp_dir.path().join("global");
+        let first = temp_dir.path().join("first.json");
+        let second = temp_dir.path().join("second.json");
+        fs::write(&first, r#"[{"original": "a", "replacement": "b"}]"#).unwrap();
+        fs::write(&second, r#"[{"original": "b", "replacement": "c"}]"#).unwrap();
+
+        // Unset or empty falls back to the config dir
+        let default_file = vec![global_dir.join(REPLACEMENTS_FILE_NAME)];
+        assert_eq!(
+            resolve_replacement_files(None, None, &global_dir),
+            default_file
+        );
+        assert_eq!(
+            resolve_replacement_files(Some(OsStr::new("")), None, &global_dir),
+            default_file
+        );
+
+        let single = resolve_replacement_files(Some(first.as_os_str()), None, &global_dir);
+        assert_eq!(single, vec![first.clone()]);
+        let rules = load_replacement_files(&single).unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].original, "a");
+
+        let joined = env::join_paths([&first, &second]).unwrap();
+        let both = resolve_replacement_files(Some(&joined), None, &global_dir);
+        assert_eq!(both, vec![first, second]);
+        let originals: Vec<String> = load_replacement_files(&both)
+            .unwrap()
+            .into_iter()
+            .map(|rule| rule.original)
+            .collect();
+        assert_eq!(originals, vec!["a", "b"]);
+    }
+}
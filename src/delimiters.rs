@@ -0,0 +1,164 @@
+// Text between user-chosen delimiter pairs, e.g. 「 and 」, is kept exactly as
+// written. The spans are swapped for placeholder characters before the
+// pipeline runs and put back afterwards, so no stage can touch them.
+use std::ops::Range;
+
+// Supplementary Private Use Area-A; one placeholder per protected span
+const PLACEHOLDER_BASE: u32 = 0xF0000;
+const PLACEHOLDER_LIMIT: u32 = 0xFFFFD;
+
+// Deserialized from a two-element array, e.g. ["「", "」"]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct DelimiterPair(pub String, pub String);
+
+// Byte ranges of the outermost matched pairs, delimiters included, in order.
+// Pairs of the same kind nest; an opening delimiter without a match protects
+// nothing and a stray closing one is ignored. Pairs whose opening and closing
+// delimiter are the same, like ["\"", "\""], alternate between the two.
+pub fn protected_ranges(text: &str, pairs: &[DelimiterPair]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for DelimiterPair(open, close) in pairs {
+        if open.is_empty() || close.is_empty() {
+            continue;
+        }
+        let mut opened: Vec<usize> = Vec::new();
+        let mut position = 0;
+        while position < text.len() {
+            let rest = &text[position..];
+            if rest.starts_with(close.as_str()) && !opened.is_empty() {
+                let start = opened.pop().unwrap_or_default();
+                position += close.len();
+                ranges.push(start..position);
+            } else if rest.starts_with(open.as_str()) {
+                opened.push(position);
+                position += open.len();
+            } else {
+                position += rest.chars().next().map_or(1, char::len_utf8);
+            }
+        }
+    }
+    // Nested and overlapping spans merge into their outermost span
+    ranges.sort_by_key(|range| (range.start, std::cmp::Reverse(range.end)));
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start < last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+fn is_placeholder(c: char) -> bool {
+    (PLACEHOLDER_BASE..=PLACEHOLDER_LIMIT).contains(&(c as u32))
+}
+
+// Placeholders in the input would be mistaken for spans by unmask
+pub fn uses_placeholders(text: &str) -> bool {
+    text.chars().any(is_placeholder)
+}
+
+// A placeholder for text a rule inserted with `protect_output`, added to the
+// spans so unmask puts it back after the last stage. None when they ran out.
+pub fn protect(output: &str, spans: &mut Vec<String>) -> Option<char> {
+    let placeholder = char::from_u32(PLACEHOLDER_BASE + u32::try_from(spans.len()).ok()?)
+        .filter(|&c| is_placeholder(c))?;
+    spans.push(output.to_string());
+    Some(placeholder)
+}
+
+// The text with each protected span replaced by a placeholder, and the spans.
+// Text that already uses the placeholder range is left unmasked.
+pub fn mask(text: &str, pairs: &[DelimiterPair]) -> (String, Vec<String>) {
+    let ranges = protected_ranges(text, pairs);
+    if ranges.is_empty()
+        || ranges.len() > (PLACEHOLDER_LIMIT - PLACEHOLDER_BASE) as usize
+        || text.chars().any(is_placeholder)
+    {
+        return (text.to_string(), Vec::new());
+    }
+    let mut masked = String::with_capacity(text.len());
+    let mut spans = Vec::with_capacity(ranges.len());
+    let mut position = 0;
+    for (index, range) in ranges.into_iter().enumerate() {
+        masked.push_str(&text[position..range.start]);
+        masked.extend(char::from_u32(PLACEHOLDER_BASE + index as u32));
+        spans.push(text[range.clone()].to_string());
+        position = range.end;
+    }
+    masked.push_str(&text[position..]);
+    (masked, spans)
+}
+
+pub fn unmask(text: &str, spans: &[String]) -> String {
+    if spans.is_empty() {
+        return text.to_string();
+    }
+    let mut unmasked = String::with_capacity(text.len());
+    for c in text.chars() {
+        let span = is_placeholder(c)
+            .then(|| spans.get((c as u32 - PLACEHOLDER_BASE) as usize))
+            .flatten();
+        match span {
+            Some(span) => unmasked.push_str(span),
+            None => unmasked.push(c),
+        }
+    }
+    unmasked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(open: &str, close: &str) -> DelimiterPair {
+        DelimiterPair(open.to_string(), close.to_string())
+    }
+
+    fn protected<'a>(text: &'a str, pairs: &[DelimiterPair]) -> Vec<&'a str> {
+        protected_ranges(text, pairs)
+            .into_iter()
+            .map(|range| &text[range])
+            .collect()
+    }
+
+    #[test]
+    fn test_protected_ranges() {
+        let corner = [pair("「", "」")];
+        assert_eq!(
+            protected("ａ「ｂ」ｃ「ｄ」", &corner),
+            vec!["「ｂ」", "「ｄ」"]
+        );
+        // Nested pairs protect the outermost span
+        assert_eq!(
+            protected("「ａ「ｂ」ｃ」ｄ", &corner),
+            vec!["「ａ「ｂ」ｃ」"]
+        );
+        // An unmatched opening delimiter protects nothing; a stray close is ignored
+        assert_eq!(protected("「ａ「ｂ」", &corner), vec!["「ｂ」"]);
+        assert_eq!(protected("ａ」ｂ「ｃ", &corner), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_protected_ranges_mixed_and_symmetric() {
+        let pairs = [pair("「", "」"), pair("『", "』"), pair("\"", "\"")];
+        assert_eq!(
+            protected("「ａ『ｂ』」 \"ｃ\" 『ｄ", &pairs),
+            vec!["「ａ『ｂ』」", "\"ｃ\""]
+        );
+    }
+
+    #[test]
+    fn test_mask_round_trip() {
+        let pairs = [pair("「", "」")];
+        let text = "ＡＢ「ＣＤ」ＥＦ「ＧＨ」";
+        let (masked, spans) = mask(text, &pairs);
+        assert_eq!(masked.chars().count(), 6);
+        assert_eq!(spans, vec!["「ＣＤ」", "「ＧＨ」"]);
+        assert_eq!(unmask(&masked, &spans), text);
+        // Nothing to protect
+        assert_eq!(mask("ＡＢ", &pairs), ("ＡＢ".to_string(), Vec::new()));
+        assert!(uses_placeholders("\u{F0000}"));
+        assert!(!uses_placeholders("ＡＢ"));
+    }
+}
@@ -0,0 +1,639 @@
+use difference::{Changeset, Difference};
+
+use crate::format::Stage;
+
+// Inputs longer than this are summarized instead of diffed; the Changeset is
+// quadratic and a multi-megabyte diff is unreadable in a log anyway
+pub const MAX_DIFF_INPUT_CHARS: usize = 2000;
+const SUMMARY_PREVIEW_CHARS: usize = 40;
+// Longest line written to the log; minified JSON or base64 is one huge line
+pub const MAX_LOG_LINE_CHARS: usize = 300;
+
+// Unchanged lines shown around each hunk of a unified diff, as in `diff -u`
+pub const UNIFIED_CONTEXT_LINES: usize = 3;
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+// What made a character change, from the --explain stage that changed it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeCause {
+    Width,
+    Replacement,
+    Whitespace,
+    Other,
+}
+
+impl ChangeCause {
+    pub fn of(stage: &Stage) -> Self {
+        if stage.rule.is_some() {
+            return ChangeCause::Replacement;
+        }
+        match stage.label.as_str() {
+            "width conversion" | "symbols" | "expansions" | "nfkc" => ChangeCause::Width,
+            "ideographic spaces" => ChangeCause::Whitespace,
+            "spaces" | "smart spacing" => ChangeCause::Whitespace,
+            _ => ChangeCause::Other,
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            ChangeCause::Width => "\x1b[34m",
+            ChangeCause::Replacement => "\x1b[33m",
+            ChangeCause::Whitespace => "\x1b[35m",
+            ChangeCause::Other => "\x1b[36m",
+        }
+    }
+}
+
+// For the log, once at startup
+pub const CAUSE_LEGEND: &str = "\x1b[34mwidth conversion\x1b[0m, \x1b[33mreplacement\x1b[0m, \x1b[35mwhitespace\x1b[0m, \x1b[36mother\x1b[0m";
+
+// A run of the final text, or of text removed along the way, with the cause
+// of the change. Unchanged runs have no cause.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Annotated {
+    Kept(String, Option<ChangeCause>),
+    Removed(String, ChangeCause),
+}
+
+// One character of the text at some stage. Removals are attached to the
+// character that follows them.
+struct Slot {
+    c: char,
+    cause: Option<ChangeCause>,
+    removed_before: Vec<(String, ChangeCause)>,
+}
+
+// Follows every character through the stages of explain_format, so each
+// character of the result is tagged with the last stage that produced it
+pub fn annotate_stages(stages: &[Stage]) -> Vec<Annotated> {
+    let Some((first, rest)) = stages.split_first() else {
+        return Vec::new();
+    };
+    let mut slots: Vec<Slot> = first
+        .text
+        .chars()
+        .map(|c| Slot {
+            c,
+            cause: None,
+            removed_before: Vec::new(),
+        })
+        .collect();
+    let mut trailing: Vec<(String, ChangeCause)> = Vec::new();
+    let mut previous = first.text.as_str();
+    for stage in rest {
+        let cause = ChangeCause::of(stage);
+        let mut old = slots.into_iter();
+        let mut next = Vec::with_capacity(stage.text.len());
+        let mut pending: Vec<(String, ChangeCause)> = Vec::new();
+        for diff in Changeset::new(previous, &stage.text, "").diffs {
+            match diff {
+                Difference::Same(text) => {
+                    for mut slot in old.by_ref().take(text.chars().count()) {
+                        pending.append(&mut slot.removed_before);
+                        slot.removed_before = std::mem::take(&mut pending);
+                        next.push(slot);
+                    }
+                }
+                Difference::Rem(text) => {
+                    for mut slot in old.by_ref().take(text.chars().count()) {
+                        pending.append(&mut slot.removed_before);
+                    }
+                    pending.push((text, cause));
+                }
+                Difference::Add(text) => {
+                    for c in text.chars() {
+                        next.push(Slot {
+                            c,
+                            cause: Some(cause),
+                            removed_before: std::mem::take(&mut pending),
+                        });
+                    }
+                }
+            }
+        }
+        trailing.append(&mut pending);
+        slots = next;
+        previous = &stage.text;
+    }
+
+    let mut runs: Vec<Annotated> = Vec::new();
+    let push_removed = |runs: &mut Vec<Annotated>, removed: Vec<(String, ChangeCause)>| {
+        runs.extend(
+            removed
+                .into_iter()
+                .map(|(text, cause)| Annotated::Removed(text, cause)),
+        );
+    };
+    for slot in slots {
+        push_removed(&mut runs, slot.removed_before);
+        match runs.last_mut() {
+            Some(Annotated::Kept(text, cause)) if *cause == slot.cause => text.push(slot.c),
+            _ => runs.push(Annotated::Kept(slot.c.to_string(), slot.cause)),
+        }
+    }
+    push_removed(&mut runs, trailing);
+    runs
+}
+
+// The result with each change colored by its cause (see CAUSE_LEGEND) and
+// removed text shown as [-text]
+pub fn highlight_causes(stages: &[Stage]) -> String {
+    annotate_stages(stages)
+        .into_iter()
+        .map(|run| match run {
+            Annotated::Kept(text, None) => text,
+            Annotated::Kept(text, Some(cause)) => format!("{}{}{}", cause.color(), text, RESET),
+            Annotated::Removed(text, cause) => format!("{}[-{}]{}", cause.color(), text, RESET),
+        })
+        .collect()
+}
+
+// Character-level diff with removals in red and additions in green
+pub fn highlight_diff(before: &str, after: &str) -> String {
+    let before_len = before.chars().count();
+    let after_len = after.chars().count();
+    if before_len > MAX_DIFF_INPUT_CHARS || after_len > MAX_DIFF_INPUT_CHARS {
+        return format!(
+            "'{}' ({} chars) -> '{}' ({} chars), diff omitted",
+            preview(before, SUMMARY_PREVIEW_CHARS),
+            before_len,
+            preview(after, SUMMARY_PREVIEW_CHARS),
+            after_len
+        );
+    }
+    Changeset::new(before, after, "")
+        .diffs
+        .iter()
+        .map(|diff| match diff {
+            Difference::Same(text) => text.clone(),
+            Difference::Rem(text) => format!("{}[-{}]{}", RED, text, RESET),
+            Difference::Add(text) => format!("{}{{+{}}}{}", GREEN, text, RESET),
+        })
+        .collect()
+}
+
+// One edit for applying a conversion to an editor buffer in place. `offset` is
+// the byte offset in the original text; ops are in order and don't overlap.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct DiffOp {
+    pub offset: usize,
+    pub deleted: String,
+    pub inserted: String,
+}
+
+// The edits turning `original` into `formatted`. A removal followed by an
+// addition is one op. Past MAX_DIFF_INPUT_CHARS the whole changed span is a
+// single op, which is coarser but still exact.
+pub fn diff_ops(original: &str, formatted: &str) -> Vec<DiffOp> {
+    if original.chars().count() > MAX_DIFF_INPUT_CHARS
+        || formatted.chars().count() > MAX_DIFF_INPUT_CHARS
+    {
+        return changed_span_op(original, formatted).into_iter().collect();
+    }
+    let mut ops: Vec<DiffOp> = Vec::new();
+    let mut offset = 0;
+    for diff in Changeset::new(original, formatted, "").diffs {
+        let adjacent = ops
+            .last_mut()
+            .filter(|op| op.offset + op.deleted.len() == offset);
+        match diff {
+            Difference::Same(text) => offset += text.len(),
+            Difference::Rem(text) => {
+                let len = text.len();
+                match adjacent {
+                    Some(op) => op.deleted.push_str(&text),
+                    None => ops.push(DiffOp {
+                        offset,
+                        deleted: text,
+                        inserted: String::new(),
+                    }),
+                }
+                offset += len;
+            }
+            Difference::Add(text) => match adjacent {
+                Some(op) => op.inserted.push_str(&text),
+                None => ops.push(DiffOp {
+                    offset,
+                    deleted: String::new(),
+                    inserted: text,
+                }),
+            },
+        }
+    }
+    ops
+}
+
+fn changed_span_op(original: &str, formatted: &str) -> Option<DiffOp> {
+    let before: Vec<char> = original.chars().collect();
+    let after: Vec<char> = formatted.chars().collect();
+    let (prefix, suffix) = common_affixes(&before, &after);
+    if prefix == before.len() && prefix == after.len() {
+        return None;
+    }
+    let bytes = |chars: &[char]| chars.iter().map(|c| c.len_utf8()).sum::<usize>();
+    let offset = bytes(&before[..prefix]);
+    Some(DiffOp {
+        offset,
+        deleted: before[prefix..before.len() - suffix].iter().collect(),
+        inserted: after[prefix..after.len() - suffix].iter().collect(),
+    })
+}
+
+// Line-level diff in the `diff -u` format, for patch viewers and review tools.
+// Empty when nothing changed. Past MAX_DIFF_INPUT_CHARS the lines between the
+// common first and last ones are all replaced, which is coarser but still exact.
+pub fn unified_diff(before: &str, after: &str, before_label: &str, after_label: &str) -> String {
+    let changeset;
+    let mut lines: Vec<(char, &str)> = Vec::new();
+    if before.chars().count() > MAX_DIFF_INPUT_CHARS || after.chars().count() > MAX_DIFF_INPUT_CHARS
+    {
+        let before: Vec<&str> = before.split('\n').collect();
+        let after: Vec<&str> = after.split('\n').collect();
+        let (prefix, suffix) = common_affixes(&before, &after);
+        lines.extend(before[..prefix].iter().map(|&line| (' ', line)));
+        lines.extend(
+            before[prefix..before.len() - suffix]
+                .iter()
+                .map(|&line| ('-', line)),
+        );
+        lines.extend(
+            after[prefix..after.len() - suffix]
+                .iter()
+                .map(|&line| ('+', line)),
+        );
+        lines.extend(
+            before[before.len() - suffix..]
+                .iter()
+                .map(|&line| (' ', line)),
+        );
+    } else {
+        changeset = Changeset::new(before, after, "\n");
+        for diff in &changeset.diffs {
+            let (tag, text) = match diff {
+                Difference::Same(text) => (' ', text),
+                Difference::Rem(text) => ('-', text),
+                Difference::Add(text) => ('+', text),
+            };
+            lines.extend(text.split('\n').map(|line| (tag, line)));
+        }
+    }
+    let changes: Vec<usize> = (0..lines.len()).filter(|&i| lines[i].0 != ' ').collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    // Changes closer than twice the context share a hunk
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &i in &changes {
+        match hunks.last_mut() {
+            Some((_, end)) if i - *end <= 2 * UNIFIED_CONTEXT_LINES => *end = i,
+            _ => hunks.push((i, i)),
+        }
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", before_label, after_label);
+    for (first, last) in hunks {
+        let start = first.saturating_sub(UNIFIED_CONTEXT_LINES);
+        let end = (last + 1 + UNIFIED_CONTEXT_LINES).min(lines.len());
+        let count = |range: &[(char, &str)], skip: char| {
+            range.iter().filter(|(tag, _)| *tag != skip).count()
+        };
+        let old_start = count(&lines[..start], '+');
+        let new_start = count(&lines[..start], '-');
+        let old_count = count(&lines[start..end], '+');
+        let new_count = count(&lines[start..end], '-');
+        // An empty side is numbered by the line before it
+        let number = |start: usize, count: usize| if count == 0 { start } else { start + 1 };
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            number(old_start, old_count),
+            old_count,
+            number(new_start, new_count),
+            new_count
+        ));
+        for (tag, line) in &lines[start..end] {
+            out.push(*tag);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+// For log output only: cuts every line to `max_chars` visible characters. ANSI
+// color codes don't count, and a cut line is reset so the color can't leak.
+pub fn cap_line_length(text: &str, max_chars: usize) -> String {
+    let mut capped = String::with_capacity(text.len().min(max_chars * 4));
+    for (index, line) in text.split('\n').enumerate() {
+        if index > 0 {
+            capped.push('\n');
+        }
+        let mut visible = 0;
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                capped.push(c);
+                for c in chars.by_ref() {
+                    capped.push(c);
+                    if c == 'm' {
+                        break;
+                    }
+                }
+                continue;
+            }
+            if visible == max_chars {
+                let remaining = 1 + visible_len(chars.as_str());
+                capped.push_str(&format!("{}…(+{} chars)", RESET, remaining));
+                break;
+            }
+            capped.push(c);
+            visible += 1;
+        }
+    }
+    capped
+}
+
+fn visible_len(text: &str) -> usize {
+    let mut len = 0;
+    let mut in_escape = false;
+    for c in text.chars() {
+        match c {
+            '\x1b' => in_escape = true,
+            'm' if in_escape => in_escape = false,
+            _ if in_escape => {}
+            _ => len += 1,
+        }
+    }
+    len
+}
+
+// Only the changed span, with the common prefix and suffix elided, e.g.
+// "foo １２３" -> "foo 123" gives "…１２３ → …123". Each side is cut to `max_chars`.
+pub fn diff_summary(before: &str, after: &str, max_chars: usize) -> String {
+    let before: Vec<char> = before.chars().collect();
+    let after: Vec<char> = after.chars().collect();
+    let (prefix, suffix) = common_affixes(&before, &after);
+    let side = |chars: &[char]| {
+        let changed: String = chars[prefix..chars.len() - suffix].iter().collect();
+        let mut side = preview(&changed, max_chars);
+        if prefix > 0 {
+            side.insert(0, '…');
+        }
+        if suffix > 0 && !side.ends_with('…') {
+            side.push('…');
+        }
+        side
+    };
+    format!("{} → {}", side(&before), side(&after))
+}
+
+// Length in chars of the longer side of the changed span; 0 when equal
+pub fn changed_chars(before: &str, after: &str) -> usize {
+    let before: Vec<char> = before.chars().collect();
+    let after: Vec<char> = after.chars().collect();
+    let (prefix, suffix) = common_affixes(&before, &after);
+    before.len().max(after.len()) - prefix - suffix
+}
+
+// Lengths of the common prefix and of the common suffix after it
+fn common_affixes<T: PartialEq>(before: &[T], after: &[T]) -> (usize, usize) {
+    let prefix = before.iter().zip(after).take_while(|(a, b)| a == b).count();
+    let suffix = before[prefix..]
+        .iter()
+        .rev()
+        .zip(after[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    (prefix, suffix)
+}
+
+fn preview(text: &str, max_chars: usize) -> String {
+    let mut preview: String = text.chars().take(max_chars).collect();
+    if text.chars().nth(max_chars).is_some() {
+        preview.push('…');
+    }
+    preview
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Replacement;
+    use crate::format::{explain_format, FormatOptions};
+
+    #[test]
+    fn test_highlight_diff() {
+        let diff = highlight_diff("ＡＢＣ頚", "ABC頸");
+        assert!(diff.contains(&format!("{}[-ＡＢＣ", RED)));
+        assert!(diff.contains(&format!("{}{{+ABC", GREEN)));
+        assert!(diff.ends_with(RESET));
+    }
+
+    // Test for diff_ops
+    fn apply_ops(original: &str, ops: &[DiffOp]) -> String {
+        let mut applied = original.to_string();
+        for op in ops.iter().rev() {
+            assert_eq!(
+                &applied[op.offset..op.offset + op.deleted.len()],
+                op.deleted
+            );
+            applied.replace_range(op.offset..op.offset + op.deleted.len(), &op.inserted);
+        }
+        applied
+    }
+
+    #[test]
+    fn test_diff_ops_use_byte_offsets() {
+        let ops = diff_ops("ａ１b２", "a1b2");
+        assert_eq!(
+            ops,
+            vec![
+                DiffOp {
+                    offset: 0,
+                    deleted: "ａ１".to_string(),
+                    inserted: "a1".to_string(),
+                },
+                DiffOp {
+                    offset: 7,
+                    deleted: "２".to_string(),
+                    inserted: "2".to_string(),
+                },
+            ]
+        );
+        assert!(diff_ops("same", "same").is_empty());
+    }
+
+    #[test]
+    fn test_diff_ops_reconstruct_formatted_text() {
+        let rules = vec![Replacement {
+            original: "foo".to_string(),
+            replacement: "bar".to_string(),
+            ..Default::default()
+        }];
+        let original = "ＡＢＣ　foo（１２３）\n頚😀ｘ";
+        let formatted = crate::format::format_text(original, &rules, &[]).unwrap();
+        assert_ne!(original, formatted);
+        assert_eq!(
+            apply_ops(original, &diff_ops(original, &formatted)),
+            formatted
+        );
+
+        // Too long to diff: one op for the changed span
+        let long = format!("{}１２３{}", "あ".repeat(MAX_DIFF_INPUT_CHARS), "い");
+        let ops = diff_ops(&long, &long.replace("１２３", "123"));
+        assert_eq!(ops.len(), 1);
+        assert_eq!(ops[0].offset, "あ".len() * MAX_DIFF_INPUT_CHARS);
+        assert_eq!(apply_ops(&long, &ops), long.replace("１２３", "123"));
+    }
+
+    // Test for coloring changes by cause
+    fn annotate(
+        text: &str,
+        replacements: &[Replacement],
+        options: &FormatOptions,
+    ) -> Vec<Annotated> {
+        annotate_stages(&explain_format(text, replacements, &[], options).unwrap())
+    }
+
+    #[test]
+    fn test_annotate_mixed_causes() {
+        let rules = vec![Replacement {
+            original: "foo".to_string(),
+            replacement: "bar".to_string(),
+            ..Default::default()
+        }];
+        let runs = annotate("ＡＢ foo", &rules, &FormatOptions::default());
+        assert_eq!(
+            runs,
+            vec![
+                Annotated::Removed("ＡＢ".to_string(), ChangeCause::Width),
+                Annotated::Kept("AB".to_string(), Some(ChangeCause::Width)),
+                Annotated::Kept(" ".to_string(), None),
+                Annotated::Removed("foo".to_string(), ChangeCause::Replacement),
+                Annotated::Kept("bar".to_string(), Some(ChangeCause::Replacement)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_annotate_whitespace_and_later_stages() {
+        let options = FormatOptions {
+            collapse_spaces: true,
+            ..Default::default()
+        };
+        // The rule's output is halved afterwards, so the width conversion owns it
+        let rules = vec![Replacement {
+            original: "x".to_string(),
+            replacement: "Ｙ".to_string(),
+            ..Default::default()
+        }];
+        let runs = annotate("a  x", &rules, &options);
+        assert_eq!(
+            runs,
+            vec![
+                Annotated::Kept("a ".to_string(), None),
+                Annotated::Removed(" ".to_string(), ChangeCause::Whitespace),
+                Annotated::Removed("x".to_string(), ChangeCause::Replacement),
+                Annotated::Removed("Ｙ".to_string(), ChangeCause::Width),
+                Annotated::Kept("Y".to_string(), Some(ChangeCause::Width)),
+            ]
+        );
+        assert!(highlight_causes(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_highlight_causes_colors() {
+        let stages = explain_format("Ａ", &[], &[], &FormatOptions::default()).unwrap();
+        assert_eq!(
+            highlight_causes(&stages),
+            "\x1b[34m[-Ａ]\x1b[0m\x1b[34mA\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_highlight_diff_unchanged() {
+        assert_eq!(highlight_diff("foo", "foo"), "foo");
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let before = "one\ntwo\nＡＢＣ\nfour\nfive\nsix\nseven\neight\nnine\nten\n１２３";
+        let after = "one\ntwo\nABC\nfour\nfive\nsix\nseven\neight\nnine\nten\n123";
+        assert_eq!(
+            unified_diff(before, after, "before", "after"),
+            "--- before\n+++ after\n\
+             @@ -1,6 +1,6 @@\n one\n two\n-ＡＢＣ\n+ABC\n four\n five\n six\n\
+             @@ -8,4 +8,4 @@\n eight\n nine\n ten\n-１２３\n+123\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_diff_single_line() {
+        assert_eq!(
+            unified_diff("foo １２３", "foo 123", "a", "b"),
+            "--- a\n+++ b\n@@ -1,1 +1,1 @@\n-foo １２３\n+foo 123\n"
+        );
+        assert_eq!(unified_diff("same\ntext", "same\ntext", "a", "b"), "");
+    }
+
+    #[test]
+    fn test_unified_diff_of_large_input_replaces_the_changed_lines() {
+        let long = "あ".repeat(MAX_DIFF_INPUT_CHARS);
+        let before = format!("one\n{}\nＡＢＣ\n１２３\nfive", long);
+        let after = format!("one\n{}\nABC\n123\nfive", long);
+        assert_eq!(
+            unified_diff(&before, &after, "a", "b"),
+            format!(
+                "--- a\n+++ b\n@@ -1,5 +1,5 @@\n one\n {}\n-ＡＢＣ\n-１２３\n+ABC\n+123\n five\n",
+                long
+            )
+        );
+        assert_eq!(unified_diff(&long, &long, "a", "b"), "");
+    }
+
+    #[test]
+    fn test_cap_line_length() {
+        let long = "x".repeat(50);
+        let text = format!("short\n{}{}{}\nend", RED, long, RESET);
+        let capped = cap_line_length(&text, 10);
+        let lines: Vec<&str> = capped.split('\n').collect();
+        assert_eq!(lines[0], "short");
+        assert_eq!(
+            lines[1],
+            format!("{}{}{}…(+40 chars)", RED, "x".repeat(10), RESET)
+        );
+        assert_eq!(lines[2], "end");
+        assert_eq!(cap_line_length("ＡＢＣ", 3), "ＡＢＣ");
+    }
+
+    #[test]
+    fn test_diff_summary() {
+        assert_eq!(diff_summary("ＡＢＣ", "ABC", 10), "ＡＢＣ → ABC");
+        assert_eq!(diff_summary("foo １２３", "foo 123", 10), "…１２３ → …123");
+        assert_eq!(diff_summary("a頚b", "a頸b", 10), "…頚… → …頸…");
+        assert_eq!(diff_summary("ＡＢＣＤ", "ABCD", 2), "ＡＢ… → AB…");
+    }
+
+    #[test]
+    fn test_changed_chars() {
+        assert_eq!(changed_chars("foo", "foo"), 0);
+        assert_eq!(changed_chars("foo １２３", "foo 123"), 3);
+        assert_eq!(changed_chars("a頚b", "a頸b"), 1);
+        assert_eq!(changed_chars("abc", "abxyzc"), 3);
+        assert_eq!(changed_chars("aaa", "aa"), 1);
+    }
+
+    #[test]
+    fn test_highlight_diff_truncates_large_input() {
+        let before = "Ａ".repeat(MAX_DIFF_INPUT_CHARS * 10);
+        let after = "A".repeat(MAX_DIFF_INPUT_CHARS * 10);
+        let diff = highlight_diff(&before, &after);
+        assert!(diff.chars().count() < MAX_DIFF_INPUT_CHARS);
+        assert!(diff.contains("diff omitted"));
+        assert!(diff.contains(&format!("({} chars)", MAX_DIFF_INPUT_CHARS * 10)));
+    }
+}
@@ -0,0 +1,80 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read {path}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Failed to parse JSON in {path}")]
+    Parse {
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("Failed to parse TOML in {path}")]
+    ParseToml {
+        path: String,
+        #[source]
+        source: toml::de::Error,
+    },
+    #[error("Failed to parse YAML in {path}")]
+    ParseYaml {
+        path: String,
+        #[source]
+        source: serde_yaml::Error,
+    },
+    #[error("Failed to parse TSV in {path} at line {line}: {message}")]
+    ParseTsv {
+        path: String,
+        line: usize,
+        message: String,
+    },
+    #[error("No config directory found; set XDG_CONFIG_HOME to choose one")]
+    NoConfigDir,
+    #[error("Path contains invalid UTF-8 characters: {0}")]
+    InvalidPath(String),
+    #[error("Failed to write default {format} config: {message}")]
+    Serialize { format: String, message: String },
+    #[error("Invalid pattern '{pattern}'")]
+    InvalidPattern {
+        pattern: String,
+        #[source]
+        source: regex::Error,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum FormatError {
+    #[error("Failed to create regex pattern")]
+    Regex(#[from] regex::Error),
+    #[error("Failed to build the replacement automaton")]
+    Automaton(#[from] aho_corasick::BuildError),
+    // `rule` is 1-based, as in --explain
+    #[error("Rule {rule} ('{original}') has an invalid pattern '{pattern}'")]
+    RulePattern {
+        rule: usize,
+        original: String,
+        pattern: String,
+        #[source]
+        source: regex::Error,
+    },
+    #[error("Invalid exclude_pattern '{pattern}'")]
+    ExcludePattern {
+        pattern: String,
+        #[source]
+        source: regex::Error,
+    },
+}
+
+impl FormatError {
+    // The rule to blame, when the text would convert without it
+    pub fn rule(&self) -> Option<usize> {
+        match self {
+            FormatError::RulePattern { rule, .. } => Some(*rule),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// Optional cue after the daemon rewrites the clipboard
+use std::io::Write;
+
+use crate::diff::diff_summary;
+
+pub const DEFAULT_NOTIFY_PREVIEW_CHARS: usize = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConvertFeedback {
+    #[default]
+    None,
+    Sound,
+    Notify,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FeedbackAction {
+    Bell,
+    Notification(String),
+}
+
+// With `redact` the body only says that something changed, since
+// notifications can end up in history or on the lock screen
+pub fn feedback_action(
+    feedback: ConvertFeedback,
+    before: &str,
+    after: &str,
+    preview_chars: usize,
+    redact: bool,
+) -> Option<FeedbackAction> {
+    match feedback {
+        ConvertFeedback::None => None,
+        ConvertFeedback::Sound => Some(FeedbackAction::Bell),
+        ConvertFeedback::Notify if redact => Some(FeedbackAction::Notification(format!(
+            "Converted clipboard text ({} chars)",
+            after.chars().count()
+        ))),
+        ConvertFeedback::Notify => Some(FeedbackAction::Notification(
+            diff_summary(before, after, preview_chars).replace(char::is_control, " "),
+        )),
+    }
+}
+
+// Feedback is best effort, so failures are only logged
+pub fn give_feedback(action: FeedbackAction) {
+    match action {
+        FeedbackAction::Bell => {
+            let mut stderr = std::io::stderr();
+            if let Err(e) = stderr.write_all(b"\x07").and_then(|_| stderr.flush()) {
+                log::debug!("Failed to ring the terminal bell: {}", e);
+            }
+        }
+        FeedbackAction::Notification(summary) => notify(&summary),
+    }
+}
+
+// Showing a notification is a D-Bus round trip that can stall, so one worker
+// thread shows them in order, off the conversion path
+#[cfg(feature = "desktop-notify")]
+fn notify(summary: &str) {
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::OnceLock;
+
+    static WORKER: OnceLock<Sender<String>> = OnceLock::new();
+    let worker = WORKER.get_or_init(|| {
+        let (tx, rx) = channel::<String>();
+        std::thread::spawn(move || {
+            for summary in rx {
+                show_notification(&summary);
+            }
+        });
+        tx
+    });
+    // The worker lives as long as the process
+    let _ = worker.send(summary.to_string());
+}
+
+#[cfg(feature = "desktop-notify")]
+fn show_notification(summary: &str) {
+    let result = notify_rust::Notification::new()
+        .summary("kill-zen-all")
+        .body(summary)
+        .timeout(notify_rust::Timeout::Milliseconds(3000))
+        .show();
+    if let Err(e) = result {
+        log::warn!("Failed to show notification: {}", e);
+    }
+}
+
+#[cfg(not(feature = "desktop-notify"))]
+fn notify(_summary: &str) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_feedback_action_per_setting() {
+        let action = |feedback| feedback_action(feedback, "ＡＢＣ", "ABC", 30, false);
+        assert_eq!(action(ConvertFeedback::None), None);
+        assert_eq!(action(ConvertFeedback::Sound), Some(FeedbackAction::Bell));
+        assert_eq!(
+            action(ConvertFeedback::Notify),
+            Some(FeedbackAction::Notification("ＡＢＣ → ABC".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_notification_body_shows_truncated_change() {
+        let action = feedback_action(
+            ConvertFeedback::Notify,
+            "価格は１２３４５円\nです",
+            "価格は12345円\nです",
+            3,
+            false,
+        );
+        assert_eq!(
+            action,
+            Some(FeedbackAction::Notification("…１２３… → …123…".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_notification_body_is_redacted() {
+        let action = feedback_action(ConvertFeedback::Notify, "ｐａｓｓ", "pass", 30, true);
+        assert_eq!(
+            action,
+            Some(FeedbackAction::Notification(
+                "Converted clipboard text (4 chars)".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,165 @@
+// Converting text written to a named pipe, for scripts on headless machines
+// with no clipboard. Requests and responses use the --pipe-server line
+// protocol; the responses go to a second FIFO.
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::ffi::CString;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::BufReader;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use crate::pipe;
+
+// An existing FIFO is reused; any other existing file is an error
+pub fn create_fifo(path: &Path) -> Result<()> {
+    if let Ok(metadata) = fs::metadata(path) {
+        if metadata.file_type().is_fifo() {
+            return Ok(());
+        }
+        bail!("{} exists and is not a FIFO", path.display());
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid FIFO path {}", path.display()))?;
+    // SAFETY: c_path is a valid NUL-terminated string for the duration of the call
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to create FIFO {}", path.display()));
+    }
+    Ok(())
+}
+
+pub struct FifoServer {
+    input_path: PathBuf,
+    output_path: PathBuf,
+    // Kept open across writers, so a reader like `cat` sees every response
+    output: Option<File>,
+}
+
+impl FifoServer {
+    pub fn new(input_path: PathBuf, output_path: PathBuf) -> Result<Self> {
+        create_fifo(&input_path)?;
+        create_fifo(&output_path)?;
+        Ok(FifoServer {
+            input_path,
+            output_path,
+            output: None,
+        })
+    }
+
+    // One session lasts until every writer has closed the input FIFO. Opening
+    // the input blocks until there is a writer, and opening the output until
+    // there is a reader.
+    pub fn serve_session<F, E>(&mut self, convert: F) -> Result<()>
+    where
+        F: FnMut(&str) -> Result<String, E>,
+        E: Display,
+    {
+        let input = File::open(&self.input_path)
+            .with_context(|| format!("Failed to open {}", self.input_path.display()))?;
+        let output = match &mut self.output {
+            Some(output) => output,
+            None => self.output.insert(
+                OpenOptions::new()
+                    .write(true)
+                    .open(&self.output_path)
+                    .with_context(|| format!("Failed to open {}", self.output_path.display()))?,
+            ),
+        };
+        let result = pipe::serve(BufReader::new(input), output, convert);
+        if result.is_err() {
+            // Most likely the reader went away; wait for a new one next time
+            self.output = None;
+        }
+        result
+    }
+
+    // Never returns: a failed session is logged and the FIFOs are reopened
+    pub fn serve<F, E>(&mut self, mut convert: F) -> !
+    where
+        F: FnMut(&str) -> Result<String, E>,
+        E: Display,
+    {
+        info!(
+            "Serving conversions from {} to {}.",
+            self.input_path.display(),
+            self.output_path.display()
+        );
+        loop {
+            if let Err(e) = self.serve_session(&mut convert) {
+                warn!("FIFO session failed: {:#}", e);
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::format_text;
+    use std::io::{BufRead, Write};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_create_fifo() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("in");
+        create_fifo(&path).unwrap();
+        assert!(fs::metadata(&path).unwrap().file_type().is_fifo());
+        // Reused as is
+        create_fifo(&path).unwrap();
+
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(create_fifo(&file).is_err());
+    }
+
+    // A FIFO whose other end never opens blocks forever; fail instead
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_serve_reopens_after_writer_closes() {
+        let temp_dir = tempdir().unwrap();
+        let input = temp_dir.path().join("in");
+        let output = temp_dir.path().join("out");
+        let mut server = FifoServer::new(input.clone(), output.clone()).unwrap();
+
+        let (lines_tx, lines_rx) = channel();
+        thread::spawn(move || {
+            let (done_tx, done_rx) = channel();
+            let server_thread = thread::spawn(move || {
+                for _ in 0..2 {
+                    server
+                        .serve_session(|text| format_text(text, &[], &[]))
+                        .unwrap();
+                    done_tx.send(()).unwrap();
+                }
+            });
+            let reader = thread::spawn(move || {
+                BufReader::new(File::open(output).unwrap())
+                    .lines()
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap()
+            });
+
+            for request in ["ＡＢＣ\n", "ｆｏｏ\\nｂａｒ\n"] {
+                let mut writer = OpenOptions::new().write(true).open(&input).unwrap();
+                writer.write_all(request.as_bytes()).unwrap();
+                drop(writer);
+                done_rx.recv().unwrap();
+            }
+            server_thread.join().unwrap();
+            lines_tx.send(reader.join().unwrap()).unwrap();
+        });
+        let lines = lines_rx
+            .recv_timeout(TIMEOUT)
+            .expect("the FIFO server did not answer in time");
+        assert_eq!(lines, vec!["ABC", "foo\\nbar"]);
+    }
+}
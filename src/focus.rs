@@ -0,0 +1,260 @@
+// `focus_convert_apps`: converting the clipboard when the user switches to an
+// app they paste into, rather than when they copy. The focused window is
+// polled every FOCUS_CHECK_INTERVAL and identified by its X11 WM_CLASS.
+// `skip_window_titles` reads the same window's title instead.
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+
+pub const FOCUS_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+// Names are compared case-insensitively, since WM_CLASS capitalization varies
+pub fn is_target_app(apps: &[String], app: &str) -> bool {
+    apps.iter().any(|target| target.eq_ignore_ascii_case(app))
+}
+
+// Globs over window titles, where `*` matches any run of characters and `?`
+// any one. Like app names, titles are matched case-insensitively.
+#[derive(Debug, Clone, Default)]
+pub struct TitleFilter {
+    patterns: Vec<(String, Regex)>,
+}
+
+impl TitleFilter {
+    pub fn new(globs: &[String]) -> Result<Self, regex::Error> {
+        let patterns = globs
+            .iter()
+            .map(|glob| Ok((glob.clone(), glob_regex(glob)?)))
+            .collect::<Result<_, regex::Error>>()?;
+        Ok(TitleFilter { patterns })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    // The first glob the whole title matches
+    pub fn matching(&self, title: &str) -> Option<&str> {
+        self.patterns
+            .iter()
+            .find(|(_, re)| re.is_match(title))
+            .map(|(glob, _)| glob.as_str())
+    }
+}
+
+fn glob_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let mut pattern = String::from("(?is)^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            _ => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern)
+}
+
+#[derive(Debug, Default)]
+pub struct FocusMonitor {
+    apps: Vec<String>,
+    last_check: Option<Instant>,
+    // None until the focused app has been read once
+    focused: Option<String>,
+}
+
+impl FocusMonitor {
+    pub fn new(apps: Vec<String>) -> Self {
+        FocusMonitor {
+            apps,
+            ..Default::default()
+        }
+    }
+
+    // True once per switch to one of the apps. The app focused at startup is
+    // not a switch, and a failed query keeps the previous answer.
+    pub fn switched_to_target(
+        &mut self,
+        now: Instant,
+        query: impl FnOnce() -> Option<String>,
+    ) -> bool {
+        if self.apps.is_empty() {
+            return false;
+        }
+        let fresh = self
+            .last_check
+            .is_some_and(|last| now.saturating_duration_since(last) < FOCUS_CHECK_INTERVAL);
+        if fresh {
+            return false;
+        }
+        self.last_check = Some(now);
+        let Some(app) = query() else {
+            return false;
+        };
+        if self.focused.as_deref() == Some(app.as_str()) {
+            return false;
+        }
+        let switched = self.focused.is_some() && is_target_app(&self.apps, &app);
+        if switched {
+            log::debug!("Switched to {}. Converting the clipboard.", app);
+        }
+        self.focused = Some(app);
+        switched
+    }
+}
+
+// The class part of WM_CLASS ("instance\0class\0") of the window named by
+// _NET_ACTIVE_WINDOW
+#[cfg(all(target_os = "linux", feature = "focus-convert"))]
+pub fn query_focused_app() -> Option<String> {
+    crate::x11::with_connection(|conn, root| {
+        let active = xcb::intern_atom(conn, true, "_NET_ACTIVE_WINDOW")
+            .get_reply()
+            .ok()?
+            .atom();
+        let window = *xcb::get_property(conn, false, root, active, xcb::ATOM_WINDOW, 0, 1)
+            .get_reply()
+            .ok()?
+            .value::<u32>()
+            .first()?;
+        let class = xcb::get_property(
+            conn,
+            false,
+            window,
+            xcb::ATOM_WM_CLASS,
+            xcb::ATOM_STRING,
+            0,
+            256,
+        )
+        .get_reply()
+        .ok()?;
+        let name = class
+            .value::<u8>()
+            .split(|&b| b == 0)
+            .rfind(|part| !part.is_empty())?;
+        Some(String::from_utf8_lossy(name).into_owned())
+    })
+}
+
+#[cfg(not(all(target_os = "linux", feature = "focus-convert")))]
+pub fn query_focused_app() -> Option<String> {
+    None
+}
+
+// The _NET_WM_NAME of the window named by _NET_ACTIVE_WINDOW, or its
+// WM_NAME for windows that only set the older property
+#[cfg(all(target_os = "linux", feature = "focus-convert"))]
+pub fn query_focused_title() -> Option<String> {
+    crate::x11::with_connection(|conn, root| {
+        let atom = |name: &str| {
+            xcb::intern_atom(conn, true, name)
+                .get_reply()
+                .ok()
+                .map(|reply| reply.atom())
+        };
+        let active = atom("_NET_ACTIVE_WINDOW")?;
+        let window = *xcb::get_property(conn, false, root, active, xcb::ATOM_WINDOW, 0, 1)
+            .get_reply()
+            .ok()?
+            .value::<u32>()
+            .first()?;
+        let property = |property, kind| {
+            xcb::get_property(conn, false, window, property, kind, 0, 1024)
+                .get_reply()
+                .ok()
+                .map(|reply| String::from_utf8_lossy(reply.value::<u8>()).into_owned())
+                .filter(|title| !title.is_empty())
+        };
+        let net_name = atom("_NET_WM_NAME").zip(atom("UTF8_STRING"));
+        net_name
+            .and_then(|(name, utf8)| property(name, utf8))
+            .or_else(|| property(xcb::ATOM_WM_NAME, xcb::ATOM_STRING))
+    })
+}
+
+#[cfg(not(all(target_os = "linux", feature = "focus-convert")))]
+pub fn query_focused_title() -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor() -> FocusMonitor {
+        FocusMonitor::new(vec!["Slack".to_string(), "libreoffice".to_string()])
+    }
+
+    #[test]
+    fn test_is_target_app() {
+        let apps = vec!["Slack".to_string()];
+        assert!(is_target_app(&apps, "slack"));
+        assert!(is_target_app(&apps, "Slack"));
+        assert!(!is_target_app(&apps, "Slacker"));
+        assert!(!is_target_app(&[], "Slack"));
+    }
+
+    #[test]
+    fn test_title_filter() {
+        let filter = TitleFilter::new(&["*Terminal*".to_string(), "*- Vim".to_string()]).unwrap();
+        assert_eq!(filter.matching("GNOME Terminal"), Some("*Terminal*"));
+        assert_eq!(
+            filter.matching("~/src - terminal - bash"),
+            Some("*Terminal*")
+        );
+        assert_eq!(filter.matching("main.rs (~/src) - VIM"), Some("*- Vim"));
+        assert_eq!(filter.matching("Vim - notes"), None);
+        assert_eq!(filter.matching("Slack"), None);
+        // Everything else in a glob is literal
+        let filter = TitleFilter::new(&["[draft] ?.txt".to_string()]).unwrap();
+        assert!(filter.matching("[draft] a.txt").is_some());
+        assert!(filter.matching("d ab.txt").is_none());
+        assert!(TitleFilter::new(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_switch_to_target_triggers_once() {
+        let mut monitor = monitor();
+        let start = Instant::now();
+        let at = |n: u32| start + FOCUS_CHECK_INTERVAL * n;
+        assert!(!monitor.switched_to_target(at(0), || Some("firefox".to_string())));
+        assert!(monitor.switched_to_target(at(1), || Some("slack".to_string())));
+        // Staying in the app does not convert again
+        assert!(!monitor.switched_to_target(at(2), || Some("slack".to_string())));
+        // Neither does a failed query in between
+        assert!(!monitor.switched_to_target(at(3), || None));
+        assert!(!monitor.switched_to_target(at(4), || Some("slack".to_string())));
+        // Leaving to another app and coming back does
+        assert!(!monitor.switched_to_target(at(5), || Some("firefox".to_string())));
+        assert!(monitor.switched_to_target(at(6), || Some("Slack".to_string())));
+        // ...as does switching between two target apps
+        assert!(monitor.switched_to_target(at(7), || Some("libreoffice".to_string())));
+    }
+
+    #[test]
+    fn test_focus_at_startup_and_between_checks_is_ignored() {
+        let mut monitor = monitor();
+        let start = Instant::now();
+        assert!(!monitor.switched_to_target(start, || Some("slack".to_string())));
+        monitor.switched_to_target(start + FOCUS_CHECK_INTERVAL, || Some("firefox".to_string()));
+        let mut queried = false;
+        assert!(
+            !monitor.switched_to_target(start + FOCUS_CHECK_INTERVAL, || {
+                queried = true;
+                Some("slack".to_string())
+            })
+        );
+        assert!(!queried);
+    }
+
+    #[test]
+    fn test_no_apps_never_queries() {
+        let mut monitor = FocusMonitor::new(Vec::new());
+        let mut queried = false;
+        assert!(!monitor.switched_to_target(Instant::now(), || {
+            queried = true;
+            Some("slack".to_string())
+        }));
+        assert!(!queried);
+    }
+}
@@ -0,0 +1,2879 @@
+use aho_corasick::{AhoCorasick, MatchKind};
+use log::{debug, warn};
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::sync::OnceLock;
+use unicode_normalization::char::{decompose_compatible, is_combining_mark};
+use unicode_normalization::UnicodeNormalization;
+use unicode_script::{Script, UnicodeScript};
+
+use crate::code::{scoped_segments, CodeAware, CodeLanguage};
+use crate::config::{Occurrence, Replacement, ReplacementAction};
+use crate::delimiters::{mask, protect, unmask, uses_placeholders, DelimiterPair};
+use crate::error::FormatError;
+use crate::mojibake::fix_mojibake;
+use crate::table::{is_tabular, map_columns, TableDelimiter};
+
+const FULLWIDTH_YEN: char = '￥';
+// Symbols the width conversion cannot reach (they sit outside ！-～) and whose
+// NFKC forms are missing or wrong, e.g. ￣ -> a space plus a combining macron.
+// ￥ is added according to `yen_sign`, and `symbols` overrides entries.
+const SYMBOL_TABLE: &[(char, &str)] = &[
+    ('￠', "¢"),
+    ('￡', "£"),
+    ('￢', "¬"),
+    ('￣', "¯"),
+    ('￤', "¦"),
+    ('￦', "₩"),
+    // MINUS SIGN, which some IMEs insert for a fullwidth hyphen
+    ('−', "-"),
+];
+// Unit and squared symbols that stand for several characters, for
+// `expand_units`. `expansions` overrides entries.
+const EXPANSION_TABLE: &[(char, &str)] = &[
+    ('㎟', "mm2"),
+    ('㎠', "cm2"),
+    ('㎡', "m2"),
+    ('㎢', "km2"),
+    ('㎣', "mm3"),
+    ('㎤', "cm3"),
+    ('㎥', "m3"),
+    ('㎦', "km3"),
+    ('㎜', "mm"),
+    ('㎝', "cm"),
+    ('㎞', "km"),
+    ('㎎', "mg"),
+    ('㎏', "kg"),
+    ('㎖', "ml"),
+    ('㎗', "dl"),
+    ('㎘', "kl"),
+    ('㏄', "cc"),
+    ('℃', "°C"),
+    ('℉', "°F"),
+];
+// Halfwidth katakana U+FF66..=U+FF9D in code point order
+const HALFWIDTH_KATAKANA: &str =
+    "ヲァィゥェォャュョッーアイウエオカキクケコサシスセソタチツテトナニヌネノハヒフヘホマミムメモヤユヨラリルレロワン";
+const CJK_CLASS: &str = r"[\p{Hiragana}\p{Katakana}\p{Han}ー]";
+const LATIN_CLASS: &str = r"[A-Za-z0-9]";
+// Sentence delimiters and brackets that Japanese text keeps fullwidth
+const JAPANESE_PUNCTUATION: &[char] = &[
+    '。', '、', '「', '」', '『', '』', '（', '）', '【', '】', '・', '！', '？',
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggressiveness {
+    // Only the fullwidth ASCII range is converted
+    #[default]
+    Low,
+    // Also NFKC-normalizes (halfwidth katakana, circled digits, ...) and
+    // applies smart spacing, for ASCII-oriented targets
+    High,
+}
+
+// What the fullwidth yen sign ￥ (U+FFE5) becomes. It sits outside ！-～, so the
+// generic conversion never touches it and only NFKC would map it (to ¥).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum YenPolicy {
+    // As before `yen_sign` existed: NFKC (aggressiveness = high) makes ￥ ¥,
+    // and ￥ is kept otherwise
+    #[default]
+    Nfkc,
+    // ￥ is kept, also under aggressiveness = high
+    Preserve,
+    // ￥ -> ¥ (U+00A5)
+    Yen,
+    // ￥ -> \, for targets where the yen sign is the path separator (Shift_JIS)
+    Backslash,
+}
+
+impl YenPolicy {
+    fn target(self) -> Option<char> {
+        match self {
+            YenPolicy::Nfkc | YenPolicy::Preserve => None,
+            YenPolicy::Yen => Some('¥'),
+            YenPolicy::Backslash => Some('\\'),
+        }
+    }
+}
+
+// The passes an exclusion can be limited to, e.g. {"char": "～", "stages": ["width"]}
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ExclusionStage {
+    Symbols,
+    Expansions,
+    Brackets,
+    // Width conversion, digit grouping included
+    Width,
+    Nfkc,
+    Katakana,
+}
+
+// Characters excluded from some stages only. Those excluded from every
+// stage are in the plain exclusion list instead.
+pub type StageExclusions = BTreeMap<char, Vec<ExclusionStage>>;
+
+// How the width conversion finds a character's halfwidth form
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WidthMapping {
+    // ！-～ shifted down by 0xFEE0 to !-~
+    #[default]
+    Offset,
+    // The <wide> compatibility mapping from the Unicode data, for every wide
+    // form that has a single, standalone counterpart
+    Compatibility,
+}
+
+impl WidthMapping {
+    fn convert(self, c: char) -> char {
+        match self {
+            WidthMapping::Offset => to_half_width(c),
+            WidthMapping::Compatibility => compatibility_half_width(c).unwrap_or(c),
+        }
+    }
+}
+
+// Whether the replacement rules see the text before or after the width
+// conversion and the other character stages. Spacing cleanups always run last.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StageOrder {
+    #[default]
+    ReplacementsFirst,
+    // Rules are written against halfwidth text
+    WidthFirst,
+}
+
+// What happens to U+3000 IDEOGRAPHIC SPACE, by its position in the line
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdeographicSpace {
+    // Left to the other stages, e.g. NFKC with aggressiveness = "high"
+    #[default]
+    Default,
+    // Every one becomes a space
+    Convert,
+    // Except at the start of a line, where it indents a paragraph
+    KeepLeading,
+    // Except at the start and the end of a line
+    KeepEnds,
+}
+
+const IDEOGRAPHIC_SPACE: char = '\u{3000}';
+
+// Each line's U+3000 outside the kept leading/trailing runs becomes a space
+fn convert_ideographic_spaces(text: &str, policy: IdeographicSpace) -> String {
+    text.split('\n')
+        .map(|line| {
+            let body = line.trim_start_matches(IDEOGRAPHIC_SPACE);
+            let leading = &line[..line.len() - body.len()];
+            let (body, trailing) = match policy {
+                IdeographicSpace::KeepEnds => {
+                    // A trailing \r stays outside the kept run
+                    let (body, cr) = match body.strip_suffix('\r') {
+                        Some(body) => (body, "\r"),
+                        None => (body, ""),
+                    };
+                    let trimmed = body.trim_end_matches(IDEOGRAPHIC_SPACE);
+                    (trimmed, format!("{}{}", &body[trimmed.len()..], cr))
+                }
+                _ => (body, String::new()),
+            };
+            let leading = match policy {
+                IdeographicSpace::Convert => leading.replace(IDEOGRAPHIC_SPACE, " "),
+                _ => leading.to_string(),
+            };
+            format!(
+                "{}{}{}",
+                leading,
+                body.replace(IDEOGRAPHIC_SPACE, " "),
+                trailing
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// CJK brackets outside the ！-～ range, which the width conversion leaves alone.
+// （） are in that range and always become ().
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BracketStyle {
+    #[default]
+    Off,
+    // 「」 -> "", 『』 -> '', 【】〔〕〖〗〘〙 -> [], 〈〉《》 -> <>
+    Ascii,
+    // Every family -> []
+    Square,
+    // Every family -> ()
+    Round,
+}
+
+#[derive(Clone, Copy)]
+enum BracketFamily {
+    Corner,
+    WhiteCorner,
+    Lenticular,
+    Angle,
+}
+
+impl BracketStyle {
+    fn target(self, c: char) -> Option<char> {
+        let (family, open) = match c {
+            '「' | '｢' => (BracketFamily::Corner, true),
+            '」' | '｣' => (BracketFamily::Corner, false),
+            '『' => (BracketFamily::WhiteCorner, true),
+            '』' => (BracketFamily::WhiteCorner, false),
+            '【' | '〔' | '〖' | '〘' => (BracketFamily::Lenticular, true),
+            '】' | '〕' | '〗' | '〙' => (BracketFamily::Lenticular, false),
+            '〈' | '《' => (BracketFamily::Angle, true),
+            '〉' | '》' => (BracketFamily::Angle, false),
+            _ => return None,
+        };
+        let (opening, closing) = match (self, family) {
+            (BracketStyle::Off, _) => return None,
+            (BracketStyle::Ascii, BracketFamily::Corner) => ('"', '"'),
+            (BracketStyle::Ascii, BracketFamily::WhiteCorner) => ('\'', '\''),
+            (BracketStyle::Ascii, BracketFamily::Lenticular) | (BracketStyle::Square, _) => {
+                ('[', ']')
+            }
+            (BracketStyle::Ascii, BracketFamily::Angle) => ('<', '>'),
+            (BracketStyle::Round, _) => ('(', ')'),
+        };
+        Some(if open { opening } else { closing })
+    }
+}
+
+// A Unicode script by its full or short name, e.g. "Latin" or "Latn". Fullwidth
+// digits and symbols belong to "Common".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct ScriptName(Script);
+
+impl TryFrom<String> for ScriptName {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        Script::from_full_name(&name)
+            .or_else(|| Script::from_short_name(&name))
+            .map(ScriptName)
+            .ok_or_else(|| format!("unknown Unicode script '{}'", name))
+    }
+}
+
+// Separators inserted into long runs of fullwidth digits as they are halved,
+// e.g. "１２３４５６" -> "123,456"
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct DigitGrouping {
+    // Shorter runs, such as years, are left alone
+    pub min_digits: usize,
+    pub group_size: usize,
+    pub separator: String,
+}
+
+impl Default for DigitGrouping {
+    fn default() -> Self {
+        DigitGrouping {
+            min_digits: 5,
+            group_size: 3,
+            separator: ",".to_string(),
+        }
+    }
+}
+
+pub const DEFAULT_MAX_GROWTH_FACTOR: f64 = 10.0;
+// Rule sets of at least this many rules are matched with one automaton instead
+// of one pass per rule, where that gives the same result. Smaller sets are
+// faster with the passes; see the crossover in benches/format.rs.
+pub const DEFAULT_AUTOMATON_THRESHOLD: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaceStrategy {
+    // One pass per rule, each on the previous rule's output
+    Sequential,
+    // A single Aho-Corasick pass over all originals
+    Automaton,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct FormatOptions {
+    pub aggressiveness: Aggressiveness,
+    pub smart_spacing: bool,
+    // Collapse runs of spaces/tabs into a single space
+    pub collapse_spaces: bool,
+    // Strip whitespace at both ends of every line
+    pub trim_lines: bool,
+    // Never touch leading whitespace, even with collapse_spaces/trim_lines
+    pub preserve_indentation: bool,
+    pub preserve_japanese_punctuation: bool,
+    pub max_replacements: Option<usize>,
+    // Output longer than this multiple of the input is discarded; 0 disables
+    pub max_growth_factor: f64,
+    pub yen_sign: YenPolicy,
+    pub bracket_style: BracketStyle,
+    pub stage_order: StageOrder,
+    pub width_mapping: WidthMapping,
+    pub ideographic_space: IdeographicSpace,
+    // Extra or overriding entries for SYMBOL_TABLE
+    pub symbols: BTreeMap<char, String>,
+    // Expand unit symbols such as ㎡ and ℃ into several characters
+    pub expand_units: bool,
+    // Extra or overriding entries for EXPANSION_TABLE; "" keeps the symbol
+    pub expansions: BTreeMap<char, String>,
+    // Compose kana followed by a combining (semi-)voiced mark, e.g. か + U+3099 -> が
+    pub compose_dakuten: bool,
+    // Re-decode UTF-8 that was misread as Windows-1252/Latin-1 before anything else
+    pub fix_mojibake: bool,
+    // Characters matching this regex (e.g. `\p{P}`) are excluded like those in exclusions.json
+    pub exclude_pattern: Option<String>,
+    // exclude_pattern compiled by compile_exclude_pattern, so conversions do not
+    // each rebuild it
+    #[serde(skip)]
+    pub exclude_regex: Option<Regex>,
+    // When not empty, characters of other scripts are excluded
+    pub scripts: Vec<ScriptName>,
+    // 0 always runs the rules one by one
+    pub automaton_threshold: usize,
+    // Fold fullwidth and halfwidth katakana to hiragana, e.g. for search keys
+    pub katakana_to_hiragana: bool,
+    // Text between these delimiters, e.g. ["「", "」"], is never converted
+    pub protected_delimiters: Vec<DelimiterPair>,
+    pub digit_grouping: Option<DigitGrouping>,
+    // Limit width conversion and NFKC to string literals and comments, or to the code around them
+    pub code_aware: CodeAware,
+    pub code_language: CodeLanguage,
+    // Treat the text as TSV/CSV and convert only these 1-based columns; empty converts it all
+    pub table_columns: Vec<usize>,
+    pub table_delimiter: TableDelimiter,
+    // From exclusions.json rather than settings.json
+    #[serde(skip)]
+    pub stage_exclusions: StageExclusions,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            aggressiveness: Aggressiveness::default(),
+            smart_spacing: false,
+            collapse_spaces: false,
+            trim_lines: false,
+            preserve_indentation: false,
+            preserve_japanese_punctuation: false,
+            max_replacements: None,
+            max_growth_factor: DEFAULT_MAX_GROWTH_FACTOR,
+            yen_sign: YenPolicy::default(),
+            bracket_style: BracketStyle::default(),
+            stage_order: StageOrder::default(),
+            width_mapping: WidthMapping::default(),
+            ideographic_space: IdeographicSpace::default(),
+            symbols: BTreeMap::new(),
+            expand_units: false,
+            expansions: BTreeMap::new(),
+            compose_dakuten: false,
+            fix_mojibake: false,
+            exclude_pattern: None,
+            exclude_regex: None,
+            scripts: Vec::new(),
+            automaton_threshold: DEFAULT_AUTOMATON_THRESHOLD,
+            katakana_to_hiragana: false,
+            protected_delimiters: Vec::new(),
+            digit_grouping: None,
+            code_aware: CodeAware::default(),
+            code_language: CodeLanguage::default(),
+            table_columns: Vec::new(),
+            table_delimiter: TableDelimiter::default(),
+            stage_exclusions: StageExclusions::new(),
+        }
+    }
+}
+
+impl FormatOptions {
+    // Called once the settings are loaded
+    pub fn compile_exclude_pattern(&mut self) -> Result<(), FormatError> {
+        self.exclude_regex = self
+            .exclude_pattern
+            .as_deref()
+            .map(exclude_regex)
+            .transpose()?;
+        Ok(())
+    }
+}
+
+fn exclude_regex(pattern: &str) -> Result<Regex, FormatError> {
+    Regex::new(pattern).map_err(|source| FormatError::ExcludePattern {
+        pattern: pattern.to_string(),
+        source,
+    })
+}
+
+pub fn format_text(
+    text: &str,
+    replacements: &[Replacement],
+    exclusion_list: &[char],
+) -> Result<String, FormatError> {
+    format_text_with_options(
+        text,
+        replacements,
+        exclusion_list,
+        &FormatOptions::default(),
+    )
+}
+
+pub fn format_text_with_options(
+    text: &str,
+    replacements: &[Replacement],
+    exclusion_list: &[char],
+    options: &FormatOptions,
+) -> Result<String, FormatError> {
+    format_traced(text, replacements, exclusion_list, options, None)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stage {
+    pub label: String,
+    pub text: String,
+    // Index of the replacement rule that produced this stage, if any
+    pub rule: Option<usize>,
+}
+
+// The result of one conversion for `--stdin --json`
+#[derive(Debug, serde::Serialize)]
+pub struct ConversionReport {
+    pub input: String,
+    pub output: String,
+    pub changed: bool,
+    // `original` of each rule that changed the text, in the order they ran
+    pub rules_fired: Vec<String>,
+}
+
+// Intermediate results for `--explain`: the input, then every stage that
+// changed the text, in pipeline order. The last entry is the final result.
+pub fn explain_format(
+    text: &str,
+    replacements: &[Replacement],
+    exclusion_list: &[char],
+    options: &FormatOptions,
+) -> Result<Vec<Stage>, FormatError> {
+    let mut trace = vec![Stage {
+        label: "input".to_string(),
+        text: text.to_string(),
+        rule: None,
+    }];
+    format_traced(
+        text,
+        replacements,
+        exclusion_list,
+        options,
+        Some(&mut trace),
+    )?;
+    trace.dedup_by(|stage, previous| stage.text == previous.text);
+    Ok(trace)
+}
+
+// The rules `--isolate-rule` picks: the one with this number, as --explain
+// shows it, or else every rule with this `original`
+pub fn find_rules(replacements: &[Replacement], label: &str) -> Vec<usize> {
+    if let Ok(number) = label.parse::<usize>() {
+        if (1..=replacements.len()).contains(&number) {
+            return vec![number - 1];
+        }
+    }
+    replacements
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| rule.original == label)
+        .map(|(index, _)| index)
+        .collect()
+}
+
+// --explain for some of the rules alone. Without `with_stages` nothing else
+// runs, not even the width conversion. Rules keep their numbers in the full list.
+pub fn explain_isolated(
+    text: &str,
+    replacements: &[Replacement],
+    indices: &[usize],
+    exclusion_list: &[char],
+    options: &FormatOptions,
+    with_stages: bool,
+) -> Result<Vec<Stage>, FormatError> {
+    let selected: Vec<Replacement> = indices
+        .iter()
+        .map(|&index| replacements[index].clone())
+        .collect();
+    let mut stages = if with_stages {
+        explain_format(text, &selected, exclusion_list, options)?
+    } else {
+        let mut trace = vec![Stage {
+            label: "input".to_string(),
+            text: text.to_string(),
+            rule: None,
+        }];
+        apply_rules(
+            text.to_string(),
+            &selected,
+            options,
+            None,
+            &mut Some(&mut trace),
+        )?;
+        trace.dedup_by(|stage, previous| stage.text == previous.text);
+        trace
+    };
+    for stage in &mut stages {
+        if let Some(rule) = stage.rule {
+            let index = indices[rule];
+            stage.rule = Some(index);
+            stage.label = rule_label(index, &replacements[index]);
+        }
+    }
+    Ok(stages)
+}
+
+fn rule_label(index: usize, rule: &Replacement) -> String {
+    format!("rule {} ({:?})", index + 1, rule.original)
+}
+
+pub fn report_format(
+    text: &str,
+    replacements: &[Replacement],
+    exclusion_list: &[char],
+    options: &FormatOptions,
+) -> Result<ConversionReport, FormatError> {
+    let stages = explain_format(text, replacements, exclusion_list, options)?;
+    let output = stages.last().map_or(text, |stage| &stage.text).to_string();
+    Ok(ConversionReport {
+        input: text.to_string(),
+        changed: output != text,
+        output,
+        rules_fired: stages
+            .iter()
+            .filter_map(|stage| stage.rule)
+            .map(|index| replacements[index].original.clone())
+            .collect(),
+    })
+}
+
+fn record(trace: &mut Option<&mut Vec<Stage>>, label: impl FnOnce() -> String, text: &str) {
+    record_rule(trace, None, label, text);
+}
+
+fn record_rule(
+    trace: &mut Option<&mut Vec<Stage>>,
+    rule: Option<usize>,
+    label: impl FnOnce() -> String,
+    text: &str,
+) {
+    if let Some(trace) = trace {
+        trace.push(Stage {
+            label: label(),
+            text: text.to_string(),
+            rule,
+        });
+    }
+}
+
+fn format_traced(
+    text: &str,
+    replacements: &[Replacement],
+    exclusion_list: &[char],
+    options: &FormatOptions,
+    mut trace: Option<&mut Vec<Stage>>,
+) -> Result<String, FormatError> {
+    // Before the delimiters and columns are found, which the damage may hide
+    let repaired = options.fix_mojibake.then(|| fix_mojibake(text)).flatten();
+    let text = match &repaired {
+        Some(repaired) => {
+            record(&mut trace, || "fix mojibake".to_string(), repaired);
+            repaired.as_str()
+        }
+        None => text,
+    };
+    if options.table_columns.is_empty() || !is_tabular(text, options.table_delimiter) {
+        return format_protected(text, replacements, exclusion_list, options, trace);
+    }
+    // Cells are converted one by one, so the trace only shows the result
+    let formatted = map_columns(
+        text,
+        options.table_delimiter,
+        &options.table_columns,
+        |cell| format_protected(cell, replacements, exclusion_list, options, None),
+    )?;
+    record(
+        &mut trace,
+        || format!("table columns {:?}", options.table_columns),
+        &formatted,
+    );
+    Ok(formatted)
+}
+
+fn format_protected(
+    text: &str,
+    replacements: &[Replacement],
+    exclusion_list: &[char],
+    options: &FormatOptions,
+    mut trace: Option<&mut Vec<Stage>>,
+) -> Result<String, FormatError> {
+    let (masked, mut spans) = mask(text, &options.protected_delimiters);
+    // Rules with protect_output add their spans after the delimiters' ones
+    let protectable = !uses_placeholders(text);
+    let traced_from = trace.as_ref().map_or(0, |trace| trace.len());
+    let formatted = format_unmasked(
+        &masked,
+        replacements,
+        exclusion_list,
+        options,
+        protectable.then_some(&mut spans),
+        trace.as_deref_mut(),
+    )?;
+    if spans.is_empty() {
+        return Ok(formatted);
+    }
+    if let Some(trace) = trace {
+        for stage in &mut trace[traced_from..] {
+            stage.text = unmask(&stage.text, &spans);
+        }
+    }
+    Ok(unmask(&formatted, &spans))
+}
+
+fn format_unmasked(
+    text: &str,
+    replacements: &[Replacement],
+    exclusion_list: &[char],
+    options: &FormatOptions,
+    mut spans: Option<&mut Vec<String>>,
+    mut trace: Option<&mut Vec<Stage>>,
+) -> Result<String, FormatError> {
+    // Runs first, so rules written with precomposed kana also match
+    let mut formatted_content = if options.compose_dakuten {
+        compose_dakuten(text)
+    } else {
+        text.to_string()
+    };
+    record(
+        &mut trace,
+        || "compose dakuten".to_string(),
+        &formatted_content,
+    );
+    if options.stage_order == StageOrder::ReplacementsFirst {
+        formatted_content = apply_rules(
+            formatted_content,
+            replacements,
+            options,
+            spans.as_deref_mut(),
+            &mut trace,
+        )?;
+    }
+    // Options built without compile_exclude_pattern compile it here
+    let uncompiled = match (&options.exclude_regex, &options.exclude_pattern) {
+        (None, Some(pattern)) => Some(exclude_regex(pattern)?),
+        _ => None,
+    };
+    let exclude_re = options.exclude_regex.as_ref().or(uncompiled.as_ref());
+    let is_excluded = |stage: ExclusionStage, c: char| {
+        exclusion_list.contains(&c)
+            || options
+                .stage_exclusions
+                .get(&c)
+                .is_some_and(|stages| stages.contains(&stage))
+            || exclude_re.is_some_and(|re| re.is_match(c.encode_utf8(&mut [0; 4])))
+            || !options.scripts.is_empty() && !options.scripts.contains(&ScriptName(c.script()))
+    };
+    let is_protected = |stage: ExclusionStage, c: char| {
+        is_excluded(stage, c)
+            || (options.preserve_japanese_punctuation && JAPANESE_PUNCTUATION.contains(&c))
+            || (c == FULLWIDTH_YEN && options.yen_sign == YenPolicy::Preserve)
+    };
+    // Before the width conversion, so the table has the final say on its symbols
+    let symbols = symbol_table(options);
+    formatted_content = formatted_content
+        .chars()
+        .fold(String::new(), |mut converted, c| {
+            match symbols.get(&c) {
+                Some(target) if !is_excluded(ExclusionStage::Symbols, c) => {
+                    converted.push_str(target)
+                }
+                _ => converted.push(c),
+            }
+            converted
+        });
+    record(&mut trace, || "symbols".to_string(), &formatted_content);
+    if options.expand_units {
+        let expansions = expansion_table(options);
+        formatted_content = formatted_content
+            .chars()
+            .fold(String::new(), |mut converted, c| {
+                match expansions.get(&c) {
+                    Some(target) if !is_excluded(ExclusionStage::Expansions, c) => {
+                        converted.push_str(target)
+                    }
+                    _ => converted.push(c),
+                }
+                converted
+            });
+        record(&mut trace, || "expansions".to_string(), &formatted_content);
+    }
+    if options.bracket_style != BracketStyle::Off {
+        formatted_content = map_in_scope(&formatted_content, options, |segment| {
+            Ok(segment
+                .chars()
+                .map(|c| match options.bracket_style.target(c) {
+                    Some(target) if !is_protected(ExclusionStage::Brackets, c) => target,
+                    _ => c,
+                })
+                .collect())
+        })?;
+        record(&mut trace, || "brackets".to_string(), &formatted_content);
+    }
+    if let Some(grouping) = &options.digit_grouping {
+        formatted_content = map_in_scope(&formatted_content, options, |segment| {
+            Ok(group_fullwidth_digits(segment, grouping, |c| {
+                is_protected(ExclusionStage::Width, c)
+            }))
+        })?;
+        record(
+            &mut trace,
+            || "digit grouping".to_string(),
+            &formatted_content,
+        );
+    }
+    formatted_content = map_in_scope(&formatted_content, options, |segment| {
+        let is_protected = |c| is_protected(ExclusionStage::Width, c);
+        match options.width_mapping {
+            WidthMapping::Offset => halve_fullwidth(segment, is_protected),
+            WidthMapping::Compatibility => Ok(halve_by_compatibility(segment, is_protected)),
+        }
+    })?;
+    record(
+        &mut trace,
+        || "width conversion".to_string(),
+        &formatted_content,
+    );
+    if options.aggressiveness == Aggressiveness::High {
+        // What the symbol table produced is final
+        let is_symbol = |c: char| symbols.values().any(|target| target.contains(c));
+        formatted_content = map_in_scope(&formatted_content, options, |segment| {
+            Ok(nfkc_except(segment, |c| {
+                is_protected(ExclusionStage::Nfkc, c)
+                    || is_symbol(c)
+                    || (c == IDEOGRAPHIC_SPACE
+                        && options.ideographic_space != IdeographicSpace::Default)
+            }))
+        })?;
+        record(&mut trace, || "nfkc".to_string(), &formatted_content);
+    }
+    // After NFKC, which would otherwise turn the kept ones into spaces
+    if options.ideographic_space != IdeographicSpace::Default
+        && !is_protected(ExclusionStage::Width, IDEOGRAPHIC_SPACE)
+    {
+        formatted_content = map_in_scope(&formatted_content, options, |segment| {
+            Ok(convert_ideographic_spaces(
+                segment,
+                options.ideographic_space,
+            ))
+        })?;
+        record(
+            &mut trace,
+            || "ideographic spaces".to_string(),
+            &formatted_content,
+        );
+    }
+    if options.katakana_to_hiragana {
+        formatted_content = katakana_to_hiragana(&formatted_content, |c| {
+            is_excluded(ExclusionStage::Katakana, c)
+        });
+        record(&mut trace, || "hiragana".to_string(), &formatted_content);
+    }
+    if options.stage_order == StageOrder::WidthFirst {
+        formatted_content = apply_rules(
+            formatted_content,
+            replacements,
+            options,
+            spans.as_deref_mut(),
+            &mut trace,
+        )?;
+    }
+    let smart_spacing = options.smart_spacing || options.aggressiveness == Aggressiveness::High;
+    // Whitespace in the regions code_aware keeps is kept too, indentation included
+    if options.code_aware != CodeAware::Off
+        && (options.collapse_spaces || options.trim_lines || smart_spacing)
+    {
+        if let Some(spans) = spans.as_deref_mut() {
+            formatted_content = protect_out_of_scope(&formatted_content, options, spans);
+        }
+    }
+    if options.collapse_spaces || options.trim_lines {
+        formatted_content = normalize_spaces(&formatted_content, options)?;
+        record(&mut trace, || "spaces".to_string(), &formatted_content);
+    }
+    if smart_spacing {
+        formatted_content = apply_smart_spacing(&formatted_content)?;
+        record(
+            &mut trace,
+            || "smart spacing".to_string(),
+            &formatted_content,
+        );
+    }
+    // A runaway rule must not be able to flood the clipboard. Protected output
+    // is measured at its real length.
+    let grown = match spans.as_deref() {
+        Some(spans) if !spans.is_empty() => unmask(&formatted_content, spans),
+        _ => formatted_content.clone(),
+    };
+    if exceeds_growth_limit(text, &grown, options.max_growth_factor) {
+        warn!(
+            "Conversion grew the text from {} to {} bytes (max_growth_factor {}). Keeping the original.",
+            text.len(),
+            grown.len(),
+            options.max_growth_factor
+        );
+        record(&mut trace, || "growth guard".to_string(), text);
+        return Ok(text.to_string());
+    }
+    Ok(formatted_content)
+}
+
+// The replacement rules, before or after the width conversion as `stage_order` says
+fn apply_rules(
+    mut formatted_content: String,
+    replacements: &[Replacement],
+    options: &FormatOptions,
+    mut spans: Option<&mut Vec<String>>,
+    trace: &mut Option<&mut Vec<Stage>>,
+) -> Result<String, FormatError> {
+    // --explain wants a stage per rule, so it always takes the sequential path
+    let strategy = if trace.is_some() {
+        ReplaceStrategy::Sequential
+    } else {
+        replace_strategy(replacements, options)
+    };
+    let mut budget = options.max_replacements.unwrap_or(usize::MAX);
+    if strategy == ReplaceStrategy::Automaton {
+        formatted_content = replace_with_automaton(&formatted_content, replacements)?;
+    }
+    // Rules run one after another in file order, each on the previous rule's
+    // output. Results depend on this order; the ordering tests below pin it down.
+    let sequential = match strategy {
+        ReplaceStrategy::Sequential => replacements,
+        ReplaceStrategy::Automaton => &[],
+    };
+    for (index, replacement) in sequential.iter().enumerate() {
+        let (matches, count) = if let Some(pattern) = replacement.pattern() {
+            let re = rule_regex(index, replacement, &pattern)?;
+            let ranges: Vec<Range<usize>> = re
+                .find_iter(&formatted_content)
+                .take(replacement.max_matches.unwrap_or(usize::MAX))
+                .map(|m| m.range())
+                .collect();
+            let ranges = replacement.occurrence.select(&ranges);
+            let count = ranges.len().min(budget);
+            formatted_content = replace_ranges(&formatted_content, &ranges[..count], |matched| {
+                let output = if replacement.action == ReplacementAction::ConvertWidth {
+                    matched
+                        .chars()
+                        .map(|c| options.width_mapping.convert(c))
+                        .collect()
+                } else {
+                    replacement.replacement.clone()
+                };
+                protect_output(replacement, output, spans.as_deref_mut())
+            });
+            (ranges.len(), count)
+        } else {
+            let positions = rule_positions(&formatted_content, index, replacement)?;
+            let positions = replacement.occurrence.select(&positions);
+            let count = positions.len().min(budget);
+            let output = protect_output(
+                replacement,
+                replacement.replacement.clone(),
+                spans.as_deref_mut(),
+            );
+            let replaced = replace_at(
+                &formatted_content,
+                &positions[..count],
+                replacement.original.len(),
+                &output,
+            );
+            if replacement.is_deletion() && replaced != formatted_content {
+                debug!("Deletion rule removed '{}'.", replacement.original);
+            }
+            formatted_content = replaced;
+            (positions.len(), count)
+        };
+        record_rule(
+            trace,
+            Some(index),
+            || rule_label(index, replacement),
+            &formatted_content,
+        );
+        budget -= count;
+        if count < matches {
+            warn!(
+                "Reached max_replacements ({}); remaining substitutions were skipped.",
+                options.max_replacements.unwrap_or_default()
+            );
+            break;
+        }
+    }
+    Ok(formatted_content)
+}
+
+fn symbol_table(options: &FormatOptions) -> BTreeMap<char, String> {
+    let mut table: BTreeMap<char, String> = SYMBOL_TABLE
+        .iter()
+        .map(|&(symbol, target)| (symbol, target.to_string()))
+        .collect();
+    if let Some(target) = options.yen_sign.target() {
+        table.insert(FULLWIDTH_YEN, target.to_string());
+    }
+    table.extend(options.symbols.clone());
+    table
+}
+
+fn expansion_table(options: &FormatOptions) -> BTreeMap<char, String> {
+    let mut table: BTreeMap<char, String> = EXPANSION_TABLE
+        .iter()
+        .map(|&(symbol, target)| (symbol, target.to_string()))
+        .collect();
+    table.extend(options.expansions.clone());
+    table.retain(|_, target| !target.is_empty());
+    table
+}
+
+// The parts `code_aware` leaves alone as placeholders, put back by unmask
+// with the delimiter spans. A part is kept as it is if placeholders run out.
+fn protect_out_of_scope(text: &str, options: &FormatOptions, spans: &mut Vec<String>) -> String {
+    scoped_segments(text, options.code_aware, options.code_language)
+        .into_iter()
+        .map(|(segment, in_scope)| match in_scope {
+            true => segment.to_string(),
+            false => protect(segment, spans).map_or_else(|| segment.to_string(), String::from),
+        })
+        .collect()
+}
+
+// Applies `convert` to the parts of the text that `code_aware` allows
+fn map_in_scope(
+    text: &str,
+    options: &FormatOptions,
+    mut convert: impl FnMut(&str) -> Result<String, FormatError>,
+) -> Result<String, FormatError> {
+    let mut converted = String::with_capacity(text.len());
+    for (segment, in_scope) in scoped_segments(text, options.code_aware, options.code_language) {
+        if in_scope {
+            converted.push_str(&convert(segment)?);
+        } else {
+            converted.push_str(segment);
+        }
+    }
+    Ok(converted)
+}
+
+fn exceeds_growth_limit(input: &str, output: &str, max_growth_factor: f64) -> bool {
+    max_growth_factor > 0.0 && output.len() as f64 > max_growth_factor * input.len() as f64
+}
+
+fn normalize_spaces(text: &str, options: &FormatOptions) -> Result<String, FormatError> {
+    let runs = Regex::new(r"[ \t]{2,}")?;
+    let lines: Vec<String> = text
+        .split('\n')
+        .map(|line| {
+            let (line, cr) = match line.strip_suffix('\r') {
+                Some(line) => (line, "\r"),
+                None => (line, ""),
+            };
+            let body_start = if options.preserve_indentation {
+                line.len() - line.trim_start().len()
+            } else {
+                0
+            };
+            let (indent, mut body) = (&line[..body_start], line[body_start..].to_string());
+            if options.collapse_spaces {
+                body = runs.replace_all(&body, " ").into_owned();
+            }
+            if options.trim_lines {
+                body = if options.preserve_indentation {
+                    body.trim_end().to_string()
+                } else {
+                    body.trim().to_string()
+                };
+            }
+            format!("{}{}{}", indent, body, cr)
+        })
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+// Only U+3099/U+309A are composed; full NFC would also touch unrelated text
+fn compose_dakuten(text: &str) -> String {
+    let mut composed = String::with_capacity(text.len());
+    let mut pending: Option<char> = None;
+    for c in text.chars() {
+        if let (Some(base), '\u{3099}' | '\u{309A}') = (pending, c) {
+            if let Some(precomposed) = unicode_normalization::char::compose(base, c) {
+                pending = Some(precomposed);
+                continue;
+            }
+        }
+        composed.extend(pending.replace(c));
+    }
+    composed.extend(pending);
+    composed
+}
+
+// Only runs of fullwidth digits are grouped, before they are halved, so numbers
+// that were already ASCII stay as written. Runs that are part of an identifier
+// ("ｖ１２３４５"), a fraction ("．１２３４５") or an already grouped number are skipped.
+fn group_fullwidth_digits(
+    text: &str,
+    grouping: &DigitGrouping,
+    is_protected: impl Fn(char) -> bool,
+) -> String {
+    let is_digit = |c: char| ('０'..='９').contains(&c);
+    let is_word = |c: char| {
+        c.is_ascii_alphanumeric()
+            || matches!(c, '_' | '＿' | 'Ａ'..='Ｚ' | 'ａ'..='ｚ' | '０'..='９')
+    };
+    let chars: Vec<char> = text.chars().collect();
+    let mut grouped = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if !is_digit(chars[i]) {
+            grouped.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && is_digit(chars[i]) {
+            i += 1;
+        }
+        let run = &chars[start..i];
+        let before = start.checked_sub(1).map(|index| chars[index]);
+        let after = chars.get(i).copied();
+        let groupable = grouping.group_size > 0
+            && run.len() >= grouping.min_digits
+            && !run.iter().any(|&c| is_protected(c))
+            && !before.is_some_and(|c| is_word(c) || matches!(c, '.' | '．' | ',' | '，'))
+            && !after.is_some_and(|c| is_word(c) || matches!(c, ',' | '，'));
+        for (index, &digit) in run.iter().enumerate() {
+            let remaining = run.len() - index;
+            if groupable && index > 0 && remaining.is_multiple_of(grouping.group_size) {
+                grouped.push_str(&grouping.separator);
+            }
+            grouped.push(digit);
+        }
+    }
+    grouped
+}
+
+// Halfwidth katakana is widened first, taking a following ﾞ/ﾟ with it, so
+// "ｶﾞ" becomes "が". Katakana without a hiragana form (ヷ, ー, ...) is kept.
+fn katakana_to_hiragana(text: &str, is_excluded: impl Fn(char) -> bool) -> String {
+    let mut folded = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if is_excluded(c) {
+            folded.push(c);
+            continue;
+        }
+        let mut kana = c;
+        if let '\u{FF66}'..='\u{FF9D}' = c {
+            kana = HALFWIDTH_KATAKANA
+                .chars()
+                .nth(c as usize - 0xFF66)
+                .unwrap_or(c);
+            let mark = match chars.peek() {
+                Some('\u{FF9E}') => Some('\u{3099}'),
+                Some('\u{FF9F}') => Some('\u{309A}'),
+                _ => None,
+            };
+            if let Some(voiced) =
+                mark.and_then(|mark| unicode_normalization::char::compose(kana, mark))
+            {
+                chars.next();
+                kana = voiced;
+            }
+        }
+        folded.push(match kana {
+            // ァ..ヶ and ヽヾ sit 0x60 above their hiragana
+            '\u{30A1}'..='\u{30F6}' | '\u{30FD}' | '\u{30FE}' => {
+                char::from_u32(kana as u32 - 0x60).unwrap_or(kana)
+            }
+            kana => kana,
+        });
+    }
+    folded
+}
+
+// NFKC-normalizes the text between protected characters, leaving those as is.
+// Normalizing whole segments keeps compositions such as "ｶﾞ" -> "ガ" intact.
+fn nfkc_except(text: &str, is_protected: impl Fn(char) -> bool) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    let mut segment = String::new();
+    for c in text.chars() {
+        if is_protected(c) {
+            normalized.extend(segment.nfkc());
+            segment.clear();
+            normalized.push(c);
+        } else {
+            segment.push(c);
+        }
+    }
+    normalized.extend(segment.nfkc());
+    normalized
+}
+
+// One automaton pass for large rule sets that give the same result that way,
+// the rules one by one otherwise
+pub fn replace_strategy(replacements: &[Replacement], options: &FormatOptions) -> ReplaceStrategy {
+    let large =
+        options.automaton_threshold > 0 && replacements.len() >= options.automaton_threshold;
+    if large && options.max_replacements.is_none() && matches_independently(replacements) {
+        ReplaceStrategy::Automaton
+    } else {
+        ReplaceStrategy::Sequential
+    }
+}
+
+// Whether one leftmost-first pass gives the same result as running the rules
+// in order. That holds for plain literal rules whose matches can neither
+// overlap nor be created by an earlier rule: distinct originals start with
+// distinct characters, no original contains another's first character after
+// its own start, and no replacement contains a character of any original.
+// Deletions are out, since joining the text around them can create a match.
+fn matches_independently(replacements: &[Replacement]) -> bool {
+    let mut originals: HashMap<char, &str> = HashMap::new();
+    let mut original_chars = HashSet::new();
+    for rule in replacements {
+        let plain = rule.action == ReplacementAction::Replace
+            && !rule.is_deletion()
+            && !rule.protect_output
+            && rule.context_before.is_none()
+            && rule.context_after.is_none()
+            && rule.occurrence == Occurrence::All;
+        let Some(first) = rule.original.chars().next() else {
+            return false;
+        };
+        if !plain || *originals.entry(first).or_insert(&rule.original) != rule.original {
+            return false;
+        }
+        original_chars.extend(rule.original.chars());
+    }
+    let inner_starts = replacements
+        .iter()
+        .flat_map(|rule| rule.original.chars().skip(1))
+        .any(|c| originals.contains_key(&c));
+    let creates_matches = replacements.iter().any(|rule| {
+        rule.replacement
+            .chars()
+            .any(|c| original_chars.contains(&c))
+    });
+    !inner_starts && !creates_matches
+}
+
+fn replace_with_automaton(text: &str, replacements: &[Replacement]) -> Result<String, FormatError> {
+    let automaton = AhoCorasick::builder()
+        .match_kind(MatchKind::LeftmostFirst)
+        .build(replacements.iter().map(|rule| &rule.original))?;
+    let targets: Vec<&str> = replacements
+        .iter()
+        .map(|rule| rule.replacement.as_str())
+        .collect();
+    Ok(automaton.replace_all(text, &targets))
+}
+
+// Compiles the rule's patterns as a conversion would, so that a rule which
+// cannot run is found once, when it is loaded
+pub fn check_rule_patterns(index: usize, rule: &Replacement) -> Result<(), FormatError> {
+    if let Some(pattern) = rule.pattern() {
+        rule_regex(index, rule, &pattern)?;
+    }
+    context_regexes(index, rule)?;
+    Ok(())
+}
+
+// A rule's compiled context_before/context_after. They follow from the
+// strings, so they are left out of comparisons and hashes.
+#[derive(Debug, Clone, Default)]
+pub struct ContextRegexes(OnceLock<(Option<Regex>, Option<Regex>)>);
+
+impl PartialEq for ContextRegexes {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for ContextRegexes {}
+
+impl Hash for ContextRegexes {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+fn context_regexes(
+    index: usize,
+    rule: &Replacement,
+) -> Result<&(Option<Regex>, Option<Regex>), FormatError> {
+    if let Some(compiled) = rule.context_regexes.0.get() {
+        return Ok(compiled);
+    }
+    let before = rule
+        .context_before
+        .as_deref()
+        .map(|pattern| rule_regex(index, rule, &format!("(?:{})$", pattern)))
+        .transpose()?;
+    let after = rule
+        .context_after
+        .as_deref()
+        .map(|pattern| rule_regex(index, rule, &format!("^(?:{})", pattern)))
+        .transpose()?;
+    Ok(rule.context_regexes.0.get_or_init(|| (before, after)))
+}
+
+fn rule_regex(index: usize, rule: &Replacement, pattern: &str) -> Result<Regex, FormatError> {
+    Regex::new(pattern).map_err(|source| FormatError::RulePattern {
+        rule: index + 1,
+        original: rule.original.clone(),
+        pattern: pattern.to_string(),
+        source,
+    })
+}
+
+// How far `context_before`/`context_after` look from a match. Checking the
+// whole text on either side would make long text quadratic.
+const MAX_CONTEXT_BYTES: usize = 256;
+
+// Byte offsets of every occurrence of `original` whose surroundings satisfy the
+// rule's context. The context is checked against the text around each match
+// rather than consumed, so adjacent matches can share it (e.g. "1・2・3").
+fn rule_positions(text: &str, index: usize, rule: &Replacement) -> Result<Vec<usize>, FormatError> {
+    let (before, after) = context_regexes(index, rule)?;
+    Ok(text
+        .match_indices(&rule.original)
+        .map(|(start, _)| start)
+        .filter(|&start| {
+            before.as_ref().is_none_or(|re| {
+                let from = char_boundary_before(text, start.saturating_sub(MAX_CONTEXT_BYTES));
+                re.is_match(&text[from..start])
+            })
+        })
+        .filter(|&start| {
+            after.as_ref().is_none_or(|re| {
+                let end = start + rule.original.len();
+                let to = char_boundary_after(text, end + MAX_CONTEXT_BYTES);
+                re.is_match(&text[end..to])
+            })
+        })
+        .collect())
+}
+
+fn char_boundary_before(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn char_boundary_after(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+// The rule's output as a single placeholder with `protect_output`, so no
+// later rule or stage can change it
+fn protect_output(rule: &Replacement, output: String, spans: Option<&mut Vec<String>>) -> String {
+    if !rule.protect_output || output.is_empty() {
+        return output;
+    }
+    match spans.and_then(|spans| protect(&output, spans)) {
+        Some(placeholder) => placeholder.to_string(),
+        None => output,
+    }
+}
+
+fn replace_at(text: &str, positions: &[usize], original_len: usize, output: &str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut last = 0;
+    for &start in positions {
+        replaced.push_str(&text[last..start]);
+        replaced.push_str(output);
+        last = start + original_len;
+    }
+    replaced.push_str(&text[last..]);
+    replaced
+}
+
+// Most clipboards (English text, URLs) have nothing in ！-～, so skip the
+// regex pass entirely then; see benches/format.rs
+fn halve_fullwidth(text: &str, is_protected: impl Fn(char) -> bool) -> Result<String, FormatError> {
+    if !text.contains(|c| matches!(c, '！'..='～')) {
+        return Ok(text.to_string());
+    }
+    halve_fullwidth_regex(text, is_protected)
+}
+
+fn halve_fullwidth_regex(
+    text: &str,
+    is_protected: impl Fn(char) -> bool,
+) -> Result<String, FormatError> {
+    let re = Regex::new(r"[！-～]")?;
+    Ok(re
+        .replace_all(text, |caps: &regex::Captures| {
+            let c = caps[0].chars().next().unwrap_or_default();
+            if is_protected(c) {
+                c.to_string()
+            } else {
+                to_half_width(c).to_string()
+            }
+        })
+        .into_owned())
+}
+
+fn replace_ranges(
+    text: &str,
+    ranges: &[Range<usize>],
+    mut replace: impl FnMut(&str) -> String,
+) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut last = 0;
+    for range in ranges {
+        replaced.push_str(&text[last..range.start]);
+        replaced.push_str(&replace(&text[range.clone()]));
+        last = range.end;
+    }
+    replaced.push_str(&text[last..]);
+    replaced
+}
+
+fn to_half_width(c: char) -> char {
+    match c {
+        '！'..='～' => (c as u32 - 0xfee0) as u8 as char,
+        _ => c,
+    }
+}
+
+// The wide forms of the Halfwidth and Fullwidth Forms block, ！-｠ and ￠-￦
+fn is_wide_form(c: char) -> bool {
+    matches!(c, '\u{FF01}'..='\u{FF60}' | '\u{FFE0}'..='\u{FFE6}')
+}
+
+// None when the compatibility mapping is missing, is several characters, or
+// is a control character or combining mark that cannot stand in for it
+fn compatibility_half_width(c: char) -> Option<char> {
+    if !is_wide_form(c) {
+        return None;
+    }
+    let mut decomposed = Vec::new();
+    decompose_compatible(c, |d| decomposed.push(d));
+    match decomposed[..] {
+        [d] if d != c && !d.is_control() && !is_combining_mark(d) => Some(d),
+        _ => None,
+    }
+}
+
+fn halve_by_compatibility(text: &str, is_protected: impl Fn(char) -> bool) -> String {
+    text.chars()
+        .map(|c| {
+            if is_protected(c) {
+                c
+            } else {
+                WidthMapping::Compatibility.convert(c)
+            }
+        })
+        .collect()
+}
+
+// Puts exactly one space between CJK and latin/digit runs, like pangu
+fn apply_smart_spacing(text: &str) -> Result<String, FormatError> {
+    let cjk_then_latin = Regex::new(&format!("({}) *({})", CJK_CLASS, LATIN_CLASS))?;
+    let latin_then_cjk = Regex::new(&format!("({}) *({})", LATIN_CLASS, CJK_CLASS))?;
+    let spaced = cjk_then_latin.replace_all(text, "$1 $2");
+    Ok(latin_then_cjk.replace_all(&spaced, "$1 $2").into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Occurrence, ReplacementAction};
+
+    // Test for format_text
+    #[test]
+    fn test_format_text_with_replacements_exclusions() {
+        // 置換リスト
+        let replacements = vec![
+            Replacement {
+                original: "foo".to_string(),
+                replacement: "bar".to_string(),
+                ..Default::default()
+            },
+            Replacement {
+                original: "baz".to_string(),
+                replacement: "qux".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        // 除外リスト
+        let exclusion_list = vec!['！', '？']; // 例: 全角の「！」「？」を除外
+
+        // テストケース
+        let input = "foo baz １２３４！？";
+        let expected = "bar qux 1234！？"; // ！？は除外されるので変換されない
+        let formatted = format_text(input, &replacements, &exclusion_list).unwrap();
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_text_with_replacements_without_exclusions() {
+        // 置換リスト
+        let replacements = vec![
+            Replacement {
+                original: "foo".to_string(),
+                replacement: "bar".to_string(),
+                ..Default::default()
+            },
+            Replacement {
+                original: "baz".to_string(),
+                replacement: "qux".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        // 除外リストなし
+        let exclusion_list = vec![];
+
+        // テストケース
+        let input = "foo baz １２３４！？";
+        let expected = "bar qux 1234!?"; // 全ての文字が変換される
+        let formatted = format_text(input, &replacements, &exclusion_list).unwrap();
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_text_without_replacements_with_exclusions() {
+        // 置換リストなし
+        let replacements = vec![];
+
+        // 除外リスト
+        let exclusion_list = vec!['！', '？']; // 例: 全角の「！」「？」を除外
+
+        // テストケース
+        let input = "foo baz １２３４！？";
+        let expected = "foo baz 1234！？"; // ！？は除外されるので変換されない
+        let formatted = format_text(input, &replacements, &exclusion_list).unwrap();
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_text_without_replacements_exclusions() {
+        // 置換リストなし
+        let replacements = vec![];
+
+        // 除外リストなし
+        let exclusion_list = vec![];
+
+        // テストケース
+        let input = "foo baz １２３４！？";
+        let expected = "foo baz 1234!?"; // 全ての文字が変換される
+        let formatted = format_text(input, &replacements, &exclusion_list).unwrap();
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_text_with_partial_exclusions() {
+        // 置換リスト
+        let replacements = vec![
+            Replacement {
+                original: "foo".to_string(),
+                replacement: "bar".to_string(),
+                ..Default::default()
+            },
+            Replacement {
+                original: "baz".to_string(),
+                replacement: "qux".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        // 部分的な除外リスト
+        let exclusion_list = vec!['！']; // 例: 全角の「！」を除外
+
+        // テストケース
+        let input = "foo baz １２３４！？";
+        let expected = "bar qux 1234！?"; // ！は変換されず、？は変換される
+        let formatted = format_text(input, &replacements, &exclusion_list).unwrap();
+
+        assert_eq!(formatted, expected);
+    }
+
+    // Test for convert_width rules
+    #[test]
+    fn test_convert_width_rule_only_converts_inside_matches() {
+        let digits: Vec<char> = ('０'..='９').collect();
+        let replacements = vec![Replacement {
+            original: r"\d{2,4}－\d{2,4}－\d{4}".to_string(),
+            action: ReplacementAction::ConvertWidth,
+            ..Default::default()
+        }];
+        let result = format_text(
+            "電話は０３－１２３４－５６７８、定員は５名",
+            &replacements,
+            &digits,
+        )
+        .unwrap();
+        assert_eq!(result, "電話は03-1234-5678、定員は５名");
+    }
+
+    #[test]
+    fn test_convert_width_literal_escape() {
+        let letters: Vec<char> = ('Ａ'..='Ｚ').collect();
+        let rule = |literal_escape| Replacement {
+            original: "Ａ.Ｂ".to_string(),
+            action: ReplacementAction::ConvertWidth,
+            literal_escape,
+            ..Default::default()
+        };
+        // Escaped, the dot only matches itself
+        let result = format_text("Ａ.Ｂ と ＡxＢ", &[rule(true)], &letters).unwrap();
+        assert_eq!(result, "A.B と ＡxＢ");
+        let result = format_text("Ａ.Ｂ と ＡxＢ", &[rule(false)], &letters).unwrap();
+        assert_eq!(result, "A.B と AxB");
+    }
+
+    // Test for max_matches
+    #[test]
+    fn test_max_matches_limits_regex_rule() {
+        let digits: Vec<char> = ('０'..='９').collect();
+        let rule = |max_matches| Replacement {
+            original: r"\d".to_string(),
+            action: ReplacementAction::ConvertWidth,
+            max_matches,
+            ..Default::default()
+        };
+        let result = format_text("１ ２ ３ ４", &[rule(Some(2))], &digits).unwrap();
+        assert_eq!(result, "1 2 ３ ４");
+        let result = format_text("１ ２ ３ ４", &[rule(None)], &digits).unwrap();
+        assert_eq!(result, "1 2 3 4");
+
+        let collapse = Replacement {
+            original: "ー".to_string(),
+            replacement: "ー".to_string(),
+            action: ReplacementAction::CollapseRuns,
+            max_matches: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text("あーーいーーうーーえ", &[collapse], &[]).unwrap(),
+            "あーいーうーーえ"
+        );
+    }
+
+    // Test for width_mapping
+    #[test]
+    fn test_compatibility_width_mapping() {
+        let options = FormatOptions {
+            width_mapping: WidthMapping::Compatibility,
+            ..Default::default()
+        };
+        // The same as the offset for ！-～
+        let ascii: String = ('！'..='～').collect();
+        assert_eq!(
+            format_text_with_options(&ascii, &[], &[], &options).unwrap(),
+            format_text(&ascii, &[], &[]).unwrap()
+        );
+        // ｟｠ have halfwidth forms too, just not in ASCII
+        assert_eq!(
+            format_text_with_options("｟ａ｠", &[], &[], &options).unwrap(),
+            "⦅a⦆"
+        );
+        assert_eq!(format_text("｟ａ｠", &[], &[]).unwrap(), "｟a｠");
+        // Excluded characters stay
+        assert_eq!(
+            format_text_with_options("｟ａ｠", &[], &['｟'], &options).unwrap(),
+            "｟a⦆"
+        );
+    }
+
+    #[test]
+    fn test_compatibility_width_mapping_keeps_unclean_forms() {
+        // ￣ decomposes to a space and a combining macron
+        assert_eq!(compatibility_half_width('￣'), None);
+        assert_eq!(compatibility_half_width('あ'), None);
+        assert_eq!(compatibility_half_width('ｱ'), None);
+        assert_eq!(compatibility_half_width('＄'), Some('$'));
+        // ￣ only reaches the width conversion when the symbol table skips it
+        let options = FormatOptions {
+            width_mapping: WidthMapping::Compatibility,
+            stage_exclusions: StageExclusions::from([('￣', vec![ExclusionStage::Symbols])]),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text_with_options("￣ａ￣", &[], &[], &options).unwrap(),
+            "￣a￣"
+        );
+    }
+
+    // Test for stage_order
+    #[test]
+    fn test_stage_order() {
+        let rules = vec![
+            Replacement {
+                original: "ＩＤ".to_string(),
+                replacement: "識別子".to_string(),
+                ..Default::default()
+            },
+            Replacement {
+                original: "No.".to_string(),
+                replacement: "#".to_string(),
+                ..Default::default()
+            },
+        ];
+        let width_first = FormatOptions {
+            stage_order: StageOrder::WidthFirst,
+            ..Default::default()
+        };
+        // Fullwidth rules only match before the conversion, halfwidth ones only after it
+        assert_eq!(
+            format_text("ＩＤ Ｎｏ．１", &rules, &[]).unwrap(),
+            "識別子 No.1"
+        );
+        assert_eq!(
+            format_text_with_options("ＩＤ Ｎｏ．１", &rules, &[], &width_first).unwrap(),
+            "ID #1"
+        );
+        let options: FormatOptions =
+            serde_json::from_str(r#"{"stage_order": "width_first"}"#).unwrap();
+        assert_eq!(options.stage_order, StageOrder::WidthFirst);
+        // The trace shows the rules after the width conversion
+        let labels: Vec<String> = explain_format("Ｎｏ．１", &rules, &[], &width_first)
+            .unwrap()
+            .into_iter()
+            .map(|stage| stage.label)
+            .collect();
+        assert_eq!(
+            labels,
+            vec!["input", "width conversion", "rule 2 (\"No.\")"]
+        );
+    }
+
+    // Test for rule ordering
+    fn rule(original: &str, replacement: &str) -> Replacement {
+        Replacement {
+            original: original.to_string(),
+            replacement: replacement.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rules_chain_in_file_order() {
+        let forward = vec![rule("a", "b"), rule("b", "c")];
+        let backward = vec![rule("b", "c"), rule("a", "b")];
+        assert_eq!(format_text("ab", &forward, &[]).unwrap(), "cc");
+        assert_eq!(format_text("ab", &backward, &[]).unwrap(), "bc");
+    }
+
+    #[test]
+    fn test_overlapping_rules_first_one_wins() {
+        let longer_first = vec![rule("ab", "X"), rule("a", "Y")];
+        let shorter_first = vec![rule("a", "Y"), rule("ab", "X")];
+        assert_eq!(format_text("abc a", &longer_first, &[]).unwrap(), "Xc Y");
+        assert_eq!(format_text("abc a", &shorter_first, &[]).unwrap(), "Ybc Y");
+    }
+
+    #[test]
+    fn test_deletion_order_against_replacement() {
+        let delete = Replacement {
+            original: "（注）".to_string(),
+            action: ReplacementAction::Delete,
+            ..Default::default()
+        };
+        let delete_first = vec![delete.clone(), rule("注", "N")];
+        let replace_first = vec![rule("注", "N"), delete];
+        assert_eq!(format_text("（注）注", &delete_first, &[]).unwrap(), "N");
+        assert_eq!(
+            format_text("（注）注", &replace_first, &[]).unwrap(),
+            "(N)N"
+        );
+    }
+
+    #[test]
+    fn test_rules_run_before_width_conversion() {
+        // The input is still fullwidth when the rules see it
+        let fullwidth = vec![rule("ＡＢ", "x")];
+        let halfwidth = vec![rule("AB", "x")];
+        assert_eq!(format_text("ＡＢ", &fullwidth, &[]).unwrap(), "x");
+        assert_eq!(format_text("ＡＢ", &halfwidth, &[]).unwrap(), "AB");
+    }
+
+    // Test for collapse_runs rules
+    #[test]
+    fn test_collapse_runs_rule() {
+        let replacements = vec![Replacement {
+            original: "ー〜".to_string(),
+            replacement: "—".to_string(),
+            action: ReplacementAction::CollapseRuns,
+            ..Default::default()
+        }];
+        let result =
+            format_text("東京ーーー大阪〜〜〜名古屋、コーヒー", &replacements, &[]).unwrap();
+        assert_eq!(result, "東京—大阪—名古屋、コーヒー");
+    }
+
+    // Test for context-gated rules
+    #[test]
+    fn test_context_gated_replacement() {
+        let replacements = vec![Replacement {
+            original: "・".to_string(),
+            replacement: ",".to_string(),
+            context_before: Some(r"\d".to_string()),
+            context_after: Some(r"\d".to_string()),
+            ..Default::default()
+        }];
+        let result = format_text("１・２・３、東京・大阪", &replacements, &[]).unwrap();
+        assert_eq!(result, "1,2,3、東京・大阪");
+    }
+
+    #[test]
+    fn test_context_is_compiled_once_per_rule() {
+        let mut replacements = vec![Replacement {
+            original: "・".to_string(),
+            replacement: ",".to_string(),
+            context_before: Some(r"\d".to_string()),
+            ..Default::default()
+        }];
+        assert_eq!(format_text("1・2", &replacements, &[]).unwrap(), "1,2");
+        // The first run's regex is kept, so the new pattern is never compiled
+        replacements[0].context_before = Some("(".to_string());
+        assert_eq!(format_text("1・2", &replacements, &[]).unwrap(), "1,2");
+        let copy = replacements.clone();
+        assert_eq!(format_text("1・2", &copy, &[]).unwrap(), "1,2");
+    }
+
+    #[test]
+    fn test_context_is_looked_for_near_the_match() {
+        let replacements = vec![Replacement {
+            original: "・".to_string(),
+            replacement: ",".to_string(),
+            context_before: Some(r"^\d+".to_string()),
+            ..Default::default()
+        }];
+        let near = format!("{}・", "1".repeat(100));
+        assert_eq!(
+            format_text(&near, &replacements, &[]).unwrap(),
+            format!("{},", "1".repeat(100))
+        );
+        // Only the last MAX_CONTEXT_BYTES are seen, and ^ matches where they start
+        let far = format!("あ{}・", "1".repeat(MAX_CONTEXT_BYTES));
+        assert!(format_text(&far, &replacements, &[])
+            .unwrap()
+            .ends_with(','));
+        let long = format!("{}・", "あ".repeat(MAX_CONTEXT_BYTES));
+        assert!(format_text(&long, &replacements, &[])
+            .unwrap()
+            .ends_with('・'));
+    }
+
+    // Test for collapse_spaces / trim_lines
+    #[test]
+    fn test_collapse_spaces_and_trim_lines() {
+        let options = FormatOptions {
+            collapse_spaces: true,
+            trim_lines: true,
+            ..Default::default()
+        };
+        let result =
+            format_text_with_options("  ａ   ｂ  \r\n\tｃ\t\tｄ", &[], &[], &options).unwrap();
+        assert_eq!(result, "a b\r\nc d");
+    }
+
+    // Test for preserve_indentation
+    #[test]
+    fn test_preserve_indentation_keeps_leading_whitespace() {
+        let options = FormatOptions {
+            collapse_spaces: true,
+            trim_lines: true,
+            preserve_indentation: true,
+            ..Default::default()
+        };
+        let text = "fn main() {\n    let x  =  １;   \n\t\tif x {}\n}";
+        let result = format_text_with_options(text, &[], &[], &options).unwrap();
+        assert_eq!(result, "fn main() {\n    let x = 1;\n\t\tif x {}\n}");
+    }
+
+    // Test for aggressiveness
+    #[test]
+    fn test_aggressiveness_presets() {
+        let text = "ｶﾞｿﾘﾝＡＢＣ①テスト！";
+        let low = format_text(text, &[], &['！']).unwrap();
+        assert_eq!(low, "ｶﾞｿﾘﾝABC①テスト！");
+
+        let high = FormatOptions {
+            aggressiveness: Aggressiveness::High,
+            ..Default::default()
+        };
+        let high = format_text_with_options(text, &[], &['！'], &high).unwrap();
+        assert_eq!(high, "ガソリン ABC1 テスト！");
+    }
+
+    // Test for the replacement strategies
+    fn with_threshold(automaton_threshold: usize) -> FormatOptions {
+        FormatOptions {
+            automaton_threshold,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_strategies_produce_identical_output() {
+        let rules = vec![
+            rule("頚", "頸"),
+            rule("CRLF", "。"),
+            rule("，", ", "),
+            rule("．", ". "),
+            rule("鴎", "鷗"),
+            rule("涜", "瀆"),
+        ];
+        let text = "頚椎CRLF鴎外，冒涜．CRLFCRL頚";
+        let sequential = with_threshold(0);
+        let automaton = with_threshold(1);
+        assert_eq!(
+            replace_strategy(&rules, &automaton),
+            ReplaceStrategy::Automaton
+        );
+        assert_eq!(
+            format_text_with_options(text, &rules, &[], &automaton).unwrap(),
+            format_text_with_options(text, &rules, &[], &sequential).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_strategy_crosses_over_at_threshold() {
+        let rules = vec![rule("頚", "頸"), rule("鴎", "鷗"), rule("涜", "瀆")];
+        assert_eq!(
+            replace_strategy(&rules, &with_threshold(4)),
+            ReplaceStrategy::Sequential
+        );
+        assert_eq!(
+            replace_strategy(&rules, &with_threshold(3)),
+            ReplaceStrategy::Automaton
+        );
+        assert_eq!(
+            replace_strategy(&rules, &with_threshold(0)),
+            ReplaceStrategy::Sequential
+        );
+    }
+
+    #[test]
+    fn test_dependent_rules_stay_sequential() {
+        let options = with_threshold(1);
+        // Chained, overlapping, sharing a first character, and a deletion
+        for rules in [
+            vec![rule("a", "b"), rule("b", "c")],
+            vec![rule("bc", "Y"), rule("ab", "X")],
+            vec![rule("ab", "X"), rule("ac", "Y")],
+            vec![rule("x", ""), rule("ab", "Z")],
+        ] {
+            assert_eq!(
+                replace_strategy(&rules, &options),
+                ReplaceStrategy::Sequential
+            );
+        }
+        let rules = vec![rule("a", "b"), rule("b", "c")];
+        assert_eq!(
+            format_text_with_options("ab", &rules, &[], &options).unwrap(),
+            "cc"
+        );
+    }
+
+    // Test for max_replacements
+    #[test]
+    fn test_max_replacements_halts_substitutions() {
+        let replacements = vec![
+            Replacement {
+                original: "a".to_string(),
+                replacement: "b".to_string(),
+                ..Default::default()
+            },
+            Replacement {
+                original: "c".to_string(),
+                replacement: "d".to_string(),
+                ..Default::default()
+            },
+        ];
+        let options = FormatOptions {
+            max_replacements: Some(3),
+            ..Default::default()
+        };
+        let result = format_text_with_options("aaccc", &replacements, &[], &options).unwrap();
+        assert_eq!(result, "bbdcc");
+
+        let result = format_text("aaccc", &replacements, &[]).unwrap();
+        assert_eq!(result, "bbddd");
+    }
+
+    // Test for preserve_japanese_punctuation
+    #[test]
+    fn test_preserve_japanese_punctuation() {
+        let options = FormatOptions {
+            preserve_japanese_punctuation: true,
+            ..Default::default()
+        };
+        let result =
+            format_text_with_options("「ＡＢＣ１２３」（注）。、！？", &[], &[], &options).unwrap();
+        assert_eq!(result, "「ABC123」（注）。、！？");
+    }
+
+    #[test]
+    fn test_preserve_japanese_punctuation_disabled_by_default() {
+        let result = format_text("（ＡＢＣ）", &[], &[]).unwrap();
+        assert_eq!(result, "(ABC)");
+    }
+
+    // Test for smart_spacing
+    #[test]
+    fn test_smart_spacing_inserts_spaces_at_boundaries() {
+        let options = FormatOptions {
+            smart_spacing: true,
+            ..Default::default()
+        };
+        let input = "Rustで１２３個のcrateを使う";
+        let expected = "Rust で 123 個の crate を使う";
+        let formatted = format_text_with_options(input, &[], &[], &options).unwrap();
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_smart_spacing_normalizes_redundant_spaces() {
+        let options = FormatOptions {
+            smart_spacing: true,
+            ..Default::default()
+        };
+        let formatted = format_text_with_options("漢字   abc  かな", &[], &[], &options).unwrap();
+
+        assert_eq!(formatted, "漢字 abc かな");
+    }
+
+    #[test]
+    fn test_smart_spacing_is_idempotent() {
+        let options = FormatOptions {
+            smart_spacing: true,
+            ..Default::default()
+        };
+        let input = "Rust で 123 個の crate を使う";
+        let once = format_text_with_options(input, &[], &[], &options).unwrap();
+        let twice = format_text_with_options(&once, &[], &[], &options).unwrap();
+
+        assert_eq!(once, input);
+        assert_eq!(twice, once);
+    }
+
+    #[test]
+    fn test_smart_spacing_disabled_by_default() {
+        let formatted = format_text("Rustで使う", &[], &[]).unwrap();
+
+        assert_eq!(formatted, "Rustで使う");
+    }
+
+    // Test for deletion rules
+    #[test]
+    fn test_format_text_delete_action_matches_empty_replacement() {
+        let explicit = vec![Replacement {
+            original: "（注）".to_string(),
+            action: ReplacementAction::Delete,
+            ..Default::default()
+        }];
+        let empty = vec![Replacement {
+            original: "（注）".to_string(),
+            replacement: "".to_string(),
+            ..Default::default()
+        }];
+
+        let input = "（注）１２３";
+        let expected = "123";
+        assert_eq!(format_text(input, &explicit, &[]).unwrap(), expected);
+        assert_eq!(format_text(input, &empty, &[]).unwrap(), expected);
+    }
+
+    // Test for max_growth_factor
+    #[test]
+    fn test_growth_guard_keeps_original() {
+        let replacements = vec![Replacement {
+            original: "a".to_string(),
+            replacement: "aaaaaaaaaaaaaaaaaaaa".to_string(),
+            ..Default::default()
+        }];
+        let input = "aaa";
+        assert_eq!(format_text(input, &replacements, &[]).unwrap(), input);
+
+        let options = FormatOptions {
+            max_growth_factor: 0.0,
+            ..Default::default()
+        };
+        let unguarded = format_text_with_options(input, &replacements, &[], &options).unwrap();
+        assert_eq!(unguarded.len(), 60);
+    }
+
+    #[test]
+    fn test_growth_guard_allows_normal_expansion() {
+        let replacements = vec![Replacement {
+            original: "㈱".to_string(),
+            replacement: "(株)".to_string(),
+            ..Default::default()
+        }];
+        assert_eq!(format_text("㈱", &replacements, &[]).unwrap(), "(株)");
+    }
+
+    // Test for yen_sign
+    #[test]
+    fn test_yen_policy() {
+        let convert = |yen_sign, aggressiveness| {
+            let options = FormatOptions {
+                yen_sign,
+                aggressiveness,
+                ..Default::default()
+            };
+            format_text_with_options("￥１００", &[], &[], &options).unwrap()
+        };
+        assert_eq!(convert(YenPolicy::Nfkc, Aggressiveness::Low), "￥100");
+        assert_eq!(convert(YenPolicy::Nfkc, Aggressiveness::High), "¥100");
+        assert_eq!(convert(YenPolicy::default(), Aggressiveness::High), "¥100");
+        assert_eq!(convert(YenPolicy::Preserve, Aggressiveness::Low), "￥100");
+        assert_eq!(convert(YenPolicy::Preserve, Aggressiveness::High), "￥100");
+        assert_eq!(convert(YenPolicy::Yen, Aggressiveness::Low), "¥100");
+        assert_eq!(convert(YenPolicy::Backslash, Aggressiveness::Low), "\\100");
+        assert_eq!(convert(YenPolicy::Backslash, Aggressiveness::High), "\\100");
+    }
+
+    #[test]
+    fn test_excluded_yen_ignores_policy() {
+        let options = FormatOptions {
+            yen_sign: YenPolicy::Backslash,
+            ..Default::default()
+        };
+        let formatted = format_text_with_options("￥１００", &[], &['￥'], &options).unwrap();
+        assert_eq!(formatted, "￥100");
+    }
+
+    // Test for the symbol table
+    #[test]
+    fn test_tricky_symbols() {
+        let convert = |text| format_text(text, &[], &[]).unwrap();
+        assert_eq!(convert("￠"), "¢");
+        assert_eq!(convert("￡"), "£");
+        assert_eq!(convert("￢"), "¬");
+        assert_eq!(convert("￣"), "¯");
+        assert_eq!(convert("￤"), "¦");
+        assert_eq!(convert("￦１００"), "₩100");
+        assert_eq!(convert("１０−３"), "10-3");
+        // Inside ！-～ the arithmetic conversion still applies
+        assert_eq!(convert("＄％＋＝"), "$%+=");
+        // NFKC must not undo the table, e.g. ￣ -> " \u{304}"
+        let options = FormatOptions {
+            aggressiveness: Aggressiveness::High,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text_with_options("￣", &[], &[], &options).unwrap(),
+            "¯"
+        );
+    }
+
+    #[test]
+    fn test_symbol_overrides() {
+        let options = FormatOptions {
+            symbols: BTreeMap::from([('￦', "KRW".to_string()), ('×', "x".to_string())]),
+            ..Default::default()
+        };
+        let formatted = format_text_with_options("￦３×４￠", &[], &['￠'], &options).unwrap();
+        assert_eq!(formatted, "KRW3x4￠");
+
+        let options: FormatOptions = serde_json::from_str(r#"{"symbols": {"￥": "JPY"}}"#).unwrap();
+        assert_eq!(
+            format_text_with_options("￥１", &[], &[], &options).unwrap(),
+            "JPY1"
+        );
+    }
+
+    // Test for expand_units
+    #[test]
+    fn test_expand_units() {
+        let options = FormatOptions {
+            expand_units: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text_with_options("１０㎡、２㎥、３６℃", &[], &[], &options).unwrap(),
+            "10m2、2m3、36°C"
+        );
+        // Off by default, and excluded symbols stay
+        assert_eq!(format_text("１０㎡", &[], &[]).unwrap(), "10㎡");
+        assert_eq!(
+            format_text_with_options("１０㎡", &[], &['㎡'], &options).unwrap(),
+            "10㎡"
+        );
+    }
+
+    #[test]
+    fn test_expansion_overrides() {
+        let options: FormatOptions = serde_json::from_str(
+            r#"{"expand_units": true, "expansions": {"℃": "deg C", "㎥": "", "㎖": "mL"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            format_text_with_options("２０℃ ５㎥ ㎡ ㎖", &[], &[], &options).unwrap(),
+            "20deg C 5㎥ m2 mL"
+        );
+        let mut options = options;
+        options.stage_exclusions = StageExclusions::from([('℃', vec![ExclusionStage::Expansions])]);
+        assert_eq!(
+            format_text_with_options("℃", &[], &[], &options).unwrap(),
+            "℃"
+        );
+    }
+
+    // Test for fix_mojibake
+    #[test]
+    fn test_fix_mojibake_before_conversion() {
+        // "ＡＢ「Ｃ」" as UTF-8 read as Windows-1252
+        let damaged = "ï¼¡ï¼¢ã€Œï¼£ã€\u{8D}";
+        let options = FormatOptions {
+            fix_mojibake: true,
+            protected_delimiters: vec![DelimiterPair("「".to_string(), "」".to_string())],
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text_with_options(damaged, &[], &[], &options).unwrap(),
+            "AB「Ｃ」"
+        );
+        assert_eq!(
+            format_text_with_options(damaged, &[], &[], &FormatOptions::default()).unwrap(),
+            damaged
+        );
+    }
+
+    // Test for ideographic_space
+    #[test]
+    fn test_ideographic_space_by_position() {
+        let text = "\u{3000}\u{3000}段落の　始まり\u{3000}\n\u{3000}次の段落\u{3000}\r\n";
+        let convert = |policy| {
+            let options = FormatOptions {
+                ideographic_space: policy,
+                ..Default::default()
+            };
+            format_text_with_options(text, &[], &[], &options).unwrap()
+        };
+        assert_eq!(
+            convert(IdeographicSpace::KeepLeading),
+            "\u{3000}\u{3000}段落の 始まり \n\u{3000}次の段落 \r\n"
+        );
+        assert_eq!(
+            convert(IdeographicSpace::KeepEnds),
+            "\u{3000}\u{3000}段落の 始まり\u{3000}\n\u{3000}次の段落\u{3000}\r\n"
+        );
+        assert_eq!(
+            convert(IdeographicSpace::Convert),
+            "  段落の 始まり \n 次の段落 \r\n"
+        );
+        assert_eq!(convert(IdeographicSpace::Default), text);
+    }
+
+    #[test]
+    fn test_ideographic_space_kept_through_nfkc() {
+        let options = FormatOptions {
+            aggressiveness: Aggressiveness::High,
+            ideographic_space: IdeographicSpace::KeepLeading,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text_with_options("\u{3000}ＡＢ\u{3000}ＣＤ", &[], &[], &options).unwrap(),
+            "\u{3000}AB CD"
+        );
+        // An exclusion still wins
+        assert_eq!(
+            format_text_with_options("\u{3000}ａ\u{3000}ｂ", &[], &['\u{3000}'], &options).unwrap(),
+            "\u{3000}a\u{3000}b"
+        );
+    }
+
+    // Test for compose_dakuten
+    #[test]
+    fn test_compose_dakuten() {
+        assert_eq!(
+            compose_dakuten("か\u{3099}ハ\u{309A}ー\u{3099}"),
+            "がパー\u{3099}"
+        );
+    }
+
+    #[test]
+    fn test_dakuten_composed_before_rules() {
+        let replacements = vec![Replacement {
+            original: "ガ".to_string(),
+            replacement: "ga".to_string(),
+            ..Default::default()
+        }];
+        let decomposed = "カ\u{3099}ＡＢ";
+        let options = FormatOptions {
+            compose_dakuten: true,
+            ..Default::default()
+        };
+        let composed = format_text_with_options(decomposed, &replacements, &[], &options).unwrap();
+        assert_eq!(composed, "gaAB");
+
+        // Off by default
+        let untouched = format_text(decomposed, &replacements, &[]).unwrap();
+        assert_eq!(untouched, "カ\u{3099}AB");
+    }
+
+    // Test for scripts
+    #[test]
+    fn test_scripts_allowlist() {
+        let options: FormatOptions =
+            serde_json::from_str(r#"{"scripts": ["Latin", "Zyyy"], "aggressiveness": "high"}"#)
+                .unwrap();
+        // ｶ would become カ under NFKC, but Katakana is not allowed. Smart
+        // spacing is not a per-character conversion and still applies.
+        let formatted = format_text_with_options("ＡＢＣ１２３漢ｶ", &[], &[], &options).unwrap();
+        assert_eq!(formatted, "ABC123 漢ｶ");
+
+        let latin_only = FormatOptions {
+            scripts: vec![ScriptName::try_from("Latin".to_string()).unwrap()],
+            ..Default::default()
+        };
+        let formatted = format_text_with_options("Ｋ１漢", &[], &[], &latin_only).unwrap();
+        assert_eq!(formatted, "K１漢");
+
+        assert!(serde_json::from_str::<FormatOptions>(r#"{"scripts": ["Klingon"]}"#).is_err());
+    }
+
+    // Test for exclude_pattern
+    #[test]
+    fn test_exclude_pattern_keeps_punctuation() {
+        let options = FormatOptions {
+            exclude_pattern: Some(r"\p{P}".to_string()),
+            ..Default::default()
+        };
+        let formatted =
+            format_text_with_options("１２３！？（ＡＢＣ）＋", &[], &[], &options).unwrap();
+        assert_eq!(formatted, "123！？（ABC）+");
+
+        let high = FormatOptions {
+            aggressiveness: Aggressiveness::High,
+            ..options
+        };
+        let formatted = format_text_with_options("①！", &[], &[], &high).unwrap();
+        assert_eq!(formatted, "1！");
+    }
+
+    #[test]
+    fn test_invalid_exclude_pattern_is_an_error() {
+        let options = FormatOptions {
+            exclude_pattern: Some("[".to_string()),
+            ..Default::default()
+        };
+        let error = format_text_with_options("ＡＢＣ", &[], &[], &options).unwrap_err();
+        assert!(matches!(error, FormatError::ExcludePattern { .. }));
+        assert_eq!(error.rule(), None);
+        let mut options = options;
+        assert!(matches!(
+            options.compile_exclude_pattern(),
+            Err(FormatError::ExcludePattern { .. })
+        ));
+    }
+
+    #[test]
+    fn test_compiled_exclude_pattern_is_used() {
+        let mut options = FormatOptions {
+            exclude_pattern: Some(r"\p{P}".to_string()),
+            ..Default::default()
+        };
+        options.compile_exclude_pattern().unwrap();
+        // Not compiled again, so a later change to the string is not seen
+        options.exclude_pattern = Some("[".to_string());
+        let formatted = format_text_with_options("１！", &[], &[], &options).unwrap();
+        assert_eq!(formatted, "1！");
+    }
+
+    // Test for rule errors
+    #[test]
+    fn test_invalid_rule_pattern_names_the_rule() {
+        let rules = vec![
+            Replacement {
+                original: "foo".to_string(),
+                replacement: "bar".to_string(),
+                ..Default::default()
+            },
+            Replacement {
+                original: "(１".to_string(),
+                action: ReplacementAction::ConvertWidth,
+                ..Default::default()
+            },
+        ];
+        let error = format_text("foo １", &rules, &[]).unwrap_err();
+        assert_eq!(error.rule(), Some(2));
+        assert_eq!(
+            error.to_string(),
+            "Rule 2 ('(１') has an invalid pattern '(１'"
+        );
+        assert!(std::error::Error::source(&error).is_some());
+
+        let context = Replacement {
+            original: "x".to_string(),
+            replacement: "y".to_string(),
+            context_before: Some("[".to_string()),
+            ..Default::default()
+        };
+        let error = format_text("x", &[context], &[]).unwrap_err();
+        assert_eq!(error.rule(), Some(1));
+        assert_eq!(
+            error.to_string(),
+            "Rule 1 ('x') has an invalid pattern '(?:[)$'"
+        );
+    }
+
+    // Test for occurrence
+    fn rule_at(original: &str, replacement: &str, occurrence: Occurrence) -> Replacement {
+        Replacement {
+            original: original.to_string(),
+            replacement: replacement.to_string(),
+            occurrence,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_occurrence_first_only() {
+        let rules = vec![rule_at("# ", "## ", Occurrence::First)];
+        assert_eq!(
+            format_text("# a\n# b\n# c", &rules, &[]).unwrap(),
+            "## a\n# b\n# c"
+        );
+    }
+
+    #[test]
+    fn test_occurrence_last_only() {
+        let rules = vec![rule_at("、", "と", Occurrence::Last)];
+        assert_eq!(
+            format_text("赤、青、黄", &rules, &[]).unwrap(),
+            "赤、青と黄"
+        );
+
+        let collapse = Replacement {
+            action: ReplacementAction::CollapseRuns,
+            ..rule_at("ー", "ー", Occurrence::Last)
+        };
+        assert_eq!(
+            format_text("あーーいーーう", &[collapse], &[]).unwrap(),
+            "あーーいーう"
+        );
+    }
+
+    #[test]
+    fn test_occurrence_all_is_default() {
+        let rules = vec![rule_at("a", "b", Occurrence::All)];
+        assert_eq!(format_text("aaa", &rules, &[]).unwrap(), "bbb");
+    }
+
+    // Test for stage-scoped exclusions
+    #[test]
+    fn test_stage_exclusion_only_skips_its_stage() {
+        let stage_options = |stages: Vec<ExclusionStage>| FormatOptions {
+            symbols: BTreeMap::from([('～', "〜".to_string())]),
+            stage_exclusions: StageExclusions::from([('～', stages)]),
+            ..Default::default()
+        };
+        // Kept from the width conversion, still normalized by the symbol table
+        let options = stage_options(vec![ExclusionStage::Width]);
+        assert_eq!(
+            format_text_with_options("Ａ～", &[], &[], &options).unwrap(),
+            "A〜"
+        );
+        let options = stage_options(vec![ExclusionStage::Symbols]);
+        assert_eq!(
+            format_text_with_options("Ａ～", &[], &[], &options).unwrap(),
+            "A~"
+        );
+        // A plain exclusion still covers every stage
+        let options = stage_options(Vec::new());
+        assert_eq!(
+            format_text_with_options("Ａ～", &[], &['～'], &options).unwrap(),
+            "A～"
+        );
+    }
+
+    #[test]
+    fn test_stage_exclusion_from_width_allows_nfkc() {
+        let options = |stages: Vec<ExclusionStage>| FormatOptions {
+            aggressiveness: Aggressiveness::High,
+            stage_exclusions: StageExclusions::from([('Ａ', stages)]),
+            ..Default::default()
+        };
+        let only_width = options(vec![ExclusionStage::Width]);
+        assert_eq!(
+            format_text_with_options("Ａ", &[], &[], &only_width).unwrap(),
+            "A"
+        );
+        let both = options(vec![ExclusionStage::Width, ExclusionStage::Nfkc]);
+        assert_eq!(
+            format_text_with_options("Ａ", &[], &[], &both).unwrap(),
+            "Ａ"
+        );
+    }
+
+    // Test for the ASCII fast path
+    #[test]
+    fn test_fast_path_matches_regex_pass() {
+        let is_protected = |c: char| c == '！';
+        for text in [
+            "",
+            "https://example.com/?q=1&r=2",
+            "plain ascii text",
+            "半角カナｶﾅと漢字",
+            "ＡＢＣ！１２３",
+            "mixed Ａ and ～ edge ！",
+        ] {
+            assert_eq!(
+                halve_fullwidth(text, is_protected).unwrap(),
+                halve_fullwidth_regex(text, is_protected).unwrap(),
+                "{}",
+                text
+            );
+        }
+    }
+
+    // Test for explain_format
+    fn stage(label: &str, text: &str) -> Stage {
+        Stage {
+            label: label.to_string(),
+            text: text.to_string(),
+            rule: None,
+        }
+    }
+
+    #[test]
+    fn test_explain_format_stages() {
+        let replacements = vec![
+            Replacement {
+                original: "頚".to_string(),
+                replacement: "頸".to_string(),
+                ..Default::default()
+            },
+            Replacement {
+                original: "unused".to_string(),
+                replacement: "x".to_string(),
+                ..Default::default()
+            },
+        ];
+        let options = FormatOptions {
+            collapse_spaces: true,
+            smart_spacing: true,
+            ..Default::default()
+        };
+        let stages = explain_format("頚  １２３foo", &replacements, &[], &options).unwrap();
+        assert_eq!(
+            stages,
+            vec![
+                stage("input", "頚  １２３foo"),
+                Stage {
+                    rule: Some(0),
+                    ..stage("rule 1 (\"頚\")", "頸  １２３foo")
+                },
+                stage("width conversion", "頸  123foo"),
+                stage("spaces", "頸 123foo"),
+            ]
+        );
+        assert_eq!(
+            stages.last().unwrap().text,
+            format_text_with_options("頚  １２３foo", &replacements, &[], &options).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_explain_format_unchanged_input() {
+        let stages = explain_format("plain", &[], &[], &FormatOptions::default()).unwrap();
+        assert_eq!(stages, vec![stage("input", "plain")]);
+    }
+
+    // Test for --isolate-rule
+    fn isolation_rules() -> Vec<Replacement> {
+        ["foo", "bar", "foo"]
+            .iter()
+            .zip(["bar", "baz", "qux"])
+            .map(|(original, replacement)| Replacement {
+                original: original.to_string(),
+                replacement: replacement.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_find_rules() {
+        let rules = isolation_rules();
+        assert_eq!(find_rules(&rules, "2"), vec![1]);
+        assert_eq!(find_rules(&rules, "foo"), vec![0, 2]);
+        assert!(find_rules(&rules, "4").is_empty());
+        assert!(find_rules(&rules, "baz").is_empty());
+    }
+
+    #[test]
+    fn test_isolated_rule_runs_alone() {
+        let rules = isolation_rules();
+        // All together, rule 1 feeds rule 2
+        assert_eq!(format_text("foo １", &rules, &[]).unwrap(), "baz 1");
+
+        let stages = explain_isolated(
+            "foo １",
+            &rules,
+            &[0],
+            &[],
+            &FormatOptions::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            stages,
+            vec![
+                stage("input", "foo １"),
+                Stage {
+                    rule: Some(0),
+                    ..stage("rule 1 (\"foo\")", "bar １")
+                },
+            ]
+        );
+        // Rule 2 alone has nothing to do
+        let stages = explain_isolated(
+            "foo １",
+            &rules,
+            &[1],
+            &[],
+            &FormatOptions::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(stages, vec![stage("input", "foo １")]);
+
+        let stages =
+            explain_isolated("foo １", &rules, &[2], &[], &FormatOptions::default(), true).unwrap();
+        assert_eq!(
+            stages,
+            vec![
+                stage("input", "foo １"),
+                Stage {
+                    rule: Some(2),
+                    ..stage("rule 3 (\"foo\")", "qux １")
+                },
+                stage("width conversion", "qux 1"),
+            ]
+        );
+    }
+
+    // Test for katakana_to_hiragana
+    #[test]
+    fn test_katakana_to_hiragana() {
+        let options = FormatOptions {
+            katakana_to_hiragana: true,
+            ..Default::default()
+        };
+        let convert = |text: &str, exclusions: &[char]| {
+            format_text_with_options(text, &[], exclusions, &options).unwrap()
+        };
+        assert_eq!(convert("カ", &[]), "か");
+        assert_eq!(convert("ｶ", &[]), "か");
+        assert_eq!(
+            convert("ｶﾞｷﾞｮｳｻﾞ ヴァイオリン", &[]),
+            "がぎょうざ ゔぁいおりん"
+        );
+        assert_eq!(convert("ヷー", &[]), "ヷー");
+        assert_eq!(convert("カタカナ", &['タ']), "かタかな");
+        assert_eq!(
+            format_text_with_options("カ", &[], &[], &FormatOptions::default()).unwrap(),
+            "カ"
+        );
+    }
+
+    // Test for protected_delimiters
+    #[test]
+    fn test_protected_delimiters() {
+        let options = FormatOptions {
+            protected_delimiters: vec![
+                DelimiterPair("「".to_string(), "」".to_string()),
+                DelimiterPair("<<".to_string(), ">>".to_string()),
+            ],
+            ..Default::default()
+        };
+        let rules = vec![rule("ＡＢＣ", "abc")];
+        let convert = |text: &str| format_text_with_options(text, &rules, &[], &options).unwrap();
+        assert_eq!(
+            convert("ＡＢＣ「ＡＢＣ１２３」１２３"),
+            "abc「ＡＢＣ１２３」123"
+        );
+        assert_eq!(convert("<<ＸＹ>>ＸＹ"), "<<ＸＹ>>XY");
+        // Nested pairs stay protected as a whole, an unclosed one protects nothing
+        assert_eq!(convert("「Ａ「Ｂ」Ｃ」Ｄ"), "「Ａ「Ｂ」Ｃ」D");
+        assert_eq!(convert("「Ａ１"), "「A1");
+
+        let stages = explain_format("１「２」", &[], &[], &options).unwrap();
+        assert_eq!(stages.last().unwrap().text, "1「２」");
+    }
+
+    // Test for digit_grouping
+    #[test]
+    fn test_digit_grouping() {
+        let options = FormatOptions {
+            digit_grouping: Some(DigitGrouping::default()),
+            ..Default::default()
+        };
+        let convert = |text: &str| format_text_with_options(text, &[], &[], &options).unwrap();
+        assert_eq!(convert("１２３４５６"), "123,456");
+        assert_eq!(convert("合計１２３４５６７円"), "合計1,234,567円");
+        // Short runs and ASCII numbers are left alone
+        assert_eq!(convert("２０２４年"), "2024年");
+        assert_eq!(convert("1234567"), "1234567");
+        // The fraction of a decimal is not grouped
+        assert_eq!(convert("１２３４５．６７８９０"), "12,345.67890");
+        // Nor are digits inside identifiers or already grouped numbers
+        assert_eq!(convert("ｖ１２３４５"), "v12345");
+        assert_eq!(convert("ID_１２３４５６"), "ID_123456");
+        assert_eq!(convert("１２，３４５６７"), "12,34567");
+    }
+
+    #[test]
+    fn test_digit_grouping_options() {
+        let options = FormatOptions {
+            digit_grouping: Some(DigitGrouping {
+                min_digits: 4,
+                group_size: 4,
+                separator: " ".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text_with_options("１２３４５６７８９", &[], &[], &options).unwrap(),
+            "1 2345 6789"
+        );
+        // Excluded digits are not halved, so they are not grouped either
+        assert_eq!(
+            format_text_with_options("１２３４５６７８９", &[], &['５'], &options).unwrap(),
+            "1234５6789"
+        );
+        assert_eq!(
+            format_text_with_options("１２３４５６", &[], &[], &FormatOptions::default()).unwrap(),
+            "123456"
+        );
+    }
+
+    // Test for report_format
+    #[test]
+    fn test_report_format_lists_fired_rules() {
+        let replacements = vec![
+            Replacement {
+                original: "unused".to_string(),
+                replacement: "x".to_string(),
+                ..Default::default()
+            },
+            Replacement {
+                original: "頚".to_string(),
+                replacement: "頸".to_string(),
+                ..Default::default()
+            },
+        ];
+        let report = report_format("頚１", &replacements, &[], &FormatOptions::default()).unwrap();
+        assert_eq!(report.output, "頸1");
+        assert!(report.changed);
+        assert_eq!(report.rules_fired, vec!["頚"]);
+
+        let report = report_format("abc", &replacements, &[], &FormatOptions::default()).unwrap();
+        assert!(!report.changed);
+        assert!(report.rules_fired.is_empty());
+    }
+
+    // Test for protect_output
+    #[test]
+    fn test_protected_output_skips_later_stages() {
+        let rule = |original: &str, replacement: &str, protect_output| Replacement {
+            original: original.to_string(),
+            replacement: replacement.to_string(),
+            protect_output,
+            ..Default::default()
+        };
+        let text = "注意!ＡＢ";
+        let unprotected = [rule("!", "\u{ff01}", false)];
+        assert_eq!(format_text(text, &unprotected, &[]).unwrap(), "注意!AB");
+        let protected = [rule("!", "\u{ff01}", true)];
+        assert_eq!(
+            format_text(text, &protected, &[]).unwrap(),
+            "注意\u{ff01}AB"
+        );
+
+        // Later rules do not see it either, and delimiter spans still come back
+        let options = FormatOptions {
+            protected_delimiters: vec![DelimiterPair("「".to_string(), "」".to_string())],
+            ..Default::default()
+        };
+        let rules = [rule("!", "\u{ff01}", true), rule("\u{ff01}", "?", false)];
+        assert_eq!(
+            format_text_with_options("「ＡＢ」!ＣＤ!", &rules, &[], &options).unwrap(),
+            "「ＡＢ」\u{ff01}CD\u{ff01}"
+        );
+        let stages = explain_format("!", &rules, &[], &options).unwrap();
+        assert!(stages.iter().all(|stage| !uses_placeholders(&stage.text)));
+    }
+
+    // Test for table_columns
+    #[test]
+    fn test_table_columns_convert_only_chosen_columns() {
+        let options = FormatOptions {
+            table_columns: vec![2],
+            ..Default::default()
+        };
+        let replacements = vec![Replacement {
+            original: "円".to_string(),
+            replacement: "".to_string(),
+            ..Default::default()
+        }];
+        let tsv = "品名\t価格\t備考\nＡ４用紙\t１，２００円\t在庫：３\n";
+        assert_eq!(
+            format_text_with_options(tsv, &replacements, &[], &options).unwrap(),
+            "品名\t価格\t備考\nＡ４用紙\t1,200\t在庫：３\n"
+        );
+
+        // In CSV the converted comma gets the cell quoted
+        let csv = "Ａ４用紙,１，２００円,\"在庫：３\"\n";
+        assert_eq!(
+            format_text_with_options(csv, &replacements, &[], &options).unwrap(),
+            "Ａ４用紙,\"1,200\",\"在庫：３\"\n"
+        );
+
+        // Anything that is not a table is converted as a whole
+        assert_eq!(
+            format_text_with_options("ＡＢＣ", &replacements, &[], &options).unwrap(),
+            "ABC"
+        );
+    }
+
+    // Test for code_aware
+    #[test]
+    fn test_code_aware_limits_conversion_to_literals() {
+        let snippet = "let ｘ = \"ＡＢＣ\"; // メモ１\nｆ('１')";
+        let convert = |code_aware| {
+            let options = FormatOptions {
+                code_aware,
+                ..Default::default()
+            };
+            format_text_with_options(snippet, &[], &[], &options).unwrap()
+        };
+        assert_eq!(
+            convert(CodeAware::Literals),
+            "let ｘ = \"ABC\"; // メモ1\nｆ('1')"
+        );
+        assert_eq!(
+            convert(CodeAware::Code),
+            "let x = \"ＡＢＣ\"; // メモ１\nf('１')"
+        );
+        assert_eq!(convert(CodeAware::Off), "let x = \"ABC\"; // メモ1\nf('1')");
+    }
+
+    #[test]
+    fn test_spacing_skips_regions_code_aware_keeps() {
+        let snippet = "    let  x =  \"ａ   ｂ\";  // ｃ   ｄ  ";
+        let collapse = |code_aware| {
+            let options = FormatOptions {
+                code_aware,
+                collapse_spaces: true,
+                trim_lines: true,
+                ..Default::default()
+            };
+            format_text_with_options(snippet, &[], &[], &options).unwrap()
+        };
+        assert_eq!(
+            collapse(CodeAware::Literals),
+            "    let  x =  \"a b\";  // c d"
+        );
+        assert_eq!(
+            collapse(CodeAware::Code),
+            "let x = \"ａ   ｂ\"; // ｃ   ｄ  "
+        );
+        assert_eq!(collapse(CodeAware::Off), "let x = \"a b\"; // c d");
+
+        // The same holds for protected_delimiters
+        let options = FormatOptions {
+            collapse_spaces: true,
+            protected_delimiters: serde_json::from_str(r#"[["「", "」"]]"#).unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text_with_options("a   「x   y」   b", &[], &[], &options).unwrap(),
+            "a 「x   y」 b"
+        );
+    }
+
+    // Test for bracket_style
+    #[test]
+    fn test_bracket_style_families() {
+        let text = "「a」『b』【c】〔d〕〈e〉《f》（g）";
+        let convert = |bracket_style, exclusions: &[char]| {
+            let options = FormatOptions {
+                bracket_style,
+                ..Default::default()
+            };
+            format_text_with_options(text, &[], exclusions, &options).unwrap()
+        };
+        assert_eq!(convert(BracketStyle::Ascii, &[]), "\"a\"'b'[c][d]<e><f>(g)");
+        assert_eq!(convert(BracketStyle::Square, &[]), "[a][b][c][d][e][f](g)");
+        assert_eq!(convert(BracketStyle::Round, &[]), "(a)(b)(c)(d)(e)(f)(g)");
+        assert_eq!(
+            convert(BracketStyle::Off, &[]),
+            "「a」『b』【c】〔d〕〈e〉《f》(g)"
+        );
+        assert_eq!(
+            convert(BracketStyle::Square, &['【', '】']),
+            "[a][b]【c】[d][e][f](g)"
+        );
+        assert_eq!(
+            format_text_with_options(
+                "｢a｣",
+                &[],
+                &[],
+                &FormatOptions {
+                    bracket_style: BracketStyle::Ascii,
+                    ..Default::default()
+                }
+            )
+            .unwrap(),
+            "\"a\""
+        );
+    }
+
+    #[test]
+    fn test_bracket_style_follows_code_aware() {
+        let snippet = "let a = 【1】; \"【b】\"";
+        let convert = |code_aware| {
+            let options = FormatOptions {
+                bracket_style: BracketStyle::Square,
+                code_aware,
+                ..Default::default()
+            };
+            format_text_with_options(snippet, &[], &[], &options).unwrap()
+        };
+        assert_eq!(convert(CodeAware::Literals), "let a = 【1】; \"[b]\"");
+        assert_eq!(convert(CodeAware::Code), "let a = [1]; \"【b】\"");
+        assert_eq!(convert(CodeAware::Off), "let a = [1]; \"[b]\"");
+    }
+}
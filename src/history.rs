@@ -0,0 +1,141 @@
+use log::info;
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::ConfigError;
+
+// Every loaded config version as JSON lines, with `reload_history` enabled
+pub const RELOAD_HISTORY_FILE_NAME: &str = "reload_history.jsonl";
+// Only the most recent loads are kept in memory
+pub const RELOAD_HISTORY_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ReloadRecord {
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    // "replacements" or "exclusions"
+    pub file: String,
+    // Short form of the hash the reload check compares
+    pub hash: String,
+}
+
+pub fn short_hash(hash: u64) -> String {
+    format!("{:08x}", hash >> 32)
+}
+
+#[derive(Debug, Default)]
+pub struct ReloadHistory {
+    records: VecDeque<ReloadRecord>,
+    // Also appended to this file when set
+    path: Option<PathBuf>,
+}
+
+impl ReloadHistory {
+    pub fn persist_to(&mut self, path: PathBuf) {
+        self.path = Some(path);
+    }
+
+    pub fn record(&mut self, file: &str, hash: u64) -> Result<(), ConfigError> {
+        let now = chrono::Local::now();
+        let record = ReloadRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+            file: file.to_string(),
+            hash: short_hash(hash),
+        };
+        info!(
+            "Loaded {} version {} at {}.",
+            record.file,
+            record.hash,
+            now.format("%Y-%m-%d %H:%M:%S")
+        );
+        if self.records.len() == RELOAD_HISTORY_LIMIT {
+            self.records.pop_front();
+        }
+        self.records.push_back(record.clone());
+        match &self.path {
+            Some(path) => append_record(path, &record),
+            None => Ok(()),
+        }
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &ReloadRecord> {
+        self.records.iter()
+    }
+}
+
+fn append_record(path: &Path, record: &ReloadRecord) -> Result<(), ConfigError> {
+    let io_error = |source| ConfigError::Io {
+        path: path.display().to_string(),
+        source,
+    };
+    let line = serde_json::to_string(record).map_err(|source| ConfigError::Parse {
+        path: path.display().to_string(),
+        source,
+    })?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(io_error)?;
+    writeln!(file, "{}", line).map_err(io_error)
+}
+
+// The on-disk history, oldest first. A missing file is an empty history.
+pub fn read_reload_history(path: &Path) -> Result<Vec<ReloadRecord>, ConfigError> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let data = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.display().to_string(),
+        source,
+    })?;
+    data.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line).map_err(|source| ConfigError::Parse {
+                path: path.display().to_string(),
+                source,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_history_is_capped() {
+        let mut history = ReloadHistory::default();
+        for hash in 0..RELOAD_HISTORY_LIMIT as u64 + 1 {
+            history.record("replacements", hash << 32).unwrap();
+        }
+        assert_eq!(history.records().count(), RELOAD_HISTORY_LIMIT);
+        assert_eq!(history.records().next().unwrap().hash, "00000001");
+    }
+
+    #[test]
+    fn test_history_round_trip() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join(RELOAD_HISTORY_FILE_NAME);
+        assert!(read_reload_history(&path).unwrap().is_empty());
+
+        let mut history = ReloadHistory::default();
+        history.persist_to(path.clone());
+        history
+            .record("replacements", 0xdead_beef_0000_0000)
+            .unwrap();
+        history.record("exclusions", 0x1234_5678_0000_0000).unwrap();
+        let saved = read_reload_history(&path).unwrap();
+        assert_eq!(saved, history.records().cloned().collect::<Vec<_>>());
+        assert_eq!(saved[0].hash, "deadbeef");
+        assert_eq!(saved[1].file, "exclusions");
+    }
+}
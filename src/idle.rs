@@ -0,0 +1,117 @@
+// Pausing the clipboard loop while nobody is using the machine. Idle time
+// comes from the platform (XScreenSaver, GetLastInputInfo, IOKit) and is
+// only queried every IDLE_CHECK_INTERVAL, not on every tick.
+use std::time::{Duration, Instant};
+
+pub const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// `idle` is None when the idle time could not be queried, in which case the
+// loop keeps running
+pub fn should_pause(threshold: Option<Duration>, idle: Option<Duration>) -> bool {
+    match (threshold, idle) {
+        (Some(threshold), Some(idle)) => idle >= threshold,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct IdleMonitor {
+    threshold: Option<Duration>,
+    last_check: Option<Instant>,
+    idle: bool,
+}
+
+impl IdleMonitor {
+    pub fn new(threshold: Option<Duration>) -> Self {
+        IdleMonitor {
+            threshold,
+            ..Default::default()
+        }
+    }
+
+    // Whether the loop should stay paused. `query` is only called once the
+    // previous answer is older than IDLE_CHECK_INTERVAL.
+    pub fn is_idle(&mut self, now: Instant, query: impl FnOnce() -> Option<Duration>) -> bool {
+        if self.threshold.is_none() {
+            return false;
+        }
+        let fresh = self
+            .last_check
+            .is_some_and(|last| now.saturating_duration_since(last) < IDLE_CHECK_INTERVAL);
+        if !fresh {
+            self.last_check = Some(now);
+            let idle = should_pause(self.threshold, query());
+            if idle != self.idle {
+                if idle {
+                    log::info!("The machine is idle. Pausing clipboard conversion.");
+                } else {
+                    log::info!("Activity detected. Resuming clipboard conversion.");
+                }
+            }
+            self.idle = idle;
+        }
+        self.idle
+    }
+}
+
+#[cfg(feature = "idle-pause")]
+pub fn query_idle_time() -> Option<Duration> {
+    match user_idle::UserIdle::get_time() {
+        Ok(idle) => Some(Duration::from_millis(
+            u64::try_from(idle.as_milliseconds()).unwrap_or(u64::MAX),
+        )),
+        Err(e) => {
+            log::debug!("Failed to query idle time: {:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "idle-pause"))]
+pub fn query_idle_time() -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_pause() {
+        let threshold = Some(Duration::from_secs(300));
+        assert!(should_pause(threshold, Some(Duration::from_secs(300))));
+        assert!(should_pause(threshold, Some(Duration::from_secs(3600))));
+        assert!(!should_pause(threshold, Some(Duration::from_secs(299))));
+        // Unknown idle time or no threshold never pauses
+        assert!(!should_pause(threshold, None));
+        assert!(!should_pause(None, Some(Duration::from_secs(3600))));
+    }
+
+    #[test]
+    fn test_idle_monitor_polls_sparingly() {
+        let mut monitor = IdleMonitor::new(Some(Duration::from_secs(60)));
+        let start = Instant::now();
+        assert!(monitor.is_idle(start, || Some(Duration::from_secs(120))));
+        // Within the check interval the cached answer is used
+        let mut queried = false;
+        let cached = monitor.is_idle(start + Duration::from_secs(1), || {
+            queried = true;
+            Some(Duration::ZERO)
+        });
+        assert!(cached);
+        assert!(!queried);
+        // Activity shows up at the next check
+        assert!(!monitor.is_idle(start + IDLE_CHECK_INTERVAL, || Some(Duration::ZERO)));
+    }
+
+    #[test]
+    fn test_idle_monitor_disabled() {
+        let mut monitor = IdleMonitor::new(None);
+        let mut queried = false;
+        assert!(!monitor.is_idle(Instant::now(), || {
+            queried = true;
+            Some(Duration::from_secs(3600))
+        }));
+        assert!(!queried);
+    }
+}
@@ -0,0 +1,431 @@
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::audit::sha256_hex;
+use crate::poll::DEFAULT_OVERRIDE_DURATION;
+use crate::private::write_private;
+
+pub const DEFAULT_IPC_PORT: u16 = 39281;
+const IPC_TIMEOUT: Duration = Duration::from_secs(5);
+// Largest text accepted by the `text` command
+pub const MAX_TEXT_BYTES: usize = 16 * 1024 * 1024;
+// Any local user can connect to the port, so commands that read or write past
+// clipboard contents need the token the daemon leaves in this file, which only
+// its owner can read
+pub const IPC_TOKEN_FILE_NAME: &str = "ipc_token";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpcCommand {
+    Convert,
+    // Conversion count and reload history as JSON
+    Stats,
+    // Converts the given text and sends it back, leaving the clipboard alone
+    Text(String),
+    // Recent conversions as JSON, newest first
+    History,
+    // Writes the original of the conversion at this history index back
+    Restore(usize),
+    // Polls at `interval` for `duration`, then at the usual interval again
+    SetInterval {
+        interval: Duration,
+        duration: Duration,
+    },
+}
+
+// `set-interval MS [SECONDS]`
+fn parse_set_interval(args: &str) -> Option<IpcCommand> {
+    let mut args = args.split_whitespace();
+    let interval = Duration::from_millis(args.next()?.parse().ok()?);
+    let duration = match args.next() {
+        Some(seconds) => Duration::from_secs(seconds.parse().ok()?),
+        None => DEFAULT_OVERRIDE_DURATION,
+    };
+    args.next()
+        .is_none()
+        .then_some(IpcCommand::SetInterval { interval, duration })
+}
+
+impl IpcCommand {
+    pub fn needs_token(&self) -> bool {
+        matches!(self, IpcCommand::History | IpcCommand::Restore(_))
+    }
+}
+
+impl FromStr for IpcCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "convert" => Ok(IpcCommand::Convert),
+            "stats" => Ok(IpcCommand::Stats),
+            "history" => Ok(IpcCommand::History),
+            restore if restore.starts_with("restore ") => restore["restore ".len()..]
+                .trim()
+                .parse()
+                .map(IpcCommand::Restore)
+                .map_err(|_| anyhow!("Malformed restore command: '{}'", restore)),
+            set if set.starts_with("set-interval ") => {
+                parse_set_interval(&set["set-interval ".len()..])
+                    .ok_or_else(|| anyhow!("Malformed set-interval command: '{}'", set))
+            }
+            text if text.starts_with("text ") => {
+                Err(anyhow!("The text command must be sent as a frame"))
+            }
+            other => Err(anyhow!("Unknown IPC command: '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for IpcCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpcCommand::Convert => write!(f, "convert"),
+            IpcCommand::Stats => write!(f, "stats"),
+            IpcCommand::Text(text) => f.write_str(&encode_text_frame(text)),
+            IpcCommand::History => write!(f, "history"),
+            IpcCommand::Restore(index) => write!(f, "restore {}", index),
+            IpcCommand::SetInterval { interval, duration } => write!(
+                f,
+                "set-interval {} {}",
+                interval.as_millis(),
+                duration.as_secs()
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct IpcRequest {
+    pub command: IpcCommand,
+    reply: Sender<String>,
+}
+
+impl IpcRequest {
+    pub fn reply(self, message: impl Into<String>) {
+        // The client may already have given up waiting; nothing to do then
+        let _ = self.reply.send(message.into());
+    }
+}
+
+// Text travels as a frame: a `text <byte length>` line followed by exactly
+// that many bytes of UTF-8, so it may contain any character, newlines included.
+// A failed request is answered with a single `error: <message>` line.
+pub fn encode_text_frame(text: &str) -> String {
+    format!("text {}\n{}", text.len(), text)
+}
+
+// The payload of a frame whose header line has already been read
+fn read_text_payload(reader: &mut impl BufRead, header: &str) -> Result<String> {
+    let length: usize = header
+        .trim_end()
+        .strip_prefix("text ")
+        .and_then(|length| length.parse().ok())
+        .with_context(|| format!("Malformed text frame header: '{}'", header.trim_end()))?;
+    if length > MAX_TEXT_BYTES {
+        return Err(anyhow!(
+            "Text of {} bytes exceeds the limit of {} bytes",
+            length,
+            MAX_TEXT_BYTES
+        ));
+    }
+    // Grown as bytes arrive rather than sized from the header, so a client
+    // claiming a huge frame costs nothing until it sends one
+    let mut payload = Vec::new();
+    reader
+        .take(length as u64)
+        .read_to_end(&mut payload)
+        .context("Failed to read the text frame")?;
+    if payload.len() < length {
+        return Err(anyhow!("Text frame ended early"));
+    }
+    String::from_utf8(payload).context("Text frame is not valid UTF-8")
+}
+
+pub fn decode_text_frame(reader: &mut impl BufRead) -> Result<String> {
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    if let Some(message) = header.strip_prefix("error: ") {
+        return Err(anyhow!("{}", message.trim_end()));
+    }
+    read_text_payload(reader, &header)
+}
+
+// 128 bits from the OS-seeded keys of the standard library's hasher
+pub fn new_token() -> String {
+    let state = RandomState::new();
+    let mut seed = String::new();
+    for salt in [0u8, 1] {
+        let mut hasher = state.build_hasher();
+        hasher.write_u8(salt);
+        seed.push_str(&hasher.finish().to_string());
+    }
+    seed.push_str(&format!("{:?}{}", SystemTime::now(), std::process::id()));
+    sha256_hex(&seed)
+}
+
+// A new token for each daemon, so one read from an old file stops working
+pub fn create_token(path: &Path) -> Result<String> {
+    let token = new_token();
+    write_private(path, token.as_bytes())
+        .with_context(|| format!("Failed to write the IPC token to {}", path.display()))?;
+    Ok(token)
+}
+
+pub fn read_token(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path)
+        .map(|token| token.trim().to_string())
+        .with_context(|| format!("Failed to read the IPC token from {}", path.display()))
+}
+
+pub fn bind(port: u16) -> Result<TcpListener> {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .with_context(|| format!("Failed to bind IPC socket on port {}", port))
+}
+
+pub fn spawn_server(listener: TcpListener, token: String) -> Receiver<IpcRequest> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_connection(stream, &tx, &token) {
+                        warn!("Failed to handle IPC connection: {:#}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to accept IPC connection: {}", e),
+            }
+        }
+    });
+    rx
+}
+
+// A command may be preceded by a `token <token>` line
+fn handle_connection(stream: TcpStream, tx: &Sender<IpcRequest>, token: &str) -> Result<()> {
+    stream.set_read_timeout(Some(IPC_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut authorized = false;
+    if let Some(sent) = line.strip_prefix("token ") {
+        authorized = sent.trim_end() == token;
+        line.clear();
+        reader.read_line(&mut line)?;
+    }
+    let command = if line.starts_with("text ") {
+        read_text_payload(&mut reader, &line).map(IpcCommand::Text)
+    } else {
+        line.parse::<IpcCommand>()
+    };
+    let response = match command {
+        Ok(command) if command.needs_token() && !authorized => {
+            format!("error: {} needs the IPC token", command)
+        }
+        Ok(command) => {
+            let (reply_tx, reply_rx) = channel();
+            tx.send(IpcRequest {
+                command,
+                reply: reply_tx,
+            })
+            .context("Daemon is no longer accepting IPC requests")?;
+            reply_rx
+                .recv_timeout(IPC_TIMEOUT)
+                .unwrap_or_else(|_| "error: no response from daemon".to_string())
+        }
+        Err(e) => format!("error: {}", e),
+    };
+    writeln!(&stream, "{}", response)?;
+    Ok(())
+}
+
+fn connect(port: u16) -> Result<TcpStream> {
+    let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+        .context("Failed to connect to kill-zen-all daemon. Is it running with IPC enabled?")?;
+    stream.set_read_timeout(Some(IPC_TIMEOUT))?;
+    Ok(stream)
+}
+
+// Client side of the `text` command, for clipboard managers and scripts
+pub fn convert_text(port: u16, text: &str) -> Result<String> {
+    let mut stream = connect(port)?;
+    stream.write_all(encode_text_frame(text).as_bytes())?;
+    decode_text_frame(&mut BufReader::new(stream))
+}
+
+pub fn send_command(port: u16, command: &IpcCommand, token: Option<&str>) -> Result<String> {
+    let mut stream = connect(port)?;
+    if let Some(token) = token {
+        writeln!(stream, "token {}", token)?;
+    }
+    writeln!(stream, "{}", command)?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(response.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ipc_command() {
+        assert_eq!(
+            "convert\n".parse::<IpcCommand>().unwrap(),
+            IpcCommand::Convert
+        );
+        assert_eq!("stats".parse::<IpcCommand>().unwrap(), IpcCommand::Stats);
+        assert_eq!(
+            "history".parse::<IpcCommand>().unwrap(),
+            IpcCommand::History
+        );
+        assert_eq!(
+            "restore 2\n".parse::<IpcCommand>().unwrap(),
+            IpcCommand::Restore(2)
+        );
+        assert_eq!(
+            IpcCommand::Restore(2)
+                .to_string()
+                .parse::<IpcCommand>()
+                .unwrap(),
+            IpcCommand::Restore(2)
+        );
+        assert!("restore -1".parse::<IpcCommand>().is_err());
+        let fast = IpcCommand::SetInterval {
+            interval: Duration::from_millis(100),
+            duration: Duration::from_secs(60),
+        };
+        assert_eq!("set-interval 100 60".parse::<IpcCommand>().unwrap(), fast);
+        assert_eq!(fast.to_string().parse::<IpcCommand>().unwrap(), fast);
+        assert_eq!(
+            "set-interval 100".parse::<IpcCommand>().unwrap(),
+            IpcCommand::SetInterval {
+                interval: Duration::from_millis(100),
+                duration: DEFAULT_OVERRIDE_DURATION,
+            }
+        );
+        assert!("set-interval fast".parse::<IpcCommand>().is_err());
+        assert!("set-interval 100 60 1".parse::<IpcCommand>().is_err());
+        assert!("explode".parse::<IpcCommand>().is_err());
+        assert!("text 3".parse::<IpcCommand>().is_err());
+        // The text command is shown as the frame that carries it
+        assert_eq!(
+            IpcCommand::Text("ａ\nb".to_string()).to_string(),
+            encode_text_frame("ａ\nb")
+        );
+    }
+
+    // Test for the text frame protocol
+    #[test]
+    fn test_text_frame_round_trip() {
+        let text = "ＡＢＣ\nline two\n";
+        let frame = encode_text_frame(text);
+        assert!(frame.starts_with("text 19\n"));
+        assert_eq!(
+            decode_text_frame(&mut std::io::Cursor::new(frame)).unwrap(),
+            text
+        );
+        assert_eq!(
+            decode_text_frame(&mut std::io::Cursor::new(encode_text_frame(""))).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_text_frame_errors() {
+        let decode = |bytes: &[u8]| decode_text_frame(&mut std::io::Cursor::new(bytes.to_vec()));
+        assert_eq!(
+            decode(b"error: paused\n").unwrap_err().to_string(),
+            "paused"
+        );
+        assert_eq!(
+            decode(b"text 10\nshort").unwrap_err().to_string(),
+            "Text frame ended early"
+        );
+        // A length at the limit with nothing behind it is not allocated up front
+        assert!(decode(format!("text {}\nshort", MAX_TEXT_BYTES).as_bytes()).is_err());
+        assert!(decode(b"text ten\n").is_err());
+        assert!(decode(b"text 2\n\xff\xfe").is_err());
+        assert!(decode(format!("text {}\n", MAX_TEXT_BYTES + 1).as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_convert_text_round_trip() {
+        let listener = bind(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let rx = spawn_server(listener, "secret".to_string());
+
+        let daemon = thread::spawn(move || {
+            let request = rx.recv().unwrap();
+            let IpcCommand::Text(text) = &request.command else {
+                panic!("unexpected command {:?}", request.command);
+            };
+            let reply = encode_text_frame(&text.to_uppercase());
+            request.reply(reply);
+        });
+
+        assert_eq!(convert_text(port, "multi\nline").unwrap(), "MULTI\nLINE");
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn test_ipc_round_trip() {
+        let listener = bind(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let rx = spawn_server(listener, "secret".to_string());
+
+        let daemon = thread::spawn(move || {
+            let request = rx.recv().unwrap();
+            assert_eq!(request.command, IpcCommand::Convert);
+            request.reply("converted");
+        });
+
+        let response = send_command(port, &IpcCommand::Convert, None).unwrap();
+        assert_eq!(response, "converted");
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn test_history_needs_the_token() {
+        let listener = bind(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let rx = spawn_server(listener, "secret".to_string());
+
+        // Refused without reaching the daemon
+        for token in [None, Some("guess")] {
+            let response = send_command(port, &IpcCommand::History, token).unwrap();
+            assert_eq!(response, "error: history needs the IPC token");
+            let response = send_command(port, &IpcCommand::Restore(0), token).unwrap();
+            assert_eq!(response, "error: restore 0 needs the IPC token");
+        }
+        assert!(rx.try_recv().is_err());
+
+        let daemon = thread::spawn(move || {
+            let request = rx.recv().unwrap();
+            assert_eq!(request.command, IpcCommand::History);
+            request.reply("[]");
+        });
+        let response = send_command(port, &IpcCommand::History, Some("secret")).unwrap();
+        assert_eq!(response, "[]");
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn test_token_file_is_private() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join(IPC_TOKEN_FILE_NAME);
+        let token = create_token(&path).unwrap();
+        assert_eq!(token.len(), 64);
+        assert_eq!(read_token(&path).unwrap(), token);
+        // Each daemon gets a new one
+        assert_ne!(create_token(&path).unwrap(), token);
+    }
+}
@@ -0,0 +1,100 @@
+use crate::config::Replacement;
+use crate::error::FormatError;
+use crate::format::{format_text_with_options, FormatOptions};
+
+// What `kill-zen-all type` sends instead of rewriting the clipboard. Line
+// breaks and tabs are pressed as keys, since typing them as text is not
+// handled the same way by every application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Keystroke {
+    Text(String),
+    Enter,
+    Tab,
+}
+
+// "\r\n" and a lone "\r" are a single Enter, like "\n"
+pub fn build_keystrokes(text: &str) -> Vec<Keystroke> {
+    let mut keystrokes = Vec::new();
+    let mut pending = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let key = match c {
+            '\r' => {
+                chars.next_if_eq(&'\n');
+                Keystroke::Enter
+            }
+            '\n' => Keystroke::Enter,
+            '\t' => Keystroke::Tab,
+            c => {
+                pending.push(c);
+                continue;
+            }
+        };
+        if !pending.is_empty() {
+            keystrokes.push(Keystroke::Text(std::mem::take(&mut pending)));
+        }
+        keystrokes.push(key);
+    }
+    if !pending.is_empty() {
+        keystrokes.push(Keystroke::Text(pending));
+    }
+    keystrokes
+}
+
+pub fn convert_to_keystrokes(
+    text: &str,
+    replacements: &[Replacement],
+    exclusions: &[char],
+    options: &FormatOptions,
+) -> Result<Vec<Keystroke>, FormatError> {
+    let converted = format_text_with_options(text, replacements, exclusions, options)?;
+    Ok(build_keystrokes(&converted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_keystrokes_splits_line_breaks_and_tabs() {
+        assert_eq!(
+            build_keystrokes("ab\r\nc\td\re\n"),
+            vec![
+                Keystroke::Text("ab".to_string()),
+                Keystroke::Enter,
+                Keystroke::Text("c".to_string()),
+                Keystroke::Tab,
+                Keystroke::Text("d".to_string()),
+                Keystroke::Enter,
+                Keystroke::Text("e".to_string()),
+                Keystroke::Enter,
+            ]
+        );
+        assert!(build_keystrokes("").is_empty());
+    }
+
+    // Test for converting before building keystrokes
+    #[test]
+    fn test_convert_to_keystrokes() {
+        let replacements = vec![Replacement {
+            original: "ｆｏｏ".to_string(),
+            replacement: "bar".to_string(),
+            ..Default::default()
+        }];
+        let keystrokes = convert_to_keystrokes(
+            "ｆｏｏ　１\n２！",
+            &replacements,
+            &['！'],
+            &FormatOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            keystrokes,
+            vec![
+                Keystroke::Text("bar　1".to_string()),
+                Keystroke::Enter,
+                Keystroke::Text("2！".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use difference::{Changeset, Difference};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::error::ConfigError;
+
+pub const SUGGESTIONS_FILE_NAME: &str = "suggestions.json";
+// Changeset is quadratic, and large pastes are never "slight" edits anyway
+const MAX_LEARN_INPUT_CHARS: usize = 1000;
+const MAX_SUGGESTION_CHARS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Suggestion {
+    pub original: String,
+    pub replacement: String,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric()
+}
+
+// Infers a rule from a single contiguous edit. Edits inside ASCII words are
+// widened to the whole word so "colour" -> "color" isn't learned as "u" -> "".
+pub fn suggest_replacement(before: &str, after: &str) -> Option<Suggestion> {
+    if before == after
+        || before.chars().count() > MAX_LEARN_INPUT_CHARS
+        || after.chars().count() > MAX_LEARN_INPUT_CHARS
+    {
+        return None;
+    }
+    let changeset = Changeset::new(before, after, "");
+    let mut prefix = String::new();
+    let mut removed = String::new();
+    let mut added = String::new();
+    let mut suffix = String::new();
+    for diff in &changeset.diffs {
+        match diff {
+            Difference::Same(text) if removed.is_empty() && added.is_empty() => {
+                prefix.push_str(text)
+            }
+            Difference::Same(text) => suffix.push_str(text),
+            // A second edit region means this wasn't a single-token fix
+            Difference::Rem(_) | Difference::Add(_) if !suffix.is_empty() => return None,
+            Difference::Rem(text) => removed.push_str(text),
+            Difference::Add(text) => added.push_str(text),
+        }
+    }
+
+    let first_changed = removed.chars().next().or_else(|| added.chars().next())?;
+    let last_changed = removed
+        .chars()
+        .next_back()
+        .or_else(|| added.chars().next_back())?;
+    let mut original = removed;
+    let mut replacement = added;
+    if is_word_char(first_changed) {
+        let head: String = prefix
+            .chars()
+            .rev()
+            .take_while(|c| is_word_char(*c))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect();
+        original.insert_str(0, &head);
+        replacement.insert_str(0, &head);
+    }
+    if is_word_char(last_changed) {
+        let tail: String = suffix.chars().take_while(|c| is_word_char(*c)).collect();
+        original.push_str(&tail);
+        replacement.push_str(&tail);
+    }
+
+    if original.is_empty()
+        || original.chars().count() > MAX_SUGGESTION_CHARS
+        || replacement.chars().count() > MAX_SUGGESTION_CHARS
+    {
+        return None;
+    }
+    Some(Suggestion {
+        original,
+        replacement,
+    })
+}
+
+#[derive(Debug)]
+pub struct Learner {
+    window: Duration,
+    last: Option<(String, Instant)>,
+}
+
+impl Learner {
+    pub fn new(window: Duration) -> Self {
+        Learner { window, last: None }
+    }
+
+    // Feeds a newly copied text; suggests a rule if it looks like a quick
+    // manual correction of the previous copy
+    pub fn observe(&mut self, content: &str, now: Instant) -> Option<Suggestion> {
+        if let Some((previous, _)) = &self.last {
+            if previous == content {
+                return None;
+            }
+        }
+        let suggestion = match &self.last {
+            Some((previous, copied_at)) if now.duration_since(*copied_at) <= self.window => {
+                suggest_replacement(previous, content)
+            }
+            _ => None,
+        };
+        self.last = Some((content.to_string(), now));
+        suggestion
+    }
+
+    // Our own clipboard writes are not user edits
+    pub fn ignore(&mut self, content: &str, now: Instant) {
+        self.last = Some((content.to_string(), now));
+    }
+}
+
+pub fn append_suggestion(path: &Path, suggestion: &Suggestion) -> Result<bool, ConfigError> {
+    let io_error = |source| ConfigError::Io {
+        path: path.display().to_string(),
+        source,
+    };
+    let mut suggestions: Vec<Suggestion> = if path.exists() {
+        let data = fs::read_to_string(path).map_err(io_error)?;
+        serde_json::from_str(&data).map_err(|source| ConfigError::Parse {
+            path: path.display().to_string(),
+            source,
+        })?
+    } else {
+        Vec::new()
+    };
+    if suggestions.contains(suggestion) {
+        return Ok(false);
+    }
+    suggestions.push(suggestion.clone());
+    let data = serde_json::to_string_pretty(&suggestions).map_err(|source| ConfigError::Parse {
+        path: path.display().to_string(),
+        source,
+    })?;
+    fs::write(path, data).map_err(io_error)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn suggestion(original: &str, replacement: &str) -> Option<Suggestion> {
+        Some(Suggestion {
+            original: original.to_string(),
+            replacement: replacement.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_suggest_cjk_single_char_edit() {
+        assert_eq!(
+            suggest_replacement("頚椎の撮影", "頸椎の撮影"),
+            suggestion("頚", "頸")
+        );
+    }
+
+    #[test]
+    fn test_suggest_widens_to_ascii_word() {
+        assert_eq!(
+            suggest_replacement("the colour red", "the color red"),
+            suggestion("colour", "color")
+        );
+        assert_eq!(
+            suggest_replacement("foo bar", "foo baz"),
+            suggestion("bar", "baz")
+        );
+    }
+
+    #[test]
+    fn test_suggest_rejects_multiple_edits() {
+        assert_eq!(suggest_replacement("foo bar baz", "fox bar bat"), None);
+    }
+
+    #[test]
+    fn test_suggest_rejects_identical_and_insertions() {
+        assert_eq!(suggest_replacement("foo", "foo"), None);
+        assert_eq!(suggest_replacement("頸椎", "頸椎。"), None);
+    }
+
+    #[test]
+    fn test_learner_window() {
+        let start = Instant::now();
+        let mut learner = Learner::new(Duration::from_secs(10));
+        assert_eq!(learner.observe("頚椎", start), None);
+        assert_eq!(
+            learner.observe("頸椎", start + Duration::from_secs(3)),
+            suggestion("頚", "頸")
+        );
+        assert_eq!(
+            learner.observe("頚椎", start + Duration::from_secs(30)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_learner_ignores_own_writes() {
+        let start = Instant::now();
+        let mut learner = Learner::new(Duration::from_secs(10));
+        learner.observe("１２３", start);
+        learner.ignore("123", start);
+        assert_eq!(learner.observe("123", start + Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn test_append_suggestion_deduplicates() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join(SUGGESTIONS_FILE_NAME);
+        let suggestion = suggestion("頚", "頸").unwrap();
+
+        assert!(append_suggestion(&file_path, &suggestion).unwrap());
+        assert!(!append_suggestion(&file_path, &suggestion).unwrap());
+        let saved: Vec<Suggestion> =
+            serde_json::from_str(&fs::read_to_string(&file_path).unwrap()).unwrap();
+        assert_eq!(saved, vec![suggestion]);
+    }
+}
@@ -0,0 +1,80 @@
+pub mod audit;
+pub mod blocks;
+pub mod cache;
+pub mod cli_clipboard;
+pub mod code;
+pub mod config;
+pub mod delimiters;
+pub mod diff;
+pub mod error;
+pub mod feedback;
+#[cfg(unix)]
+pub mod fifo;
+pub mod focus;
+pub mod format;
+pub mod history;
+pub mod idle;
+pub mod ipc;
+pub mod keystrokes;
+pub mod learn;
+pub mod links;
+pub mod log_throttle;
+pub mod merge;
+pub mod migrate;
+pub mod mojibake;
+pub mod observe;
+pub mod osc52;
+pub mod pipe;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod poll;
+pub mod post;
+pub mod private;
+pub mod quiet;
+pub mod reload;
+pub mod remote_rules;
+pub mod rename;
+pub mod rule_cache;
+pub mod rules;
+pub mod selection;
+pub mod sensitive;
+pub mod setup;
+pub mod snapshots;
+pub mod table;
+pub mod tee;
+pub mod throughput;
+pub mod usage;
+pub mod verbosity;
+pub mod wasm;
+mod x11;
+
+pub use blocks::{BlockTally, UnicodeBlock};
+pub use cache::ConversionCache;
+pub use code::{CodeAware, CodeLanguage};
+pub use config::{
+    check_exclude_ranges, create_default_config, create_default_config_in, find_combined_config,
+    find_config_file, find_project_config_dir, fullwidth_latin_exclusions, get_config_dir,
+    load_exclusion_list, load_exclusion_reasons, load_layered_exclusions, load_replacement_files,
+    load_replacements, load_settings, load_stage_exclusions, project_exclusions_file,
+    resolve_config_file, resolve_replacement_files, resolve_settings_file, ConfigFormat,
+    EffectiveConfig, ExcludeRange, LargeInputPolicy, Occurrence, OutputMode, Replacement,
+    ReplacementAction, Settings, TextFlavor, TriggerMode,
+};
+pub use diff::{
+    annotate_stages, changed_chars, diff_ops, diff_summary, highlight_causes, highlight_diff,
+    unified_diff, Annotated, ChangeCause, DiffOp,
+};
+pub use error::{ConfigError, FormatError};
+pub use feedback::ConvertFeedback;
+pub use format::{
+    explain_format, explain_isolated, find_rules, format_text, format_text_with_options,
+    replace_strategy, report_format, Aggressiveness, BracketStyle, ConversionReport, DigitGrouping,
+    ExclusionStage, FormatOptions, IdeographicSpace, ReplaceStrategy, ScriptName, Stage,
+    StageExclusions, StageOrder, WidthMapping, YenPolicy,
+};
+pub use keystrokes::{build_keystrokes, convert_to_keystrokes, Keystroke};
+pub use learn::{suggest_replacement, Learner, Suggestion};
+pub use log_throttle::LogThrottle;
+pub use reload::{calculate_hash, FileFingerprint, ReloadHashStrategy};
+pub use rules::{check_idempotent, idempotency_samples, CompiledRules, IdempotencyError};
+pub use sensitive::SensitiveFilter;
@@ -0,0 +1,186 @@
+// A warning, not a block, for conversions that rewrite part of a URL or an
+// email address, naming the stage that did it.
+use crate::diff::MAX_DIFF_INPUT_CHARS;
+use crate::format::Stage;
+use difference::{Changeset, Difference};
+use regex::Regex;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+// Fullwidth forms are matched too, so a link is recognised before halving.
+// Addresses are only letters and digits of the Latin alphabet, or Japanese
+// text next to one would be taken as part of it.
+const LINK_PATTERN: &str = concat!(
+    r"(?:https?|ｈｔｔｐｓ?)[:：][/／]{2}[^\s<>\x22「」]+",
+    r"|[0-9A-Za-z０-９Ａ-Ｚａ-ｚ._%+\-]+[@＠]",
+    r"[0-9A-Za-z０-９Ａ-Ｚａ-ｚ\-]+(?:[.．][0-9A-Za-z０-９Ａ-Ｚａ-ｚ\-]+)+",
+);
+
+fn link_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(LINK_PATTERN).unwrap())
+}
+
+// Byte ranges of `before` that the conversion removed or inserted into.
+// An insertion is an empty range at its position.
+fn changed_ranges(before: &str, after: &str) -> Vec<Range<usize>> {
+    if before.chars().count() > MAX_DIFF_INPUT_CHARS || after.chars().count() > MAX_DIFF_INPUT_CHARS
+    {
+        // Too long to diff; everything between the common prefix and suffix
+        let prefix = before
+            .char_indices()
+            .zip(after.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(before.len().min(after.len()), |((i, _), _)| i);
+        let suffix = before[prefix..]
+            .chars()
+            .rev()
+            .zip(after[prefix..].chars().rev())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum::<usize>();
+        #[allow(clippy::single_range_in_vec_init)]
+        return vec![prefix..before.len() - suffix];
+    }
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    for diff in Changeset::new(before, after, "").diffs {
+        match diff {
+            Difference::Same(text) => offset += text.len(),
+            Difference::Rem(text) => {
+                ranges.push(offset..offset + text.len());
+                offset += text.len();
+            }
+            Difference::Add(_) => ranges.push(offset..offset),
+        }
+    }
+    ranges
+}
+
+fn touches(link: &Range<usize>, change: &Range<usize>) -> bool {
+    if change.is_empty() {
+        // Appending right after a link, or right before it, leaves it alone
+        link.start < change.start && change.start < link.end
+    } else {
+        change.start < link.end && link.start < change.end
+    }
+}
+
+// The URLs and email addresses in `before` that the conversion changed
+pub fn touched_links<'a>(before: &'a str, after: &str) -> Vec<&'a str> {
+    if before == after {
+        return Vec::new();
+    }
+    let links: Vec<_> = link_regex().find_iter(before).collect();
+    if links.is_empty() {
+        return Vec::new();
+    }
+    let changes = changed_ranges(before, after);
+    links
+        .into_iter()
+        .filter(|link| changes.iter().any(|change| touches(&link.range(), change)))
+        .map(|link| link.as_str())
+        .collect()
+}
+
+// The links each stage changed, as they read before that stage, from
+// --explain's stages. Pairs of the stage's label and the link.
+pub fn links_changed_by_stages(stages: &[Stage]) -> Vec<(&str, &str)> {
+    stages
+        .windows(2)
+        .flat_map(|pair| {
+            touched_links(&pair[0].text, &pair[1].text)
+                .into_iter()
+                .map(|link| (pair[1].label.as_str(), link))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Replacement;
+    use crate::format::{explain_format, FormatOptions};
+
+    #[test]
+    fn test_touched_url_is_reported() {
+        let before = "see https://example.com/ｐａｔｈ now";
+        let after = "see https://example.com/path now";
+        assert_eq!(
+            touched_links(before, after),
+            vec!["https://example.com/ｐａｔｈ"]
+        );
+        assert_eq!(
+            touched_links("ｈｔｔｐｓ：／／example.com", "https://example.com"),
+            vec!["ｈｔｔｐｓ：／／example.com"]
+        );
+    }
+
+    #[test]
+    fn test_touched_email_is_reported() {
+        assert_eq!(
+            touched_links("mail user＠example.com", "mail user@example.com"),
+            vec!["user＠example.com"]
+        );
+    }
+
+    #[test]
+    fn test_untouched_link_is_not_reported() {
+        let before = "ＡＢＣ https://example.com/path user@example.com ＤＥＦ";
+        let after = "ABC https://example.com/path user@example.com DEF";
+        assert!(touched_links(before, after).is_empty());
+        assert!(touched_links("https://example.com", "https://example.com").is_empty());
+        // Text appended right after the link does not change the link
+        assert!(touched_links("https://example.com", "https://example.com!").is_empty());
+    }
+
+    #[test]
+    fn test_email_stops_at_japanese_text() {
+        assert_eq!(
+            touched_links("連絡はuser＠example.comまで", "連絡はuser@example.comまで"),
+            vec!["user＠example.com"]
+        );
+    }
+
+    #[test]
+    fn test_every_stage_changing_links_is_reported() {
+        let rules = vec![Replacement {
+            original: "example".to_string(),
+            replacement: "exanple".to_string(),
+            ..Default::default()
+        }];
+        let options = FormatOptions::default();
+        let stages = explain_format("https：／／ｅｘａｍｐｌｅ.com", &[], &[], &options).unwrap();
+        let changed = links_changed_by_stages(&stages);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].1, "https：／／ｅｘａｍｐｌｅ.com");
+        let stages = explain_format("https://example.com", &rules, &[], &options).unwrap();
+        let changed = links_changed_by_stages(&stages);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].1, "https://example.com");
+        assert_eq!(
+            changed[0].0,
+            stages
+                .iter()
+                .find(|stage| stage.rule.is_some())
+                .unwrap()
+                .label
+        );
+        let stages = explain_format("ＡＢＣ https://example.com", &[], &[], &options).unwrap();
+        assert!(links_changed_by_stages(&stages).is_empty());
+    }
+
+    #[test]
+    fn test_long_input_falls_back_to_changed_span() {
+        let padding = "a".repeat(MAX_DIFF_INPUT_CHARS);
+        let before = format!("{} https://example.com/ｘ", padding);
+        let after = format!("{} https://example.com/x", padding);
+        assert_eq!(
+            touched_links(&before, &after),
+            vec!["https://example.com/ｘ"]
+        );
+        let before = format!("https://example.com {}Ａ", padding);
+        let after = format!("https://example.com {}A", padding);
+        assert!(touched_links(&before, &after).is_empty());
+    }
+}
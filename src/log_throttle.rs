@@ -0,0 +1,93 @@
+use log::warn;
+
+// Collapses identical consecutive messages, reporting them again only after
+// 2, 4, 8, ... repeats so a persistent failure doesn't flood the log.
+#[derive(Debug)]
+pub struct LogThrottle {
+    last_message: Option<String>,
+    repeats: u64,
+    next_report: u64,
+}
+
+impl Default for LogThrottle {
+    fn default() -> Self {
+        LogThrottle::new()
+    }
+}
+
+impl LogThrottle {
+    pub fn new() -> Self {
+        LogThrottle {
+            last_message: None,
+            repeats: 0,
+            next_report: 2,
+        }
+    }
+
+    pub fn filter(&mut self, message: &str) -> Option<String> {
+        if self.last_message.as_deref() == Some(message) {
+            self.repeats += 1;
+            if self.repeats < self.next_report {
+                return None;
+            }
+            self.next_report *= 2;
+            return Some(format!("{} (repeated {}×)", message, self.repeats));
+        }
+        self.last_message = Some(message.to_string());
+        self.repeats = 1;
+        self.next_report = 2;
+        Some(message.to_string())
+    }
+
+    pub fn warn(&mut self, message: &str) {
+        if let Some(line) = self.filter(message) {
+            warn!("{}", line);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = LogThrottle::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle_collapses_repeats() {
+        let mut throttle = LogThrottle::new();
+        let emitted: Vec<String> = (0..20)
+            .filter_map(|_| throttle.filter("Clipboard unavailable"))
+            .collect();
+
+        assert_eq!(
+            emitted,
+            vec![
+                "Clipboard unavailable",
+                "Clipboard unavailable (repeated 2×)",
+                "Clipboard unavailable (repeated 4×)",
+                "Clipboard unavailable (repeated 8×)",
+                "Clipboard unavailable (repeated 16×)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_throttle_new_message_is_emitted() {
+        let mut throttle = LogThrottle::new();
+        assert!(throttle.filter("foo").is_some());
+        assert!(throttle.filter("foo").is_some());
+        assert!(throttle.filter("foo").is_none());
+        assert_eq!(throttle.filter("bar").as_deref(), Some("bar"));
+    }
+
+    #[test]
+    fn test_throttle_reset() {
+        let mut throttle = LogThrottle::new();
+        throttle.filter("foo");
+        throttle.filter("foo");
+        throttle.reset();
+        assert_eq!(throttle.filter("foo").as_deref(), Some("foo"));
+    }
+}
@@ -1,35 +1,337 @@
 use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
 use clipboard::{ClipboardContext, ClipboardProvider};
-use env_logger::Builder as EnvLoggerBuilder;
+use log::debug;
 use log::info;
 use log::warn;
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
-use regex::Regex;
-use std::char;
-use std::collections::hash_map::DefaultHasher;
-use std::env;
-use std::fs;
-use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
-use std::sync::mpsc::channel;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::string::FromUtf8Error;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
-const DEFAULT_REPLACEMENTS: &str = include_str!("default_replacements.json");
-const DEFAULT_EXCLUSIONS: &str = include_str!("default_exclusions.json");
-const REPLACEMENTS_FILE_NAME: &str = "replacements.json";
-const EXCLUSIONS_FILE_NAME: &str = "exclusions.json";
+use kill_zen_all::audit::AuditLog;
+use kill_zen_all::cli_clipboard::{detect_cli_tool, CliTool};
+use kill_zen_all::config::{EXCLUSIONS_FILE_STEM, REPLACEMENTS_ENV_VAR, REPLACEMENTS_FILE_STEM};
+use kill_zen_all::diff::{
+    cap_line_length, highlight_causes, CAUSE_LEGEND, MAX_DIFF_INPUT_CHARS, MAX_LOG_LINE_CHARS,
+};
+use kill_zen_all::feedback::{feedback_action, give_feedback};
+use kill_zen_all::focus::{query_focused_app, query_focused_title, FocusMonitor, TitleFilter};
+use kill_zen_all::history::{read_reload_history, ReloadHistory, RELOAD_HISTORY_FILE_NAME};
+use kill_zen_all::idle::{query_idle_time, IdleMonitor};
+use kill_zen_all::ipc::{self, IpcCommand, IpcRequest, IPC_TOKEN_FILE_NAME};
+#[cfg(feature = "type-paste")]
+use kill_zen_all::keystrokes::{convert_to_keystrokes, Keystroke};
+use kill_zen_all::learn::{append_suggestion, SUGGESTIONS_FILE_NAME};
+use kill_zen_all::links::{links_changed_by_stages, touched_links};
+use kill_zen_all::merge::merge_files;
+use kill_zen_all::migrate::{
+    migrate_defaults, read_defaults_version, remove_disabled_defaults, DEFAULTS_VERSION,
+};
+use kill_zen_all::mojibake::looks_like_mojibake;
+use kill_zen_all::observe::Observer;
+use kill_zen_all::osc52::Osc52;
+use kill_zen_all::pipe;
+#[cfg(feature = "plugins")]
+use kill_zen_all::plugin::{run_plugins, Plugin};
+use kill_zen_all::poll::{PollOverride, DEFAULT_OVERRIDE_DURATION};
+use kill_zen_all::post::PostCommand;
+use kill_zen_all::quiet::{is_quiet, local_minute_of_day, QuietWindow};
+use kill_zen_all::remote_rules::RemoteUpdates;
+#[cfg(feature = "rules-url")]
+use kill_zen_all::remote_rules::{
+    HttpSource, RemoteRules, MIN_RULES_URL_INTERVAL_SECS, RULES_URL_CACHE_FILE_NAME,
+    RULES_URL_TIMEOUT,
+};
+use kill_zen_all::rename::{rename_to_halfwidth, RenameOutcome};
+use kill_zen_all::rule_cache::{default_cache_dir, load_config_cached};
+use kill_zen_all::rules::{check_idempotent, idempotency_samples, CompiledRules, SharedRules};
+#[cfg(all(target_os = "linux", feature = "primary-selection"))]
+use kill_zen_all::selection::should_convert_primary;
+use kill_zen_all::selection::{query_modifier_mask, should_skip_copy, Modifier};
+use kill_zen_all::setup::{ask, run_setup};
+use kill_zen_all::snapshots::{Snapshots, SNAPSHOTS_FILE_NAME};
+use kill_zen_all::tee::{Tee, TeeDelimiter};
+use kill_zen_all::throughput::{
+    check_min_throughput, measure_throughput, synthetic_input, GuardOutcome, GUARD_DURATION,
+    PROFILE_DURATION, PROFILE_INPUT_BYTES,
+};
+use kill_zen_all::usage::{record_conversions, USAGE_FILE_NAME};
+use kill_zen_all::verbosity::{init_logger, VerbosityWindow};
+use kill_zen_all::{
+    calculate_hash, changed_chars, create_default_config_in, explain_format, explain_isolated,
+    find_combined_config, find_config_file, find_project_config_dir, find_rules,
+    format_text_with_options, fullwidth_latin_exclusions, get_config_dir, highlight_diff,
+    load_exclusion_reasons, load_layered_exclusions, load_replacement_files, load_replacements,
+    load_settings, load_stage_exclusions, project_exclusions_file, replace_strategy, report_format,
+    resolve_config_file, resolve_replacement_files, resolve_settings_file, unified_diff,
+    BlockTally, ConfigFormat, ConversionCache, ConvertFeedback, EffectiveConfig, FileFingerprint,
+    FormatOptions, LargeInputPolicy, Learner, LogThrottle, OutputMode, ReloadHashStrategy,
+    ReplaceStrategy, Replacement, SensitiveFilter, Settings, Stage, StageExclusions, TextFlavor,
+    TriggerMode,
+};
 
-#[derive(Debug, serde::Deserialize, Hash)]
-struct Replacement {
-    original: String,
-    replacement: String,
+type WatchEvents = Receiver<notify::Result<notify::Event>>;
+// Reads the held modifiers as an X11 mask
+type ModifierQuery = fn() -> Option<u16>;
+type TitleQuery = fn() -> Option<String>;
+type Clock = fn() -> Instant;
+type Osc52Sink = Box<dyn FnMut(&str) -> Result<()>>;
+
+const TRANSIENT_RETRY_LIMIT: usize = 3;
+const TRANSIENT_RETRY_DELAY: Duration = Duration::from_millis(100);
+// Consecutive failed writes tolerated before the clipboard context is recreated
+const SET_FAILURE_ESCALATION: usize = 3;
+// Consecutive failures of one component, recreations included, before everything is started over
+const WATCHDOG_REINIT_THRESHOLD: usize = 10;
+// Conversions applied to reach the canonical form before giving up on a fixed point
+const MAX_CANONICAL_PASSES: usize = 4;
+// Backends report contention (e.g. another app holding the clipboard) only through the message
+const TRANSIENT_ERROR_PATTERNS: &[&str] = &[
+    "access is denied",
+    "busy",
+    "locked",
+    "temporarily unavailable",
+    "timed out",
+    "timeout",
+];
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Do not watch the config files for changes
+    #[arg(long)]
+    no_watch: bool,
+    /// Use `.kill-zen-all/` from the current directory or its parents, falling back to the global config
+    #[arg(long)]
+    project: bool,
+    /// Format used when creating the default replacements/exclusions files
+    #[arg(long, value_enum, default_value_t = ConfigFormat::Json)]
+    config_format: ConfigFormat,
+    /// Log the conversions that would happen without touching the clipboard
+    #[arg(long)]
+    dry_run: bool,
+    /// Color each change in the log by its cause (width conversion, replacement, whitespace)
+    #[arg(long)]
+    annotate_causes: bool,
+    /// With --dry-run, append each would-be conversion to this file as JSON lines
+    #[arg(long, value_name = "PATH", requires = "dry_run")]
+    diff_out: Option<PathBuf>,
+    /// Never write the clipboard; keep a report of would-be conversions (counts by rule and character) in this JSON file
+    #[arg(long, value_name = "PATH")]
+    observe: Option<PathBuf>,
+    /// Also write each converted result to stdout, for piping into another program
+    #[arg(long)]
+    tee: bool,
+    /// How --tee separates results: escaped lines, or raw text ending in a NUL byte
+    #[arg(long, value_enum, default_value_t = TeeDelimiter::Line, requires = "tee")]
+    tee_delimiter: TeeDelimiter,
+    /// Append each conversion to this file as a unified diff ("-" for stderr)
+    #[arg(long, value_name = "PATH")]
+    unified_diff: Option<PathBuf>,
+    /// Exit with an error when a config file fails to load, including on reload
+    #[arg(long)]
+    strict: bool,
+    /// Print the effective replacement and exclusion rules as JSON and exit
+    #[arg(long)]
+    dump_config: bool,
+    /// Measure how fast the loaded rules convert a synthetic input and exit
+    #[arg(long)]
+    profile_benchmark: bool,
+    /// Refuse to start when the loaded rules convert slower than this many MB/s
+    #[arg(long, value_name = "MB_PER_SEC")]
+    benchmark_config: Option<f64>,
+    /// With --benchmark-config, only warn instead of refusing to start
+    #[arg(long, requires = "benchmark_config")]
+    benchmark_warn_only: bool,
+    /// Stop after this many loop iterations (for tests and smoke checks)
+    #[arg(long, hide = true)]
+    max_iterations: Option<u64>,
+    /// Add default rules from newer releases to the replacements file and exit
+    // Hidden while DEFAULT_RULE_HISTORY holds only version 1: there is nothing
+    // to add yet
+    #[arg(long, hide = true)]
+    migrate: bool,
+    /// Print the intermediate result of each conversion stage for TEXT and exit
+    #[arg(long, value_name = "TEXT")]
+    explain: Option<String>,
+    /// With --explain or --stdin, apply only the rule with this number (as --explain shows it) or `original`
+    #[arg(long, value_name = "LABEL")]
+    isolate_rule: Option<String>,
+    /// With --isolate-rule, also run the width conversion and the other built-in stages
+    #[arg(long, requires = "isolate_rule")]
+    with_stages: bool,
+    /// Convert newline-delimited text from stdin to stdout instead of watching the clipboard
+    #[arg(long)]
+    pipe_server: bool,
+    /// Convert newline-delimited text written to the FIFO IN and write the results to the FIFO OUT (Unix only)
+    #[arg(long, num_args = 2, value_names = ["IN", "OUT"])]
+    fifo: Option<Vec<PathBuf>>,
+    /// Convert all of stdin, print the result to stdout and exit
+    #[arg(long)]
+    stdin: bool,
+    /// With --stdin, print the input, output and the rules that fired as JSON
+    #[arg(long, requires = "stdin")]
+    json: bool,
+    /// Convert the clipboard once with the replacements in this file instead of the installed ones, and exit
+    #[arg(long, value_name = "PATH")]
+    try_rules: Option<PathBuf>,
+    /// Convert the clipboard once, show the change and write it back only after answering y in the terminal
+    #[arg(long, conflicts_with_all = ["stdin", "try_rules"])]
+    interactive: bool,
+    /// Never write the clipboard during these daily windows, e.g. 22:00-07:00,12:00-13:00 (overrides quiet_hours)
+    #[arg(long, value_name = "HH:MM-HH:MM", value_delimiter = ',')]
+    quiet_hours: Vec<QuietWindow>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Ask the running daemon to convert the current clipboard once
+    Convert,
+    /// Print the running daemon's conversion count and config reload history as JSON
+    Stats,
+    /// Print the daemon's recent conversions as JSON, newest first at index 0
+    History {
+        /// Write the original text of the conversion at this index back to the clipboard
+        #[arg(long, value_name = "INDEX")]
+        restore: Option<usize>,
+    },
+    /// Make the running daemon poll the clipboard every MS milliseconds for a while
+    SetInterval {
+        ms: u64,
+        /// Go back to the usual interval after this many seconds (default 300)
+        #[arg(long = "for", value_name = "SECONDS")]
+        for_secs: Option<u64>,
+    },
+    /// Type the converted clipboard text into the focused window, leaving the clipboard as is
+    #[cfg(feature = "type-paste")]
+    Type,
+    /// Interactively choose what to convert and write a matching config
+    Setup,
+    /// Rename files to their halfwidth-converted names (only prints the plan without --apply)
+    RenameFiles {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Actually rename; an existing file is never overwritten
+        #[arg(long)]
+        apply: bool,
+    },
+    /// Merge replacement files, dropping duplicate rules, and print the result to stdout
+    Merge {
+        #[arg(required = true, num_args = 2..)]
+        files: Vec<PathBuf>,
+    },
+    /// Print a shell completion script to stdout
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+// Debug-quoted, so whitespace and control characters are visible
+fn write_explanation(stages: &[Stage], out: &mut dyn std::io::Write) -> std::io::Result<()> {
+    let width = stages
+        .iter()
+        .map(|stage| stage.label.chars().count())
+        .max()
+        .unwrap_or_default();
+    for stage in stages {
+        writeln!(
+            out,
+            "{:<width$}  {:?}",
+            stage.label,
+            stage.text,
+            width = width
+        )?;
+    }
+    Ok(())
+}
+
+// --isolate-rule: the stages of converting `text` with only the rules `label` picks
+fn isolate_rule(
+    text: &str,
+    label: &str,
+    replacements: &[Replacement],
+    exclusion_list: &[char],
+    options: &FormatOptions,
+    with_stages: bool,
+) -> Result<Vec<Stage>> {
+    let indices = find_rules(replacements, label);
+    if indices.is_empty() {
+        anyhow::bail!("No rule is numbered '{}' or has it as its original", label);
+    }
+    Ok(explain_isolated(
+        text,
+        replacements,
+        &indices,
+        exclusion_list,
+        options,
+        with_stages,
+    )?)
+}
+
+// "Ａ, Ｂ (reason)": each character with the reason given for excluding it, if any
+fn describe_exclusions(chars: &[char], reasons: &BTreeMap<char, String>) -> String {
+    chars
+        .iter()
+        .map(|c| match reasons.get(c) {
+            Some(reason) => format!("{} ({})", c, reason),
+            None => c.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(feature = "type-paste")]
+fn type_keystrokes(keystrokes: &[Keystroke]) -> Result<()> {
+    use enigo::{Direction, Enigo, Key, Keyboard, Settings as EnigoSettings};
+
+    let mut enigo =
+        Enigo::new(&EnigoSettings::default()).context("Failed to start input simulation")?;
+    for keystroke in keystrokes {
+        match keystroke {
+            Keystroke::Text(text) => enigo.text(text),
+            Keystroke::Enter => enigo.key(Key::Return, Direction::Click),
+            Keystroke::Tab => enigo.key(Key::Tab, Direction::Click),
+        }
+        .context("Failed to type the converted text")?;
+    }
+    Ok(())
+}
+
+fn convert_stdin(
+    input: &str,
+    config: &LoadedConfig,
+    options: &FormatOptions,
+    json: bool,
+    out: &mut dyn std::io::Write,
+) -> Result<()> {
+    if json {
+        let report = report_format(input, &config.replacements, &config.exclusion_list, options)?;
+        writeln!(out, "{}", serde_json::to_string(&report)?)?;
+    } else {
+        let output =
+            format_text_with_options(input, &config.replacements, &config.exclusion_list, options)?;
+        write!(out, "{}", output)?;
+    }
+    Ok(())
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct Exclusions {
-    exclude: Vec<char>,
+fn write_completions(shell: Shell, out: &mut dyn std::io::Write) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, out);
 }
 
 #[derive(Debug, Error)]
@@ -40,95 +342,140 @@ enum ClipboardError {
     SetContents(String),
     #[error("Failed to get clipboard contents: {0}")]
     GetContents(String),
+    #[error("Clipboard contents are not valid UTF-8: {0}")]
+    InvalidUtf8(#[from] FromUtf8Error),
+    #[error("Clipboard did not respond within {0:?}; it may be held by another process")]
+    Timeout(Duration),
 }
 
-fn calculate_hash<T: Hash>(t: &T) -> u64 {
-    let mut s = DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
+impl ClipboardError {
+    fn is_transient(&self) -> bool {
+        match self {
+            ClipboardError::GetContents(message) | ClipboardError::SetContents(message) => {
+                let message = message.to_lowercase();
+                TRANSIENT_ERROR_PATTERNS
+                    .iter()
+                    .any(|pattern| message.contains(pattern))
+            }
+            // Retrying would only wait on the same stuck clipboard again
+            ClipboardError::CreateContext(_)
+            | ClipboardError::InvalidUtf8(_)
+            | ClipboardError::Timeout(_) => false,
+        }
+    }
 }
 
-fn get_config_dir() -> Result<PathBuf> {
-    let config_dir = if let Some(config_dir) = env::var_os("XDG_CONFIG_HOME") {
-        PathBuf::from(config_dir)
-    } else {
-        dirs::config_dir().context("Failed to get config directory")?
-    };
-    Ok(config_dir.join("kill-zen-all"))
+trait ClipboardBackend {
+    // Raw bytes so that invalid UTF-8 is rejected here rather than lossily decoded
+    fn get_bytes(&mut self) -> Result<Vec<u8>, ClipboardError>;
+    fn set_text(&mut self, content: String) -> Result<(), ClipboardError>;
+    // Whether the current entry carries a password manager's "do not record"
+    // marker (x-kde-passwordManagerHint). Backends that cannot see the
+    // entry's targets report false.
+    fn is_sensitive(&mut self) -> bool {
+        false
+    }
+    // Writes as a specific target. The `clipboard` crate always offers its
+    // own set of targets, so by default the flavor is not honoured.
+    fn set_text_as(&mut self, content: String, flavor: TextFlavor) -> Result<(), ClipboardError> {
+        if let Some(target) = flavor.target() {
+            debug!(
+                "This clipboard backend cannot choose the target; not writing as {}.",
+                target
+            );
+        }
+        self.set_text(content)
+    }
 }
 
-fn create_default_config() -> Result<()> {
-    let config_dir = get_config_dir()?;
-    if !config_dir.exists() {
-        fs::create_dir_all(&config_dir).context("Failed to create config directory")?;
+// The X11 backend decodes the bytes itself, but its error for invalid UTF-8
+// still holds them, so they reach the same check as the other backends'
+fn contents_to_bytes(
+    contents: Result<String, Box<dyn std::error::Error>>,
+) -> Result<Vec<u8>, ClipboardError> {
+    match contents {
+        Ok(text) => Ok(text.into_bytes()),
+        Err(e) => match e.downcast::<FromUtf8Error>() {
+            Ok(invalid) => Ok(invalid.into_bytes()),
+            Err(e) => Err(ClipboardError::GetContents(e.to_string())),
+        },
     }
-    let replacement_path = config_dir.join(REPLACEMENTS_FILE_NAME);
-    if !replacement_path.exists() {
-        let default_replacements = DEFAULT_REPLACEMENTS;
-        fs::write(&replacement_path, default_replacements)
-            .context("Failed to create default replacements file")?;
-        info!(
-            "Created default replacements file: {}",
-            replacement_path
-                .to_str()
-                .context("Failed to convert path to string")?
-        );
+}
+
+impl ClipboardBackend for ClipboardContext {
+    fn get_bytes(&mut self) -> Result<Vec<u8>, ClipboardError> {
+        contents_to_bytes(self.get_contents())
     }
-    let exclusion_path = config_dir.join(EXCLUSIONS_FILE_NAME);
-    if !exclusion_path.exists() {
-        let default_exclusions = DEFAULT_EXCLUSIONS;
-        fs::write(&exclusion_path, default_exclusions)
-            .context("Failed to create default exclusions file")?;
-        info!(
-            "Created default exclusions file: {}",
-            exclusion_path
-                .to_str()
-                .context("Failed to convert path to string")?
-        );
+
+    fn set_text(&mut self, content: String) -> Result<(), ClipboardError> {
+        self.set_contents(content)
+            .map_err(|e| ClipboardError::SetContents(e.to_string()))
     }
-    Ok(())
 }
 
-fn load_json<T>(file_path: &str) -> Result<T>
-where
-    T: serde::de::DeserializeOwned,
-{
-    let data = fs::read_to_string(file_path)?;
-    serde_json::from_str(&data).context("Failed to parse JSON")
-}
+#[cfg(all(target_os = "linux", feature = "primary-selection"))]
+type PrimaryContext =
+    clipboard::x11_clipboard::X11ClipboardContext<clipboard::x11_clipboard::Primary>;
+
+#[cfg(all(target_os = "linux", feature = "primary-selection"))]
+impl ClipboardBackend for PrimaryContext {
+    fn get_bytes(&mut self) -> Result<Vec<u8>, ClipboardError> {
+        contents_to_bytes(self.get_contents())
+    }
 
-fn load_replacements(file_path: &str) -> Result<Vec<Replacement>> {
-    load_json::<Vec<Replacement>>(file_path).context("Failed to load replacements")
+    fn set_text(&mut self, content: String) -> Result<(), ClipboardError> {
+        self.set_contents(content)
+            .map_err(|e| ClipboardError::SetContents(e.to_string()))
+    }
 }
 
-fn load_exclusion_list(file_path: &str) -> Result<Vec<char>> {
-    let exclusions: Exclusions = load_json(file_path)?;
-    Ok(exclusions.exclude)
+// The PRIMARY selection gets its own converter so its self-write tracking does
+// not interfere with the clipboard's
+#[cfg(all(target_os = "linux", feature = "primary-selection"))]
+struct PrimarySelection {
+    ctx: TimedClipboard,
+    converter: Converter,
+    modifier: Modifier,
 }
 
-fn format_text(
-    text: &str,
-    replacements: &[Replacement],
-    exclusion_list: &[char],
-) -> Result<String> {
-    let mut formatted_content = text.to_string();
-    for replacement in replacements {
-        formatted_content =
-            formatted_content.replace(&replacement.original, &replacement.replacement);
-    }
-    let re = Regex::new(r"[！-～]").context("Failed to create regex pattern")?;
-    formatted_content = re
-        .replace_all(&formatted_content, |caps: &regex::Captures| {
-            let c = caps[0].chars().next().unwrap_or_default();
-            if exclusion_list.contains(&c) {
-                c.to_string()
-            } else {
-                let half_width_char = (c as u32 - 0xfee0) as u8 as char;
-                half_width_char.to_string()
-            }
-        })
-        .to_string();
-    Ok(formatted_content)
+#[cfg(all(target_os = "linux", feature = "primary-selection"))]
+impl PrimarySelection {
+    fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        if !settings.primary_selection {
+            return Ok(None);
+        }
+        // Behind the same worker as the clipboard, so a stuck X server cannot
+        // hang the poll loop
+        let ctx = TimedClipboard::spawn(
+            || PrimaryContext::new().map_err(|e| ClipboardError::CreateContext(e.to_string())),
+            settings.clipboard_timeout(),
+        )?;
+        info!(
+            "Converting the PRIMARY selection while {:?} is held.",
+            settings.primary_modifier
+        );
+        let mut converter = Converter::from_settings(settings)?;
+        // Here a held modifier is what asks for conversion
+        converter.skip_modifier = None;
+        Ok(Some(PrimarySelection {
+            ctx,
+            converter,
+            modifier: settings.primary_modifier,
+        }))
+    }
+
+    fn poll(&mut self, config: &LoadedConfig) -> Result<()> {
+        if should_convert_primary(true, self.modifier, query_modifier_mask()) {
+            let rules = config.rules.snapshot();
+            self.converter.use_stage_exclusions(&rules);
+            self.converter.convert_clipboard(
+                &mut self.ctx,
+                &rules.replacements,
+                &rules.exclusion_list,
+            )?;
+        }
+        Ok(())
+    }
 }
 
 fn create_clipboard_context() -> Result<ClipboardContext, ClipboardError> {
@@ -142,393 +489,4680 @@ fn create_clipboard_context() -> Result<ClipboardContext, ClipboardError> {
     Ok(ctx)
 }
 
-fn set_clipboard_contents(
-    ctx: &mut ClipboardContext,
-    content: String,
-) -> Result<(), ClipboardError> {
-    ctx.set_contents(content)
-        .map_err(|e| ClipboardError::SetContents(e.to_string()))?;
-    Ok(())
+enum ClipboardRequest {
+    GetBytes,
+    SetText(String),
+    SetTextAs(String, TextFlavor),
+    IsSensitive,
 }
 
-fn get_clipboard_contents(ctx: &mut ClipboardContext) -> Result<String, ClipboardError> {
-    ctx.get_contents()
-        .map_err(|e| ClipboardError::GetContents(e.to_string()))
+enum ClipboardResponse {
+    Bytes(Result<Vec<u8>, ClipboardError>),
+    Set(Result<(), ClipboardError>),
+    Sensitive(bool),
 }
 
-fn main() -> Result<()> {
-    EnvLoggerBuilder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    create_default_config()?;
-
-    let replacement_path = get_config_dir()?.join(REPLACEMENTS_FILE_NAME);
-    let exclusion_path = get_config_dir()?.join(EXCLUSIONS_FILE_NAME);
-
-    let mut replacements = load_replacements(
-        replacement_path
-            .to_str()
-            .context("Replacement path contains invalid UTF-8 characters")?,
-    )?;
-    let mut exclusion_list = load_exclusion_list(
-        exclusion_path
-            .to_str()
-            .context("Exclusion path contains invalid UTF-8 characters")?,
-    )?;
-    let (tx, rx) = channel();
-    let config = Config::default().with_poll_interval(Duration::from_secs(2));
-    let mut watcher: RecommendedWatcher =
-        Watcher::new(tx, config).context("Failed to initialize file watcher")?;
-    watcher
-        .watch(&replacement_path, RecursiveMode::NonRecursive)
-        .context("Failed to watch replacements file")?;
-    watcher
-        .watch(&exclusion_path, RecursiveMode::NonRecursive)
-        .context("Failed to watch exclusions file")?;
-    let mut ctx: ClipboardContext =
-        create_clipboard_context().context("Failed to create context")?;
-
-    let mut previous_replacement_hash = calculate_hash(&replacements);
-    let mut previous_exclusion_hash = calculate_hash(&exclusion_list);
-
-    let mut replacement_failed = false;
-    let mut exclusion_failed = false;
+// Runs clipboard calls on a worker thread that owns the backend, so a call
+// blocked by another process holding the clipboard times out instead of
+// freezing the loop. The backend is created on the worker too, since
+// clipboard contexts are not necessarily Send.
+struct TimedClipboard {
+    requests: Sender<(u64, ClipboardRequest)>,
+    responses: Receiver<(u64, ClipboardResponse)>,
+    // Requests up to this id were given up on; the worker skips them if they
+    // are still queued, so a stale set never lands late
+    abandoned: Arc<AtomicU64>,
+    next_id: u64,
+    timeout: Option<Duration>,
+}
 
-    loop {
-        let clipboard_content =
-            get_clipboard_contents(&mut ctx).context("Failed to get contents")?;
-        let formatted_content = format_text(&clipboard_content, &replacements, &exclusion_list)?;
-        if clipboard_content != formatted_content {
-            info!(
-                "Replace '{}' to '{}'.",
-                clipboard_content, formatted_content
-            );
-            set_clipboard_contents(&mut ctx, formatted_content)?;
-        }
-
-        if let Ok(events) = rx.try_recv() {
-            for event in events.iter() {
-                if event.paths.contains(&replacement_path) {
-                    let Ok(new_replacements) = load_replacements(
-                        replacement_path
-                            .to_str()
-                            .context("Failed to convert path to string")?,
-                    ) else {
-                        if !replacement_failed {
-                            warn!("Failed to load replacements.")
-                        };
-                        replacement_failed = true;
+impl TimedClipboard {
+    fn spawn<C, F>(create: F, timeout: Option<Duration>) -> Result<Self, ClipboardError>
+    where
+        C: ClipboardBackend,
+        F: FnOnce() -> Result<C, ClipboardError> + Send + 'static,
+    {
+        let (request_tx, request_rx) = channel::<(u64, ClipboardRequest)>();
+        let (response_tx, response_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let abandoned = Arc::new(AtomicU64::new(0));
+        let worker_abandoned = Arc::clone(&abandoned);
+        thread::Builder::new()
+            .name("clipboard".to_string())
+            .spawn(move || {
+                let mut backend = match create() {
+                    Ok(backend) => backend,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = ready_tx.send(Ok(()));
+                for (id, request) in request_rx {
+                    if id <= worker_abandoned.load(Ordering::SeqCst) {
                         continue;
-                    };
-                    let new_replacement_hash = calculate_hash(&new_replacements);
-                    if previous_replacement_hash != new_replacement_hash {
-                        info!("{} has been modified.", REPLACEMENTS_FILE_NAME);
-                        info!("Reloading replacements...");
-                        replacements = new_replacements;
-                        previous_replacement_hash = new_replacement_hash;
-                        replacement_failed = false;
                     }
-                }
-                if event.paths.contains(&exclusion_path) {
-                    let Ok(new_exclusion_list) = load_exclusion_list(
-                        exclusion_path
-                            .to_str()
-                            .context("Failed to convert path to string")?,
-                    ) else {
-                        if !exclusion_failed {
-                            warn!("Failed to load exclusions.");
+                    let response = match request {
+                        ClipboardRequest::GetBytes => ClipboardResponse::Bytes(backend.get_bytes()),
+                        ClipboardRequest::SetText(content) => {
+                            ClipboardResponse::Set(backend.set_text(content))
+                        }
+                        ClipboardRequest::SetTextAs(content, flavor) => {
+                            ClipboardResponse::Set(backend.set_text_as(content, flavor))
+                        }
+                        ClipboardRequest::IsSensitive => {
+                            ClipboardResponse::Sensitive(backend.is_sensitive())
                         }
-                        exclusion_failed = true;
-                        continue;
                     };
-                    let new_exclusion_hash = calculate_hash(&new_exclusion_list);
-                    if previous_exclusion_hash != new_exclusion_hash {
-                        info!("{} has been modified.", EXCLUSIONS_FILE_NAME);
-                        info!("Reloading exclusions...");
-                        exclusion_list = new_exclusion_list;
-                        previous_exclusion_hash = new_exclusion_hash;
-                        exclusion_failed = false;
+                    if response_tx.send((id, response)).is_err() {
+                        break;
                     }
                 }
+            })
+            .map_err(|e| ClipboardError::CreateContext(e.to_string()))?;
+        let ready = match timeout {
+            Some(timeout) => ready_rx
+                .recv_timeout(timeout)
+                .map_err(|_| ClipboardError::Timeout(timeout))?,
+            None => ready_rx
+                .recv()
+                .map_err(|e| ClipboardError::CreateContext(e.to_string()))?,
+        };
+        ready?;
+        Ok(TimedClipboard {
+            requests: request_tx,
+            responses: response_rx,
+            abandoned,
+            next_id: 1,
+            timeout,
+        })
+    }
+
+    fn call(&mut self, request: ClipboardRequest) -> Result<ClipboardResponse, ClipboardError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let lost = || ClipboardError::GetContents("clipboard worker has stopped".to_string());
+        self.requests.send((id, request)).map_err(|_| lost())?;
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let received = match deadline {
+                Some(deadline) => self
+                    .responses
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => self.responses.recv().map_err(Into::into),
+            };
+            match received {
+                Ok((response_id, response)) if response_id == id => return Ok(response),
+                // A late answer to a call that already timed out
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.abandoned.store(id, Ordering::SeqCst);
+                    return Err(ClipboardError::Timeout(self.timeout.unwrap_or_default()));
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(lost()),
             }
         }
-        thread::sleep(Duration::from_secs(1));
     }
 }
 
-// Test code
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    // Test for get_config_dir
-    use std::env;
-    use std::fs;
-    use tempfile::tempdir;
-
-    #[test]
-    fn test_create_default_config() {
-        // 一時ディレクトリを作成
-        let temp_dir = tempdir().unwrap();
-        let temp_path = temp_dir.path().to_path_buf();
-
-        // 一時ディレクトリをXDG_CONFIG_HOMEに設定
-        env::set_var("XDG_CONFIG_HOME", &temp_path);
-
-        // create_default_config()を呼び出す
-        create_default_config().unwrap();
+impl ClipboardBackend for TimedClipboard {
+    fn get_bytes(&mut self) -> Result<Vec<u8>, ClipboardError> {
+        match self.call(ClipboardRequest::GetBytes)? {
+            ClipboardResponse::Bytes(result) => result,
+            _ => unreachable!("response does not match the request"),
+        }
+    }
 
-        // 設定ファイルが正しい場所に作成されたかを確認
-        let replacements_path = temp_path.join("kill-zen-all").join("replacements.json");
-        let exclusions_path = temp_path.join("kill-zen-all").join("exclusions.json");
+    fn set_text(&mut self, content: String) -> Result<(), ClipboardError> {
+        match self.call(ClipboardRequest::SetText(content))? {
+            ClipboardResponse::Set(result) => result,
+            _ => unreachable!("response does not match the request"),
+        }
+    }
 
-        assert!(
-            replacements_path.exists(),
-            "replacements.json が存在しません"
-        );
-        assert!(exclusions_path.exists(), "exclusions.json が存在しません");
+    // A probe that fails or times out counts as a marker: leaving one copy
+    // alone is better than converting and logging a password
+    fn is_sensitive(&mut self) -> bool {
+        match self.call(ClipboardRequest::IsSensitive) {
+            Ok(ClipboardResponse::Sensitive(sensitive)) => sensitive,
+            Ok(_) => unreachable!("response does not match the request"),
+            Err(e) => {
+                debug!(
+                    "Could not check the clipboard for a password manager marker: {}",
+                    e
+                );
+                true
+            }
+        }
+    }
 
-        // replacements.json の内容を検証
-        let replacements_content =
-            fs::read_to_string(&replacements_path).expect("Failed to read replacements.json");
-        let expected_replacements_content = r#"[
-  { "original": "，", "replacement": ", " },
-  { "original": "．", "replacement": ". " },
-  { "original": "CRLF", "replacement": "。" },
-  { "original": "頚", "replacement": "頸" }
-]"#
-        .trim(); // テスト用に改行とインデントを除去
+    fn set_text_as(&mut self, content: String, flavor: TextFlavor) -> Result<(), ClipboardError> {
+        match self.call(ClipboardRequest::SetTextAs(content, flavor))? {
+            ClipboardResponse::Set(result) => result,
+            _ => unreachable!("response does not match the request"),
+        }
+    }
+}
 
-        assert_eq!(replacements_content.trim(), expected_replacements_content);
+// The native clipboard, or a command-line tool standing in for it
+enum FallbackClipboard {
+    // The `clipboard` crate only reads text, so the tool, when installed,
+    // looks for markers the native clipboard cannot see
+    Native(ClipboardContext, Option<CliTool>),
+    Cli(CliTool),
+    Osc52(Box<Osc52Clipboard<FallbackClipboard>>),
+}
 
-        // exclusions.json の内容を検証
-        let exclusions_content =
-            fs::read_to_string(&exclusions_path).expect("Failed to read exclusions.json");
-        let expected_exclusions_content = r#"{
-  "exclude": ["　", "！", "？", "〜", "～"]
-}"#
-        .trim(); // テスト用に改行とインデントを除去
+// Reads from the clipboard beneath and writes through the terminal. The write
+// never reaches the clipboard beneath, which keeps holding the original, so
+// until something else is copied that original reads as what was written.
+struct Osc52Clipboard<R> {
+    reader: R,
+    // The terminal, or a recorder in tests
+    sink: Osc52Sink,
+    // Hash of the clipboard beneath as last read
+    last_read: Option<u64>,
+    // Hash of the input last written through OSC 52, and what was written for it
+    written: Option<(u64, String)>,
+}
 
-        assert_eq!(exclusions_content.trim(), expected_exclusions_content);
+impl<R: ClipboardBackend> Osc52Clipboard<R> {
+    fn new(reader: R, osc52: Osc52) -> Self {
+        Self::with_sink(reader, Box::new(move |text| osc52.write(text)))
+    }
 
-        // 環境変数のクリーンアップ
-        env::remove_var("XDG_CONFIG_HOME");
+    fn with_sink(reader: R, sink: Osc52Sink) -> Self {
+        Osc52Clipboard {
+            reader,
+            sink,
+            last_read: None,
+            written: None,
+        }
     }
-    // Test for load_replacements
-    use std::fs::File;
-    use std::io::Write;
+}
 
-    #[test]
-    fn test_load_replacements() {
-        let test_data = r#"
-        [
-            {"original": "foo", "replacement": "bar"},
-            {"original": "baz", "replacement": "qux"}
-        ]
-        "#;
+impl<R: ClipboardBackend> ClipboardBackend for Osc52Clipboard<R> {
+    fn get_bytes(&mut self) -> Result<Vec<u8>, ClipboardError> {
+        let bytes = self.reader.get_bytes()?;
+        let hash = calculate_hash(&bytes);
+        self.last_read = Some(hash);
+        match &self.written {
+            Some((input, written)) if *input == hash => Ok(written.clone().into_bytes()),
+            _ => Ok(bytes),
+        }
+    }
 
-        // Create a test file
-        let file_path = "test_replacements.json";
-        let mut file = File::create(file_path).unwrap();
-        file.write_all(test_data.as_bytes()).unwrap();
+    fn set_text(&mut self, content: String) -> Result<(), ClipboardError> {
+        (self.sink)(&content).map_err(|e| ClipboardError::SetContents(format!("{:#}", e)))?;
+        self.written = self.last_read.map(|input| (input, content));
+        Ok(())
+    }
 
-        let replacements = load_replacements(file_path).unwrap();
-        assert_eq!(replacements.len(), 2);
-        assert_eq!(replacements[0].original, "foo");
-        assert_eq!(replacements[0].replacement, "bar");
-        assert_eq!(replacements[1].original, "baz");
-        assert_eq!(replacements[1].replacement, "qux");
+    fn is_sensitive(&mut self) -> bool {
+        self.reader.is_sensitive()
+    }
+}
 
-        // Remove the test file
-        fs::remove_file(file_path).unwrap();
+impl ClipboardBackend for FallbackClipboard {
+    fn get_bytes(&mut self) -> Result<Vec<u8>, ClipboardError> {
+        match self {
+            FallbackClipboard::Native(ctx, _) => ctx.get_bytes(),
+            FallbackClipboard::Cli(tool) => tool
+                .read()
+                .map_err(|e| ClipboardError::GetContents(format!("{:#}", e))),
+            FallbackClipboard::Osc52(osc52) => osc52.get_bytes(),
+        }
     }
 
-    // Test for load_replacements with nonexistent file
-    #[test]
-    fn test_load_replacements_no_file() {
-        let file_path = "nonexistent.json";
-        let result = load_replacements(file_path);
-        assert!(result.is_err());
+    fn set_text(&mut self, content: String) -> Result<(), ClipboardError> {
+        match self {
+            FallbackClipboard::Native(ctx, _) => ctx.set_text(content),
+            FallbackClipboard::Cli(tool) => tool
+                .write(&content)
+                .map_err(|e| ClipboardError::SetContents(format!("{:#}", e))),
+            FallbackClipboard::Osc52(osc52) => osc52.set_text(content),
+        }
     }
 
-    // Test for load_replacements with invalid JSON
-    #[test]
-    fn test_load_replacements_invalid_json() {
-        let test_data = r#"
-        [
-            {"original": "foo", "replacement": "bar"},
-            {"original": "baz", "replacement": "qux"}
-        "#;
+    fn is_sensitive(&mut self) -> bool {
+        match self {
+            FallbackClipboard::Native(_, probe) => {
+                probe.is_some_and(|tool| tool.is_marked_sensitive())
+            }
+            FallbackClipboard::Cli(tool) => tool.is_marked_sensitive(),
+            FallbackClipboard::Osc52(osc52) => osc52.is_sensitive(),
+        }
+    }
 
-        // Create a test file
-        let file_path = "test_invalid_replacements.json";
-        let mut file = File::create(file_path).unwrap();
-        file.write_all(test_data.as_bytes()).unwrap();
+    fn set_text_as(&mut self, content: String, flavor: TextFlavor) -> Result<(), ClipboardError> {
+        match self {
+            FallbackClipboard::Cli(tool) if tool.can_choose_target() => tool
+                .write_as(&content, flavor.target())
+                .map_err(|e| ClipboardError::SetContents(format!("{:#}", e))),
+            _ => {
+                if let Some(target) = flavor.target() {
+                    debug!(
+                        "This clipboard backend cannot choose the target; not writing as {}.",
+                        target
+                    );
+                }
+                self.set_text(content)
+            }
+        }
+    }
+}
 
-        let result = load_replacements(file_path);
-        println!("{:?}", result);
-        assert!(result.is_err());
+// The command-line tool to switch to once the native clipboard keeps failing.
+// Clones share the switch, so the daemon can flip it for the context factory.
+#[derive(Debug, Clone, Default)]
+struct CliFallback {
+    tool: Option<CliTool>,
+    active: Arc<AtomicBool>,
+}
 
-        // Remove the test file
-        fs::remove_file(file_path).unwrap();
+impl CliFallback {
+    fn new(enabled: bool) -> Self {
+        CliFallback {
+            tool: if enabled { detect_cli_tool() } else { None },
+            active: Arc::new(AtomicBool::new(false)),
+        }
     }
 
-    // Test for load_exclusion_list
-    #[test]
-    fn test_load_exclusion_list() {
-        let test_data = r#"
-        {
-            "exclude": ["！", "？"]
+    // Only switches once; there is no way back to the native clipboard
+    fn activate(&self) {
+        if let Some(tool) = self.tool {
+            if !self.active.swap(true, Ordering::SeqCst) {
+                warn!(
+                    "The native clipboard keeps failing. Switching to {}.",
+                    tool.name()
+                );
+            }
         }
-        "#;
-
-        // Create a test file
-        let file_path = "test_exclusions.json";
-        let mut file = File::create(file_path).unwrap();
-        file.write_all(test_data.as_bytes()).unwrap();
-
-        let exclusions = load_exclusion_list(file_path).unwrap();
-        assert_eq!(exclusions.len(), 2);
-        assert_eq!(exclusions[0], '！');
-        assert_eq!(exclusions[1], '？');
+    }
 
-        // Remove the test file
-        fs::remove_file(file_path).unwrap();
+    // For text_flavor, which only the tools can honour: switches before the
+    // native clipboard is ever used
+    fn prefer_for_target(&self, target: &str) -> bool {
+        match self.tool.filter(|tool| tool.can_choose_target()) {
+            Some(tool) => {
+                self.active.store(true, Ordering::SeqCst);
+                info!(
+                    "text_flavor: writing through {} to offer the text as {}.",
+                    tool.name(),
+                    target
+                );
+                true
+            }
+            None => false,
+        }
     }
 
-    // Test for load_exclusion_list with nonexistent file
-    #[test]
-    fn test_load_exclusion_list_no_file() {
-        let file_path = "nonexistent.json";
-        let result = load_exclusion_list(file_path);
-        assert!(result.is_err());
+    fn active_tool(&self) -> Option<CliTool> {
+        self.tool.filter(|_| self.active.load(Ordering::SeqCst))
     }
+}
 
-    // Test for load_exclusion_list with invalid JSON
-    #[test]
-    fn test_load_exclusion_list_invalid_json() {
-        let test_data = r#"
-        {
-            "exclude": ["！", "？
+// A native clipboard that cannot even be created falls back right away
+fn create_clipboard_backend(fallback: &CliFallback) -> Result<FallbackClipboard, ClipboardError> {
+    if let Some(tool) = fallback.active_tool() {
+        return Ok(FallbackClipboard::Cli(tool));
+    }
+    match create_clipboard_context() {
+        Ok(ctx) => Ok(FallbackClipboard::Native(
+            ctx,
+            fallback.tool.or_else(detect_cli_tool),
+        )),
+        Err(e) if fallback.tool.is_some() => {
+            warn!("{}", e);
+            fallback.activate();
+            create_clipboard_backend(fallback)
         }
-        "#;
+        Err(e) => Err(e),
+    }
+}
 
-        // Create a test file
-        let file_path = "test_invalid_exclusions.json";
-        let mut file = File::create(file_path).unwrap();
-        file.write_all(test_data.as_bytes()).unwrap();
+// OSC 52 cannot read, so without a clipboard beneath there would be no input
+fn create_osc52_backend(
+    fallback: &CliFallback,
+    osc52: Osc52,
+) -> Result<FallbackClipboard, ClipboardError> {
+    let reader = create_clipboard_backend(fallback).map_err(|e| {
+        ClipboardError::CreateContext(format!("{}; OSC 52 needs a clipboard to read from", e))
+    })?;
+    Ok(FallbackClipboard::Osc52(Box::new(Osc52Clipboard::new(
+        reader, osc52,
+    ))))
+}
 
-        let result = load_exclusion_list(file_path);
-        assert!(result.is_err());
+fn create_timed_clipboard(
+    timeout: Option<Duration>,
+    fallback: &CliFallback,
+    osc52: Option<Osc52>,
+) -> Result<TimedClipboard, ClipboardError> {
+    let fallback = fallback.clone();
+    TimedClipboard::spawn(
+        move || match osc52 {
+            Some(osc52) => create_osc52_backend(&fallback, osc52),
+            None => create_clipboard_backend(&fallback),
+        },
+        timeout,
+    )
+}
 
-        // Remove the test file
-        fs::remove_file(file_path).unwrap();
+// Retries transient errors, doubling the delay after each attempt
+fn retry_transient<T>(
+    mut operation: impl FnMut() -> Result<T, ClipboardError>,
+) -> Result<T, ClipboardError> {
+    let mut attempts = 0;
+    let mut delay = TRANSIENT_RETRY_DELAY;
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) if e.is_transient() && attempts < TRANSIENT_RETRY_LIMIT => {
+                attempts += 1;
+                debug!(
+                    "Transient clipboard error: {}. Retrying ({}/{})...",
+                    e, attempts, TRANSIENT_RETRY_LIMIT
+                );
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            Err(e) => return Err(e),
+        }
     }
+}
 
-    // Test for format_text
-    #[test]
-    fn test_format_text_with_replacements_exclusions() {
-        // 置換リスト
-        let replacements = vec![
-            Replacement {
-                original: "foo".to_string(),
-                replacement: "bar".to_string(),
-            },
-            Replacement {
-                original: "baz".to_string(),
-                replacement: "qux".to_string(),
-            },
-        ];
+fn set_clipboard_contents<C: ClipboardBackend>(
+    ctx: &mut C,
+    content: String,
+    flavor: TextFlavor,
+) -> Result<(), ClipboardError> {
+    retry_transient(|| ctx.set_text_as(content.clone(), flavor))
+}
 
-        // 除外リスト
-        let exclusion_list = vec!['！', '？']; // 例: 全角の「！」「？」を除外
+fn get_clipboard_contents<C: ClipboardBackend>(ctx: &mut C) -> Result<String, ClipboardError> {
+    let bytes = retry_transient(|| ctx.get_bytes())?;
+    Ok(String::from_utf8(bytes)?)
+}
 
-        // テストケース
-        let input = "foo baz １２３４！？";
-        let expected = "bar qux 1234！？"; // ！？は除外されるので変換されない
-        let formatted = format_text(input, &replacements, &exclusion_list).unwrap();
+// A failing post_command must not block conversion, so its error only costs the filtering
+fn post_process(post_command: &mut PostCommand, converted: String) -> String {
+    match post_command.run(&converted, Instant::now()) {
+        None => converted,
+        Some(Ok(filtered)) => filtered,
+        Some(Err(e)) => {
+            warn!("post_command failed: {:#}. Using the unfiltered result.", e);
+            converted
+        }
+    }
+}
 
-        assert_eq!(formatted, expected);
+// Converts until the text stops changing, or `passes` times at most
+fn converge<E>(
+    text: &str,
+    passes: usize,
+    mut convert: impl FnMut(&str) -> Result<String, E>,
+) -> Result<String, E> {
+    let mut current = convert(text)?;
+    for _ in 1..passes {
+        let next = convert(&current)?;
+        if next == current {
+            break;
+        }
+        current = next;
     }
+    Ok(current)
+}
 
-    #[test]
-    fn test_format_text_with_replacements_without_exclusions() {
-        // 置換リスト
-        let replacements = vec![
-            Replacement {
-                original: "foo".to_string(),
-                replacement: "bar".to_string(),
-            },
-            Replacement {
-                original: "baz".to_string(),
-                replacement: "qux".to_string(),
-            },
-        ];
+fn should_convert(trigger_mode: TriggerMode, triggered: bool) -> bool {
+    match trigger_mode {
+        TriggerMode::Continuous => true,
+        TriggerMode::Manual => triggered,
+    }
+}
 
-        // 除外リストなし
-        let exclusion_list = vec![];
+// Scheduled re-conversion; an already normalized clipboard simply comes back Unchanged
+fn interval_due(interval: Option<Duration>, last_run: Instant, now: Instant) -> bool {
+    interval.is_some_and(|interval| now.duration_since(last_run) >= interval)
+}
 
-        // テストケース
-        let input = "foo baz １２３４！？";
-        let expected = "bar qux 1234!?"; // 全ての文字が変換される
-        let formatted = format_text(input, &replacements, &exclusion_list).unwrap();
+#[derive(Debug, PartialEq, Eq)]
+enum ConvertOutcome {
+    Converted,
+    Unchanged,
+    SkippedSensitive,
+    SkippedInvalid,
+    // The change was below min_changed_chars
+    SkippedTrivial,
+    // Longer than max_input_bytes with large_input_policy = skip
+    SkippedTooLarge,
+    DryRun,
+    // Waiting for the clipboard to settle, with coalesce_ms
+    Pending,
+    // Copied while skip_modifier was held
+    SkippedModifier,
+    // Copied from a window whose title matches skip_window_titles
+    SkippedWindowTitle,
+    // Answered no with --interactive
+    Declined,
+}
 
-        assert_eq!(formatted, expected);
+impl ConvertOutcome {
+    fn label(&self) -> &'static str {
+        match self {
+            ConvertOutcome::Converted => "converted",
+            ConvertOutcome::Unchanged => "unchanged",
+            ConvertOutcome::DryRun => "dry-run",
+            ConvertOutcome::Pending => "pending",
+            ConvertOutcome::SkippedSensitive
+            | ConvertOutcome::SkippedInvalid
+            | ConvertOutcome::SkippedTrivial
+            | ConvertOutcome::SkippedTooLarge
+            | ConvertOutcome::SkippedModifier
+            | ConvertOutcome::SkippedWindowTitle => "skipped",
+            ConvertOutcome::Declined => "declined",
+        }
     }
+}
 
-    #[test]
-    fn test_format_text_without_replacements_with_exclusions() {
-        // 置換リストなし
-        let replacements = vec![];
+#[derive(Default)]
+struct Converter {
+    options: FormatOptions,
+    sensitive_filter: SensitiveFilter,
+    learner: Option<Learner>,
+    suggestions_path: Option<PathBuf>,
+    skip_whitespace_only: bool,
+    output_mode: OutputMode,
+    output_separator: String,
+    // Hash of our own last write, so appended/prepended output is not converted again
+    last_written: Option<u64>,
+    cache: Option<ConversionCache>,
+    dry_run: bool,
+    // Logged changes are colored by cause instead of as a plain diff
+    annotate_causes: bool,
+    diff_out: Option<PathBuf>,
+    unified_diff_out: Option<PathBuf>,
+    // Aggregate report of would-be conversions, with --observe
+    observer: Option<Observer>,
+    // Input hash of the last dry-run report, so a clipboard that stays put is reported once
+    last_dry_run: Option<u64>,
+    // Hash of the clipboard at startup, left alone until something else is copied
+    existing: Option<u64>,
+    // Converted results also go here, with --tee
+    tee: Option<Tee>,
+    // Recent conversions, for the `history` command
+    snapshots: Snapshots,
+    audit: Option<AuditLog>,
+    #[cfg(feature = "plugins")]
+    plugins: Vec<Plugin>,
+    // coalesce_ms and the clock it is measured with; swapped out in tests
+    coalesce: Option<(Duration, Clock)>,
+    warn_on_link_change: bool,
+    // The latest clipboard value and when it first appeared, while coalescing
+    pending: Option<(u64, Instant)>,
+    // Converted characters per Unicode block during this run
+    blocks: BlockTally,
+    feedback: ConvertFeedback,
+    notify_preview_chars: usize,
+    // Smaller changes are not written back, so clipboard managers don't record
+    // a near-duplicate entry for them
+    min_changed_chars: usize,
+    max_input_bytes: Option<usize>,
+    large_input_policy: LargeInputPolicy,
+    post_command: PostCommand,
+    text_flavor: TextFlavor,
+    enforce_canonical: bool,
+    // Hash of the canonical form last seen or written, with enforce_canonical
+    canonical: Option<u64>,
+    // The last clipboard value that converted to itself, so a clipboard left
+    // as it is isn't converted, or run through post_command, on every poll
+    last_unchanged: Option<u64>,
+    // The last clipboard value warned about as mojibake, so text left in the
+    // clipboard is warned about once rather than on every poll
+    mojibake_warned: Option<u64>,
+    // The modifier and how to read the held modifiers; swapped out in tests
+    skip_modifier: Option<(Modifier, ModifierQuery)>,
+    // The clipboard value the modifier was last checked for, and whether it was held.
+    // Checked once when a value first appears, as the key is soon let go.
+    modifier_checked: Option<(u64, bool)>,
+    // skip_window_titles and how to read the focused window's title; swapped out in tests
+    skip_titles: Option<(TitleFilter, TitleQuery)>,
+    // Like modifier_checked, as the focus may move on before the next check
+    title_checked: Option<(u64, bool)>,
+    // The clipboard value the password manager marker was last checked for,
+    // and whether it was there. Checking can start a process, so once per value.
+    sensitive_checked: Option<(u64, bool)>,
+}
+
+impl Converter {
+    fn from_settings(settings: &Settings) -> Result<Self> {
+        // Compiled here so a typo fails at startup rather than on every conversion
+        let mut options = settings.format.clone();
+        options.compile_exclude_pattern()?;
+        let learner = settings
+            .learn_mode
+            .then(|| Learner::new(Duration::from_secs(settings.learn_window_secs)));
+        Ok(Converter {
+            options,
+            sensitive_filter: SensitiveFilter::new(&settings.secret_patterns)?,
+            learner,
+            suggestions_path: Some(get_config_dir()?.join(SUGGESTIONS_FILE_NAME)),
+            skip_whitespace_only: settings.skip_whitespace_only,
+            output_mode: settings.output_mode,
+            output_separator: settings.output_separator.clone(),
+            last_written: None,
+            cache: ConversionCache::new(settings.cache_size),
+            dry_run: false,
+            annotate_causes: false,
+            diff_out: None,
+            unified_diff_out: None,
+            observer: None,
+            last_dry_run: None,
+            existing: None,
+            tee: None,
+            snapshots: Snapshots::new(settings.snapshot_limit),
+            audit: None,
+            #[cfg(feature = "plugins")]
+            plugins: Vec::new(),
+            coalesce: settings
+                .coalesce_window()
+                .map(|window| (window, Instant::now as Clock)),
+            warn_on_link_change: settings.warn_on_link_change,
+            pending: None,
+            blocks: BlockTally::default(),
+            feedback: settings.on_convert_feedback,
+            notify_preview_chars: settings.notify_preview_chars,
+            min_changed_chars: settings.min_changed_chars,
+            max_input_bytes: settings.max_input_bytes,
+            large_input_policy: settings.large_input_policy,
+            post_command: PostCommand::new(
+                settings.post_command.clone(),
+                Duration::from_millis(settings.post_command_timeout_ms),
+            ),
+            text_flavor: settings.text_flavor,
+            enforce_canonical: settings.enforce_canonical,
+            canonical: None,
+            last_unchanged: None,
+            mojibake_warned: None,
+            skip_modifier: settings
+                .skip_modifier
+                .map(|modifier| (modifier, query_modifier_mask as ModifierQuery)),
+            modifier_checked: None,
+            skip_titles: Some((
+                TitleFilter::new(&settings.skip_window_titles)
+                    .context("Invalid skip_window_titles")?,
+                query_focused_title as TitleQuery,
+            ))
+            .filter(|(filter, _)| !filter.is_empty()),
+            title_checked: None,
+            sensitive_checked: None,
+        })
+    }
 
-        // 除外リスト
-        let exclusion_list = vec!['！', '？']; // 例: 全角の「！」「？」を除外
+    // The change for the log. Causes come from re-running the conversion with
+    // a trace, which only matches `after` when no post_command or
+    // large_input_policy tail was involved; otherwise this is a plain diff.
+    fn describe_change(
+        &self,
+        before: &str,
+        after: &str,
+        replacements: &[Replacement],
+        exclusion_list: &[char],
+    ) -> String {
+        if self.annotate_causes && before.chars().count() <= MAX_DIFF_INPUT_CHARS {
+            if let Ok(stages) = explain_format(before, replacements, exclusion_list, &self.options)
+            {
+                if stages.last().is_some_and(|stage| stage.text == after) {
+                    return highlight_causes(&stages);
+                }
+            }
+        }
+        highlight_diff(before, after)
+    }
 
-        // テストケース
-        let input = "foo baz １２３４！？";
-        let expected = "foo baz 1234！？"; // ！？は除外されるので変換されない
-        let formatted = format_text(input, &replacements, &exclusion_list).unwrap();
+    // The stages that made the change, as --explain labels them
+    fn change_labels(
+        &self,
+        before: &str,
+        after: &str,
+        replacements: &[Replacement],
+        exclusion_list: &[char],
+    ) -> Vec<String> {
+        if before.chars().count() > MAX_DIFF_INPUT_CHARS {
+            return Vec::new();
+        }
+        match explain_format(before, replacements, exclusion_list, &self.options) {
+            Ok(stages) if stages.last().is_some_and(|stage| stage.text == after) => stages
+                .into_iter()
+                .skip(1)
+                .map(|stage| stage.label)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
 
-        assert_eq!(formatted, expected);
+    // warn_on_link_change. Over the diff cap the stages are not traced, and
+    // only the links the whole conversion changed are named.
+    fn warn_about_changed_links(
+        &self,
+        before: &str,
+        after: &str,
+        replacements: &[Replacement],
+        exclusion_list: &[char],
+    ) {
+        if before.chars().count() > MAX_DIFF_INPUT_CHARS {
+            for link in touched_links(before, after) {
+                warn!(
+                    "The conversion changes a URL or email address: {}",
+                    cap_line_length(link, MAX_LOG_LINE_CHARS)
+                );
+            }
+            return;
+        }
+        let stages =
+            explain_format(before, replacements, exclusion_list, &self.options).unwrap_or_default();
+        for (stage, link) in links_changed_by_stages(&stages) {
+            warn!(
+                "{} changes a URL or email address: {}",
+                stage,
+                cap_line_length(link, MAX_LOG_LINE_CHARS)
+            );
+        }
     }
 
-    #[test]
-    fn test_format_text_without_replacements_exclusions() {
-        // 置換リストなし
-        let replacements = vec![];
+    // --interactive: the clipboard is only written when the answer read from
+    // `input` is yes
+    fn convert_interactively<C: ClipboardBackend>(
+        &self,
+        ctx: &mut C,
+        replacements: &[Replacement],
+        exclusion_list: &[char],
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> Result<ConvertOutcome> {
+        let clipboard_content = get_clipboard_contents(ctx)?;
+        let formatted_content = format_text_with_options(
+            &clipboard_content,
+            replacements,
+            exclusion_list,
+            &self.options,
+        )?;
+        if clipboard_content == formatted_content {
+            return Ok(ConvertOutcome::Unchanged);
+        }
+        writeln!(
+            output,
+            "{}",
+            self.describe_change(
+                &clipboard_content,
+                &formatted_content,
+                replacements,
+                exclusion_list
+            )
+        )?;
+        if !ask(
+            input,
+            output,
+            "Write the converted text to the clipboard?",
+            false,
+        )? {
+            return Ok(ConvertOutcome::Declined);
+        }
+        set_clipboard_contents(ctx, formatted_content, self.text_flavor)?;
+        Ok(ConvertOutcome::Converted)
+    }
 
-        // 除外リストなし
-        let exclusion_list = vec![];
+    // An unreadable clipboard has nothing to skip
+    fn skip_existing<C: ClipboardBackend>(&mut self, ctx: &mut C) {
+        if let Ok(content) = get_clipboard_contents(ctx) {
+            self.existing = Some(calculate_hash(&content));
+            debug!("Leaving the clipboard content from before startup alone.");
+        }
+    }
 
-        // テストケース
-        let input = "foo baz １２３４！？";
-        let expected = "foo baz 1234!?"; // 全ての文字が変換される
-        let formatted = format_text(input, &replacements, &exclusion_list).unwrap();
+    // Stage exclusions come with the rules rather than the settings, so they
+    // are taken from the same snapshot as the rules before each conversion
+    fn use_stage_exclusions(&mut self, rules: &CompiledRules) {
+        if self.options.stage_exclusions != rules.stage_exclusions {
+            self.options.stage_exclusions = rules.stage_exclusions.clone();
+            self.clear_cache();
+        }
+    }
 
-        assert_eq!(formatted, expected);
+    // Both remember what the old rules made of a value
+    fn clear_cache(&mut self) {
+        if let Some(cache) = &mut self.cache {
+            cache.clear();
+        }
+        self.last_unchanged = None;
     }
 
-    #[test]
-    fn test_format_text_with_partial_exclusions() {
-        // 置換リスト
-        let replacements = vec![
-            Replacement {
-                original: "foo".to_string(),
-                replacement: "bar".to_string(),
-            },
-            Replacement {
-                original: "baz".to_string(),
-                replacement: "qux".to_string(),
-            },
-        ];
+    fn learn(&mut self, content: &str) {
+        let Some(learner) = &mut self.learner else {
+            return;
+        };
+        let Some(suggestion) = learner.observe(content, Instant::now()) else {
+            return;
+        };
+        info!(
+            "Suggested replacement: '{}' -> '{}'.",
+            suggestion.original, suggestion.replacement
+        );
+        if let Some(path) = &self.suggestions_path {
+            if let Err(e) = append_suggestion(path, &suggestion) {
+                warn!("Failed to save suggestion: {:#}", e);
+            }
+        }
+    }
 
-        // 部分的な除外リスト
-        let exclusion_list = vec!['！']; // 例: 全角の「！」を除外
+    fn convert_clipboard<C: ClipboardBackend>(
+        &mut self,
+        ctx: &mut C,
+        replacements: &[Replacement],
+        exclusion_list: &[char],
+    ) -> Result<ConvertOutcome> {
+        let clipboard_content = match get_clipboard_contents(ctx) {
+            Err(ClipboardError::InvalidUtf8(e)) => {
+                debug!("Skipping clipboard content: {}", e);
+                return Ok(ConvertOutcome::SkippedInvalid);
+            }
+            result => result?,
+        };
+        let input_hash = calculate_hash(&clipboard_content);
+        if self.last_written == Some(input_hash)
+            || self.last_dry_run == Some(input_hash)
+            || self.last_unchanged == Some(input_hash)
+        {
+            return Ok(ConvertOutcome::Unchanged);
+        }
+        if self.enforce_canonical {
+            if self.canonical == Some(input_hash) {
+                return Ok(ConvertOutcome::Unchanged);
+            }
+            // The startup content has to converge too
+            self.existing = None;
+        }
+        if let Some(existing) = self.existing {
+            if existing == input_hash {
+                return Ok(ConvertOutcome::Unchanged);
+            }
+            // Copying the same text again later is a new copy
+            self.existing = None;
+        }
+        if let Some((modifier, query)) = self.skip_modifier {
+            let held = match self.modifier_checked {
+                Some((hash, held)) if hash == input_hash => held,
+                _ => {
+                    let held = should_skip_copy(Some(modifier), query());
+                    if held {
+                        info!(
+                            "{:?} was held while copying. Leaving the copy as it is.",
+                            modifier
+                        );
+                    }
+                    self.modifier_checked = Some((input_hash, held));
+                    held
+                }
+            };
+            if held {
+                return Ok(ConvertOutcome::SkippedModifier);
+            }
+        }
+        if let Some((filter, query)) = &self.skip_titles {
+            let skipped = match self.title_checked {
+                Some((hash, skipped)) if hash == input_hash => skipped,
+                _ => {
+                    let title = query();
+                    let glob = title.as_deref().and_then(|title| filter.matching(title));
+                    if let Some(glob) = glob {
+                        info!(
+                            "Copied from a window matching '{}'. Leaving the copy as it is.",
+                            glob
+                        );
+                    }
+                    self.title_checked = Some((input_hash, glob.is_some()));
+                    glob.is_some()
+                }
+            };
+            if skipped {
+                return Ok(ConvertOutcome::SkippedWindowTitle);
+            }
+        }
+        if let Some((window, clock)) = self.coalesce {
+            let now = clock();
+            match self.pending {
+                Some((hash, since)) if hash == input_hash => {
+                    if now.saturating_duration_since(since) < window {
+                        return Ok(ConvertOutcome::Pending);
+                    }
+                }
+                // A newer value replaces the one that was waiting
+                _ => {
+                    self.pending = Some((input_hash, now));
+                    return Ok(ConvertOutcome::Pending);
+                }
+            }
+        }
+        // Nothing worth converting, and nothing worth logging
+        if self.skip_whitespace_only && clipboard_content.trim().is_empty() {
+            return Ok(ConvertOutcome::Unchanged);
+        }
+        let marked = match self.sensitive_checked {
+            Some((hash, marked)) if hash == input_hash => marked,
+            _ => {
+                let marked = ctx.is_sensitive();
+                self.sensitive_checked = Some((input_hash, marked));
+                marked
+            }
+        };
+        if self
+            .sensitive_filter
+            .is_sensitive(marked, &clipboard_content)
+        {
+            // Never log the content itself on this path
+            debug!("Skipping sensitive clipboard content.");
+            return Ok(ConvertOutcome::SkippedSensitive);
+        }
+        if !self.options.fix_mojibake
+            && self.mojibake_warned != Some(input_hash)
+            && looks_like_mojibake(&clipboard_content)
+        {
+            self.mojibake_warned = Some(input_hash);
+            warn!("The clipboard looks like UTF-8 that was read as Latin-1; set fix_mojibake to recover it.");
+        }
+        let (head, tail) = match self.max_input_bytes {
+            Some(max_bytes) => match self.large_input_policy.split(&clipboard_content, max_bytes) {
+                Some(split) => split,
+                None => {
+                    debug!(
+                        "Skipping clipboard content of {} bytes (max_input_bytes {}).",
+                        clipboard_content.len(),
+                        max_bytes
+                    );
+                    return Ok(ConvertOutcome::SkippedTooLarge);
+                }
+            },
+            None => (clipboard_content.as_str(), ""),
+        };
+        self.learn(&clipboard_content);
+        // Only the head is converted; the tail is appended as it was
+        let passes = if self.enforce_canonical {
+            MAX_CANONICAL_PASSES
+        } else {
+            1
+        };
+        // Without the `plugins` feature there are none to run
+        #[cfg(feature = "plugins")]
+        let plugins = &self.plugins;
+        let plugin_stage = |text: String| {
+            #[cfg(feature = "plugins")]
+            return run_plugins(plugins, text);
+            #[cfg(not(feature = "plugins"))]
+            text
+        };
+        let format = || {
+            converge(head, passes, |text| {
+                format_text_with_options(text, replacements, exclusion_list, &self.options)
+            })
+            .map(|converted| plugin_stage(converted) + tail)
+        };
+        let converted = match &mut self.cache {
+            Some(cache) => cache.get_or_try_insert(&clipboard_content, format)?,
+            None => format()?,
+        };
+        // Outside the cache, so a run that fell back is not remembered
+        let formatted_content = post_process(&mut self.post_command, converted);
+        if clipboard_content == formatted_content {
+            if self.enforce_canonical {
+                self.canonical = Some(input_hash);
+            }
+            self.last_unchanged = Some(input_hash);
+            return Ok(ConvertOutcome::Unchanged);
+        }
+        if self.enforce_canonical {
+            if self.canonical.is_some() {
+                debug!("The clipboard drifted from its canonical form. Converting it again.");
+            }
+        } else if changed_chars(&clipboard_content, &formatted_content) < self.min_changed_chars {
+            return Ok(ConvertOutcome::SkippedTrivial);
+        }
+        if self.warn_on_link_change {
+            self.warn_about_changed_links(
+                &clipboard_content,
+                &formatted_content,
+                replacements,
+                exclusion_list,
+            );
+        }
+        if self.dry_run {
+            info!(
+                "Dry run: would replace: {}",
+                cap_line_length(
+                    &self.describe_change(
+                        &clipboard_content,
+                        &formatted_content,
+                        replacements,
+                        exclusion_list
+                    ),
+                    MAX_LOG_LINE_CHARS
+                )
+            );
+            self.last_dry_run = Some(input_hash);
+            if let Some(path) = &self.diff_out {
+                let labels = self.change_labels(
+                    &clipboard_content,
+                    &formatted_content,
+                    replacements,
+                    exclusion_list,
+                );
+                if let Err(e) =
+                    append_diff_record(path, &clipboard_content, &formatted_content, &labels)
+                {
+                    warn!("Failed to write the diff record: {:#}", e);
+                }
+            }
+            if let Some(path) = &self.unified_diff_out {
+                if let Err(e) = append_unified_diff(path, &clipboard_content, &formatted_content) {
+                    warn!("Failed to write the unified diff: {:#}", e);
+                }
+            }
+            if let Some(observer) = &mut self.observer {
+                observer.record(&report_format(
+                    &clipboard_content,
+                    replacements,
+                    exclusion_list,
+                    &self.options,
+                )?);
+            }
+            return Ok(ConvertOutcome::DryRun);
+        }
+        info!(
+            "Replace: {}",
+            cap_line_length(
+                &self.describe_change(
+                    &clipboard_content,
+                    &formatted_content,
+                    replacements,
+                    exclusion_list
+                ),
+                MAX_LOG_LINE_CHARS
+            )
+        );
+        if let Some(path) = &self.unified_diff_out {
+            if let Err(e) = append_unified_diff(path, &clipboard_content, &formatted_content) {
+                warn!("Failed to write the unified diff: {:#}", e);
+            }
+        }
+        // Anything but the converted text would itself drift from the canonical form
+        let output_mode = if self.enforce_canonical {
+            OutputMode::Replace
+        } else {
+            self.output_mode
+        };
+        let output = output_mode.compose(
+            &clipboard_content,
+            &formatted_content,
+            &self.output_separator,
+        );
+        if let Some(learner) = &mut self.learner {
+            learner.ignore(&output, Instant::now());
+        }
+        let output_hash = calculate_hash(&output);
+        set_clipboard_contents(ctx, output, self.text_flavor)?;
+        if let Some(tee) = &mut self.tee {
+            tee.send(&formatted_content);
+        }
+        self.last_written = Some(output_hash);
+        if self.enforce_canonical {
+            self.canonical = Some(output_hash);
+        }
+        if let Err(e) = self
+            .snapshots
+            .record(&clipboard_content, &formatted_content)
+        {
+            warn!("Failed to save the conversion history: {:#}", e);
+        }
+        if let Some(audit) = &mut self.audit {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default();
+            if let Err(e) = audit.record(timestamp, &clipboard_content, &formatted_content) {
+                warn!("Failed to write the audit log: {:#}", e);
+            }
+        }
+        self.blocks.record(&clipboard_content, &formatted_content);
+        if let Some(action) = feedback_action(
+            self.feedback,
+            &clipboard_content,
+            &formatted_content,
+            self.notify_preview_chars,
+            self.sensitive_filter.is_active(),
+        ) {
+            give_feedback(action);
+        }
+        Ok(ConvertOutcome::Converted)
+    }
+}
+
+// "-" writes to stderr. The labels carry the time so diffs in one file can be told apart.
+fn append_unified_diff(path: &Path, before: &str, after: &str) -> Result<()> {
+    let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    let diff = unified_diff(
+        before,
+        after,
+        &format!("clipboard\t{}", now),
+        &format!("converted\t{}", now),
+    );
+    if path == Path::new("-") {
+        eprint!("{}", diff);
+        return Ok(());
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.write_all(diff.as_bytes())
+        .with_context(|| format!("Failed to write to {}", path.display()))?;
+    Ok(())
+}
+
+// One JSON object per line, so the file can be appended to and tailed
+fn append_diff_record(path: &Path, before: &str, after: &str, labels: &[String]) -> Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let record = serde_json::json!({
+        "timestamp": timestamp,
+        "before": before,
+        "after": after,
+        "labels": labels,
+    });
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    writeln!(file, "{}", record)
+        .with_context(|| format!("Failed to write to {}", path.display()))?;
+    Ok(())
+}
+
+struct LoadedConfig {
+    replacement_path: PathBuf,
+    // Further files from KZA_REPLACEMENTS, whose rules follow `replacement_path`'s
+    extra_replacement_paths: Vec<PathBuf>,
+    exclusion_path: PathBuf,
+    // A project's exclusions, layered over `exclusion_path`
+    exclusion_layer_path: Option<PathBuf>,
+    replacements: Vec<Replacement>,
+    exclusion_list: Vec<char>,
+    stage_exclusions: StageExclusions,
+    // What conversions read; republished as a whole after every change above
+    rules: SharedRules,
+    previous_replacement_hash: u64,
+    previous_exclusion_hash: u64,
+    replacement_failed: bool,
+    exclusion_failed: bool,
+    reload_hash: ReloadHashStrategy,
+    replacement_fingerprint: Option<FileFingerprint>,
+    exclusion_fingerprint: Option<FileFingerprint>,
+    // Fail instead of keeping the last-good rules when a reload breaks
+    strict: bool,
+    // Bundled rules to leave out, by `original`
+    disabled_defaults: Vec<String>,
+    history: ReloadHistory,
+    // From `rules_url`, applied before the local replacements
+    remote_replacements: Vec<Replacement>,
+}
+
+// Reports whether the file is unchanged since it was last loaded so the caller
+// can skip parsing it. Both strategies first compare mtime and size; the bytes
+// strategy then falls back to comparing the content hash.
+fn file_unchanged(
+    reload_hash: ReloadHashStrategy,
+    fingerprint: &mut Option<FileFingerprint>,
+    path: &Path,
+) -> bool {
+    if reload_hash == ReloadHashStrategy::Parsed {
+        if let Some(previous) = fingerprint {
+            if previous.metadata_matches(path).unwrap_or(false) {
+                return true;
+            }
+        }
+        *fingerprint = FileFingerprint::from_path(path).ok();
+        return false;
+    }
+    let refreshed = match fingerprint {
+        Some(previous) => previous.refresh(path),
+        None => FileFingerprint::from_path(path).map(|current| (current, true)),
+    };
+    match refreshed {
+        Ok((current, changed)) => {
+            *fingerprint = Some(current);
+            !changed
+        }
+        Err(_) => false,
+    }
+}
+
+impl LoadedConfig {
+    // With `contain`, a file that fails to load starts out as failed, with no
+    // rules from it, exactly as if a reload had broken it
+    fn load(
+        mut replacement_paths: Vec<PathBuf>,
+        exclusion_path: PathBuf,
+        exclusion_layer_path: Option<PathBuf>,
+        reload_hash: ReloadHashStrategy,
+        rule_cache: bool,
+        contain: bool,
+    ) -> Result<Self> {
+        // A config the cache cannot give is read below, which reports what is wrong with it
+        let cached = rule_cache
+            .then(|| {
+                let Some(cache_dir) = default_cache_dir() else {
+                    warn!("No cache directory found; rule_cache is not used.");
+                    return None;
+                };
+                load_config_cached(
+                    &cache_dir,
+                    &replacement_paths,
+                    &exclusion_path,
+                    exclusion_layer_path.as_deref(),
+                )
+                .ok()
+            })
+            .flatten();
+        let (replacements, exclusions) = match cached {
+            Some(config) => (
+                Ok(config.rules.replacements),
+                Ok((config.rules.exclusion_list, config.rules.stage_exclusions)),
+            ),
+            None => (
+                load_replacement_files(&replacement_paths).map_err(Into::into),
+                load_exclusions(&exclusion_path, exclusion_layer_path.as_deref()),
+            ),
+        };
+        let (replacements, replacement_failed) = match replacements {
+            Ok(replacements) => (replacements, false),
+            Err(e) if contain => {
+                warn!("Failed to load replacements: {:#}", e);
+                (Vec::new(), true)
+            }
+            Err(e) => return Err(e),
+        };
+        let ((exclusion_list, stage_exclusions), exclusion_failed) = match exclusions {
+            Ok(exclusions) => (exclusions, false),
+            Err(e) if contain => {
+                warn!("Failed to load exclusions: {:#}", e);
+                (Default::default(), true)
+            }
+            Err(e) => return Err(e),
+        };
+        let replacement_path = replacement_paths.remove(0);
+        Ok(LoadedConfig {
+            previous_replacement_hash: calculate_hash(&replacements),
+            previous_exclusion_hash: calculate_hash(&(&exclusion_list, &stage_exclusions)),
+            rules: SharedRules::new(CompiledRules {
+                replacements: replacements.clone(),
+                exclusion_list: exclusion_list.clone(),
+                stage_exclusions: stage_exclusions.clone(),
+            }),
+            replacements,
+            exclusion_list,
+            stage_exclusions,
+            replacement_failed,
+            exclusion_failed,
+            reload_hash,
+            // A failed file must be read again on its next change
+            replacement_fingerprint: FileFingerprint::from_path(&replacement_path)
+                .ok()
+                .filter(|_| !replacement_failed),
+            exclusion_fingerprint: FileFingerprint::from_path(&exclusion_path)
+                .ok()
+                .filter(|_| !exclusion_failed),
+            replacement_path,
+            extra_replacement_paths: replacement_paths,
+            exclusion_path,
+            exclusion_layer_path,
+            strict: false,
+            disabled_defaults: Vec::new(),
+            history: ReloadHistory::default(),
+            remote_replacements: Vec::new(),
+        })
+    }
+
+    // Every file the daemon reads, merged as it merges them; --dump-config
+    // goes through here too so that it shows what the daemon would use. With
+    // `contain`, the daemon starts paused by safe_mode instead of failing.
+    fn open(
+        replacement_paths: Vec<PathBuf>,
+        exclusion_path: PathBuf,
+        exclusion_layer_path: Option<PathBuf>,
+        settings: &Settings,
+        contain: bool,
+    ) -> Result<Self> {
+        let mut config = LoadedConfig::load(
+            replacement_paths,
+            exclusion_path,
+            exclusion_layer_path,
+            settings.reload_hash,
+            settings.rule_cache,
+            contain,
+        )?;
+        config.disable_defaults(&settings.disabled_defaults);
+        Ok(config)
+    }
+
+    fn effective(&self) -> Result<EffectiveConfig> {
+        let exclusion_path = self
+            .exclusion_path
+            .to_str()
+            .context("Exclusion path contains invalid UTF-8 characters")?;
+        let layer_path = self
+            .exclusion_layer_path
+            .as_deref()
+            .map(|path| {
+                path.to_str()
+                    .context("Exclusion path contains invalid UTF-8 characters")
+            })
+            .transpose()?;
+        Ok(EffectiveConfig::new(
+            self.replacements.clone(),
+            self.exclusion_list.clone(),
+            load_exclusion_reasons(exclusion_path, layer_path)?,
+            self.stage_exclusions.clone(),
+        ))
+    }
+
+    fn publish(&self) {
+        self.rules.publish(CompiledRules {
+            replacements: self
+                .remote_replacements
+                .iter()
+                .chain(&self.replacements)
+                .cloned()
+                .collect(),
+            exclusion_list: self.exclusion_list.clone(),
+            stage_exclusions: self.stage_exclusions.clone(),
+        });
+    }
+
+    fn replacement_files(&self) -> Vec<PathBuf> {
+        std::iter::once(&self.replacement_path)
+            .chain(&self.extra_replacement_paths)
+            .cloned()
+            .collect()
+    }
+
+    // The versions the daemon starts with, as the first history entries
+    fn record_loaded(&mut self) {
+        self.record_reload("replacements", self.previous_replacement_hash);
+        self.record_reload("exclusions", self.previous_exclusion_hash);
+    }
+
+    // A history that cannot be written must not stop the reload itself
+    fn record_reload(&mut self, file: &str, hash: u64) {
+        if let Err(e) = self.history.record(file, hash) {
+            warn!("Failed to record the reload: {:#}", e);
+        }
+    }
+
+    fn set_remote_replacements(&mut self, replacements: Vec<Replacement>) {
+        self.record_reload("rules_url", calculate_hash(&replacements));
+        self.remote_replacements = replacements;
+        self.publish();
+    }
+
+    fn disable_defaults(&mut self, disabled: &[String]) {
+        self.disabled_defaults = disabled.to_vec();
+        remove_disabled_defaults(&mut self.replacements, &self.disabled_defaults);
+        self.publish();
+    }
+
+    // Swaps in rules from another file for this run only. The paths being
+    // watched are left alone, so the installed config is never written.
+    fn override_replacements(&mut self, path: &Path) -> Result<()> {
+        self.replacements = load_replacements(
+            path.to_str()
+                .context("Replacement path contains invalid UTF-8 characters")?,
+        )?;
+        self.publish();
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: &notify::Event) -> Result<bool> {
+        let mut reloaded = false;
+        let extra_changed = self
+            .extra_replacement_paths
+            .iter()
+            .any(|path| event.paths.contains(path));
+        if extra_changed
+            || event.paths.contains(&self.replacement_path)
+                && !file_unchanged(
+                    self.reload_hash,
+                    &mut self.replacement_fingerprint,
+                    &self.replacement_path,
+                )
+        {
+            let mut new_replacements = match load_replacement_files(&self.replacement_files()) {
+                Ok(new_replacements) => new_replacements,
+                Err(e) if self.strict => return Err(e.into()),
+                Err(_) => {
+                    if !self.replacement_failed {
+                        warn!("Failed to load replacements.")
+                    };
+                    self.replacement_failed = true;
+                    return Ok(reloaded);
+                }
+            };
+            let new_replacement_hash = calculate_hash(&new_replacements);
+            // A file restored to its last-good content is valid again too
+            self.replacement_failed = false;
+            if self.previous_replacement_hash != new_replacement_hash {
+                info!("Replacements have been modified.");
+                info!("Reloading replacements...");
+                remove_disabled_defaults(&mut new_replacements, &self.disabled_defaults);
+                self.replacements = new_replacements;
+                self.previous_replacement_hash = new_replacement_hash;
+                self.record_reload("replacements", new_replacement_hash);
+                reloaded = true;
+            }
+        }
+        let layer_changed = self
+            .exclusion_layer_path
+            .as_ref()
+            .is_some_and(|path| event.paths.contains(path));
+        if layer_changed
+            || event.paths.contains(&self.exclusion_path)
+                && !file_unchanged(
+                    self.reload_hash,
+                    &mut self.exclusion_fingerprint,
+                    &self.exclusion_path,
+                )
+        {
+            let (new_exclusion_list, new_stage_exclusions) =
+                match load_exclusions(&self.exclusion_path, self.exclusion_layer_path.as_deref()) {
+                    Ok(new_exclusions) => new_exclusions,
+                    Err(e) if self.strict => return Err(e),
+                    Err(_) => {
+                        if !self.exclusion_failed {
+                            warn!("Failed to load exclusions.");
+                        }
+                        self.exclusion_failed = true;
+                        if reloaded {
+                            self.publish();
+                        }
+                        return Ok(reloaded);
+                    }
+                };
+            let new_exclusion_hash = calculate_hash(&(&new_exclusion_list, &new_stage_exclusions));
+            self.exclusion_failed = false;
+            if self.previous_exclusion_hash != new_exclusion_hash {
+                info!("{} has been modified.", self.exclusion_path.display());
+                info!("Reloading exclusions...");
+                self.exclusion_list = new_exclusion_list;
+                self.stage_exclusions = new_stage_exclusions;
+                self.previous_exclusion_hash = new_exclusion_hash;
+                self.record_reload("exclusions", new_exclusion_hash);
+                reloaded = true;
+            }
+        }
+        if reloaded {
+            self.publish();
+        }
+        Ok(reloaded)
+    }
+
+    fn has_failures(&self) -> bool {
+        self.replacement_failed || self.exclusion_failed
+    }
+}
+
+// The characters excluded from every stage, and those excluded from some
+fn load_exclusions(
+    exclusion_path: &Path,
+    layer_path: Option<&Path>,
+) -> Result<(Vec<char>, StageExclusions)> {
+    let layer_path = layer_path
+        .map(|path| {
+            path.to_str()
+                .context("Exclusion path contains invalid UTF-8 characters")
+        })
+        .transpose()?;
+    let exclusion_path = exclusion_path
+        .to_str()
+        .context("Exclusion path contains invalid UTF-8 characters")?;
+    Ok((
+        load_layered_exclusions(exclusion_path, layer_path)?,
+        load_stage_exclusions(exclusion_path, layer_path)?,
+    ))
+}
+
+// Returns whether any rules were reloaded
+#[derive(Debug, PartialEq, Eq)]
+enum WatchPoll {
+    Idle,
+    Reloaded,
+    // The watcher reported an error or has stopped
+    Failed(String),
+}
+
+fn poll_config_changes(
+    watch_rx: Option<&WatchEvents>,
+    config: &mut LoadedConfig,
+) -> Result<WatchPoll> {
+    let Some(rx) = watch_rx else {
+        return Ok(WatchPoll::Idle);
+    };
+    match rx.try_recv() {
+        Ok(Ok(event)) => Ok(if config.handle_event(&event)? {
+            WatchPoll::Reloaded
+        } else {
+            WatchPoll::Idle
+        }),
+        Ok(Err(e)) => Ok(WatchPoll::Failed(e.to_string())),
+        Err(TryRecvError::Empty) => Ok(WatchPoll::Idle),
+        Err(TryRecvError::Disconnected) => {
+            Ok(WatchPoll::Failed("the watcher has stopped".to_string()))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Recovery {
+    Wait,
+    RecreateContext,
+    RecreateWatcher,
+    // Both the clipboard context and the watcher are started over
+    FullReinit,
+}
+
+// Consecutive failures of the clipboard context and the config watcher, and
+// what the loop should do about each one
+#[derive(Default)]
+struct Watchdog {
+    clipboard_failures: usize,
+    set_failures: usize,
+    watcher_failures: usize,
+}
+
+impl Watchdog {
+    // Failed reads mean the context is broken, so it is recreated right away.
+    // Failed writes are usually another app holding the clipboard, so those
+    // only escalate after several cycles in a row. Errors that are not about
+    // the clipboard (e.g. a broken rule) are not the context's fault.
+    fn clipboard_failed(&mut self, error: &anyhow::Error) -> Recovery {
+        let Some(error) = error.downcast_ref::<ClipboardError>() else {
+            return Recovery::Wait;
+        };
+        self.clipboard_failures += 1;
+        if self.clipboard_failures >= WATCHDOG_REINIT_THRESHOLD {
+            *self = Watchdog::default();
+            return Recovery::FullReinit;
+        }
+        if let ClipboardError::SetContents(_) = error {
+            self.set_failures += 1;
+            if self.set_failures < SET_FAILURE_ESCALATION {
+                return Recovery::Wait;
+            }
+        }
+        self.set_failures = 0;
+        Recovery::RecreateContext
+    }
+
+    fn clipboard_ok(&mut self) {
+        self.clipboard_failures = 0;
+        self.set_failures = 0;
+    }
+
+    fn watcher_failed(&mut self) -> Recovery {
+        self.watcher_failures += 1;
+        if self.watcher_failures >= WATCHDOG_REINIT_THRESHOLD {
+            *self = Watchdog::default();
+            return Recovery::FullReinit;
+        }
+        Recovery::RecreateWatcher
+    }
+
+    fn watcher_ok(&mut self) {
+        self.watcher_failures = 0;
+    }
+}
+
+fn watch_config(config: &LoadedConfig) -> Result<(RecommendedWatcher, WatchEvents)> {
+    let (tx, rx) = channel();
+    let watcher_config = Config::default().with_poll_interval(Duration::from_secs(2));
+    let mut watcher: RecommendedWatcher =
+        Watcher::new(tx, watcher_config).context("Failed to initialize file watcher")?;
+    let replacement_files = config.replacement_files();
+    for replacement_path in &replacement_files {
+        watcher
+            .watch(replacement_path, RecursiveMode::NonRecursive)
+            .context("Failed to watch replacements file")?;
+    }
+    // A combined config.json is both, and is watched once
+    if !replacement_files.contains(&config.exclusion_path) {
+        watcher
+            .watch(&config.exclusion_path, RecursiveMode::NonRecursive)
+            .context("Failed to watch exclusions file")?;
+    }
+    if let Some(layer_path) = &config.exclusion_layer_path {
+        watcher
+            .watch(layer_path, RecursiveMode::NonRecursive)
+            .context("Failed to watch project exclusions file")?;
+    }
+    Ok((watcher, rx))
+}
+
+// State of the main loop, split out of `main` so tests can drive it with a
+// mock clipboard for a fixed number of iterations
+struct Daemon {
+    settings: Settings,
+    config: LoadedConfig,
+    converter: Converter,
+    // The watcher stops delivering events once dropped, so it lives as long as the loop
+    watcher: Option<RecommendedWatcher>,
+    watch_rx: Option<WatchEvents>,
+    watchdog: Watchdog,
+    ipc_rx: Option<Receiver<IpcRequest>>,
+    #[cfg(all(target_os = "linux", feature = "primary-selection"))]
+    primary: Option<PrimarySelection>,
+    clipboard_warnings: LogThrottle,
+    context_warnings: LogThrottle,
+    last_interval_run: Instant,
+    paused: bool,
+    idle: IdleMonitor,
+    focus: FocusMonitor,
+    verbosity: VerbosityWindow,
+    quiet: bool,
+    // Minutes since local midnight; swapped out in tests
+    clock: fn() -> u32,
+    // WM_CLASS of the focused window; swapped out in tests
+    focused_app: fn() -> Option<String>,
+    // Monotonic time for the interval and focus checks; swapped out in tests
+    now: Clock,
+    poll_interval: Duration,
+    // A temporary interval set with `kill-zen-all set-interval`
+    poll_override: PollOverride,
+    remote_rules: Option<RemoteUpdates>,
+    // Set from the Ctrl-C/SIGTERM handler; the loop exits at the next iteration
+    shutdown: Arc<AtomicBool>,
+    // Conversions written to the clipboard during this run
+    conversions: u64,
+    cli_fallback: CliFallback,
+}
+
+impl Daemon {
+    fn new(settings: Settings, config: LoadedConfig, converter: Converter) -> Self {
+        let idle = IdleMonitor::new(settings.idle_pause());
+        let focus = FocusMonitor::new(settings.focus_convert_apps.clone());
+        let verbosity = VerbosityWindow::new(settings.verbose_after_error);
+        Daemon {
+            settings,
+            config,
+            converter,
+            watcher: None,
+            watch_rx: None,
+            watchdog: Watchdog::default(),
+            ipc_rx: None,
+            #[cfg(all(target_os = "linux", feature = "primary-selection"))]
+            primary: None,
+            clipboard_warnings: LogThrottle::new(),
+            context_warnings: LogThrottle::new(),
+            last_interval_run: Instant::now(),
+            paused: false,
+            idle,
+            focus,
+            verbosity,
+            quiet: false,
+            clock: local_minute_of_day,
+            focused_app: query_focused_app,
+            now: Instant::now,
+            poll_interval: Duration::from_secs(1),
+            poll_override: PollOverride::default(),
+            remote_rules: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            conversions: 0,
+            cli_fallback: CliFallback::default(),
+        }
+    }
+
+    // Runs forever unless `max_iterations` is given (tests and smoke checks)
+    fn run<C: ClipboardBackend>(
+        &mut self,
+        ctx: &mut C,
+        new_context: impl Fn() -> Result<C, ClipboardError>,
+        max_iterations: Option<u64>,
+    ) -> Result<()> {
+        let mut iterations = 0;
+        loop {
+            self.tick(ctx, &new_context)?;
+            iterations += 1;
+            if max_iterations.is_some_and(|max| iterations >= max) {
+                return Ok(());
+            }
+            thread::sleep(
+                self.poll_override
+                    .interval(self.poll_interval, Instant::now()),
+            );
+            if self.shutdown.load(Ordering::SeqCst) {
+                info!("Shutting down.");
+                return Ok(());
+            }
+        }
+    }
+
+    // One line of JSON, since IPC replies are line-based
+    fn stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "conversions": self.conversions,
+            "blocks": self.converter.blocks,
+            "reloads": self.config.history.records().collect::<Vec<_>>(),
+        })
+    }
+
+    fn log_summary(&self) {
+        if self.converter.blocks.is_empty() {
+            info!("Conversions this session: {}", self.conversions);
+        } else {
+            info!(
+                "Conversions this session: {} ({})",
+                self.conversions, self.converter.blocks
+            );
+        }
+    }
+
+    // Neither converter may keep what the old rules made of a value
+    fn forget_old_rules(&mut self) {
+        self.converter.clear_cache();
+        #[cfg(all(target_os = "linux", feature = "primary-selection"))]
+        if let Some(primary) = &mut self.primary {
+            primary.converter.clear_cache();
+        }
+    }
+
+    // Reply to the `text` command: the input converted with the current rules,
+    // as a frame. The clipboard is not touched.
+    fn convert_text(&mut self, text: &str) -> String {
+        if self.paused {
+            return "error: paused: configuration is invalid".to_string();
+        }
+        let rules = self.config.rules.snapshot();
+        self.converter.use_stage_exclusions(&rules);
+        match format_text_with_options(
+            text,
+            &rules.replacements,
+            &rules.exclusion_list,
+            &self.converter.options,
+        ) {
+            Ok(converted) => ipc::encode_text_frame(&converted),
+            Err(e) => format!("error: {}", e),
+        }
+    }
+
+    // Writes the original of a recent conversion back. It counts as our own
+    // write, so it is not converted again.
+    fn restore_snapshot<C: ClipboardBackend>(&mut self, ctx: &mut C, index: usize) -> Result<()> {
+        let snapshots = &self.converter.snapshots;
+        let original = snapshots
+            .get(index)
+            .with_context(|| {
+                format!(
+                    "No conversion at index {} ({} in history)",
+                    index,
+                    snapshots.len()
+                )
+            })?
+            .original
+            .clone();
+        let hash = calculate_hash(&original);
+        set_clipboard_contents(ctx, original, self.converter.text_flavor)?;
+        self.converter.last_written = Some(hash);
+        info!("Restored the original of conversion {}.", index);
+        Ok(())
+    }
+
+    // Best effort: a broken usage file must not turn a clean exit into an error
+    fn record_usage(&self) {
+        if !self.settings.usage_counter {
+            return;
+        }
+        let recorded = get_config_dir()
+            .and_then(|dir| record_conversions(&dir.join(USAGE_FILE_NAME), self.conversions));
+        match recorded {
+            Ok(total) => info!("Lifetime conversions: {}", total),
+            Err(e) => warn!("Failed to update the usage counter: {:#}", e),
+        }
+    }
+
+    fn tick<C: ClipboardBackend>(
+        &mut self,
+        ctx: &mut C,
+        new_context: &impl Fn() -> Result<C, ClipboardError>,
+    ) -> Result<()> {
+        self.verbosity.advance();
+        let mut request = self.ipc_rx.as_ref().and_then(|rx| rx.try_recv().ok());
+        if let Some(stats) = request.take_if(|request| request.command == IpcCommand::Stats) {
+            stats.reply(self.stats().to_string());
+        }
+        if let Some(history) = request.take_if(|request| request.command == IpcCommand::History) {
+            history.reply(self.converter.snapshots.to_json().to_string());
+        }
+        if let Some(set) =
+            request.take_if(|request| matches!(request.command, IpcCommand::SetInterval { .. }))
+        {
+            if let IpcCommand::SetInterval { interval, duration } = set.command {
+                let duration = self.poll_override.set(interval, duration, Instant::now());
+                let interval = self
+                    .poll_override
+                    .interval(self.poll_interval, Instant::now());
+                info!(
+                    "Polling every {} ms for {} s.",
+                    interval.as_millis(),
+                    duration.as_secs()
+                );
+                set.reply(format!(
+                    "polling every {} ms for {} s",
+                    interval.as_millis(),
+                    duration.as_secs()
+                ));
+            }
+        }
+        if let Some(text) =
+            request.take_if(|request| matches!(request.command, IpcCommand::Text(_)))
+        {
+            if let IpcCommand::Text(input) = &text.command {
+                let reply = self.convert_text(input);
+                text.reply(reply);
+            }
+        }
+        let now = (self.now)();
+        let scheduled = interval_due(
+            self.settings.interval_convert(),
+            self.last_interval_run,
+            now,
+        );
+        if scheduled {
+            self.last_interval_run = now;
+        }
+        let focused = self.focus.switched_to_target(now, self.focused_app);
+        let triggered = scheduled
+            || focused
+            || matches!(
+                request.as_ref().map(|request| &request.command),
+                Some(IpcCommand::Convert)
+            );
+        // In safe mode a broken config means pass-through rather than converting
+        // with a mix of last-good and default rules
+        let failing = self.settings.safe_mode && self.config.has_failures();
+        if failing != self.paused {
+            self.paused = failing;
+            if self.paused {
+                warn!(
+                    "Safe mode: configuration is invalid. Conversion is paused until it is fixed."
+                );
+            } else {
+                info!("Configuration is valid again. Resuming conversion.");
+            }
+        }
+        let quiet = is_quiet(&self.settings.quiet_hours, (self.clock)());
+        if quiet != self.quiet {
+            self.quiet = quiet;
+            if quiet {
+                info!("Quiet hours have started. The clipboard will not be written.");
+            } else {
+                info!("Quiet hours are over. Resuming conversion.");
+            }
+        }
+        if let Some(restore) =
+            request.take_if(|request| matches!(request.command, IpcCommand::Restore(_)))
+        {
+            if let IpcCommand::Restore(index) = restore.command {
+                let reply = if self.quiet {
+                    "paused: quiet hours".to_string()
+                } else {
+                    match self.restore_snapshot(ctx, index) {
+                        Ok(()) => "restored".to_string(),
+                        Err(e) => format!("error: {:#}", e),
+                    }
+                };
+                restore.reply(reply);
+            }
+        }
+        // An explicit request means someone is at the machine after all
+        let idle = request.is_none() && self.idle.is_idle(now, query_idle_time);
+        let active = !idle && !self.quiet;
+        if self.paused {
+            if let Some(request) = request {
+                request.reply("paused: configuration is invalid");
+            }
+        } else if self.quiet {
+            if let Some(request) = request {
+                request.reply("paused: quiet hours");
+            }
+        } else if active && should_convert(self.settings.trigger_mode, triggered) {
+            let rules = self.config.rules.snapshot();
+            self.converter.use_stage_exclusions(&rules);
+            match self
+                .converter
+                .convert_clipboard(ctx, &rules.replacements, &rules.exclusion_list)
+            {
+                Ok(outcome) => {
+                    self.clipboard_warnings.reset();
+                    self.watchdog.clipboard_ok();
+                    if outcome == ConvertOutcome::Converted {
+                        self.conversions += 1;
+                    }
+                    if let Some(request) = request {
+                        request.reply(outcome.label());
+                    }
+                }
+                Err(e) => {
+                    if let Some(request) = request {
+                        request.reply(format!("error: {:#}", e));
+                    }
+                    self.verbosity.widen();
+                    match self.watchdog.clipboard_failed(&e) {
+                        Recovery::RecreateContext => {
+                            self.clipboard_warnings
+                                .warn(&format!("{:#}. Recreating clipboard context...", e));
+                            self.recreate_context(ctx, new_context);
+                        }
+                        Recovery::FullReinit => {
+                            self.clipboard_warnings.warn(&format!("{:#}", e));
+                            self.reinitialize(ctx, new_context);
+                        }
+                        _ => self.clipboard_warnings.warn(&format!("{:#}", e)),
+                    }
+                }
+            }
+        }
+
+        #[cfg(all(target_os = "linux", feature = "primary-selection"))]
+        if let Some(primary) = self.primary.as_mut().filter(|_| active) {
+            if let Err(e) = primary.poll(&self.config) {
+                self.clipboard_warnings
+                    .warn(&format!("PRIMARY selection: {:#}", e));
+            }
+        }
+
+        let was_failing = self.config.has_failures();
+        let poll = poll_config_changes(self.watch_rx.as_ref(), &mut self.config)?;
+        if self.config.has_failures() && !was_failing {
+            self.verbosity.widen();
+        }
+        match poll {
+            WatchPoll::Idle => self.watchdog.watcher_ok(),
+            WatchPoll::Reloaded => {
+                self.watchdog.watcher_ok();
+                self.forget_old_rules();
+            }
+            WatchPoll::Failed(message) => {
+                warn!("Config watcher failed: {}", message);
+                self.verbosity.widen();
+                match self.watchdog.watcher_failed() {
+                    Recovery::FullReinit => self.reinitialize(ctx, new_context),
+                    _ => self.recreate_watcher(),
+                }
+            }
+        }
+        if let Some(remote) = &self.remote_rules {
+            if let Ok(replacements) = remote.updates.try_recv() {
+                info!("Reloading the rules from {}...", remote.url);
+                self.config.set_remote_replacements(replacements);
+                self.forget_old_rules();
+            }
+        }
+        if let Some(observer) = &mut self.converter.observer {
+            if let Err(e) = observer.flush_if_due(now) {
+                warn!("Failed to write the observation report: {:#}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn recreate_context<C: ClipboardBackend>(
+        &mut self,
+        ctx: &mut C,
+        new_context: &impl Fn() -> Result<C, ClipboardError>,
+    ) {
+        match new_context() {
+            Ok(new_ctx) => {
+                *ctx = new_ctx;
+                self.context_warnings.reset();
+            }
+            Err(e) => self.context_warnings.warn(&e.to_string()),
+        }
+    }
+
+    // On failure the old receiver is kept, so the next poll fails again and
+    // the watchdog keeps counting towards a full re-init
+    fn recreate_watcher(&mut self) {
+        match watch_config(&self.config) {
+            Ok((watcher, rx)) => {
+                self.watcher = Some(watcher);
+                self.watch_rx = Some(rx);
+            }
+            Err(e) => warn!("Failed to recreate the config watcher: {:#}", e),
+        }
+    }
+
+    fn reinitialize<C: ClipboardBackend>(
+        &mut self,
+        ctx: &mut C,
+        new_context: &impl Fn() -> Result<C, ClipboardError>,
+    ) {
+        warn!("Watchdog: too many consecutive failures. Reinitializing the clipboard context and the config watcher.");
+        self.cli_fallback.activate();
+        self.recreate_context(ctx, new_context);
+        if self.watch_rx.is_some() {
+            self.recreate_watcher();
+        }
+    }
+}
+
+// Modes that convert and exit, or serve without watching the config. Only the
+// daemon can wait for a broken config to be fixed.
+fn is_one_shot(cli: &Cli) -> bool {
+    cli.isolate_rule.is_some()
+        || cli.explain.is_some()
+        || cli.stdin
+        || cli.pipe_server
+        || cli.fifo.is_some()
+        || cli.profile_benchmark
+        || cli.benchmark_config.is_some()
+        || cli.command.is_some()
+        || cli.interactive
+        || cli.try_rules.is_some()
+}
+
+fn main() -> Result<()> {
+    init_logger();
+    let cli = Cli::parse();
+    if let Some(Command::Completions { shell }) = cli.command {
+        write_completions(shell, &mut std::io::stdout());
+        return Ok(());
+    }
+    if let Some(Command::Merge { files }) = &cli.command {
+        let (output, merged) = merge_files(files)?;
+        for conflict in &merged.conflicts {
+            eprintln!("conflict: {}", conflict);
+        }
+        if merged.duplicates > 0 {
+            eprintln!("Dropped {} duplicate rule(s).", merged.duplicates);
+        }
+        println!("{}", output.trim_end());
+        return Ok(());
+    }
+    if let Some(Command::Setup) = cli.command {
+        return run_setup(
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout(),
+            &get_config_dir()?,
+            cli.config_format,
+        );
+    }
+    let settings_path = resolve_settings_file(&get_config_dir()?);
+    let mut settings = load_settings(
+        settings_path
+            .to_str()
+            .context("Settings path contains invalid UTF-8 characters")?,
+    )?;
+    if !cli.quiet_hours.is_empty() {
+        settings.quiet_hours = cli.quiet_hours.clone();
+    }
+
+    if let Some(Command::Convert) = cli.command {
+        let response = ipc::send_command(settings.ipc_port, &IpcCommand::Convert, None)?;
+        println!("{}", response);
+        return Ok(());
+    }
+    if let Some(Command::Stats) = cli.command {
+        let response = ipc::send_command(settings.ipc_port, &IpcCommand::Stats, None)?;
+        println!("{}", response);
+        return Ok(());
+    }
+    if let Some(Command::SetInterval { ms, for_secs }) = cli.command {
+        let command = IpcCommand::SetInterval {
+            interval: Duration::from_millis(ms),
+            duration: for_secs.map_or(DEFAULT_OVERRIDE_DURATION, Duration::from_secs),
+        };
+        let response = ipc::send_command(settings.ipc_port, &command, None)?;
+        println!("{}", response);
+        return Ok(());
+    }
+    if let Some(Command::History { restore }) = cli.command {
+        let command = match restore {
+            Some(index) => IpcCommand::Restore(index),
+            None => IpcCommand::History,
+        };
+        let token = ipc::read_token(&get_config_dir()?.join(IPC_TOKEN_FILE_NAME))?;
+        let response = ipc::send_command(settings.ipc_port, &command, Some(&token))?;
+        println!("{}", response);
+        return Ok(());
+    }
+
+    let project_dir = if cli.project {
+        find_project_config_dir(&std::env::current_dir()?)
+    } else {
+        None
+    };
+    match &project_dir {
+        Some(dir) => info!("Using project config in {}", dir.display()),
+        None if cli.project => info!("No project config found. Using the global config."),
+        None => {}
+    }
+    let global_dir = get_config_dir()?;
+    let env_replacements = std::env::var_os(REPLACEMENTS_ENV_VAR).filter(|value| !value.is_empty());
+    if env_replacements.is_some() {
+        info!("Using the replacements listed in {}.", REPLACEMENTS_ENV_VAR);
+    }
+    let replacement_paths = || {
+        resolve_replacement_files(
+            env_replacements.as_deref(),
+            project_dir.as_deref(),
+            &global_dir,
+        )
+    };
+    let exclusion_path = || resolve_config_file(None, &global_dir, EXCLUSIONS_FILE_STEM);
+    let exclusion_layer_path = project_exclusions_file(project_dir.as_deref());
+
+    if cli.dump_config {
+        let config = LoadedConfig::open(
+            replacement_paths(),
+            exclusion_path(),
+            exclusion_layer_path,
+            &settings,
+            false,
+        )?;
+        let mut effective = config.effective()?;
+        effective.reload_history = read_reload_history(&global_dir.join(RELOAD_HISTORY_FILE_NAME))?;
+        println!("{}", serde_json::to_string_pretty(&effective)?);
+        return Ok(());
+    }
+
+    match create_default_config_in(cli.config_format) {
+        // The config dir may well be read-only where KZA_REPLACEMENTS is used
+        Err(e) if env_replacements.is_some() => {
+            warn!("Failed to create the default config: {:#}", e)
+        }
+        result => result?,
+    }
+    let global_replacement_path = find_config_file(&global_dir, REPLACEMENTS_FILE_STEM);
+    if cli.migrate {
+        if let Some(combined) = find_combined_config(&global_dir) {
+            anyhow::bail!(
+                "--migrate only updates the split replacements file. Add the new defaults to {} by hand.",
+                combined.display()
+            );
+        }
+        let added = migrate_defaults(&global_dir, &global_replacement_path)?;
+        println!(
+            "Added {} new default rule(s) to {}",
+            added,
+            global_replacement_path.display()
+        );
+        return Ok(());
+    }
+    if read_defaults_version(&global_dir) < DEFAULTS_VERSION {
+        info!("Newer default rules are available. Run `kill-zen-all --migrate` to add the ones you haven't overridden.");
+    }
+    let mut converter = Converter::from_settings(&settings)?;
+    converter.dry_run = cli.dry_run;
+    converter.annotate_causes = cli.annotate_causes;
+    if cli.annotate_causes {
+        info!("Changes are colored by cause: {}", CAUSE_LEGEND);
+    }
+    converter.diff_out = cli.diff_out.clone();
+    converter.unified_diff_out = cli.unified_diff.clone();
+    if cli.tee {
+        converter.tee = Some(Tee::spawn(std::io::stdout(), cli.tee_delimiter));
+    }
+    if let Some(path) = &cli.observe {
+        converter.dry_run = true;
+        converter.observer = Some(Observer::new(path.clone())?);
+        info!(
+            "Observing: the clipboard will not be modified. Writing a report to {}.",
+            path.display()
+        );
+    } else if cli.dry_run {
+        info!("Dry run: the clipboard will not be modified.");
+    }
+
+    let mut config = LoadedConfig::open(
+        replacement_paths(),
+        exclusion_path(),
+        exclusion_layer_path,
+        &settings,
+        settings.safe_mode && !cli.strict && !is_one_shot(&cli),
+    )?;
+    config.strict = cli.strict;
+    if let Some(path) = &cli.try_rules {
+        config.override_replacements(path)?;
+        info!("Trying the replacements in {}", path.display());
+    }
+    settings.format.stage_exclusions = config.stage_exclusions.clone();
+    settings.format.compile_exclude_pattern()?;
+    if let Some(label) = &cli.isolate_rule {
+        let input = match &cli.explain {
+            Some(text) => text.clone(),
+            None if cli.stdin => {
+                let mut input = String::new();
+                std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)
+                    .context("Failed to read stdin")?;
+                input
+            }
+            None => anyhow::bail!("--isolate-rule needs --explain TEXT or --stdin"),
+        };
+        let stages = isolate_rule(
+            &input,
+            label,
+            &config.replacements,
+            &config.exclusion_list,
+            &settings.format,
+            cli.with_stages,
+        )?;
+        if cli.explain.is_some() {
+            write_explanation(&stages, &mut std::io::stdout().lock())?;
+        } else if let Some(result) = stages.last() {
+            print!("{}", result.text);
+        }
+        return Ok(());
+    }
+    if let Some(text) = &cli.explain {
+        let stages = explain_format(
+            text,
+            &config.replacements,
+            &config.exclusion_list,
+            &settings.format,
+        )?;
+        write_explanation(&stages, &mut std::io::stdout().lock())?;
+        return Ok(());
+    }
+    if cli.stdin {
+        let mut input = String::new();
+        std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)
+            .context("Failed to read stdin")?;
+        return convert_stdin(
+            &input,
+            &config,
+            &settings.format,
+            cli.json,
+            &mut std::io::stdout().lock(),
+        );
+    }
+    if cli.pipe_server {
+        info!("Serving conversions on stdin/stdout.");
+        let options = settings.format.clone();
+        return pipe::serve(std::io::stdin().lock(), std::io::stdout().lock(), |text| {
+            format_text_with_options(text, &config.replacements, &config.exclusion_list, &options)
+        });
+    }
+    if let Some(paths) = cli.fifo {
+        #[cfg(unix)]
+        {
+            let options = settings.format.clone();
+            let [input, output] = <[PathBuf; 2]>::try_from(paths)
+                .map_err(|_| anyhow::anyhow!("--fifo takes two paths"))?;
+            kill_zen_all::fifo::FifoServer::new(input, output)?.serve(|text| {
+                format_text_with_options(
+                    text,
+                    &config.replacements,
+                    &config.exclusion_list,
+                    &options,
+                )
+            });
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = paths;
+            anyhow::bail!("--fifo is only available on Unix");
+        }
+    }
+    #[cfg(feature = "type-paste")]
+    if let Some(Command::Type) = cli.command {
+        let mut ctx = create_clipboard_context().context("Failed to create context")?;
+        let keystrokes = convert_to_keystrokes(
+            &get_clipboard_contents(&mut ctx)?,
+            &config.replacements,
+            &config.exclusion_list,
+            &settings.format,
+        )?;
+        return type_keystrokes(&keystrokes);
+    }
+    if cli.profile_benchmark {
+        let input = synthetic_input(PROFILE_INPUT_BYTES);
+        let regex_rules = config
+            .replacements
+            .iter()
+            .filter(|rule| rule.pattern().is_some())
+            .count();
+        println!(
+            "Rules: {} ({} regex), {} exclusions",
+            config.replacements.len(),
+            regex_rules,
+            config.exclusion_list.len()
+        );
+        println!("Input: {} KiB of mixed text", input.len() / 1024);
+        let throughput = measure_throughput(
+            &input,
+            &config.replacements,
+            &config.exclusion_list,
+            &settings.format,
+            PROFILE_DURATION,
+        )?;
+        println!(
+            "Throughput: {:.2} MB/s, {:.1} ns/char ({} runs in {:.2?})",
+            throughput.mb_per_sec(),
+            throughput.ns_per_char(),
+            throughput.iterations,
+            throughput.elapsed
+        );
+        return Ok(());
+    }
+    if let Some(min_mb_per_sec) = cli.benchmark_config {
+        match check_min_throughput(
+            &config.replacements,
+            &config.exclusion_list,
+            &settings.format,
+            min_mb_per_sec,
+            GUARD_DURATION,
+        )? {
+            GuardOutcome::Passed(throughput) => info!(
+                "Benchmark passed: {:.2} MB/s (minimum {:.2} MB/s).",
+                throughput.mb_per_sec(),
+                min_mb_per_sec
+            ),
+            GuardOutcome::TooSlow(throughput) if cli.benchmark_warn_only => warn!(
+                "The rules convert at {:.2} MB/s, below the minimum of {:.2} MB/s. Copies may be slow to convert.",
+                throughput.mb_per_sec(),
+                min_mb_per_sec
+            ),
+            GuardOutcome::TooSlow(throughput) => anyhow::bail!(
+                "The rules convert at {:.2} MB/s, below the minimum of {:.2} MB/s. Run --profile-benchmark to measure them.",
+                throughput.mb_per_sec(),
+                min_mb_per_sec
+            ),
+        }
+    }
+    if let Some(Command::RenameFiles { paths, apply }) = &cli.command {
+        // One file that can't be renamed doesn't stop the others
+        let mut failed = 0;
+        for path in paths {
+            let convert = |name: &str| {
+                format_text_with_options(
+                    name,
+                    &config.replacements,
+                    &config.exclusion_list,
+                    &settings.format,
+                )
+            };
+            match rename_to_halfwidth(path, convert, *apply) {
+                Ok(RenameOutcome::Unchanged) => {}
+                Ok(RenameOutcome::WouldRename(target)) => {
+                    println!("{} -> {}", path.display(), target.display())
+                }
+                Ok(RenameOutcome::Renamed(target)) => {
+                    info!("Renamed {} to {}", path.display(), target.display())
+                }
+                Ok(RenameOutcome::Skipped(reason)) => {
+                    warn!("Skipping {}: {}", path.display(), reason)
+                }
+                Err(e) => {
+                    warn!("{:#}", e);
+                    failed += 1;
+                }
+            }
+        }
+        if !apply {
+            println!("Nothing was renamed. Run again with --apply to rename.");
+        }
+        if failed > 0 {
+            anyhow::bail!("{} of {} files could not be renamed", failed, paths.len());
+        }
+        return Ok(());
+    }
+    if cli.interactive {
+        if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+            anyhow::bail!("--interactive needs a terminal on stdin and stdout");
+        }
+        let mut ctx: ClipboardContext =
+            create_clipboard_context().context("Failed to create context")?;
+        let outcome = converter.convert_interactively(
+            &mut ctx,
+            &config.replacements,
+            &config.exclusion_list,
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout(),
+        )?;
+        println!("{}", outcome.label());
+        return Ok(());
+    }
+    if cli.try_rules.is_some() {
+        // The same clipboard the daemon would use, so a hung clipboard owner
+        // times out here too
+        let mut ctx = create_timed_clipboard(
+            settings.clipboard_timeout(),
+            &CliFallback::new(settings.clipboard_cli_fallback),
+            settings.osc52.then(Osc52::detect),
+        )
+        .context("Failed to create context")?;
+        let outcome =
+            converter.convert_clipboard(&mut ctx, &config.replacements, &config.exclusion_list)?;
+        println!("{}", outcome.label());
+        return Ok(());
+    }
+    let excluded_letters = fullwidth_latin_exclusions(&config.exclusion_list);
+    if !excluded_letters.is_empty() {
+        // The list itself already loaded, so a failure here only loses the reasons
+        let reasons = config
+            .exclusion_path
+            .to_str()
+            .and_then(|path| {
+                let layer = config
+                    .exclusion_layer_path
+                    .as_deref()
+                    .and_then(Path::to_str);
+                load_exclusion_reasons(path, layer).ok()
+            })
+            .unwrap_or_default();
+        warn!(
+            "{} fullwidth Latin letter(s) are excluded and will never be converted: {}. Remove them from the exclusions unless this is intended.",
+            excluded_letters.len(),
+            describe_exclusions(&excluded_letters, &reasons)
+        );
+    }
+    let (watcher, watch_rx) = if cli.no_watch {
+        info!("File watching is disabled.");
+        (None, None)
+    } else {
+        let (watcher, rx) = watch_config(&config)?;
+        (Some(watcher), Some(rx))
+    };
+    let clipboard_timeout = settings.clipboard_timeout();
+    let cli_fallback = CliFallback::new(settings.clipboard_cli_fallback);
+    if let Some(target) = settings.text_flavor.target() {
+        if !cli_fallback.prefer_for_target(target) {
+            warn!(
+                "text_flavor: writing as {} needs wl-copy or xclip with clipboard_cli_fallback. Using the default targets.",
+                target
+            );
+        }
+    }
+    let osc52 = settings.osc52.then(Osc52::detect);
+    if let Some(osc52) = osc52 {
+        info!(
+            "Writing the clipboard through OSC 52{}.",
+            if osc52.tmux {
+                " (tmux passthrough)"
+            } else {
+                ""
+            }
+        );
+    }
+    let mut ctx = create_timed_clipboard(clipboard_timeout, &cli_fallback, osc52)
+        .context("Failed to create context")?;
+    let ipc_rx = if settings.ipc_required() {
+        let listener = ipc::bind(settings.ipc_port)?;
+        // Without the file only history and restore are out of reach
+        let token = get_config_dir()
+            .map_err(Into::into)
+            .and_then(|dir| ipc::create_token(&dir.join(IPC_TOKEN_FILE_NAME)))
+            .unwrap_or_else(|e| {
+                warn!("{:#}. `kill-zen-all history` will be refused.", e);
+                ipc::new_token()
+            });
+        info!("Listening for IPC commands on port {}", settings.ipc_port);
+        Some(ipc::spawn_server(listener, token))
+    } else {
+        None
+    };
+    if settings.trigger_mode == TriggerMode::Manual {
+        info!("Trigger mode is manual. Run `kill-zen-all convert` to convert the clipboard.");
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "focus-convert")))]
+    if !settings.focus_convert_apps.is_empty() {
+        warn!(
+            "focus_convert_apps needs a Linux build with the `focus-convert` feature. Ignoring it."
+        );
+    }
+    #[cfg(not(all(target_os = "linux", feature = "focus-convert")))]
+    if !settings.skip_window_titles.is_empty() {
+        warn!(
+            "skip_window_titles needs a Linux build with the `focus-convert` feature. Ignoring it."
+        );
+    }
+    #[cfg(all(target_os = "linux", feature = "primary-selection"))]
+    let primary = PrimarySelection::from_settings(&settings)?;
+    #[cfg(not(all(target_os = "linux", feature = "primary-selection")))]
+    if settings.primary_selection {
+        warn!("primary_selection needs a Linux build with the `primary-selection` feature. Ignoring it.");
+    }
+    #[cfg(not(feature = "idle-pause"))]
+    if settings.idle_pause().is_some() {
+        warn!("idle_pause_secs needs a build with the `idle-pause` feature. Ignoring it.");
+    }
+    #[cfg(not(all(
+        target_os = "linux",
+        any(feature = "primary-selection", feature = "skip-modifier")
+    )))]
+    if settings.skip_modifier.is_some() {
+        warn!("skip_modifier needs a Linux build with the `skip-modifier` feature. Ignoring it.");
+    }
+    #[cfg(not(feature = "desktop-notify"))]
+    if settings.on_convert_feedback == ConvertFeedback::Notify {
+        warn!("on_convert_feedback = \"notify\" needs a build with the `desktop-notify` feature. Ignoring it.");
+    }
+
+    if replace_strategy(&config.replacements, &settings.format) == ReplaceStrategy::Automaton {
+        info!(
+            "Matching the {} replacement rules in a single pass.",
+            config.replacements.len()
+        );
+    }
+    if settings.check_idempotent {
+        let rules = config.rules.snapshot();
+        match check_idempotent(&rules, &settings.format, &idempotency_samples(&rules)) {
+            Ok(()) => info!("Self-test passed: converting again changes nothing."),
+            Err(e) => warn!(
+                "Self-test failed: {}. The rules keep changing their own output, so the clipboard will be rewritten over and over.",
+                e
+            ),
+        }
+    }
+    if settings.enforce_canonical && settings.output_mode != OutputMode::Replace {
+        warn!("enforce_canonical writes the converted text only. Ignoring output_mode.");
+    }
+    if settings.persist_snapshots {
+        let path = global_dir.join(SNAPSHOTS_FILE_NAME);
+        match Snapshots::persisted(path, settings.snapshot_limit) {
+            Ok(snapshots) => converter.snapshots = snapshots,
+            Err(e) => warn!(
+                "Failed to read the conversion history: {:#}. Starting a new one.",
+                e
+            ),
+        }
+    }
+    #[cfg(feature = "plugins")]
+    for path in &settings.plugins {
+        match Plugin::load(path) {
+            Ok(plugin) => {
+                info!("Loaded the plugin {}", plugin.name());
+                converter.plugins.push(plugin);
+            }
+            Err(e) => warn!("{:#}. Skipping it.", e),
+        }
+    }
+    #[cfg(not(feature = "plugins"))]
+    if !settings.plugins.is_empty() {
+        warn!("plugins needs a build with the `plugins` feature. Ignoring it.");
+    }
+    if let Some(path) = &settings.audit_log {
+        match AuditLog::open(path.clone()) {
+            Ok(audit) => {
+                info!("Recording conversions in {}", audit.path().display());
+                converter.audit = Some(audit);
+            }
+            Err(e) => warn!("Failed to open the audit log: {:#}. Not recording.", e),
+        }
+    }
+    if settings.reload_history {
+        config
+            .history
+            .persist_to(global_dir.join(RELOAD_HISTORY_FILE_NAME));
+    }
+    config.record_loaded();
+    #[cfg(feature = "rules-url")]
+    let remote_rules = match settings.rules_url.clone() {
+        Some(url) => {
+            let interval = settings
+                .rules_url_interval_secs
+                .max(MIN_RULES_URL_INTERVAL_SECS);
+            let mut remote = RemoteRules::new(
+                url,
+                Duration::from_secs(interval),
+                Box::new(HttpSource::new(RULES_URL_TIMEOUT)),
+            )?
+            .with_cache(global_dir.join(RULES_URL_CACHE_FILE_NAME));
+            if let Some(cached) = remote.load_cached() {
+                info!(
+                    "Using the cached rules from {} until it is fetched.",
+                    remote.url()
+                );
+                config.set_remote_replacements(cached);
+            }
+            Some(remote.spawn()?)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "rules-url"))]
+    let remote_rules = {
+        if settings.rules_url.is_some() {
+            warn!("rules_url needs a build with the `rules-url` feature. Ignoring it.");
+        }
+        None
+    };
+    let mut daemon = Daemon::new(settings, config, converter);
+    daemon.remote_rules = remote_rules;
+    daemon.watcher = watcher;
+    daemon.watch_rx = watch_rx;
+    daemon.ipc_rx = ipc_rx;
+    daemon.cli_fallback = cli_fallback.clone();
+    #[cfg(all(target_os = "linux", feature = "primary-selection"))]
+    {
+        daemon.primary = primary;
+    }
+    // Only the long-running daemon traps Ctrl-C, to save its state on the way
+    // out; a bounded smoke-check run ends by itself and is killed as usual
+    if cli.max_iterations.is_none() {
+        let shutdown = Arc::clone(&daemon.shutdown);
+        ctrlc::set_handler(move || shutdown.store(true, Ordering::SeqCst))
+            .context("Failed to install the shutdown handler")?;
+    }
+    if !daemon.settings.process_existing && daemon.settings.trigger_mode == TriggerMode::Continuous
+    {
+        daemon.converter.skip_existing(&mut ctx);
+    }
+    let result = daemon.run(
+        &mut ctx,
+        || create_timed_clipboard(clipboard_timeout, &cli_fallback, osc52),
+        cli.max_iterations,
+    );
+    daemon.log_summary();
+    daemon.record_usage();
+    if let Some(observer) = &mut daemon.converter.observer {
+        if let Err(e) = observer.flush() {
+            warn!("Failed to write the observation report: {:#}", e);
+        }
+    }
+    result
+}
+
+// Test code
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kill_zen_all::format_text;
+    use kill_zen_all::history::short_hash;
+    use kill_zen_all::ConfigError;
+    use std::cell::{Cell, RefCell};
+    use std::fs;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+    use tempfile::tempdir;
+
+    // Test for get_clipboard_contents
+    use std::collections::VecDeque;
+
+    #[derive(Default)]
+    struct MockClipboard {
+        get_results: VecDeque<Result<String, ClipboardError>>,
+        set_results: VecDeque<Result<(), ClipboardError>>,
+        get_calls: usize,
+        set_calls: usize,
+        content: String,
+        // Overrides `content` with bytes that need not be valid UTF-8
+        raw: Option<Vec<u8>>,
+        sensitive: bool,
+        sensitive_checks: usize,
+        html: Option<String>,
+        // The target of the last write, when one was asked for
+        flavor: Option<TextFlavor>,
+    }
+
+    impl ClipboardBackend for MockClipboard {
+        fn get_bytes(&mut self) -> Result<Vec<u8>, ClipboardError> {
+            self.get_calls += 1;
+            if let Some(raw) = &self.raw {
+                return Ok(raw.clone());
+            }
+            self.get_results
+                .pop_front()
+                .unwrap_or_else(|| Ok(self.content.clone()))
+                .map(String::into_bytes)
+        }
+
+        fn set_text(&mut self, content: String) -> Result<(), ClipboardError> {
+            self.set_calls += 1;
+            if let Some(result) = self.set_results.pop_front() {
+                result?;
+            }
+            self.content = content;
+            // Like a real clipboard, a plain text write replaces every flavor
+            self.html = None;
+            Ok(())
+        }
+
+        fn is_sensitive(&mut self) -> bool {
+            self.sensitive_checks += 1;
+            self.sensitive
+        }
+
+        fn set_text_as(
+            &mut self,
+            content: String,
+            flavor: TextFlavor,
+        ) -> Result<(), ClipboardError> {
+            self.set_text(content)?;
+            self.flavor = Some(flavor);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_clipboard_error_classification() {
+        assert!(
+            ClipboardError::GetContents("Access is denied. (os error 5)".to_string())
+                .is_transient()
+        );
+        assert!(ClipboardError::GetContents("Selection timed out".to_string()).is_transient());
+        assert!(!ClipboardError::GetContents("Connection refused".to_string()).is_transient());
+        assert!(!ClipboardError::CreateContext("Access is denied".to_string()).is_transient());
+    }
+
+    #[test]
+    fn test_get_clipboard_contents_retries_transient_errors() {
+        let mut ctx = MockClipboard {
+            get_results: VecDeque::from(vec![
+                Err(ClipboardError::GetContents("Access is denied".to_string())),
+                Err(ClipboardError::GetContents("Access is denied".to_string())),
+                Ok("foo".to_string()),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(get_clipboard_contents(&mut ctx).unwrap(), "foo");
+        assert_eq!(ctx.get_calls, 3);
+    }
+
+    #[test]
+    fn test_get_clipboard_contents_gives_up_after_retry_limit() {
+        let mut ctx = MockClipboard {
+            get_results: (0..=TRANSIENT_RETRY_LIMIT)
+                .map(|_| Err(ClipboardError::GetContents("Access is denied".to_string())))
+                .collect(),
+            ..Default::default()
+        };
+        assert!(get_clipboard_contents(&mut ctx).is_err());
+        assert_eq!(ctx.get_calls, TRANSIENT_RETRY_LIMIT + 1);
+    }
+
+    #[test]
+    fn test_set_clipboard_contents_retries_transient_errors() {
+        let mut ctx = MockClipboard {
+            set_results: VecDeque::from(vec![
+                Err(ClipboardError::SetContents(
+                    "Clipboard is locked".to_string(),
+                )),
+                Ok(()),
+            ]),
+            ..Default::default()
+        };
+        set_clipboard_contents(&mut ctx, "foo".to_string(), TextFlavor::Default).unwrap();
+        assert_eq!(ctx.set_calls, 2);
+        assert_eq!(ctx.content, "foo");
+    }
+
+    #[test]
+    fn test_repeated_set_failures_escalate_to_new_context() {
+        let mut ctx = MockClipboard {
+            content: "ＡＢＣ".to_string(),
+            set_results: (0..SET_FAILURE_ESCALATION)
+                .map(|_| {
+                    Err(ClipboardError::SetContents(
+                        "Connection refused".to_string(),
+                    ))
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut converter = Converter::default();
+        let mut watchdog = Watchdog::default();
+
+        for _ in 1..SET_FAILURE_ESCALATION {
+            let e = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap_err();
+            assert_eq!(watchdog.clipboard_failed(&e), Recovery::Wait);
+        }
+        let e = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap_err();
+        assert_eq!(watchdog.clipboard_failed(&e), Recovery::RecreateContext);
+        assert_eq!(ctx.set_calls, SET_FAILURE_ESCALATION);
+
+        // A failed read recreates the context straight away
+        ctx.get_results.push_back(Err(ClipboardError::GetContents(
+            "Connection refused".to_string(),
+        )));
+        let e = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap_err();
+        assert_eq!(watchdog.clipboard_failed(&e), Recovery::RecreateContext);
+    }
+
+    // Test for the command-line clipboard fallback
+    #[test]
+    fn test_cli_fallback_switches_once_a_tool_is_known() {
+        let none = CliFallback::default();
+        none.activate();
+        assert_eq!(none.active_tool(), None);
+
+        let fallback = CliFallback {
+            tool: Some(CliTool::Xclip),
+            ..Default::default()
+        };
+        let shared = fallback.clone();
+        assert_eq!(fallback.active_tool(), None);
+        shared.activate();
+        assert_eq!(fallback.active_tool(), Some(CliTool::Xclip));
+        assert!(matches!(
+            create_clipboard_backend(&fallback),
+            Ok(FallbackClipboard::Cli(CliTool::Xclip))
+        ));
+    }
+
+    #[test]
+    fn test_text_flavor_prefers_a_tool_that_can_choose_the_target() {
+        let pbcopy = CliFallback {
+            tool: Some(CliTool::Pasteboard),
+            ..Default::default()
+        };
+        assert!(!pbcopy.prefer_for_target("UTF8_STRING"));
+        assert_eq!(pbcopy.active_tool(), None);
+
+        let xclip = CliFallback {
+            tool: Some(CliTool::Xclip),
+            ..Default::default()
+        };
+        assert!(xclip.prefer_for_target("UTF8_STRING"));
+        assert_eq!(xclip.active_tool(), Some(CliTool::Xclip));
+    }
+
+    // Test for osc52
+    #[test]
+    fn test_osc52_reads_from_the_clipboard_beneath() {
+        let fallback = CliFallback {
+            tool: Some(CliTool::Xclip),
+            ..Default::default()
+        };
+        fallback.activate();
+        let osc52 = Osc52 { tmux: false };
+        assert!(matches!(
+            create_osc52_backend(&fallback, osc52),
+            Ok(FallbackClipboard::Osc52(backend)) if matches!(backend.reader, FallbackClipboard::Cli(CliTool::Xclip))
+        ));
+    }
+
+    #[test]
+    fn test_osc52_write_is_not_repeated() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let settings = Settings::default();
+        let converter = Converter::from_settings(&settings).unwrap();
+        let mut daemon = Daemon::new(settings, config, converter);
+        daemon.poll_interval = Duration::ZERO;
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&sent);
+        let reader = MockClipboard {
+            content: "ＡＢＣ".to_string(),
+            ..Default::default()
+        };
+        let mut ctx = Osc52Clipboard::with_sink(
+            reader,
+            Box::new(move |text| {
+                sink.borrow_mut().push(text.to_string());
+                Ok(())
+            }),
+        );
+        let new_context = || -> Result<Osc52Clipboard<MockClipboard>, ClipboardError> {
+            unreachable!("the context is not recreated")
+        };
+        daemon.run(&mut ctx, new_context, Some(2)).unwrap();
+        // The clipboard beneath still holds the original on the second tick
+        assert_eq!(ctx.reader.content, "ＡＢＣ");
+        assert_eq!(*sent.borrow(), ["ABC"]);
+        assert_eq!(daemon.conversions, 1);
+
+        // Copying something else converts again
+        ctx.reader.content = "１２３".to_string();
+        daemon.run(&mut ctx, new_context, Some(2)).unwrap();
+        assert_eq!(*sent.borrow(), ["ABC", "123"]);
+        assert_eq!(daemon.conversions, 2);
+    }
+
+    // Test for the watchdog
+    #[test]
+    fn test_watchdog_full_reinit_after_repeated_failures() {
+        let mut watchdog = Watchdog::default();
+        let read_error = anyhow::Error::from(ClipboardError::GetContents("gone".to_string()));
+        for _ in 1..WATCHDOG_REINIT_THRESHOLD {
+            assert_eq!(
+                watchdog.clipboard_failed(&read_error),
+                Recovery::RecreateContext
+            );
+        }
+        assert_eq!(watchdog.clipboard_failed(&read_error), Recovery::FullReinit);
+        // Counting starts over after a re-init
+        assert_eq!(
+            watchdog.clipboard_failed(&read_error),
+            Recovery::RecreateContext
+        );
+
+        // A success in between resets the count
+        watchdog.clipboard_ok();
+        for _ in 1..WATCHDOG_REINIT_THRESHOLD {
+            watchdog.clipboard_failed(&read_error);
+        }
+        assert_eq!(watchdog.clipboard_failed(&read_error), Recovery::FullReinit);
+
+        for _ in 1..WATCHDOG_REINIT_THRESHOLD {
+            assert_eq!(watchdog.watcher_failed(), Recovery::RecreateWatcher);
+        }
+        assert_eq!(watchdog.watcher_failed(), Recovery::FullReinit);
+
+        // Errors that are not about the clipboard never escalate
+        let rule_error = anyhow::anyhow!("invalid rule");
+        for _ in 0..WATCHDOG_REINIT_THRESHOLD {
+            assert_eq!(watchdog.clipboard_failed(&rule_error), Recovery::Wait);
+        }
+    }
+
+    #[test]
+    fn test_disconnected_watcher_is_reported() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = write_test_config(temp_dir.path());
+        let (tx, rx) = channel();
+        drop(tx);
+        assert!(matches!(
+            poll_config_changes(Some(&rx), &mut config).unwrap(),
+            WatchPoll::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn test_native_invalid_utf8_keeps_its_bytes() {
+        let invalid = String::from_utf8(vec![b'a', 0xff]).unwrap_err();
+        assert_eq!(
+            contents_to_bytes(Err(Box::new(invalid))).unwrap(),
+            vec![b'a', 0xff]
+        );
+        assert!(matches!(
+            contents_to_bytes(Err("timed out".into())),
+            Err(ClipboardError::GetContents(message)) if message == "timed out"
+        ));
+        assert_eq!(
+            contents_to_bytes(Ok("Ａ".to_string())).unwrap(),
+            "Ａ".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_get_clipboard_contents_rejects_invalid_utf8() {
+        let mut ctx = MockClipboard {
+            raw: Some(vec![b'a', 0xff, 0xfe]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            get_clipboard_contents(&mut ctx),
+            Err(ClipboardError::InvalidUtf8(_))
+        ));
+        assert_eq!(ctx.get_calls, 1);
+
+        let outcome = Converter::default()
+            .convert_clipboard(&mut ctx, &[], &[])
+            .unwrap();
+        assert_eq!(outcome, ConvertOutcome::SkippedInvalid);
+        assert_eq!(ctx.set_calls, 0);
+    }
+
+    #[test]
+    fn test_get_clipboard_contents_does_not_retry_fatal_errors() {
+        let mut ctx = MockClipboard {
+            get_results: VecDeque::from(vec![
+                Err(ClipboardError::GetContents(
+                    "Connection refused".to_string(),
+                )),
+                Ok("foo".to_string()),
+            ]),
+            ..Default::default()
+        };
+        assert!(get_clipboard_contents(&mut ctx).is_err());
+        assert_eq!(ctx.get_calls, 1);
+    }
+
+    // Test for completions
+    #[test]
+    fn test_write_completions() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let mut out = Vec::new();
+            write_completions(shell, &mut out);
+            let script = String::from_utf8(out).unwrap();
+            assert!(script.contains("kill-zen-all"), "{}", shell);
+            assert!(script.contains("dump-config"), "{}", shell);
+        }
+    }
+
+    // Test for audit_log
+    #[test]
+    fn test_conversion_is_audited_without_its_text() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("audit.jsonl");
+        let mut converter = Converter {
+            audit: Some(AuditLog::open(path.clone()).unwrap()),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "ＡＢＣ".to_string(),
+            ..Default::default()
+        };
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        // An unchanged clipboard is not a conversion
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+
+        let log = fs::read_to_string(&path).unwrap();
+        assert_eq!(log.lines().count(), 1);
+        let record: serde_json::Value = serde_json::from_str(log.trim_end()).unwrap();
+        assert_eq!(
+            record["original_sha256"],
+            kill_zen_all::audit::sha256_hex("ＡＢＣ")
+        );
+        assert_eq!(
+            record["converted_sha256"],
+            kill_zen_all::audit::sha256_hex("ABC")
+        );
+        assert!(!log.contains("ＡＢＣ") && !log.contains("ABC"));
+    }
+
+    // Test for --dry-run
+    #[test]
+    fn test_dry_run_appends_diff_record() {
+        let temp_dir = tempdir().unwrap();
+        let diff_out = temp_dir.path().join("diff.jsonl");
+        let mut converter = Converter {
+            dry_run: true,
+            diff_out: Some(diff_out.clone()),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "ＡＢＣ".to_string(),
+            ..Default::default()
+        };
+
+        for _ in 0..3 {
+            let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+            assert_ne!(outcome, ConvertOutcome::Converted);
+        }
+        assert_eq!(ctx.content, "ＡＢＣ");
+        assert_eq!(ctx.set_calls, 0);
+
+        let records = fs::read_to_string(&diff_out).unwrap();
+        let lines: Vec<&str> = records.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["before"], "ＡＢＣ");
+        assert_eq!(record["after"], "ABC");
+        assert_eq!(record["labels"], serde_json::json!(["width conversion"]));
+        assert!(record["timestamp"].is_u64());
+    }
+
+    #[test]
+    fn test_diff_record_failure_does_not_fail_the_conversion() {
+        let temp_dir = tempdir().unwrap();
+        let mut converter = Converter {
+            dry_run: true,
+            diff_out: Some(temp_dir.path().join("missing").join("diff.jsonl")),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "ＡＢＣ".to_string(),
+            ..Default::default()
+        };
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::DryRun);
+    }
+
+    // Test for --unified-diff
+    #[test]
+    fn test_conversion_appends_unified_diff() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("clipboard.diff");
+        let mut converter = Converter {
+            unified_diff_out: Some(path.clone()),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "ＡＢＣ".to_string(),
+            ..Default::default()
+        };
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(ctx.content, "ABC");
+
+        let diff = fs::read_to_string(&path).unwrap();
+        assert!(diff.starts_with("--- clipboard\t"));
+        assert!(diff.ends_with("@@ -1,1 +1,1 @@\n-ＡＢＣ\n+ABC\n"));
+    }
+
+    #[test]
+    fn test_unified_diff_failure_does_not_fail_the_conversion() {
+        let temp_dir = tempdir().unwrap();
+        let mut converter = Converter {
+            unified_diff_out: Some(temp_dir.path().join("missing").join("clipboard.diff")),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "ＡＢＣ".to_string(),
+            ..Default::default()
+        };
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::Converted);
+        assert_eq!(ctx.content, "ABC");
+    }
+
+    // Test for --observe
+    #[test]
+    fn test_observer_aggregates_clipboard_values() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("observe.json");
+        let mut converter = Converter {
+            dry_run: true,
+            observer: Some(Observer::new(path.clone()).unwrap()),
+            ..Default::default()
+        };
+        let rules = vec![Replacement {
+            original: "foo".to_string(),
+            replacement: "bar".to_string(),
+            ..Default::default()
+        }];
+        let mut ctx = MockClipboard::default();
+        for content in ["foo １", "foo １", "ＡＢ", "nothing", "foo"] {
+            ctx.content = content.to_string();
+            converter.convert_clipboard(&mut ctx, &rules, &[]).unwrap();
+        }
+        assert_eq!(ctx.set_calls, 0);
+
+        let observer = converter.observer.as_mut().unwrap();
+        // The repeated clipboard value is only observed once
+        assert_eq!(observer.report().conversions, 3);
+        assert_eq!(observer.report().rules.get("foo"), Some(&2));
+        assert_eq!(observer.report().chars.get("１"), Some(&1));
+        assert_eq!(observer.report().chars.get("Ｂ"), Some(&1));
+        observer.flush().unwrap();
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("\"conversions\": 3"));
+    }
+
+    // Test for output_mode
+    #[test]
+    fn test_append_output_is_not_reprocessed() {
+        let mut converter = Converter {
+            output_mode: OutputMode::Append,
+            output_separator: "\n".to_string(),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "ＡＢＣ".to_string(),
+            ..Default::default()
+        };
+
+        for _ in 0..3 {
+            converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        }
+        assert_eq!(ctx.content, "ＡＢＣ\nABC");
+        assert_eq!(ctx.set_calls, 1);
+    }
+
+    // Test for interval_convert
+    #[test]
+    fn test_interval_due() {
+        let start = Instant::now();
+        let interval = Some(Duration::from_secs(30));
+        assert!(!interval_due(interval, start, start));
+        assert!(!interval_due(
+            interval,
+            start,
+            start + Duration::from_secs(29)
+        ));
+        assert!(interval_due(
+            interval,
+            start,
+            start + Duration::from_secs(30)
+        ));
+        assert!(interval_due(
+            interval,
+            start,
+            start + Duration::from_secs(90)
+        ));
+        assert!(!interval_due(None, start, start + Duration::from_secs(90)));
+    }
+
+    // Test for trigger_mode
+    #[test]
+    fn test_manual_trigger_mode_waits_for_trigger() {
+        let mut ctx = MockClipboard {
+            content: "foo １２３".to_string(),
+            ..Default::default()
+        };
+        let replacements = vec![];
+        let exclusion_list = vec![];
+        let mut converter = Converter::default();
+
+        for _ in 0..3 {
+            if should_convert(TriggerMode::Manual, false) {
+                converter
+                    .convert_clipboard(&mut ctx, &replacements, &exclusion_list)
+                    .unwrap();
+            }
+        }
+        assert_eq!(ctx.content, "foo １２３");
+        assert_eq!(ctx.get_calls, 0);
+
+        if should_convert(TriggerMode::Manual, true) {
+            assert_eq!(
+                converter
+                    .convert_clipboard(&mut ctx, &replacements, &exclusion_list)
+                    .unwrap(),
+                ConvertOutcome::Converted
+            );
+        }
+        assert_eq!(ctx.content, "foo 123");
+    }
+
+    #[test]
+    fn test_continuous_trigger_mode_always_converts() {
+        assert!(should_convert(TriggerMode::Continuous, false));
+        assert!(should_convert(TriggerMode::Continuous, true));
+    }
+
+    // Test for --no-watch
+    fn write_test_config(dir: &std::path::Path) -> LoadedConfig {
+        let replacement_path = dir.join("replacements.json");
+        let exclusion_path = dir.join("exclusions.json");
+        fs::write(
+            &replacement_path,
+            r#"[{"original": "foo", "replacement": "bar"}]"#,
+        )
+        .unwrap();
+        fs::write(&exclusion_path, r#"{"exclude": ["！"]}"#).unwrap();
+        LoadedConfig::load(
+            vec![replacement_path],
+            exclusion_path,
+            None,
+            ReloadHashStrategy::Parsed,
+            false,
+            false,
+        )
+        .unwrap()
+    }
+
+    // Test for --dump-config
+    #[test]
+    fn test_dump_config_merges_every_file() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let replacement_path = dir.join("replacements.json");
+        let extra_path = dir.join("extra.json");
+        let exclusion_path = dir.join("exclusions.json");
+        let layer_path = dir.join("layer.json");
+        fs::write(
+            &replacement_path,
+            r#"[{"original": "foo", "replacement": "bar"}, {"original": "（注）", "action": "delete"}]"#,
+        )
+        .unwrap();
+        fs::write(&extra_path, r#"[{"original": "頚", "replacement": "頸"}]"#).unwrap();
+        fs::write(&exclusion_path, r#"{"exclude": ["！"]}"#).unwrap();
+        fs::write(
+            &layer_path,
+            r#"{"exclude": [{"char": "？", "reason": "questions"}], "unexclude": ["！"]}"#,
+        )
+        .unwrap();
+        let settings = Settings::default();
+
+        let config = LoadedConfig::open(
+            vec![replacement_path, extra_path],
+            exclusion_path,
+            Some(layer_path),
+            &settings,
+            false,
+        )
+        .unwrap();
+        let dumped = serde_json::to_value(config.effective().unwrap()).unwrap();
+        assert_eq!(
+            dumped,
+            serde_json::json!({
+                "replacements": [
+                    {"original": "foo", "replacement": "bar", "action": "replace"},
+                    {"original": "頚", "replacement": "頸", "action": "replace"},
+                    {"original": "（注）", "replacement": "", "action": "delete"}
+                ],
+                "exclusions": ["？"],
+                "exclusion_reasons": {"？": "questions"}
+            })
+        );
+    }
+
+    // Test for describe_exclusions
+    #[test]
+    fn test_describe_exclusions_includes_reasons() {
+        let reasons = BTreeMap::from([('Ｚ', "model numbers".to_string())]);
+        assert_eq!(
+            describe_exclusions(&['Ａ', 'Ｚ'], &reasons),
+            "Ａ, Ｚ (model numbers)"
+        );
+    }
+
+    // Test for disabled_defaults
+    #[test]
+    fn test_disabled_default_has_no_effect() {
+        let temp_dir = tempdir().unwrap();
+        let replacement_path = temp_dir.path().join("replacements.json");
+        let exclusion_path = temp_dir.path().join("exclusions.json");
+        fs::write(&replacement_path, include_str!("default_replacements.json")).unwrap();
+        fs::write(&exclusion_path, "{}").unwrap();
+        let mut config = LoadedConfig::load(
+            vec![replacement_path],
+            exclusion_path,
+            None,
+            ReloadHashStrategy::Parsed,
+            false,
+            false,
+        )
+        .unwrap();
+        config.disable_defaults(&["CRLF".to_string()]);
+
+        let mut ctx = MockClipboard {
+            content: "CRLF頚".to_string(),
+            ..Default::default()
+        };
+        Converter::default()
+            .convert_clipboard(&mut ctx, &config.replacements, &config.exclusion_list)
+            .unwrap();
+        assert_eq!(ctx.content, "CRLF頸");
+    }
+
+    // Test for --stdin --json
+    #[test]
+    fn test_convert_stdin_json_output() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let mut out = Vec::new();
+        convert_stdin(
+            "foo１！",
+            &config,
+            &FormatOptions::default(),
+            true,
+            &mut out,
+        )
+        .unwrap();
+
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(report["input"], "foo１！");
+        assert_eq!(report["output"], "bar1！");
+        assert_eq!(report["changed"], true);
+        assert_eq!(report["rules_fired"], serde_json::json!(["foo"]));
+
+        let mut out = Vec::new();
+        convert_stdin(
+            "foo１！",
+            &config,
+            &FormatOptions::default(),
+            false,
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "bar1！");
+    }
+
+    // Test for --try-rules
+    #[test]
+    fn test_try_rules_overrides_without_touching_config() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = write_test_config(temp_dir.path());
+        let installed = fs::read_to_string(&config.replacement_path).unwrap();
+        let try_dir = tempdir().unwrap();
+        let try_path = try_dir.path().join("rules.json");
+        fs::write(&try_path, r#"[{"original": "foo", "replacement": "baz"}]"#).unwrap();
+
+        config.override_replacements(&try_path).unwrap();
+        let mut ctx = MockClipboard {
+            content: "foo！".to_string(),
+            ..Default::default()
+        };
+        let outcome = Converter::default()
+            .convert_clipboard(&mut ctx, &config.replacements, &config.exclusion_list)
+            .unwrap();
+        assert_eq!(outcome, ConvertOutcome::Converted);
+        // The installed exclusions still apply
+        assert_eq!(ctx.content, "baz！");
+        assert_eq!(
+            config.replacement_path,
+            temp_dir.path().join("replacements.json")
+        );
+        assert_eq!(
+            fs::read_to_string(&config.replacement_path).unwrap(),
+            installed
+        );
+    }
+
+    #[test]
+    fn test_empty_config_still_converts_width() {
+        let temp_dir = tempdir().unwrap();
+        let replacement_path = temp_dir.path().join("replacements.json");
+        let exclusion_path = temp_dir.path().join("exclusions.json");
+        fs::write(&replacement_path, "[]").unwrap();
+        fs::write(&exclusion_path, r#"{"exclude": []}"#).unwrap();
+        let config = LoadedConfig::load(
+            vec![replacement_path],
+            exclusion_path,
+            None,
+            ReloadHashStrategy::Parsed,
+            false,
+            false,
+        )
+        .unwrap();
+        let mut ctx = MockClipboard {
+            content: "ＡＢＣ　１２３！".to_string(),
+            ..Default::default()
+        };
+
+        Converter::default()
+            .convert_clipboard(&mut ctx, &config.replacements, &config.exclusion_list)
+            .unwrap();
+        assert_eq!(ctx.content, "ABC　123!");
+    }
+
+    #[test]
+    fn test_loop_without_watching() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = write_test_config(temp_dir.path());
+        let mut ctx = MockClipboard {
+            content: "foo １２３！".to_string(),
+            ..Default::default()
+        };
+
+        let mut converter = Converter::default();
+        for _ in 0..3 {
+            poll_config_changes(None, &mut config).unwrap();
+            converter
+                .convert_clipboard(&mut ctx, &config.replacements, &config.exclusion_list)
+                .unwrap();
+        }
+        assert_eq!(ctx.content, "bar 123！");
+    }
+
+    // Test for --explain output
+    #[test]
+    fn test_write_explanation() {
+        let stages = explain_format("ＡＢ\t", &[], &[], &FormatOptions::default()).unwrap();
+        let mut out = Vec::new();
+        write_explanation(&stages, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "input             \"ＡＢ\\t\"\nwidth conversion  \"AB\\t\"\n"
+        );
+    }
+
+    // Test for --interactive
+    #[test]
+    fn test_interactive_writes_only_when_confirmed() {
+        let converter = Converter::default();
+        let interact = |answer: &str| {
+            let mut ctx = MockClipboard {
+                content: "ＡＢ".to_string(),
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            let outcome = converter
+                .convert_interactively(&mut ctx, &[], &[], &mut answer.as_bytes(), &mut output)
+                .unwrap();
+            (outcome, ctx.content, String::from_utf8(output).unwrap())
+        };
+        let (outcome, content, output) = interact("y\n");
+        assert_eq!(outcome, ConvertOutcome::Converted);
+        assert_eq!(content, "AB");
+        assert!(output.contains("Write the converted text to the clipboard? [y/N]"));
+        // No, no answer at all, and an unclear answer followed by no
+        for answer in ["n\n", "", "maybe\nno\n"] {
+            let (outcome, content, _) = interact(answer);
+            assert_eq!(outcome, ConvertOutcome::Declined);
+            assert_eq!(content, "ＡＢ");
+        }
+    }
+
+    #[test]
+    fn test_interactive_skips_the_question_without_a_change() {
+        let mut ctx = MockClipboard {
+            content: "AB".to_string(),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let outcome = Converter::default()
+            .convert_interactively(&mut ctx, &[], &[], &mut "y\n".as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(outcome, ConvertOutcome::Unchanged);
+        assert!(output.is_empty());
+        assert_eq!(ctx.set_calls, 0);
+    }
+
+    // Test for --isolate-rule
+    #[test]
+    fn test_isolate_rule_applies_only_that_rule() {
+        let rule = |original: &str, replacement: &str| Replacement {
+            original: original.to_string(),
+            replacement: replacement.to_string(),
+            ..Default::default()
+        };
+        let rules = vec![rule("ａ", "x"), rule("ｂ", "y")];
+        let options = FormatOptions::default();
+        let stages = isolate_rule("ａｂＣ", "2", &rules, &[], &options, false).unwrap();
+        assert_eq!(stages.last().unwrap().text, "ａyＣ");
+        let stages = isolate_rule("ａｂＣ", "ａ", &rules, &[], &options, true).unwrap();
+        assert_eq!(stages.last().unwrap().text, "xbC");
+        assert!(isolate_rule("ａｂＣ", "ｚ", &rules, &[], &options, false).is_err());
+    }
+
+    // Test for clipboard timeouts
+    // Blocks on the first call, as a clipboard held by another process would
+    struct StuckClipboard {
+        stall: Option<Duration>,
+        sets: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ClipboardBackend for StuckClipboard {
+        fn get_bytes(&mut self) -> Result<Vec<u8>, ClipboardError> {
+            if let Some(stall) = self.stall.take() {
+                thread::sleep(stall);
+            }
+            Ok(b"ok".to_vec())
+        }
+
+        fn set_text(&mut self, content: String) -> Result<(), ClipboardError> {
+            self.sets.lock().unwrap().push(content);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_timed_clipboard_times_out_instead_of_blocking() {
+        let sets = Arc::new(Mutex::new(Vec::new()));
+        let worker_sets = Arc::clone(&sets);
+        let mut ctx = TimedClipboard::spawn(
+            move || {
+                Ok(StuckClipboard {
+                    stall: Some(Duration::from_millis(500)),
+                    sets: worker_sets,
+                })
+            },
+            Some(Duration::from_millis(50)),
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        assert!(matches!(ctx.get_bytes(), Err(ClipboardError::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_millis(400));
+        // Queued behind the stuck call, so it times out too and is dropped
+        assert!(matches!(
+            ctx.set_text("stale".to_string()),
+            Err(ClipboardError::Timeout(_))
+        ));
+
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(ctx.get_bytes().unwrap(), b"ok");
+        assert!(sets.lock().unwrap().is_empty());
+    }
+
+    // Test for post_command
+    #[cfg(unix)]
+    #[test]
+    fn test_post_command_filters_converted_text() {
+        let mut converter = Converter {
+            post_command: PostCommand::new(
+                vec!["tr".to_string(), "a-z".to_string(), "A-Z".to_string()],
+                Duration::from_secs(5),
+            ),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "ａｂｃ".to_string(),
+            ..Default::default()
+        };
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(ctx.content, "ABC");
+
+        // Falls back to the unfiltered result
+        converter.post_command =
+            PostCommand::new(vec!["false".to_string()], Duration::from_secs(5));
+        ctx.content = "ｘｙｚ".to_string();
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(ctx.content, "xyz");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_post_command_runs_once_for_unchanged_clipboard() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let runs = temp_dir.path().join("runs");
+        let settings = Settings {
+            post_command: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!("echo run >> '{}'; cat", runs.display()),
+            ],
+            ..Default::default()
+        };
+        let converter = Converter::from_settings(&settings).unwrap();
+        let mut daemon = Daemon::new(settings, config, converter);
+        daemon.poll_interval = Duration::ZERO;
+        let mut ctx = MockClipboard {
+            content: "plain text".to_string(),
+            ..Default::default()
+        };
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(2))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&runs).unwrap(), "run\n");
+        assert_eq!(ctx.set_calls, 0);
+    }
+
+    // Rich text next to the converted text is dropped rather than left stale
+    #[test]
+    fn test_stale_html_is_cleared() {
+        let mut converter = Converter::default();
+        let mut ctx = MockClipboard {
+            content: "ＡＢＣ".to_string(),
+            html: Some("<b>ＡＢＣ</b>".to_string()),
+            ..Default::default()
+        };
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(ctx.content, "ABC");
+        assert_eq!(ctx.html, None);
+    }
+
+    // Test for text_flavor
+    #[test]
+    fn test_converted_text_is_written_as_requested_flavor() {
+        let mut converter = Converter {
+            text_flavor: TextFlavor::TextPlainUtf8,
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "ＡＢＣ".to_string(),
+            ..Default::default()
+        };
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(ctx.content, "ABC");
+        assert_eq!(ctx.flavor, Some(TextFlavor::TextPlainUtf8));
+        assert_eq!(
+            TextFlavor::TextPlainUtf8.target(),
+            Some("text/plain;charset=utf-8")
+        );
+    }
+
+    #[test]
+    fn test_flavor_survives_timed_clipboard() {
+        let (flavor_tx, flavor_rx) = channel();
+        let mut ctx =
+            TimedClipboard::spawn(move || Ok(FlavorRecorder { flavors: flavor_tx }), None).unwrap();
+        set_clipboard_contents(&mut ctx, "x".to_string(), TextFlavor::Utf8String).unwrap();
+        assert_eq!(flavor_rx.recv().unwrap(), TextFlavor::Utf8String);
+    }
+
+    // Reports each flavor out of the clipboard thread
+    struct FlavorRecorder {
+        flavors: Sender<TextFlavor>,
+    }
+
+    impl ClipboardBackend for FlavorRecorder {
+        fn get_bytes(&mut self) -> Result<Vec<u8>, ClipboardError> {
+            Ok(Vec::new())
+        }
+
+        fn set_text(&mut self, _content: String) -> Result<(), ClipboardError> {
+            Ok(())
+        }
+
+        fn set_text_as(
+            &mut self,
+            _content: String,
+            flavor: TextFlavor,
+        ) -> Result<(), ClipboardError> {
+            self.flavors.send(flavor).unwrap();
+            Ok(())
+        }
+    }
+
+    // Test for --annotate-causes
+    #[test]
+    fn test_describe_change_by_cause() {
+        let rules = vec![Replacement {
+            original: "foo".to_string(),
+            replacement: "bar".to_string(),
+            ..Default::default()
+        }];
+        let mut converter = Converter::default();
+        let plain = converter.describe_change("Ａ foo", "A bar", &rules, &[]);
+        assert_eq!(plain, highlight_diff("Ａ foo", "A bar"));
+
+        converter.annotate_causes = true;
+        let annotated = converter.describe_change("Ａ foo", "A bar", &rules, &[]);
+        assert!(annotated.contains("\x1b[34mA\x1b[0m"));
+        assert!(annotated.contains("\x1b[33mbar\x1b[0m"));
+        // A result the trace cannot reproduce falls back to the plain diff
+        let filtered = converter.describe_change("Ａ foo", "A BAR", &rules, &[]);
+        assert_eq!(filtered, highlight_diff("Ａ foo", "A BAR"));
+    }
+
+    // Test for large_input_policy
+    #[test]
+    fn test_large_input_prefix_keeps_tail() {
+        let mut converter = Converter {
+            max_input_bytes: Some(7),
+            large_input_policy: LargeInputPolicy::Prefix,
+            ..Default::default()
+        };
+        // Only "ab１" fits: a 7th byte would split "２"
+        let mut ctx = MockClipboard {
+            content: "ab１２３４".to_string(),
+            ..Default::default()
+        };
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::Converted);
+        assert_eq!(ctx.content, "ab1２３４");
+
+        let mut converter = Converter {
+            max_input_bytes: Some(7),
+            ..Default::default()
+        };
+        ctx.content = "ab１２３４".to_string();
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::SkippedTooLarge);
+        assert_eq!(ctx.content, "ab１２３４");
+    }
+
+    thread_local! {
+        // What TestLogger saw on this thread
+        static LOGGED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct TestLogger;
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGGED.with(|logged| logged.borrow_mut().push(record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    // Records what this thread logs from here on; read it back with logged()
+    fn capture_logs() {
+        static LOGGER: TestLogger = TestLogger;
+        // Installed by whichever test gets here first
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+        LOGGED.with(|logged| logged.borrow_mut().clear());
+    }
+
+    fn logged() -> Vec<String> {
+        LOGGED.with(|logged| logged.borrow().clone())
+    }
+
+    #[test]
+    fn test_mojibake_is_warned_about_once() {
+        capture_logs();
+        // Skipped as trivial on every poll, so nothing else stops the check
+        let mut converter = Converter {
+            min_changed_chars: 5,
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "cafÃ© １".to_string(),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+            assert_eq!(outcome, ConvertOutcome::SkippedTrivial);
+        }
+        let warnings = logged()
+            .into_iter()
+            .filter(|line| line.contains("fix_mojibake"))
+            .count();
+        assert_eq!(warnings, 1);
+    }
+
+    // Test for warn_on_link_change
+    #[test]
+    fn test_link_change_is_warned_about_past_the_diff_cap() {
+        capture_logs();
+        let mut converter = Converter {
+            warn_on_link_change: true,
+            ..Default::default()
+        };
+        for content in [
+            "https：／／example.com".to_string(),
+            format!(
+                "{} https：／／example.com",
+                "a".repeat(MAX_DIFF_INPUT_CHARS)
+            ),
+        ] {
+            let mut ctx = MockClipboard {
+                content,
+                ..Default::default()
+            };
+            let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+            assert_eq!(outcome, ConvertOutcome::Converted);
+        }
+        let warnings: Vec<_> = logged()
+            .into_iter()
+            .filter(|line| line.contains("https：／／example.com"))
+            .collect();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[1].starts_with("The conversion changes"));
+    }
+
+    // Test for min_changed_chars
+    #[test]
+    fn test_trivial_change_is_not_written() {
+        let mut converter = Converter {
+            min_changed_chars: 3,
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "foo １２".to_string(),
+            ..Default::default()
+        };
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::SkippedTrivial);
+        assert_eq!(ctx.set_calls, 0);
+
+        ctx.content = "foo １２３".to_string();
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::Converted);
+        assert_eq!(ctx.content, "foo 123");
+    }
+
+    // Test for --tee
+    #[test]
+    fn test_conversion_is_teed() {
+        let temp_dir = tempdir().unwrap();
+        let output = temp_dir.path().join("tee");
+        let mut converter = Converter {
+            tee: Some(Tee::spawn(
+                fs::File::create(&output).unwrap(),
+                TeeDelimiter::Line,
+            )),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "ＡＢＣ\n１２３".to_string(),
+            ..Default::default()
+        };
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::Converted);
+        assert_eq!(ctx.content, "ABC\n123");
+        // Unchanged content is not teed
+        ctx.content = "abc".to_string();
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+
+        // Dropping the tee waits for the writer
+        converter.tee = None;
+        assert_eq!(fs::read_to_string(output).unwrap(), "ABC\\n123\n");
+    }
+
+    // Test for skip_window_titles
+    #[test]
+    fn test_copy_from_matching_window_title_is_skipped() {
+        let filter = TitleFilter::new(&["*Terminal*".to_string()]).unwrap();
+        let mut converter = Converter {
+            skip_titles: Some((filter.clone(), || Some("GNOME Terminal".to_string()))),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "ＡＢ".to_string(),
+            ..Default::default()
+        };
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::SkippedWindowTitle);
+        assert_eq!(ctx.content, "ＡＢ");
+        // The decision sticks to the copy even after the focus moves on
+        converter.skip_titles = Some((filter.clone(), || Some("Firefox".to_string())));
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::SkippedWindowTitle);
+
+        let mut converter = Converter {
+            skip_titles: Some((filter, || Some("Firefox".to_string()))),
+            ..Default::default()
+        };
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::Converted);
+        assert_eq!(ctx.content, "AB");
+    }
+
+    // Test for skip_modifier
+    #[test]
+    fn test_copy_with_modifier_held_is_skipped() {
+        // X11 ShiftMask
+        let mut converter = Converter {
+            skip_modifier: Some((Modifier::Shift, || Some(0x01))),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "１２３".to_string(),
+            ..Default::default()
+        };
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::SkippedModifier);
+        assert_eq!(ctx.set_calls, 0);
+
+        // Letting go of the key later does not convert the same copy
+        converter.skip_modifier = Some((Modifier::Shift, || Some(0)));
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::SkippedModifier);
+        assert_eq!(ctx.content, "１２３");
+
+        ctx.content = "４５６".to_string();
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::Converted);
+        assert_eq!(ctx.content, "456");
+    }
+
+    // Test for enforce_canonical
+    #[test]
+    fn test_non_canonical_copies_are_normalized_again() {
+        let mut converter = Converter {
+            enforce_canonical: true,
+            min_changed_chars: 3,
+            output_mode: OutputMode::Append,
+            ..Default::default()
+        };
+        let replacements = vec![Replacement {
+            original: "foo".to_string(),
+            replacement: "bar".to_string(),
+            ..Default::default()
+        }];
+        let mut ctx = MockClipboard {
+            content: "foo １２３".to_string(),
+            ..Default::default()
+        };
+        converter.skip_existing(&mut ctx);
+        let outcome = converter
+            .convert_clipboard(&mut ctx, &replacements, &[])
+            .unwrap();
+        assert_eq!(outcome, ConvertOutcome::Converted);
+        assert_eq!(ctx.content, "bar 123");
+
+        // An app copying back a slightly different version, again and again
+        for _ in 0..3 {
+            ctx.content = "bar 12３".to_string();
+            let outcome = converter
+                .convert_clipboard(&mut ctx, &replacements, &[])
+                .unwrap();
+            assert_eq!(outcome, ConvertOutcome::Converted);
+            assert_eq!(ctx.content, "bar 123");
+            let outcome = converter
+                .convert_clipboard(&mut ctx, &replacements, &[])
+                .unwrap();
+            assert_eq!(outcome, ConvertOutcome::Unchanged);
+        }
+        assert_eq!(ctx.set_calls, 4);
+    }
+
+    #[test]
+    fn test_converge_stops_at_fixed_point() {
+        let mut calls = 0;
+        let result: Result<String, ()> = converge("aaa", 10, |text| {
+            calls += 1;
+            Ok(text.replacen('a', "b", 1))
+        });
+        assert_eq!(result.unwrap(), "bbb");
+        assert_eq!(calls, 4);
+
+        let result: Result<String, ()> = converge("aaa", 2, |text| Ok(text.replacen('a', "b", 1)));
+        assert_eq!(result.unwrap(), "bba");
+    }
+
+    // Test for verbose_after_error
+    #[test]
+    fn test_clipboard_error_raises_verbosity() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let settings = Settings {
+            verbose_after_error: 2,
+            ..Default::default()
+        };
+        let mut daemon = Daemon::new(settings, config, Converter::default());
+        daemon.poll_interval = Duration::ZERO;
+        let mut ctx = MockClipboard {
+            get_results: VecDeque::from(vec![Err(ClipboardError::GetContents(
+                "Connection refused".to_string(),
+            ))]),
+            ..Default::default()
+        };
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+            .unwrap();
+        assert!(daemon.verbosity.advance());
+        assert!(daemon.verbosity.advance());
+        assert!(!daemon.verbosity.advance());
+    }
+
+    // Test for the IPC text command
+    #[test]
+    fn test_daemon_converts_ipc_text() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let mut daemon = Daemon::new(Settings::default(), config, Converter::default());
+        let reply = daemon.convert_text("foo １２３\nＡＢＣ");
+        assert_eq!(
+            ipc::decode_text_frame(&mut std::io::Cursor::new(reply)).unwrap(),
+            "bar 123\nABC"
+        );
+
+        daemon.paused = true;
+        let reply = daemon.convert_text("foo");
+        assert!(ipc::decode_text_frame(&mut std::io::Cursor::new(reply)).is_err());
+    }
+
+    // Test for errors that fail the conversion
+    #[test]
+    fn test_format_error_fails_the_conversion() {
+        let mut converter = Converter::default();
+        converter.options.exclude_pattern = Some("(".to_string());
+        let mut ctx = MockClipboard {
+            content: "foo ２".to_string(),
+            ..Default::default()
+        };
+        let error = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid exclude_pattern '('");
+        assert_eq!(ctx.content, "foo ２");
+    }
+
+    // Test for the history command
+    #[test]
+    fn test_restore_writes_original_back() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let settings = Settings::default();
+        let converter = Converter::from_settings(&settings).unwrap();
+        let mut daemon = Daemon::new(settings, config, converter);
+        daemon.poll_interval = Duration::ZERO;
+        let mut ctx = MockClipboard::default();
+        for content in ["foo １", "ＡＢＣ"] {
+            ctx.content = content.to_string();
+            daemon
+                .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+                .unwrap();
+        }
+        assert_eq!(ctx.content, "ABC");
+        let history = daemon.converter.snapshots.to_json();
+        assert_eq!(history[0]["original"], "ＡＢＣ");
+        assert_eq!(history[1]["converted"], "bar 1");
+
+        daemon.restore_snapshot(&mut ctx, 1).unwrap();
+        assert_eq!(ctx.content, "foo １");
+        // The restored text is left alone
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+            .unwrap();
+        assert_eq!(ctx.content, "foo １");
+        assert_eq!(daemon.conversions, 2);
+
+        let error = daemon.restore_snapshot(&mut ctx, 2).unwrap_err();
+        assert_eq!(error.to_string(), "No conversion at index 2 (2 in history)");
+        assert_eq!(ctx.content, "foo １");
+    }
+
+    thread_local! {
+        // What fake_now() reads; each test runs on its own thread
+        static FAKE_NOW: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+
+    // A clock that only moves with advance()
+    fn fake_now() -> Instant {
+        FAKE_NOW.with(|now| {
+            let instant = now.get().unwrap_or_else(Instant::now);
+            now.set(Some(instant));
+            instant
+        })
+    }
+
+    fn advance(by: Duration) {
+        let instant = fake_now() + by;
+        FAKE_NOW.with(|now| now.set(Some(instant)));
+    }
+
+    // Test for focus_convert_apps
+    #[test]
+    fn test_switching_to_target_app_converts_once() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let settings = Settings {
+            trigger_mode: TriggerMode::Manual,
+            focus_convert_apps: vec!["Slack".to_string()],
+            ..Default::default()
+        };
+        let converter = Converter::from_settings(&settings).unwrap();
+        let mut daemon = Daemon::new(settings, config, converter);
+        daemon.poll_interval = Duration::ZERO;
+        daemon.now = fake_now;
+        daemon.focused_app = || Some("firefox".to_string());
+        let mut ctx = MockClipboard {
+            content: "foo １２３".to_string(),
+            ..Default::default()
+        };
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+            .unwrap();
+        assert_eq!(ctx.get_calls, 0);
+
+        daemon.focused_app = || Some("slack".to_string());
+        advance(kill_zen_all::focus::FOCUS_CHECK_INTERVAL);
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+            .unwrap();
+        assert_eq!(ctx.content, "bar 123");
+        assert_eq!(daemon.conversions, 1);
+
+        // Still in the app: a new copy waits for the next switch
+        ctx.content = "foo ４".to_string();
+        advance(kill_zen_all::focus::FOCUS_CHECK_INTERVAL);
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+            .unwrap();
+        assert_eq!(ctx.content, "foo ４");
+    }
+
+    // Test for coalesce_ms
+    #[test]
+    fn test_burst_of_copies_converts_only_the_last() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let settings = Settings {
+            coalesce_ms: 200,
+            ..Default::default()
+        };
+        let mut converter = Converter::from_settings(&settings).unwrap();
+        converter.coalesce = Some((Duration::from_millis(200), fake_now));
+        let mut daemon = Daemon::new(settings, config, converter);
+        daemon.poll_interval = Duration::ZERO;
+        let mut ctx = MockClipboard::default();
+        for content in ["foo １", "foo ２", "foo ３"] {
+            ctx.content = content.to_string();
+            daemon
+                .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+                .unwrap();
+            advance(Duration::from_millis(150));
+        }
+        assert_eq!(ctx.set_calls, 0);
+
+        // The last value has not changed for a whole window
+        advance(Duration::from_millis(50));
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+            .unwrap();
+        assert_eq!(ctx.set_calls, 1);
+        assert_eq!(ctx.content, "bar 3");
+        assert_eq!(daemon.conversions, 1);
+    }
+
+    // Test for rules_url
+    #[test]
+    fn test_daemon_reloads_rules_url() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let mut daemon = Daemon::new(Settings::default(), config, Converter::default());
+        daemon.poll_interval = Duration::ZERO;
+        let (updates_tx, updates) = channel();
+        daemon.remote_rules = Some(RemoteUpdates {
+            url: "https://example.com/rules.json".to_string(),
+            updates,
+        });
+        let rule: Vec<Replacement> =
+            serde_json::from_str(r#"[{"original": "baz", "replacement": "qux"}]"#).unwrap();
+        updates_tx.send(rule).unwrap();
+        let mut ctx = MockClipboard::default();
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+            .unwrap();
+        let reply = daemon.convert_text("foo baz");
+        assert_eq!(
+            ipc::decode_text_frame(&mut std::io::Cursor::new(reply)).unwrap(),
+            "bar qux"
+        );
+
+        // No news from the fetch thread keeps the last-good rules
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+            .unwrap();
+        let reply = daemon.convert_text("foo baz");
+        assert_eq!(
+            ipc::decode_text_frame(&mut std::io::Cursor::new(reply)).unwrap(),
+            "bar qux"
+        );
+    }
+
+    // Test for process_existing
+    #[test]
+    fn test_existing_clipboard_is_skipped() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let mut daemon = Daemon::new(Settings::default(), config, Converter::default());
+        daemon.poll_interval = Duration::ZERO;
+        let mut ctx = MockClipboard {
+            content: "foo １２３".to_string(),
+            ..Default::default()
+        };
+        daemon.converter.skip_existing(&mut ctx);
+
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(2))
+            .unwrap();
+        assert_eq!(ctx.set_calls, 0);
+        assert_eq!(ctx.content, "foo １２３");
+
+        ctx.content = "foo ４５６".to_string();
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+            .unwrap();
+        assert_eq!(ctx.content, "bar 456");
+
+        // The startup content is converted once copied again
+        ctx.content = "foo １２３".to_string();
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+            .unwrap();
+        assert_eq!(ctx.content, "bar 123");
+    }
+
+    // Test for quiet_hours
+    #[test]
+    fn test_quiet_hours_block_writes() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let settings = Settings {
+            quiet_hours: vec!["22:00-07:00".parse().unwrap()],
+            ..Default::default()
+        };
+        let mut daemon = Daemon::new(settings, config, Converter::default());
+        daemon.poll_interval = Duration::ZERO;
+        daemon.clock = || 23 * 60;
+        let mut ctx = MockClipboard {
+            content: "foo １２３".to_string(),
+            ..Default::default()
+        };
+
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(2))
+            .unwrap();
+        assert!(daemon.quiet);
+        assert_eq!(ctx.set_calls, 0);
+        assert_eq!(ctx.content, "foo １２３");
+
+        daemon.clock = || 7 * 60;
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(1))
+            .unwrap();
+        assert!(!daemon.quiet);
+        assert_eq!(ctx.content, "bar 123");
+    }
+
+    // Test for running the real loop a fixed number of times
+    #[test]
+    fn test_daemon_stops_after_max_iterations() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let mut daemon = Daemon::new(Settings::default(), config, Converter::default());
+        daemon.poll_interval = Duration::ZERO;
+        let mut ctx = MockClipboard {
+            content: "foo １２３！".to_string(),
+            ..Default::default()
+        };
+
+        daemon
+            .run(&mut ctx, || Ok(MockClipboard::default()), Some(3))
+            .unwrap();
+        assert_eq!(ctx.get_calls, 3);
+        assert_eq!(ctx.set_calls, 1);
+        assert_eq!(daemon.conversions, 1);
+        assert_eq!(ctx.content, "bar 123！");
+        let stats = daemon.stats();
+        assert_eq!(stats["blocks"]["fullwidth_digits"], 3);
+        assert_eq!(stats["blocks"]["other"], 3);
+    }
+
+    #[test]
+    fn test_handle_event_reloads_replacements() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = write_test_config(temp_dir.path());
+        fs::write(
+            &config.replacement_path,
+            r#"[{"original": "foo", "replacement": "baz"}]"#,
+        )
+        .unwrap();
+
+        let before = config.rules.snapshot();
+        let event =
+            notify::Event::new(notify::EventKind::Any).add_path(config.replacement_path.clone());
+        config.handle_event(&event).unwrap();
+        assert_eq!(config.replacements[0].replacement, "baz");
+        // A conversion holding the old snapshot keeps the old rules
+        assert_eq!(before.replacements[0].replacement, "bar");
+        assert_eq!(config.rules.snapshot().replacements[0].replacement, "baz");
+    }
+
+    // Test for the reload history
+    #[test]
+    fn test_reload_appends_history_entry() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = write_test_config(temp_dir.path());
+        config
+            .history
+            .persist_to(temp_dir.path().join(RELOAD_HISTORY_FILE_NAME));
+        config.record_loaded();
+        fs::write(
+            &config.replacement_path,
+            r#"[{"original": "foo", "replacement": "baz"}]"#,
+        )
+        .unwrap();
+
+        let event =
+            notify::Event::new(notify::EventKind::Any).add_path(config.replacement_path.clone());
+        assert!(config.handle_event(&event).unwrap());
+        let records: Vec<_> = config.history.records().cloned().collect();
+        assert_eq!(records.len(), 3);
+        let last = records.last().unwrap();
+        assert_eq!(last.file, "replacements");
+        assert_eq!(last.hash, short_hash(calculate_hash(&config.replacements)));
+        assert_ne!(last.hash, records[0].hash);
+        let saved = read_reload_history(&temp_dir.path().join(RELOAD_HISTORY_FILE_NAME)).unwrap();
+        assert_eq!(saved, records);
+    }
+
+    #[test]
+    fn test_handle_event_reloads_exclusion_layer() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let layer_path = temp_dir.path().join("project_exclusions.json");
+        fs::write(&layer_path, r#"{"exclude": ["？"]}"#).unwrap();
+        let mut config = LoadedConfig::load(
+            vec![config.replacement_path],
+            config.exclusion_path,
+            Some(layer_path.clone()),
+            ReloadHashStrategy::Parsed,
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(config.exclusion_list, vec!['！', '？']);
+
+        fs::write(&layer_path, r#"{"unexclude": ["！"]}"#).unwrap();
+        let event = notify::Event::new(notify::EventKind::Any).add_path(layer_path);
+        assert!(config.handle_event(&event).unwrap());
+        assert!(config.exclusion_list.is_empty());
+    }
+
+    #[test]
+    fn test_stage_exclusions_travel_with_the_rules() {
+        let temp_dir = tempdir().unwrap();
+        let config = write_test_config(temp_dir.path());
+        let exclusion_path = config.exclusion_path.clone();
+        let settings = Settings::default();
+        let converter = Converter::from_settings(&settings).unwrap();
+        let mut daemon = Daemon::new(settings, config, converter);
+
+        fs::write(
+            &exclusion_path,
+            r#"{"exclude": [{"char": "Ａ", "stages": ["width"]}]}"#,
+        )
+        .unwrap();
+        let event = notify::Event::new(notify::EventKind::Any).add_path(exclusion_path);
+        assert!(daemon.config.handle_event(&event).unwrap());
+        let snapshot = daemon.config.rules.snapshot();
+        assert_eq!(snapshot.stage_exclusions, daemon.config.stage_exclusions);
+        // Picked up from the snapshot, without waiting for the next tick
+        let reply = daemon.convert_text("ＡＢ");
+        assert_eq!(
+            ipc::decode_text_frame(&mut std::io::Cursor::new(reply)).unwrap(),
+            "ＡB"
+        );
+    }
+
+    #[test]
+    fn test_metadata_only_change_does_not_reparse() {
+        let temp_dir = tempdir().unwrap();
+        let replacement_path = temp_dir.path().join("replacements.json");
+        let past = std::time::SystemTime::now() - Duration::from_secs(60);
+        let set_mtime = |path: &Path| {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(past)
+                .unwrap();
+        };
+        let mut config = write_test_config(temp_dir.path());
+        set_mtime(&replacement_path);
+        config = LoadedConfig::load(
+            vec![replacement_path.clone()],
+            config.exclusion_path,
+            None,
+            ReloadHashStrategy::Parsed,
+            false,
+            false,
+        )
+        .unwrap();
+
+        // Same size and mtime: the new content must not be picked up
+        fs::write(
+            &replacement_path,
+            r#"[{"original": "foo", "replacement": "baz"}]"#,
+        )
+        .unwrap();
+        set_mtime(&replacement_path);
+        let event = notify::Event::new(notify::EventKind::Any).add_path(replacement_path.clone());
+        config.handle_event(&event).unwrap();
+        assert_eq!(config.replacements[0].replacement, "bar");
+
+        // Once the mtime moves, the file is reparsed
+        fs::write(
+            &replacement_path,
+            r#"[{"original": "foo", "replacement": "baz"}]"#,
+        )
+        .unwrap();
+        config.handle_event(&event).unwrap();
+        assert_eq!(config.replacements[0].replacement, "baz");
+    }
+
+    // Test for --strict
+    #[test]
+    fn test_strict_reload_fails_on_parse_error() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = write_test_config(temp_dir.path());
+        config.strict = true;
+        fs::write(&config.replacement_path, "[{").unwrap();
+        let event =
+            notify::Event::new(notify::EventKind::Any).add_path(config.replacement_path.clone());
+
+        let error = config.handle_event(&event).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ConfigError>(),
+            Some(ConfigError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn test_non_strict_reload_keeps_previous_config() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = write_test_config(temp_dir.path());
+        fs::write(&config.replacement_path, "[{").unwrap();
+        let event =
+            notify::Event::new(notify::EventKind::Any).add_path(config.replacement_path.clone());
+
+        assert!(!config.handle_event(&event).unwrap());
+        assert!(config.has_failures());
+        assert_eq!(config.replacements[0].replacement, "bar");
+    }
+
+    // Test for safe_mode
+    #[test]
+    fn test_failed_exclusions_pause_until_fixed() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = write_test_config(temp_dir.path());
+        let event =
+            notify::Event::new(notify::EventKind::Any).add_path(config.exclusion_path.clone());
+        assert!(!config.has_failures());
+
+        fs::write(&config.exclusion_path, r#"{"exclude": ["！""#).unwrap();
+        config.handle_event(&event).unwrap();
+        assert!(config.has_failures());
+        // Still the last-good exclusions, but the daemon must not use them
+        assert_eq!(config.exclusion_list, vec!['！']);
+
+        // Restoring the previous content clears the failure as well
+        fs::write(&config.exclusion_path, r#"{"exclude": ["！"]}"#).unwrap();
+        config.handle_event(&event).unwrap();
+        assert!(!config.has_failures());
+    }
+
+    #[test]
+    fn test_safe_mode_contains_a_broken_config_at_startup() {
+        let temp_dir = tempdir().unwrap();
+        let replacement_path = temp_dir.path().join("replacements.json");
+        let exclusion_path = temp_dir.path().join("exclusions.json");
+        fs::write(&replacement_path, r#"[{"original": "foo""#).unwrap();
+        fs::write(&exclusion_path, r#"{"exclude": ["！"]}"#).unwrap();
+        let settings = Settings::default();
+        let open = |contain| {
+            LoadedConfig::open(
+                vec![replacement_path.clone()],
+                exclusion_path.clone(),
+                None,
+                &settings,
+                contain,
+            )
+        };
+        assert!(open(false).is_err());
+
+        let mut config = open(true).unwrap();
+        assert!(config.has_failures());
+        assert!(config.replacements.is_empty());
+        assert_eq!(config.exclusion_list, vec!['！']);
+
+        fs::write(
+            &replacement_path,
+            r#"[{"original": "foo", "replacement": "bar"}]"#,
+        )
+        .unwrap();
+        let event = notify::Event::new(notify::EventKind::Any).add_path(replacement_path.clone());
+        assert!(config.handle_event(&event).unwrap());
+        assert!(!config.has_failures());
+        assert_eq!(config.replacements[0].replacement, "bar");
+    }
+
+    // Test for the conversion cache
+    #[test]
+    fn test_reload_clears_conversion_cache() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = write_test_config(temp_dir.path());
+        let mut converter = Converter {
+            cache: ConversionCache::new(8),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "foo".to_string(),
+            ..Default::default()
+        };
+        converter
+            .convert_clipboard(&mut ctx, &config.replacements, &config.exclusion_list)
+            .unwrap();
+        assert_eq!(ctx.content, "bar");
+        assert_eq!(converter.cache.as_ref().unwrap().len(), 1);
+
+        fs::write(
+            &config.replacement_path,
+            r#"[{"original": "foo", "replacement": "qux!"}]"#,
+        )
+        .unwrap();
+        let event =
+            notify::Event::new(notify::EventKind::Any).add_path(config.replacement_path.clone());
+        if config.handle_event(&event).unwrap() {
+            converter.clear_cache();
+        }
+        assert!(converter.cache.as_ref().unwrap().is_empty());
+
+        ctx.content = "foo".to_string();
+        converter
+            .convert_clipboard(&mut ctx, &config.replacements, &config.exclusion_list)
+            .unwrap();
+        assert_eq!(ctx.content, "qux!");
+    }
+
+    // Test for sensitive content
+    #[test]
+    fn test_sensitive_marker_skips_conversion() {
+        capture_logs();
+        let mut ctx = MockClipboard {
+            content: "ｐａｓｓｗｏｒｄ１２３".to_string(),
+            sensitive: true,
+            ..Default::default()
+        };
+        let outcome = Converter::default()
+            .convert_clipboard(&mut ctx, &[], &[])
+            .unwrap();
+
+        assert_eq!(outcome, ConvertOutcome::SkippedSensitive);
+        assert_eq!(ctx.content, "ｐａｓｓｗｏｒｄ１２３");
+        assert_eq!(ctx.set_calls, 0);
+        // Not even at trace level, in either form
+        let logged = logged();
+        assert!(!logged.is_empty());
+        assert!(logged
+            .iter()
+            .all(|line| !line.contains("ｐａｓｓ") && !line.contains("pass")));
+    }
+
+    #[test]
+    fn test_marker_is_checked_once_per_value() {
+        let mut ctx = MockClipboard {
+            content: "ｐａｓｓｗｏｒｄ１２３".to_string(),
+            sensitive: true,
+            ..Default::default()
+        };
+        let mut converter = Converter::default();
+        for _ in 0..2 {
+            let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+            assert_eq!(outcome, ConvertOutcome::SkippedSensitive);
+        }
+        assert_eq!(ctx.sensitive_checks, 1);
+
+        ctx.content = "ＡＢＣ".to_string();
+        ctx.sensitive = false;
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::Converted);
+        assert_eq!(ctx.sensitive_checks, 2);
+    }
+
+    // Answers everything but the marker probe, which hangs
+    struct SlowMarkerClipboard;
+
+    impl ClipboardBackend for SlowMarkerClipboard {
+        fn get_bytes(&mut self) -> Result<Vec<u8>, ClipboardError> {
+            Ok("ｈｕｎｔｅｒ２".as_bytes().to_vec())
+        }
+
+        fn set_text(&mut self, _: String) -> Result<(), ClipboardError> {
+            Ok(())
+        }
+
+        fn is_sensitive(&mut self) -> bool {
+            thread::sleep(Duration::from_millis(500));
+            false
+        }
+    }
+
+    #[test]
+    fn test_marker_probe_timeout_counts_as_sensitive() {
+        let mut ctx =
+            TimedClipboard::spawn(|| Ok(SlowMarkerClipboard), Some(Duration::from_millis(50)))
+                .unwrap();
+        let outcome = Converter::default()
+            .convert_clipboard(&mut ctx, &[], &[])
+            .unwrap();
+        assert_eq!(outcome, ConvertOutcome::SkippedSensitive);
+    }
+
+    #[test]
+    fn test_secret_pattern_skips_conversion() {
+        let mut ctx = MockClipboard {
+            content: "token：ｓｅｃｒｅｔ".to_string(),
+            ..Default::default()
+        };
+        let mut converter = Converter {
+            sensitive_filter: SensitiveFilter::new(&["^token".to_string()]).unwrap(),
+            ..Default::default()
+        };
+        capture_logs();
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+
+        assert_eq!(outcome, ConvertOutcome::SkippedSensitive);
+        assert_eq!(ctx.set_calls, 0);
+        assert!(logged()
+            .iter()
+            .all(|line| !line.contains("ｓｅｃｒｅｔ") && !line.contains("secret")));
+    }
+
+    #[test]
+    fn test_bytes_strategy_skips_unchanged_file() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(&file_path, "[]").unwrap();
+        let mut fingerprint = FileFingerprint::from_path(&file_path).ok();
+
+        fs::write(&file_path, "[]").unwrap();
+        assert!(file_unchanged(
+            ReloadHashStrategy::Bytes,
+            &mut fingerprint,
+            &file_path
+        ));
+        assert!(!file_unchanged(
+            ReloadHashStrategy::Parsed,
+            &mut fingerprint,
+            &file_path
+        ));
+
+        fs::write(&file_path, r#"[{"original": "a", "replacement": "b"}]"#).unwrap();
+        assert!(!file_unchanged(
+            ReloadHashStrategy::Bytes,
+            &mut fingerprint,
+            &file_path
+        ));
+    }
+
+    // Test for skip_whitespace_only
+    #[test]
+    fn test_skip_whitespace_only() {
+        let mut converter = Converter {
+            skip_whitespace_only: true,
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "　 \n　".to_string(),
+            ..Default::default()
+        };
+        let outcome = converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert_eq!(outcome, ConvertOutcome::Unchanged);
+        assert_eq!(ctx.set_calls, 0);
+        assert_eq!(ctx.content, "　 \n　");
+    }
+
+    // Test for learn_mode
+    #[test]
+    fn test_learn_mode_ignores_own_conversion() {
+        let temp_dir = tempdir().unwrap();
+        let suggestions_path = temp_dir.path().join(SUGGESTIONS_FILE_NAME);
+        let mut converter = Converter {
+            learner: Some(Learner::new(Duration::from_secs(60))),
+            suggestions_path: Some(suggestions_path.clone()),
+            ..Default::default()
+        };
+        let mut ctx = MockClipboard {
+            content: "１２３".to_string(),
+            ..Default::default()
+        };
 
-        // テストケース
-        let input = "foo baz １２３４！？";
-        let expected = "bar qux 1234！?"; // ！は変換されず、？は変換される
-        let formatted = format_text(input, &replacements, &exclusion_list).unwrap();
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        assert!(!suggestions_path.exists());
 
-        assert_eq!(formatted, expected);
+        ctx.content = "頚椎".to_string();
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        ctx.content = "頸椎".to_string();
+        converter.convert_clipboard(&mut ctx, &[], &[]).unwrap();
+        let saved = fs::read_to_string(&suggestions_path).unwrap();
+        assert!(saved.contains("頚"));
     }
 
     // Test for kill-zen-all
@@ -549,10 +5183,12 @@ mod tests {
             Replacement {
                 original: "foo".to_string(),
                 replacement: "bar".to_string(),
+                ..Default::default()
             },
             Replacement {
                 original: "baz".to_string(),
                 replacement: "qux".to_string(),
+                ..Default::default()
             },
         ];
         let exclusion_list = vec![];
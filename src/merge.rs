@@ -0,0 +1,157 @@
+// Combining shared rule files into one, for `kill-zen-all merge`. Identical
+// rules are kept once. Rules for the same match (same `original`, action and
+// context) that disagree are conflicts; the first file's rule wins.
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::config::{load_replacements, serialize_replacements, Replacement};
+use crate::error::ConfigError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub kept: Replacement,
+    pub kept_from: PathBuf,
+    pub dropped: Replacement,
+    pub dropped_from: PathBuf,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "'{}': keeping '{}' from {}, dropping '{}' from {}",
+            self.kept.original,
+            self.kept.replacement,
+            self.kept_from.display(),
+            self.dropped.replacement,
+            self.dropped_from.display()
+        )
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Merged {
+    pub rules: Vec<Replacement>,
+    pub conflicts: Vec<Conflict>,
+    // Identical rules that were dropped
+    pub duplicates: usize,
+}
+
+// What a rule matches; two rules with the same key compete for the same text
+fn same_match(a: &Replacement, b: &Replacement) -> bool {
+    (&a.original, a.action, &a.context_before, &a.context_after)
+        == (&b.original, b.action, &b.context_before, &b.context_after)
+}
+
+pub fn merge_rules(files: &[(PathBuf, Vec<Replacement>)]) -> Merged {
+    let mut merged = Merged::default();
+    let mut origins: Vec<&Path> = Vec::new();
+    for (path, rules) in files {
+        for rule in rules {
+            match merged.rules.iter().position(|kept| same_match(kept, rule)) {
+                Some(index) if merged.rules[index] == *rule => merged.duplicates += 1,
+                Some(index) => merged.conflicts.push(Conflict {
+                    kept: merged.rules[index].clone(),
+                    kept_from: origins[index].to_path_buf(),
+                    dropped: rule.clone(),
+                    dropped_from: path.clone(),
+                }),
+                None => {
+                    merged.rules.push(rule.clone());
+                    origins.push(path);
+                }
+            }
+        }
+    }
+    merged
+}
+
+// The merged rules are serialized in the format of the first file
+pub fn merge_files(paths: &[PathBuf]) -> Result<(String, Merged), ConfigError> {
+    let files = paths
+        .iter()
+        .map(|path| {
+            let path_str = path
+                .to_str()
+                .ok_or_else(|| ConfigError::InvalidPath(path.display().to_string()))?;
+            Ok((path.clone(), load_replacements(path_str)?))
+        })
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+    let merged = merge_rules(&files);
+    let output = serialize_replacements(&paths[0], &merged.rules)?;
+    Ok((output, merged))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn rule(original: &str, replacement: &str) -> Replacement {
+        Replacement {
+            original: original.to_string(),
+            replacement: replacement.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_merge_rules_dedups_and_reports_conflicts() {
+        let a = PathBuf::from("a.json");
+        let b = PathBuf::from("b.json");
+        let with_context = Replacement {
+            context_before: Some("x".to_string()),
+            ..rule("foo", "qux")
+        };
+        let merged = merge_rules(&[
+            (a.clone(), vec![rule("foo", "bar"), rule("ｶ", "カ")]),
+            (
+                b.clone(),
+                vec![rule("ｶ", "カ"), rule("foo", "baz"), with_context.clone()],
+            ),
+        ]);
+        assert_eq!(
+            merged.rules,
+            vec![rule("foo", "bar"), rule("ｶ", "カ"), with_context]
+        );
+        assert_eq!(merged.duplicates, 1);
+        assert_eq!(
+            merged.conflicts,
+            vec![Conflict {
+                kept: rule("foo", "bar"),
+                kept_from: a,
+                dropped: rule("foo", "baz"),
+                dropped_from: b,
+            }]
+        );
+        assert_eq!(
+            merged.conflicts[0].to_string(),
+            "'foo': keeping 'bar' from a.json, dropping 'baz' from b.json"
+        );
+    }
+
+    #[test]
+    fn test_merge_files_prints_first_format() {
+        let temp_dir = tempdir().unwrap();
+        let a = temp_dir.path().join("a.json");
+        let b = temp_dir.path().join("b.yaml");
+        fs::write(
+            &a,
+            r#"[{"original": "foo", "replacement": "bar"}, {"original": "ｶ", "replacement": "カ"}]"#,
+        )
+        .unwrap();
+        fs::write(
+            &b,
+            "- original: foo\n  replacement: baz\n- original: ｶ\n  replacement: カ\n- original: ｷ\n  replacement: キ\n",
+        )
+        .unwrap();
+        let (output, merged) = merge_files(&[a, b]).unwrap();
+        assert_eq!(merged.conflicts.len(), 1);
+        let rules: Vec<Replacement> = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            rules,
+            vec![rule("foo", "bar"), rule("ｶ", "カ"), rule("ｷ", "キ")]
+        );
+    }
+}
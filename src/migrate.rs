@@ -0,0 +1,199 @@
+use log::info;
+use std::fs;
+use std::path::Path;
+
+use crate::config::{read_replacements, serialize_replacements, Replacement};
+use crate::error::ConfigError;
+
+// Bump together with a new DEFAULT_RULE_HISTORY entry whenever default rules
+// are added, so existing users are offered them by `--migrate`
+pub const DEFAULTS_VERSION: u32 = 1;
+pub const DEFAULTS_VERSION_FILE_NAME: &str = "defaults_version";
+
+// Default rules by the version that introduced them. Version 1 is the set
+// shipped before versioning existed; later additions go in their own entry
+// instead of being edited into default_replacements.json.
+const DEFAULT_RULE_HISTORY: &[(u32, &str)] = &[(1, include_str!("default_replacements.json"))];
+
+// A config directory without a marker predates versioning, i.e. has version 1
+pub fn read_defaults_version(config_dir: &Path) -> u32 {
+    fs::read_to_string(config_dir.join(DEFAULTS_VERSION_FILE_NAME))
+        .ok()
+        .and_then(|data| data.trim().parse().ok())
+        .unwrap_or(1)
+}
+
+pub fn write_defaults_version(config_dir: &Path, version: u32) -> Result<(), ConfigError> {
+    let path = config_dir.join(DEFAULTS_VERSION_FILE_NAME);
+    fs::write(&path, format!("{}\n", version)).map_err(|source| ConfigError::Io {
+        path: path.display().to_string(),
+        source,
+    })
+}
+
+fn history() -> Vec<(u32, Vec<Replacement>)> {
+    DEFAULT_RULE_HISTORY
+        .iter()
+        .map(|(version, rules)| {
+            let rules = serde_json::from_str(rules).expect("default replacements are valid");
+            (*version, rules)
+        })
+        .collect()
+}
+
+pub fn default_rules() -> Vec<Replacement> {
+    history().into_iter().flat_map(|(_, rules)| rules).collect()
+}
+
+// Drops the bundled rules whose `original` is listed in `disabled_defaults`.
+// Only rules identical to a default are dropped, so the user's own rule for
+// the same `original` keeps working.
+pub fn remove_disabled_defaults(rules: &mut Vec<Replacement>, disabled: &[String]) {
+    if disabled.is_empty() {
+        return;
+    }
+    let defaults: Vec<Replacement> = default_rules()
+        .into_iter()
+        .filter(|rule| disabled.contains(&rule.original))
+        .collect();
+    rules.retain(|rule| !defaults.contains(rule));
+}
+
+// Defaults added after `since_version`, minus any whose `original` the user
+// already has a rule for: that is either the same rule or a deliberate override
+fn pending_defaults(
+    history: &[(u32, Vec<Replacement>)],
+    user_rules: &[Replacement],
+    since_version: u32,
+) -> Vec<Replacement> {
+    let mut pending: Vec<Replacement> = Vec::new();
+    for rule in history
+        .iter()
+        .filter(|(version, _)| *version > since_version)
+        .flat_map(|(_, rules)| rules)
+    {
+        let known = user_rules
+            .iter()
+            .chain(&pending)
+            .any(|existing| existing.original == rule.original);
+        if !known {
+            pending.push(rule.clone());
+        }
+    }
+    pending
+}
+
+// Appends the new defaults to the user's replacements file and records the
+// current version. Returns the number of rules added.
+pub fn migrate_defaults(config_dir: &Path, replacement_path: &Path) -> Result<usize, ConfigError> {
+    let path_str = replacement_path.display().to_string();
+    // Not load_replacements: the rules it drops must be written back as well
+    let mut rules = read_replacements(&path_str)?;
+    let pending = pending_defaults(&history(), &rules, read_defaults_version(config_dir));
+    let added = pending.len();
+    if added > 0 {
+        rules.extend(pending);
+        let content = serialize_replacements(replacement_path, &rules)?;
+        fs::write(replacement_path, content).map_err(|source| ConfigError::Io {
+            path: path_str,
+            source,
+        })?;
+        info!(
+            "Added {} new default rule(s) to {}",
+            added,
+            replacement_path.display()
+        );
+    }
+    write_defaults_version(config_dir, DEFAULTS_VERSION)?;
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load_replacements;
+    use tempfile::tempdir;
+
+    fn rule(original: &str, replacement: &str) -> Replacement {
+        Replacement {
+            original: original.to_string(),
+            replacement: replacement.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pending_defaults_only_new_and_non_conflicting() {
+        let history = vec![
+            (1, vec![rule("頚", "頸")]),
+            (2, vec![rule("（注）", ""), rule("～", "〜")]),
+            (3, vec![rule("“", "\""), rule("～", "~")]),
+        ];
+        // The user deleted 頚 (v1) and overrode ～ themselves
+        let user_rules = vec![rule("～", "-")];
+
+        let pending = pending_defaults(&history, &user_rules, 1);
+        assert_eq!(pending, vec![rule("（注）", ""), rule("“", "\"")]);
+        assert!(pending_defaults(&history, &user_rules, 3).is_empty());
+    }
+
+    #[test]
+    fn test_migrate_defaults_appends_and_records_version() {
+        let temp_dir = tempdir().unwrap();
+        let replacement_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &replacement_path,
+            r#"[{"original": "foo", "replacement": "bar"}]"#,
+        )
+        .unwrap();
+        write_defaults_version(temp_dir.path(), 0).unwrap();
+
+        let added = migrate_defaults(temp_dir.path(), &replacement_path).unwrap();
+        let rules = load_replacements(replacement_path.to_str().unwrap()).unwrap();
+        assert_eq!(rules.len(), added + 1);
+        assert_eq!(rules[0], rule("foo", "bar"));
+        assert_eq!(read_defaults_version(temp_dir.path()), DEFAULTS_VERSION);
+
+        // Nothing left to add the second time
+        assert_eq!(
+            migrate_defaults(temp_dir.path(), &replacement_path).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_migrate_defaults_keeps_rules_that_change_nothing() {
+        let temp_dir = tempdir().unwrap();
+        let replacement_path = temp_dir.path().join("replacements.json");
+        fs::write(
+            &replacement_path,
+            r#"[{"original": "ＡＢＣ", "replacement": "ＡＢＣ"}]"#,
+        )
+        .unwrap();
+        write_defaults_version(temp_dir.path(), 0).unwrap();
+
+        migrate_defaults(temp_dir.path(), &replacement_path).unwrap();
+        let rules = read_replacements(replacement_path.to_str().unwrap()).unwrap();
+        assert_eq!(rules[0], rule("ＡＢＣ", "ＡＢＣ"));
+    }
+
+    // Test for disabled_defaults
+    #[test]
+    fn test_remove_disabled_defaults_keeps_user_rules() {
+        let mut rules = default_rules();
+        rules.push(rule("CRLF", "\n"));
+        let before = rules.len();
+
+        remove_disabled_defaults(&mut rules, &["CRLF".to_string()]);
+        assert_eq!(rules.len(), before - 1);
+        assert!(!rules.contains(&rule("CRLF", "。")));
+        assert!(rules.contains(&rule("CRLF", "\n")));
+        assert!(rules.contains(&rule("頚", "頸")));
+    }
+
+    #[test]
+    fn test_missing_marker_is_version_one() {
+        let temp_dir = tempdir().unwrap();
+        assert_eq!(read_defaults_version(temp_dir.path()), 1);
+    }
+}
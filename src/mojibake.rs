@@ -0,0 +1,110 @@
+// UTF-8 that was decoded as Windows-1252 or Latin-1 on the way to the
+// clipboard, e.g. "Ã©" for "é". Such text maps back to its bytes
+// one character per byte, and those bytes are valid UTF-8 again.
+
+// Windows-1252's characters for the bytes 0x80..=0x9F; the five bytes it
+// leaves undefined come through as the C1 control of the same value
+const CP1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+// The byte a Windows-1252 or Latin-1 decoder turned into `c`, for non-ASCII `c`
+fn misdecoded_byte(c: char) -> Option<u8> {
+    if let Some(index) = CP1252_HIGH.iter().position(|&high| high == c) {
+        return Some(0x80 + index as u8);
+    }
+    match c as u32 {
+        0x80..=0xFF => Some(c as u8),
+        _ => None,
+    }
+}
+
+// Runs of non-ASCII characters that all map back to bytes, with their
+// re-decoded text when those bytes are valid UTF-8
+fn repairs(text: &str) -> impl Iterator<Item = (std::ops::Range<usize>, String)> + '_ {
+    let mut chars = text.char_indices().peekable();
+    std::iter::from_fn(move || loop {
+        let (start, _) = *chars.peek()?;
+        let mut bytes = Vec::new();
+        let mut end = start;
+        while let Some(&(position, c)) = chars.peek() {
+            match misdecoded_byte(c) {
+                Some(byte) if !c.is_ascii() => {
+                    bytes.push(byte);
+                    end = position + c.len_utf8();
+                    chars.next();
+                }
+                _ => break,
+            }
+        }
+        if bytes.is_empty() {
+            chars.next();
+            continue;
+        }
+        // A lone byte is never a multibyte sequence, so "é" alone stays
+        if let Ok(repaired) = String::from_utf8(bytes) {
+            return Some((start..end, repaired));
+        }
+    })
+}
+
+pub fn looks_like_mojibake(text: &str) -> bool {
+    repairs(text).next().is_some()
+}
+
+// The text with every recoverable run re-decoded; None when there is none
+pub fn fix_mojibake(text: &str) -> Option<String> {
+    let mut fixed = String::with_capacity(text.len());
+    let mut position = 0;
+    for (range, repaired) in repairs(text) {
+        fixed.push_str(&text[position..range.start]);
+        fixed.push_str(&repaired);
+        position = range.end;
+    }
+    if position == 0 {
+        return None;
+    }
+    fixed.push_str(&text[position..]);
+    Some(fixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The bytes of `text` decoded as Windows-1252, as a misconfigured source would
+    fn misdecode(text: &str) -> String {
+        text.bytes()
+            .map(|byte| match byte {
+                0x80..=0x9F => CP1252_HIGH[usize::from(byte - 0x80)],
+                _ => char::from(byte),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_detects_mojibake() {
+        assert_eq!(misdecode("あ"), "ã\u{81}‚");
+        assert!(looks_like_mojibake(&misdecode("ＡＢＣ")));
+        assert!(looks_like_mojibake("cafÃ©"));
+        // Correctly decoded text
+        assert!(!looks_like_mojibake("café ＡＢＣ あいう"));
+        assert!(!looks_like_mojibake("plain ascii"));
+    }
+
+    #[test]
+    fn test_fix_mojibake() {
+        let original = "全角のＡＢＣ、café";
+        assert_eq!(
+            fix_mojibake(&misdecode(original)).as_deref(),
+            Some(original)
+        );
+        // Correct text around the damaged run is kept
+        assert_eq!(
+            fix_mojibake(&format!("ＸＹ {}", misdecode("テスト"))).as_deref(),
+            Some("ＸＹ テスト")
+        );
+        assert_eq!(fix_mojibake("café ＡＢＣ"), None);
+    }
+}
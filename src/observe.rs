@@ -0,0 +1,180 @@
+// `--observe`: watches the clipboard like --dry-run and keeps an aggregate
+// report of the would-be conversions in a JSON file, for teams that want to
+// see what their text looks like before turning conversion on
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::blocks::{converted_chars, UnicodeBlock};
+use crate::error::ConfigError;
+use crate::format::ConversionReport;
+
+// The report file is rewritten at most this often, and once more on exit
+pub const OBSERVE_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ObserveReport {
+    // Seconds since the Unix epoch of the last write
+    pub updated: u64,
+    // Clipboard contents that would have been converted
+    pub conversions: u64,
+    // How often each rule fired, by its `original`
+    pub rules: BTreeMap<String, u64>,
+    // Fullwidth and halfwidth-form characters that would have been converted
+    pub chars: BTreeMap<String, u64>,
+}
+
+impl ObserveReport {
+    pub fn record(&mut self, report: &ConversionReport) {
+        if !report.changed {
+            return;
+        }
+        self.conversions += 1;
+        for rule in &report.rules_fired {
+            *self.rules.entry(rule.clone()).or_default() += 1;
+        }
+        for c in converted_chars(&report.input, &report.output) {
+            if UnicodeBlock::of(c) != UnicodeBlock::Other {
+                *self.chars.entry(c.to_string()).or_default() += 1;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Observer {
+    path: PathBuf,
+    report: ObserveReport,
+    dirty: bool,
+    last_flush: Instant,
+}
+
+impl Observer {
+    // Picks up the counts of an earlier run from `path`, if there is one
+    pub fn new(path: PathBuf) -> Result<Self, ConfigError> {
+        let report = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).map_err(|source| ConfigError::Parse {
+                path: path.display().to_string(),
+                source,
+            })?,
+            Err(_) => ObserveReport::default(),
+        };
+        Ok(Observer {
+            path,
+            report,
+            dirty: false,
+            last_flush: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, report: &ConversionReport) {
+        self.report.record(report);
+        self.dirty |= report.changed;
+    }
+
+    pub fn report(&self) -> &ObserveReport {
+        &self.report
+    }
+
+    pub fn flush_if_due(&mut self, now: Instant) -> Result<(), ConfigError> {
+        if now.saturating_duration_since(self.last_flush) < OBSERVE_FLUSH_INTERVAL {
+            return Ok(());
+        }
+        self.last_flush = now;
+        self.flush()
+    }
+
+    // Written to a temporary file first, so a dashboard never reads half a report
+    pub fn flush(&mut self) -> Result<(), ConfigError> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.report.updated = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let data =
+            serde_json::to_string_pretty(&self.report).map_err(|source| ConfigError::Parse {
+                path: self.path.display().to_string(),
+                source,
+            })?;
+        write_atomically(&self.path, &data)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+fn write_atomically(path: &Path, data: &str) -> Result<(), ConfigError> {
+    let io_error = |source| ConfigError::Io {
+        path: path.display().to_string(),
+        source,
+    };
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, data).map_err(io_error)?;
+    fs::rename(&temp, path).map_err(io_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::format::report_format;
+    use crate::Replacement;
+    use tempfile::tempdir;
+
+    fn observe(report: &mut ObserveReport, text: &str) {
+        let rules = vec![Replacement {
+            original: "foo".to_string(),
+            replacement: "bar".to_string(),
+            ..Default::default()
+        }];
+        let options = Default::default();
+        report.record(&report_format(text, &rules, &[], &options).unwrap());
+    }
+
+    #[test]
+    fn test_report_accumulates() {
+        let mut report = ObserveReport::default();
+        observe(&mut report, "foo １２");
+        observe(&mut report, "ＡＢ１");
+        observe(&mut report, "plain text");
+        observe(&mut report, "foo foo");
+        assert_eq!(report.conversions, 3);
+        assert_eq!(report.rules.get("foo"), Some(&2));
+        assert_eq!(report.chars.get("１"), Some(&2));
+        assert_eq!(report.chars.get("２"), Some(&1));
+        assert_eq!(report.chars.get("Ａ"), Some(&1));
+        // Characters replaced by a rule are not fullwidth forms
+        assert_eq!(report.chars.get("f"), None);
+    }
+
+    #[test]
+    fn test_observer_flushes_and_resumes() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("observe.json");
+        let mut observer = Observer::new(path.clone()).unwrap();
+        let report = report_format("ＡＢ", &[], &[], &Default::default()).unwrap();
+        observer.record(&report);
+
+        // Not due yet
+        observer.flush_if_due(Instant::now()).unwrap();
+        assert!(!path.exists());
+        observer
+            .flush_if_due(Instant::now() + OBSERVE_FLUSH_INTERVAL)
+            .unwrap();
+        let saved: ObserveReport =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved.conversions, 1);
+        assert!(saved.updated > 0);
+
+        // A new run continues from the saved counts
+        let mut observer = Observer::new(path.clone()).unwrap();
+        observer.record(&report);
+        observer.flush().unwrap();
+        assert_eq!(observer.report().conversions, 2);
+        assert_eq!(observer.report().chars.get("Ａ"), Some(&2));
+    }
+}
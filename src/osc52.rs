@@ -0,0 +1,91 @@
+// `osc52`: writing the clipboard with the OSC 52 escape sequence, which the
+// terminal turns into a write to its own clipboard. This works over SSH,
+// where there is no native clipboard, and inside tmux, which has to be asked
+// to pass the sequence through to the terminal outside it.
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io::Write;
+
+// Many terminals and tmux drop longer sequences; this is the text that fits
+// in 100000 bytes of sequence once encoded
+pub const MAX_OSC52_BYTES: usize = 74_994;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Osc52 {
+    // Wrap the sequence in tmux's DCS passthrough
+    pub tmux: bool,
+}
+
+impl Osc52 {
+    pub fn detect() -> Self {
+        Osc52 {
+            tmux: std::env::var_os("TMUX").is_some(),
+        }
+    }
+
+    pub fn sequence(self, text: &str) -> Result<String> {
+        if text.len() > MAX_OSC52_BYTES {
+            bail!(
+                "{} bytes is more than OSC 52 can carry ({} bytes)",
+                text.len(),
+                MAX_OSC52_BYTES
+            );
+        }
+        let sequence = format!("\x1b]52;c;{}\x07", STANDARD.encode(text));
+        if !self.tmux {
+            return Ok(sequence);
+        }
+        // Inside the passthrough every ESC is doubled
+        Ok(format!(
+            "\x1bPtmux;{}\x1b\\",
+            sequence.replace('\x1b', "\x1b\x1b")
+        ))
+    }
+
+    // To the controlling terminal rather than stdout, which may be redirected
+    pub fn write(self, text: &str) -> Result<()> {
+        let sequence = self.sequence(text)?;
+        #[cfg(unix)]
+        let mut terminal = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/tty")
+            .context("Failed to open the controlling terminal")?;
+        #[cfg(not(unix))]
+        let mut terminal = std::io::stdout();
+        terminal
+            .write_all(sequence.as_bytes())
+            .and_then(|()| terminal.flush())
+            .context("Failed to write to the terminal")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osc52_sequence() {
+        let osc52 = Osc52 { tmux: false };
+        assert_eq!(osc52.sequence("ABC").unwrap(), "\x1b]52;c;QUJD\x07");
+        // UTF-8 is encoded as its bytes
+        assert_eq!(osc52.sequence("あ").unwrap(), "\x1b]52;c;44GC\x07");
+        assert_eq!(osc52.sequence("").unwrap(), "\x1b]52;c;\x07");
+    }
+
+    #[test]
+    fn test_osc52_sequence_in_tmux() {
+        let osc52 = Osc52 { tmux: true };
+        assert_eq!(
+            osc52.sequence("ABC").unwrap(),
+            "\x1bPtmux;\x1b\x1b]52;c;QUJD\x07\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_osc52_too_long() {
+        let osc52 = Osc52 { tmux: false };
+        assert!(osc52.sequence(&"a".repeat(MAX_OSC52_BYTES)).is_ok());
+        assert!(osc52.sequence(&"a".repeat(MAX_OSC52_BYTES + 1)).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::Result;
+use log::warn;
+use std::fmt::Display;
+use std::io::{BufRead, Write};
+
+// One request per line, one response per line. Newlines inside the text are
+// sent as `\n` (and `\r` as `\r`), so a literal backslash is sent as `\\`.
+pub fn encode_frame(text: &str) -> String {
+    let mut frame = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => frame.push_str("\\\\"),
+            '\n' => frame.push_str("\\n"),
+            '\r' => frame.push_str("\\r"),
+            c => frame.push(c),
+        }
+    }
+    frame
+}
+
+// Unknown escapes are kept as they are rather than rejected
+pub fn decode_frame(frame: &str) -> String {
+    let mut text = String::with_capacity(frame.len());
+    let mut chars = frame.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => text.push('\\'),
+            Some('n') => text.push('\n'),
+            Some('r') => text.push('\r'),
+            Some(other) => {
+                text.push('\\');
+                text.push(other);
+            }
+            None => text.push('\\'),
+        }
+    }
+    text
+}
+
+// Serves until stdin is closed. A request that fails to convert, or is not
+// UTF-8, is answered with its input unchanged, so the client always gets one
+// line back.
+pub fn serve<R, W, F, E>(mut input: R, mut output: W, mut convert: F) -> Result<()>
+where
+    R: BufRead,
+    W: Write,
+    F: FnMut(&str) -> Result<String, E>,
+    E: Display,
+{
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        let frame = line.strip_suffix(b"\n").unwrap_or(&line);
+        let frame = frame.strip_suffix(b"\r").unwrap_or(frame);
+        match std::str::from_utf8(frame) {
+            Ok(frame) => {
+                let text = decode_frame(frame);
+                let converted = convert(&text).unwrap_or_else(|e| {
+                    warn!("Failed to convert pipe request: {}", e);
+                    text.clone()
+                });
+                writeln!(output, "{}", encode_frame(&converted))?;
+            }
+            Err(e) => {
+                warn!("Pipe request is not valid UTF-8: {}", e);
+                output.write_all(frame)?;
+                output.write_all(b"\n")?;
+            }
+        }
+        output.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::format_text;
+
+    #[test]
+    fn test_frame_round_trip() {
+        let text = "ＡＢＣ\nC:\\path\r\n\\n";
+        let frame = encode_frame(text);
+        assert!(!frame.contains('\n'));
+        assert_eq!(decode_frame(&frame), text);
+        assert_eq!(decode_frame("a\\tb\\"), "a\\tb\\");
+    }
+
+    // Test for serving framed requests
+    #[test]
+    fn test_serve_handles_several_requests() {
+        let input = "ＡＢＣ\nｆｏｏ\\nｂａｒ\r\nfail\n";
+        let mut output = Vec::new();
+        serve(input.as_bytes(), &mut output, |text| {
+            if text == "fail" {
+                return Err("boom".to_string());
+            }
+            format_text(text, &[], &[]).map_err(|e| e.to_string())
+        })
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "ABC\nfoo\\nbar\nfail\n");
+    }
+
+    #[test]
+    fn test_serve_answers_invalid_utf8_unchanged() {
+        let input = b"a\xff\r\n\xef\xbc\xa1\n";
+        let mut output = Vec::new();
+        serve(&input[..], &mut output, |text| format_text(text, &[], &[])).unwrap();
+        assert_eq!(output, b"a\xff\nA\n");
+    }
+}
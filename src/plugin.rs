@@ -0,0 +1,182 @@
+// `plugins`: shared libraries that run as an extra stage after the
+// conversion, for normalizations the rules cannot express. A plugin may be
+// written in any language that can export these C functions:
+//
+//   uint32_t kza_abi_version(void);          // must return KZA_ABI_VERSION
+//   char *kza_transform(const char *input);  // NULL leaves the text as it is
+//   void kza_free(char *output);             // frees what kza_transform returned
+//
+// Strings are NUL-terminated UTF-8. A plugin runs inside the daemon, so one
+// that crashes or aborts (including a Rust panic, which cannot unwind across
+// the C ABI) takes the daemon down with it. Only load plugins you trust.
+use anyhow::{bail, Context, Result};
+use log::warn;
+use std::ffi::{c_char, CStr, CString};
+use std::path::Path;
+
+pub const KZA_ABI_VERSION: u32 = 1;
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type TransformFn = unsafe extern "C" fn(*const c_char) -> *mut c_char;
+type FreeFn = unsafe extern "C" fn(*mut c_char);
+
+pub struct Plugin {
+    name: String,
+    transform: TransformFn,
+    free: FreeFn,
+    // Keeps the functions above loaded
+    _library: libloading::Library,
+}
+
+impl Plugin {
+    pub fn load(path: &Path) -> Result<Self> {
+        // SAFETY: loading runs the library's initializers; `plugins` lists
+        // libraries the user chose to trust
+        let library = unsafe { libloading::Library::new(path) }
+            .with_context(|| format!("Failed to load the plugin {}", path.display()))?;
+        // SAFETY: the symbols are declared with the signatures of the plugin ABI
+        let (abi_version, transform, free) = unsafe {
+            let symbol = |name: &str| format!("{} does not export {}", path.display(), name);
+            (
+                *library
+                    .get::<AbiVersionFn>(b"kza_abi_version\0")
+                    .with_context(|| symbol("kza_abi_version"))?,
+                *library
+                    .get::<TransformFn>(b"kza_transform\0")
+                    .with_context(|| symbol("kza_transform"))?,
+                *library
+                    .get::<FreeFn>(b"kza_free\0")
+                    .with_context(|| symbol("kza_free"))?,
+            )
+        };
+        // SAFETY: takes no arguments and returns a plain integer
+        let version = unsafe { abi_version() };
+        if version != KZA_ABI_VERSION {
+            bail!(
+                "{} implements plugin ABI version {}, not {}",
+                path.display(),
+                version,
+                KZA_ABI_VERSION
+            );
+        }
+        Ok(Plugin {
+            name: path.display().to_string(),
+            transform,
+            free,
+            _library: library,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // None when the plugin leaves the text as it is
+    pub fn transform(&self, input: &str) -> Result<Option<String>> {
+        let input = CString::new(input).context("The text contains a NUL character")?;
+        // SAFETY: `input` is a valid C string that outlives the call
+        let output = unsafe { (self.transform)(input.as_ptr()) };
+        if output.is_null() {
+            return Ok(None);
+        }
+        // SAFETY: a non-null result is a C string owned by the plugin until
+        // it is handed back to kza_free, which happens right after copying it
+        let converted = unsafe { CStr::from_ptr(output) }
+            .to_str()
+            .map(str::to_string);
+        unsafe { (self.free)(output) };
+        converted
+            .map(Some)
+            .context("The plugin returned invalid UTF-8")
+    }
+}
+
+// Each plugin in turn; a failing one is skipped, leaving the text as it was
+pub fn run_plugins(plugins: &[Plugin], text: String) -> String {
+    plugins
+        .iter()
+        .fold(text, |text, plugin| match plugin.transform(&text) {
+            Ok(Some(transformed)) => transformed,
+            Ok(None) => text,
+            Err(e) => {
+                warn!("Plugin {} failed: {:#}. Skipping it.", plugin.name(), e);
+                text
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempfile::tempdir;
+
+    // Uppercases ASCII, leaves text with "skip" alone and fails on "bad"
+    // by returning invalid UTF-8
+    const TEST_PLUGIN: &str = r#"
+        use std::ffi::{c_char, CStr, CString};
+
+        #[no_mangle]
+        pub extern "C" fn kza_abi_version() -> u32 {
+            1
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn kza_transform(input: *const c_char) -> *mut c_char {
+            let input = CStr::from_ptr(input).to_str().unwrap_or_default();
+            if input.contains("skip") {
+                return std::ptr::null_mut();
+            }
+            if input.contains("bad") {
+                return CString::from_vec_unchecked(vec![0xff]).into_raw();
+            }
+            CString::new(input.to_ascii_uppercase()).unwrap().into_raw()
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn kza_free(output: *mut c_char) {
+            drop(CString::from_raw(output));
+        }
+    "#;
+
+    fn build_plugin(dir: &Path, source: &str) -> std::path::PathBuf {
+        let source_path = dir.join("plugin.rs");
+        std::fs::write(&source_path, source).unwrap();
+        let library = dir.join(libloading::library_filename("kza_test_plugin"));
+        let status = Command::new(std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()))
+            .args(["--crate-type", "cdylib", "--edition", "2021", "-o"])
+            .arg(&library)
+            .arg(&source_path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        library
+    }
+
+    #[test]
+    fn test_plugin_load_and_call() {
+        let temp_dir = tempdir().unwrap();
+        let plugin = Plugin::load(&build_plugin(temp_dir.path(), TEST_PLUGIN)).unwrap();
+        assert_eq!(plugin.transform("abc").unwrap().as_deref(), Some("ABC"));
+        assert_eq!(plugin.transform("skip me").unwrap(), None);
+        assert!(plugin.transform("bad").is_err());
+        assert!(plugin.transform("a\0b").is_err());
+
+        let plugins = [plugin];
+        assert_eq!(run_plugins(&plugins, "abc".to_string()), "ABC");
+        // Failures pass the text through
+        assert_eq!(run_plugins(&plugins, "bad".to_string()), "bad");
+        assert_eq!(run_plugins(&plugins, "skip".to_string()), "skip");
+    }
+
+    #[test]
+    fn test_plugin_load_errors() {
+        let temp_dir = tempdir().unwrap();
+        assert!(Plugin::load(&temp_dir.path().join("missing.so")).is_err());
+        let old_abi = TEST_PLUGIN.replace("-> u32 {\n            1", "-> u32 {\n            0");
+        let error = Plugin::load(&build_plugin(temp_dir.path(), &old_abi))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("ABI version 0"), "{}", error);
+    }
+}
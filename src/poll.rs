@@ -0,0 +1,103 @@
+// `kill-zen-all set-interval`: a poll interval that applies for a while, e.g.
+// a fast one during a burst of copy-paste work, and then gives way to the
+// usual one again.
+use std::time::{Duration, Instant};
+
+// Faster polling would keep a core busy for little gain
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// Slower polling would leave the daemon asleep, even to Ctrl-C, for that long
+pub const MAX_POLL_INTERVAL: Duration = Duration::from_secs(60);
+// How long an override lasts when no duration is given
+pub const DEFAULT_OVERRIDE_DURATION: Duration = Duration::from_secs(300);
+pub const MAX_OVERRIDE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Default)]
+pub struct PollOverride {
+    // The interval and when it expires
+    active: Option<(Duration, Instant)>,
+}
+
+impl PollOverride {
+    // Returns how long the override lasts, after clamping
+    pub fn set(&mut self, interval: Duration, duration: Duration, now: Instant) -> Duration {
+        let interval = interval.clamp(MIN_POLL_INTERVAL, MAX_POLL_INTERVAL);
+        let mut duration = duration.min(MAX_OVERRIDE_DURATION);
+        let until = now.checked_add(duration).unwrap_or_else(|| {
+            duration = Duration::ZERO;
+            now
+        });
+        self.active = Some((interval, until));
+        duration
+    }
+
+    // The overridden interval until it expires, then `base` again
+    pub fn interval(&mut self, base: Duration, now: Instant) -> Duration {
+        match self.active {
+            Some((interval, until)) if now < until => interval,
+            Some(_) => {
+                self.active = None;
+                log::info!(
+                    "The poll interval override expired. Back to {} ms.",
+                    base.as_millis()
+                );
+                base
+            }
+            None => base,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_override_applies_then_reverts() {
+        let base = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut poll = PollOverride::default();
+        assert_eq!(poll.interval(base, start), base);
+
+        poll.set(Duration::from_millis(100), Duration::from_secs(60), start);
+        assert_eq!(poll.interval(base, start), Duration::from_millis(100));
+        assert_eq!(
+            poll.interval(base, start + Duration::from_secs(59)),
+            Duration::from_millis(100)
+        );
+        assert_eq!(poll.interval(base, start + Duration::from_secs(60)), base);
+        // Expired for good, even if asked about an earlier time
+        assert_eq!(poll.interval(base, start), base);
+    }
+
+    #[test]
+    fn test_override_is_clamped() {
+        let start = Instant::now();
+        let mut poll = PollOverride::default();
+        poll.set(Duration::ZERO, Duration::from_secs(1), start);
+        assert_eq!(
+            poll.interval(Duration::from_secs(1), start),
+            MIN_POLL_INTERVAL
+        );
+        poll.set(Duration::MAX, Duration::from_secs(1), start);
+        assert_eq!(
+            poll.interval(Duration::from_secs(1), start),
+            MAX_POLL_INTERVAL
+        );
+    }
+
+    #[test]
+    fn test_huge_duration_does_not_overflow() {
+        let start = Instant::now();
+        let mut poll = PollOverride::default();
+        let applied = poll.set(
+            Duration::from_millis(100),
+            Duration::from_secs(u64::MAX),
+            start,
+        );
+        assert_eq!(applied, MAX_OVERRIDE_DURATION);
+        assert_eq!(
+            poll.interval(Duration::from_secs(1), start),
+            Duration::from_millis(100)
+        );
+    }
+}
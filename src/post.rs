@@ -0,0 +1,146 @@
+use anyhow::{bail, Context, Result};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_POST_COMMAND_TIMEOUT_MS: u64 = 5000;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// How long a failed command is skipped for, doubling after each failure in a row
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// post_command as the converter runs it. After a failure the command is
+// skipped for a while and then tried again, so one hiccup does not disable it.
+#[derive(Debug, Default)]
+pub struct PostCommand {
+    command: Vec<String>,
+    timeout: Duration,
+    delay: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl PostCommand {
+    pub fn new(command: Vec<String>, timeout: Duration) -> Self {
+        PostCommand {
+            command,
+            timeout,
+            delay: FIRST_RETRY_DELAY,
+            retry_at: None,
+        }
+    }
+
+    // None when no command is set, or while it is skipped after a failure
+    pub fn run(&mut self, input: &str, now: Instant) -> Option<Result<String>> {
+        if self.command.is_empty() || self.retry_at.is_some_and(|at| now < at) {
+            return None;
+        }
+        let result = run_post_command(&self.command, input, self.timeout);
+        if result.is_ok() {
+            self.delay = FIRST_RETRY_DELAY;
+            self.retry_at = None;
+        } else {
+            self.retry_at = Some(now + self.delay);
+            self.delay = (self.delay * 2).min(MAX_RETRY_DELAY);
+        }
+        Some(result)
+    }
+}
+
+// Pipes `input` through `command` (program and arguments, run without a
+// shell) and returns its stdout. A command that fails, exits non-zero, prints
+// invalid UTF-8 or runs past `timeout` is an error; it is killed on timeout.
+pub fn run_post_command(command: &[String], input: &str, timeout: Duration) -> Result<String> {
+    let (program, args) = command.split_first().context("post_command is empty")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to start '{}'", program))?;
+
+    // Written and read on their own threads so a command that only reads
+    // part of its input cannot deadlock against a full pipe
+    let mut stdin = child.stdin.take().context("stdin is not piped")?;
+    let input = input.to_string();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let mut stdout = child.stdout.take().context("stdout is not piped")?;
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("'{}' did not finish within {:?}", program, timeout);
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    // A command that exits without reading all of its input is fine
+    let _ = writer.join();
+    let output = reader
+        .join()
+        .map_err(|_| anyhow::anyhow!("Failed to read the output of '{}'", program))??;
+    if !status.success() {
+        bail!("'{}' exited with {}", program, status);
+    }
+    String::from_utf8(output).with_context(|| format!("'{}' printed invalid UTF-8", program))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_run_post_command_pipes_through() {
+        let output = run_post_command(
+            &command(&["tr", "a-z", "A-Z"]),
+            "abc 123\n",
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(output, "ABC 123\n");
+    }
+
+    #[test]
+    fn test_run_post_command_errors() {
+        let timeout = Duration::from_secs(5);
+        assert!(run_post_command(&command(&["false"]), "x", timeout).is_err());
+        assert!(run_post_command(&command(&["no-such-command-xyz"]), "x", timeout).is_err());
+        assert!(run_post_command(&[], "x", timeout).is_err());
+
+        let start = Instant::now();
+        let result = run_post_command(&command(&["sleep", "5"]), "x", Duration::from_millis(100));
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_failed_command_is_retried_with_backoff() {
+        let start = Instant::now();
+        let mut post = PostCommand::new(command(&["false"]), Duration::from_secs(5));
+        assert!(post.run("x", start).unwrap().is_err());
+        assert!(post.run("x", start + Duration::from_millis(500)).is_none());
+        assert!(post.run("x", start + FIRST_RETRY_DELAY).unwrap().is_err());
+        // Twice as long after the second failure
+        let second = start + FIRST_RETRY_DELAY;
+        assert!(post.run("x", second + FIRST_RETRY_DELAY).is_none());
+
+        post.command = command(&["cat"]);
+        let retry = second + FIRST_RETRY_DELAY * 2;
+        assert_eq!(post.run("x", retry).unwrap().unwrap(), "x");
+        assert_eq!(post.delay, FIRST_RETRY_DELAY);
+        assert!(PostCommand::default().run("x", retry).is_none());
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::Timelike;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+// A daily "HH:MM-HH:MM" window during which the clipboard is never written.
+// The start is inclusive and the end exclusive; a window that ends before it
+// starts runs past midnight (e.g. "22:00-07:00").
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct QuietWindow {
+    start: u32,
+    end: u32,
+}
+
+impl QuietWindow {
+    // `minute` counts from local midnight
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+fn parse_minute(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.trim().split_once(':')?;
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    // "24:00" is allowed as the end of the day
+    let minute = hours * 60 + minutes;
+    (minutes < 60 && minute <= MINUTES_PER_DAY).then_some(minute)
+}
+
+impl FromStr for QuietWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid quiet hours '{}' (expected HH:MM-HH:MM)", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start = parse_minute(start).ok_or_else(invalid)?;
+        let end = parse_minute(end).ok_or_else(invalid)?;
+        if start == end {
+            return Err(format!("quiet hours '{}' are empty", s));
+        }
+        Ok(QuietWindow {
+            start: start % MINUTES_PER_DAY,
+            end,
+        })
+    }
+}
+
+impl TryFrom<String> for QuietWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for QuietWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+pub fn is_quiet(windows: &[QuietWindow], minute: u32) -> bool {
+    windows.iter().any(|window| window.contains(minute))
+}
+
+// Minutes since local midnight, the default clock for quiet hours
+pub fn local_minute_of_day() -> u32 {
+    let now = chrono::Local::now();
+    now.hour() * 60 + now.minute()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hours: u32, minutes: u32) -> u32 {
+        hours * 60 + minutes
+    }
+
+    #[test]
+    fn test_parse_quiet_window() {
+        let window: QuietWindow = "09:30-17:00".parse().unwrap();
+        assert_eq!(window.to_string(), "09:30-17:00");
+        let window: QuietWindow = " 22:00 - 24:00 ".parse().unwrap();
+        assert_eq!(window.to_string(), "22:00-24:00");
+        assert!("9-17".parse::<QuietWindow>().is_err());
+        assert!("09:60-10:00".parse::<QuietWindow>().is_err());
+        assert!("25:00-10:00".parse::<QuietWindow>().is_err());
+        assert!("10:00-10:00".parse::<QuietWindow>().is_err());
+    }
+
+    #[test]
+    fn test_in_window() {
+        let windows = vec!["09:00-17:00".parse().unwrap()];
+        assert!(is_quiet(&windows, at(9, 0)));
+        assert!(is_quiet(&windows, at(16, 59)));
+        assert!(!is_quiet(&windows, at(17, 0)));
+        assert!(!is_quiet(&windows, at(8, 59)));
+        assert!(!is_quiet(&[], at(12, 0)));
+    }
+
+    #[test]
+    fn test_window_past_midnight() {
+        let windows = vec![
+            "22:00-07:00".parse().unwrap(),
+            "12:00-13:00".parse().unwrap(),
+        ];
+        assert!(is_quiet(&windows, at(23, 30)));
+        assert!(is_quiet(&windows, at(0, 0)));
+        assert!(is_quiet(&windows, at(6, 59)));
+        assert!(is_quiet(&windows, at(12, 30)));
+        assert!(!is_quiet(&windows, at(7, 0)));
+        assert!(!is_quiet(&windows, at(21, 59)));
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+// Coarse filesystem timestamps can hide a same-size rewrite made right after
+// the fingerprint was taken, so mtimes this close to it are not trusted
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReloadHashStrategy {
+    // Parse the file and hash the resulting rules
+    #[default]
+    Parsed,
+    // Hash the raw file bytes and only parse when they changed
+    Bytes,
+}
+
+pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
+    let mut s = DefaultHasher::new();
+    t.hash(&mut s);
+    s.finish()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileFingerprint {
+    modified: Option<SystemTime>,
+    len: u64,
+    content_hash: u64,
+    recorded: SystemTime,
+}
+
+impl FileFingerprint {
+    pub fn from_path(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let data = fs::read(path)?;
+        Ok(FileFingerprint {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            content_hash: calculate_hash(&data),
+            recorded: SystemTime::now(),
+        })
+    }
+
+    // Cheap stat-only check: true when mtime and size are as recorded and the
+    // mtime is old enough to be trusted
+    pub fn metadata_matches(&self, path: &Path) -> io::Result<bool> {
+        let metadata = fs::metadata(path)?;
+        let Some(modified) = self.modified else {
+            return Ok(false);
+        };
+        let settled = self
+            .recorded
+            .duration_since(modified)
+            .is_ok_and(|age| age >= RACY_WINDOW);
+        Ok(settled && metadata.modified().ok() == Some(modified) && metadata.len() == self.len)
+    }
+
+    // Returns the up-to-date fingerprint and whether the content changed.
+    // The file is only read when its mtime or size moved.
+    pub fn refresh(&self, path: &Path) -> io::Result<(Self, bool)> {
+        if self.metadata_matches(path)? {
+            return Ok((self.clone(), false));
+        }
+        let current = FileFingerprint::from_path(path)?;
+        let changed = current.content_hash != self.content_hash;
+        Ok((current, changed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_refresh_unchanged_file() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(&file_path, "[]").unwrap();
+
+        let fingerprint = FileFingerprint::from_path(&file_path).unwrap();
+        let (refreshed, changed) = fingerprint.refresh(&file_path).unwrap();
+        assert!(!changed);
+        assert_eq!(refreshed.content_hash, fingerprint.content_hash);
+    }
+
+    #[test]
+    fn test_refresh_rewritten_with_same_bytes() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(&file_path, "[]").unwrap();
+        let fingerprint = FileFingerprint::from_path(&file_path).unwrap();
+
+        // Same bytes written again, possibly with a new mtime
+        fs::write(&file_path, "[]").unwrap();
+        let (_, changed) = fingerprint.refresh(&file_path).unwrap();
+        assert!(!changed);
+    }
+
+    #[test]
+    fn test_refresh_changed_content() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(&file_path, "[]").unwrap();
+        let fingerprint = FileFingerprint::from_path(&file_path).unwrap();
+
+        fs::write(&file_path, r#"[{"original": "a", "replacement": "b"}]"#).unwrap();
+        let (refreshed, changed) = fingerprint.refresh(&file_path).unwrap();
+        assert!(changed);
+        assert_ne!(refreshed, fingerprint);
+    }
+
+    #[test]
+    fn test_metadata_matches() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("replacements.json");
+        fs::write(&file_path, "[]").unwrap();
+        let past = SystemTime::now() - Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&file_path)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+        let fingerprint = FileFingerprint::from_path(&file_path).unwrap();
+        assert!(fingerprint.metadata_matches(&file_path).unwrap());
+
+        // A fresh mtime is never trusted
+        fs::write(&file_path, "[]").unwrap();
+        let fingerprint = FileFingerprint::from_path(&file_path).unwrap();
+        assert!(!fingerprint.metadata_matches(&file_path).unwrap());
+    }
+}
@@ -0,0 +1,376 @@
+// `rules_url`: replacements shared by a team, fetched from a URL every
+// `rules_url_interval_secs` on a thread of its own, so a slow server never
+// holds up the clipboard. The ETag of the last response is sent back so an
+// unchanged file costs no download, and the last-good body is cached on disk
+// so a daemon started offline still has the rules.
+use crate::config::{parse_replacements, Replacement};
+use crate::reload::calculate_hash;
+use anyhow::{bail, Result};
+use log::{info, warn};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The last-good body, next to the other config files
+pub const RULES_URL_CACHE_FILE_NAME: &str = "rules_url_cache.json";
+pub const DEFAULT_RULES_URL_INTERVAL_SECS: u64 = 3600;
+// Shorter intervals are raised to this, so a typo cannot hammer the server
+pub const MIN_RULES_URL_INTERVAL_SECS: u64 = 60;
+// A server that hangs would hold back the next fetch
+pub const RULES_URL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Fetched {
+    // The server answered 304 to the ETag that was sent
+    NotModified,
+    Body { body: String, etag: Option<String> },
+}
+
+pub trait RulesSource: Send {
+    fn fetch(&self, url: &str, etag: Option<&str>) -> Result<Fetched>;
+}
+
+#[cfg(feature = "rules-url")]
+pub struct HttpSource {
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "rules-url")]
+impl HttpSource {
+    pub fn new(timeout: Duration) -> Self {
+        HttpSource {
+            agent: ureq::AgentBuilder::new().timeout(timeout).build(),
+        }
+    }
+}
+
+#[cfg(feature = "rules-url")]
+impl RulesSource for HttpSource {
+    fn fetch(&self, url: &str, etag: Option<&str>) -> Result<Fetched> {
+        use anyhow::Context;
+        let mut request = self.agent.get(url);
+        if let Some(etag) = etag {
+            request = request.set("If-None-Match", etag);
+        }
+        let response = request
+            .call()
+            .with_context(|| format!("Failed to fetch {}", url))?;
+        if response.status() == 304 {
+            return Ok(Fetched::NotModified);
+        }
+        let etag = response.header("ETag").map(str::to_string);
+        let body = response
+            .into_string()
+            .with_context(|| format!("Failed to read the response from {}", url))?;
+        Ok(Fetched::Body { body, etag })
+    }
+}
+
+// The rules rewrite whatever is copied, so they must not come over a
+// connection anyone on the path can tamper with
+pub fn check_rules_url(url: &str) -> Result<()> {
+    if !url.starts_with("https://") {
+        bail!("rules_url must be an https:// URL, not '{}'", url);
+    }
+    Ok(())
+}
+
+// What the cache file holds, so rules cached for one URL are not used for another
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedRules {
+    url: String,
+    body: String,
+}
+
+// The daemon's end: the rules fetched in the background, whenever they change
+pub struct RemoteUpdates {
+    pub url: String,
+    pub updates: Receiver<Vec<Replacement>>,
+}
+
+pub struct RemoteRules {
+    url: String,
+    interval: Duration,
+    source: Box<dyn RulesSource>,
+    cache_path: Option<PathBuf>,
+    etag: Option<String>,
+    // Of the last body that parsed, for servers that send no ETag
+    body_hash: Option<u64>,
+    last_fetch: Option<Instant>,
+    // Warn once per failing streak rather than on every fetch
+    failed: bool,
+}
+
+impl RemoteRules {
+    pub fn new(url: String, interval: Duration, source: Box<dyn RulesSource>) -> Result<Self> {
+        check_rules_url(&url)?;
+        Ok(RemoteRules {
+            url,
+            interval,
+            source,
+            cache_path: None,
+            etag: None,
+            body_hash: None,
+            last_fetch: None,
+            failed: false,
+        })
+    }
+
+    pub fn with_cache(mut self, path: PathBuf) -> Self {
+        self.cache_path = Some(path);
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // The rules from the last run, until the first fetch succeeds. Rules
+    // cached for a different URL are not used.
+    pub fn load_cached(&mut self) -> Option<Vec<Replacement>> {
+        let path = self.cache_path.as_ref()?;
+        let data = std::fs::read_to_string(path).ok()?;
+        let cached: CachedRules = serde_json::from_str(&data).ok()?;
+        if cached.url != self.url {
+            return None;
+        }
+        let replacements = parse_replacements(&path.display().to_string(), &cached.body).ok()?;
+        self.body_hash = Some(calculate_hash(&cached.body));
+        Some(replacements)
+    }
+
+    // Fetches on a thread of its own from now on
+    pub fn spawn(mut self) -> Result<RemoteUpdates> {
+        let url = self.url.clone();
+        let (tx, rx) = channel();
+        thread::Builder::new()
+            .name("rules_url".to_string())
+            .spawn(move || loop {
+                if let Some(replacements) = self.poll(Instant::now()) {
+                    if tx.send(replacements).is_err() {
+                        return;
+                    }
+                }
+                thread::sleep(self.interval);
+            })?;
+        Ok(RemoteUpdates { url, updates: rx })
+    }
+
+    pub fn due(&self, now: Instant) -> bool {
+        self.last_fetch
+            .is_none_or(|last| now.duration_since(last) >= self.interval)
+    }
+
+    // The new rules when they changed. None when it is not time to fetch yet,
+    // when nothing changed, or when the fetch failed, in which case the
+    // caller keeps the last-good rules.
+    pub fn poll(&mut self, now: Instant) -> Option<Vec<Replacement>> {
+        if !self.due(now) {
+            return None;
+        }
+        self.last_fetch = Some(now);
+        match self.fetch() {
+            Ok(changed) => {
+                if self.failed {
+                    info!("{} is reachable again.", self.url);
+                }
+                self.failed = false;
+                changed
+            }
+            Err(e) => {
+                if !self.failed {
+                    warn!("{:#}. Keeping the last-good rules.", e);
+                }
+                self.failed = true;
+                None
+            }
+        }
+    }
+
+    fn fetch(&mut self) -> Result<Option<Vec<Replacement>>> {
+        let (body, etag) = match self.source.fetch(&self.url, self.etag.as_deref())? {
+            Fetched::NotModified => return Ok(None),
+            Fetched::Body { body, etag } => (body, etag),
+        };
+        let body_hash = calculate_hash(&body);
+        if self.body_hash == Some(body_hash) {
+            self.etag = etag;
+            return Ok(None);
+        }
+        // The ETag is only kept once the body parses, so a broken file is
+        // fetched again rather than answered with 304
+        let replacements = parse_replacements(&self.url, &body)?;
+        self.etag = etag;
+        self.body_hash = Some(body_hash);
+        if let Some(path) = &self.cache_path {
+            let cached = CachedRules {
+                url: self.url.clone(),
+                body,
+            };
+            let written = serde_json::to_string(&cached)
+                .map_err(std::io::Error::from)
+                .and_then(|data| std::fs::write(path, data));
+            if let Err(e) = written {
+                warn!("Failed to cache {}: {}", self.url, e);
+            }
+        }
+        Ok(Some(replacements))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tempfile::tempdir;
+
+    const RULES: &str = r#"[{"original": "ｆｏｏ", "replacement": "bar"}]"#;
+
+    // Answers from a script and records the ETags it was sent
+    type SentEtags = Arc<Mutex<Vec<Option<String>>>>;
+
+    #[derive(Default)]
+    struct StubSource {
+        responses: Mutex<Vec<Result<Fetched>>>,
+        sent_etags: SentEtags,
+    }
+
+    impl RulesSource for StubSource {
+        fn fetch(&self, _url: &str, etag: Option<&str>) -> Result<Fetched> {
+            self.sent_etags
+                .lock()
+                .unwrap()
+                .push(etag.map(str::to_string));
+            let mut responses = self.responses.lock().unwrap();
+            if responses.is_empty() {
+                return Err(anyhow::anyhow!("no more responses"));
+            }
+            responses.remove(0)
+        }
+    }
+
+    fn remote_at(url: &str, responses: Vec<Result<Fetched>>) -> (RemoteRules, SentEtags) {
+        let source = StubSource {
+            responses: Mutex::new(responses),
+            ..Default::default()
+        };
+        let sent_etags = source.sent_etags.clone();
+        let remote =
+            RemoteRules::new(url.to_string(), Duration::from_secs(60), Box::new(source)).unwrap();
+        (remote, sent_etags)
+    }
+
+    fn remote(responses: Vec<Result<Fetched>>) -> (RemoteRules, SentEtags) {
+        remote_at("https://example.com/rules.json", responses)
+    }
+
+    fn body(body: &str, etag: &str) -> Result<Fetched> {
+        Ok(Fetched::Body {
+            body: body.to_string(),
+            etag: Some(etag.to_string()),
+        })
+    }
+
+    // Test for fetching and reloading
+    #[test]
+    fn test_poll_fetches_and_parses() {
+        let changed = r#"[{"original": "ｆｏｏ", "replacement": "baz"}]"#;
+        let (mut remote, _) = remote(vec![body(RULES, "\"v1\""), body(changed, "\"v2\"")]);
+        let start = Instant::now();
+        let rules = remote.poll(start).unwrap();
+        assert_eq!(rules[0].replacement, "bar");
+        // Not due again until the interval has passed
+        assert_eq!(remote.poll(start + Duration::from_secs(30)), None);
+        let rules = remote.poll(start + Duration::from_secs(60)).unwrap();
+        assert_eq!(rules[0].replacement, "baz");
+    }
+
+    #[test]
+    fn test_poll_skips_unchanged_by_etag() {
+        let (mut remote, sent_etags) =
+            remote(vec![body(RULES, "\"v1\""), Ok(Fetched::NotModified)]);
+        let start = Instant::now();
+        assert!(remote.poll(start).is_some());
+        assert_eq!(remote.poll(start + Duration::from_secs(60)), None);
+        assert_eq!(
+            *sent_etags.lock().unwrap(),
+            [None, Some("\"v1\"".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_poll_skips_unchanged_body_without_etag() {
+        let same = || {
+            Ok(Fetched::Body {
+                body: RULES.to_string(),
+                etag: None,
+            })
+        };
+        let (mut remote, _) = remote(vec![same(), same()]);
+        let start = Instant::now();
+        assert!(remote.poll(start).is_some());
+        assert_eq!(remote.poll(start + Duration::from_secs(60)), None);
+    }
+
+    #[test]
+    fn test_poll_keeps_last_good_on_failure() {
+        let (mut remote, sent_etags) = remote(vec![
+            body(RULES, "\"v1\""),
+            Err(anyhow::anyhow!("connection refused")),
+            body("[{", "\"broken\""),
+            body(RULES, "\"v1\""),
+        ]);
+        let start = Instant::now();
+        assert!(remote.poll(start).is_some());
+        assert_eq!(remote.poll(start + Duration::from_secs(60)), None);
+        assert_eq!(remote.poll(start + Duration::from_secs(120)), None);
+        // The broken body's ETag was not kept, and the old body is no change
+        assert_eq!(remote.poll(start + Duration::from_secs(180)), None);
+        assert_eq!(sent_etags.lock().unwrap()[3].as_deref(), Some("\"v1\""));
+    }
+
+    // Test for the on-disk cache
+    #[test]
+    fn test_cache_survives_restart() {
+        let temp_dir = tempdir().unwrap();
+        let cache = temp_dir.path().join(RULES_URL_CACHE_FILE_NAME);
+        let (remote_rules, _) = remote(vec![body(RULES, "\"v1\"")]);
+        let mut remote_rules = remote_rules.with_cache(cache.clone());
+        assert_eq!(remote_rules.load_cached(), None);
+        remote_rules.poll(Instant::now()).unwrap();
+
+        let (restarted, _) = remote(vec![Err(anyhow::anyhow!("offline"))]);
+        let mut restarted = restarted.with_cache(cache.clone());
+        assert_eq!(restarted.load_cached().unwrap()[0].replacement, "bar");
+        assert_eq!(restarted.poll(Instant::now()), None);
+
+        // Once rules_url points elsewhere, the old URL's rules are not used
+        let (moved, _) = remote_at("https://example.org/rules.json", vec![]);
+        assert_eq!(moved.with_cache(cache).load_cached(), None);
+    }
+
+    #[test]
+    fn test_rules_url_must_be_https() {
+        assert!(check_rules_url("https://example.com/rules.json").is_ok());
+        assert!(check_rules_url("http://example.com/rules.json").is_err());
+        assert!(check_rules_url("example.com/rules.json").is_err());
+        let source = Box::new(StubSource::default());
+        assert!(RemoteRules::new(
+            "http://example.com/rules.json".to_string(),
+            Duration::from_secs(60),
+            source
+        )
+        .is_err());
+    }
+
+    // Test for the background fetch
+    #[test]
+    fn test_spawned_fetch_sends_rules() {
+        let (remote, _) = remote(vec![body(RULES, "\"v1\"")]);
+        let RemoteUpdates { url, updates } = remote.spawn().unwrap();
+        assert_eq!(url, "https://example.com/rules.json");
+        let rules = updates.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(rules[0].replacement, "bar");
+    }
+}
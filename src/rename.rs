@@ -0,0 +1,205 @@
+// Renaming files to their halfwidth-converted names. Renaming is destructive,
+// so nothing is touched unless `apply` is set, and an existing file is never
+// overwritten: a numbered name is used instead.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::error::FormatError;
+
+// Numbered names tried when the converted name is taken, "a (2).txt" onwards
+const MAX_COLLISION_SUFFIX: u32 = 100;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RenameOutcome {
+    Unchanged,
+    // Without `apply`: the name the file would get, before collision handling
+    WouldRename(PathBuf),
+    Renamed(PathBuf),
+    Skipped(String),
+}
+
+// The converted name, or the reason it can't be used as a file name
+pub fn convert_file_name(
+    name: &str,
+    convert: impl FnOnce(&str) -> Result<String, FormatError>,
+) -> Result<Option<String>, String> {
+    let converted = convert(name).map_err(|e| e.to_string())?;
+    if converted == name {
+        return Ok(None);
+    }
+    // ／ and ＼ become separators, which would move the file somewhere else
+    if converted.is_empty()
+        || converted == "."
+        || converted == ".."
+        || converted.contains(['/', '\\', '\0'])
+    {
+        return Err(format!("'{}' is not a valid file name", converted));
+    }
+    Ok(Some(converted))
+}
+
+pub fn rename_to_halfwidth(
+    path: &Path,
+    convert: impl FnOnce(&str) -> Result<String, FormatError>,
+    apply: bool,
+) -> Result<RenameOutcome> {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(RenameOutcome::Skipped(
+            "the name is not valid UTF-8".to_string(),
+        ));
+    };
+    if !path.is_file() {
+        return Ok(RenameOutcome::Skipped("not a regular file".to_string()));
+    }
+    let converted = match convert_file_name(name, convert) {
+        Ok(Some(converted)) => converted,
+        Ok(None) => return Ok(RenameOutcome::Unchanged),
+        Err(reason) => return Ok(RenameOutcome::Skipped(reason)),
+    };
+    let target = path.with_file_name(converted);
+    if !apply {
+        return Ok(RenameOutcome::WouldRename(target));
+    }
+    let renamed = rename_no_clobber(path, &target)
+        .with_context(|| format!("Failed to rename {}", path.display()))?;
+    Ok(RenameOutcome::Renamed(renamed))
+}
+
+// "name.ext" -> "name (n).ext"
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{} ({}).{}", stem, n, ext.to_string_lossy()),
+        None => format!("{} ({})", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+// fs::rename replaces the target on Unix, so taken names are skipped first.
+// Unlike a hard link, a rename also works on filesystems such as FAT.
+fn rename_no_clobber(from: &Path, to: &Path) -> io::Result<PathBuf> {
+    for n in 1..=MAX_COLLISION_SUFFIX {
+        let target = if n == 1 {
+            to.to_path_buf()
+        } else {
+            numbered(to, n)
+        };
+        // symlink_metadata, so a dangling symlink counts as taken too
+        if fs::symlink_metadata(&target).is_ok() {
+            continue;
+        }
+        fs::rename(from, &target)?;
+        return Ok(target);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!(
+            "{} and {} numbered alternatives already exist",
+            to.display(),
+            MAX_COLLISION_SUFFIX - 1
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::format::{format_text_with_options, FormatOptions};
+    use tempfile::tempdir;
+
+    fn halfwidth(name: &str) -> Result<String, FormatError> {
+        format_text_with_options(name, &[], &[], &FormatOptions::default())
+    }
+
+    #[test]
+    fn test_convert_file_name() {
+        assert_eq!(
+            convert_file_name("レポート２０２４.ｔｘｔ", halfwidth),
+            Ok(Some("レポート2024.txt".to_string()))
+        );
+        assert_eq!(convert_file_name("plain.txt", halfwidth), Ok(None));
+        assert!(convert_file_name("ａ／ｂ.txt", halfwidth).is_err());
+        assert!(convert_file_name("．．", halfwidth).is_err());
+    }
+
+    #[test]
+    fn test_rename_requires_apply() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("ＡＢＣ.txt");
+        fs::write(&path, "data").unwrap();
+
+        let outcome = rename_to_halfwidth(&path, halfwidth, false).unwrap();
+        assert_eq!(
+            outcome,
+            RenameOutcome::WouldRename(temp_dir.path().join("ABC.txt"))
+        );
+        assert!(path.exists());
+
+        let outcome = rename_to_halfwidth(&path, halfwidth, true).unwrap();
+        let target = temp_dir.path().join("ABC.txt");
+        assert_eq!(outcome, RenameOutcome::Renamed(target.clone()));
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(target).unwrap(), "data");
+    }
+
+    #[test]
+    fn test_rename_collision_keeps_existing_file() {
+        let temp_dir = tempdir().unwrap();
+        let existing = temp_dir.path().join("ABC.txt");
+        fs::write(&existing, "existing").unwrap();
+        fs::write(temp_dir.path().join("ABC (2).txt"), "also existing").unwrap();
+        let path = temp_dir.path().join("ＡＢＣ.txt");
+        fs::write(&path, "new").unwrap();
+
+        let outcome = rename_to_halfwidth(&path, halfwidth, true).unwrap();
+        let target = temp_dir.path().join("ABC (3).txt");
+        assert_eq!(outcome, RenameOutcome::Renamed(target.clone()));
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "existing");
+        assert_eq!(fs::read_to_string(target).unwrap(), "new");
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rename_never_replaces_a_dangling_symlink() {
+        let temp_dir = tempdir().unwrap();
+        let link = temp_dir.path().join("ABC.txt");
+        std::os::unix::fs::symlink(temp_dir.path().join("missing"), &link).unwrap();
+        let path = temp_dir.path().join("ＡＢＣ.txt");
+        fs::write(&path, "new").unwrap();
+
+        let outcome = rename_to_halfwidth(&path, halfwidth, true).unwrap();
+        assert_eq!(
+            outcome,
+            RenameOutcome::Renamed(temp_dir.path().join("ABC (2).txt"))
+        );
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    }
+
+    #[test]
+    fn test_rename_skips_directories_and_unchanged() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().join("ＤＩＲ");
+        fs::create_dir(&dir).unwrap();
+        assert!(matches!(
+            rename_to_halfwidth(&dir, halfwidth, true).unwrap(),
+            RenameOutcome::Skipped(_)
+        ));
+        let plain = temp_dir.path().join("plain.txt");
+        fs::write(&plain, "").unwrap();
+        assert_eq!(
+            rename_to_halfwidth(&plain, halfwidth, true).unwrap(),
+            RenameOutcome::Unchanged
+        );
+    }
+}
@@ -0,0 +1,360 @@
+// `rule_cache`: the rules compiled from every config file, kept in the cache
+// directory so startup with a huge rule set can skip parsing. The cache
+// records a hash of every source file and the version that wrote it; a change
+// to any of them makes it stale, and it is rebuilt from the sources. The
+// warnings loading the sources gave are kept too, and logged on every startup.
+use log::{debug, warn};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::config::{
+    load_checked_exclusions, load_checked_replacements, Occurrence, Replacement, ReplacementAction,
+};
+use crate::error::ConfigError;
+use crate::format::StageExclusions;
+use crate::reload::calculate_hash;
+use crate::rules::CompiledRules;
+
+// What the daemon runs with, compiled from its replacement and exclusion files
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompiledConfig {
+    pub rules: CompiledRules,
+    pub warnings: Vec<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CacheFile {
+    version: String,
+    sources_hash: u64,
+    rules: Vec<CachedRule>,
+    exclusion_list: Vec<char>,
+    stage_exclusions: StageExclusions,
+    warnings: Vec<String>,
+}
+
+// Replacement's own serde form skips default fields, which a format without
+// field names cannot read back, so the cache has every field spelled out
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedRule {
+    original: String,
+    replacement: String,
+    action: ReplacementAction,
+    context_before: Option<String>,
+    context_after: Option<String>,
+    occurrence: Occurrence,
+    literal_escape: bool,
+    max_matches: Option<usize>,
+    protect_output: bool,
+}
+
+impl From<&Replacement> for CachedRule {
+    fn from(rule: &Replacement) -> Self {
+        CachedRule {
+            original: rule.original.clone(),
+            replacement: rule.replacement.clone(),
+            action: rule.action,
+            context_before: rule.context_before.clone(),
+            context_after: rule.context_after.clone(),
+            occurrence: rule.occurrence,
+            literal_escape: rule.literal_escape,
+            max_matches: rule.max_matches,
+            protect_output: rule.protect_output,
+        }
+    }
+}
+
+impl From<CachedRule> for Replacement {
+    fn from(rule: CachedRule) -> Self {
+        Replacement {
+            original: rule.original,
+            replacement: rule.replacement,
+            action: rule.action,
+            context_before: rule.context_before,
+            context_after: rule.context_after,
+            occurrence: rule.occurrence,
+            literal_escape: rule.literal_escape,
+            max_matches: rule.max_matches,
+            protect_output: rule.protect_output,
+            context_regexes: Default::default(),
+        }
+    }
+}
+
+// Where caches go; None when the platform has no cache directory
+pub fn default_cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("kill-zen-all"))
+}
+
+// One cache per set of source paths, so that configs which differ only in
+// their extra files do not keep rebuilding each other's cache
+pub fn cache_path(cache_dir: &Path, sources: &[PathBuf]) -> PathBuf {
+    cache_dir.join(format!("rules-{:016x}.bin", calculate_hash(&sources)))
+}
+
+// The files the config is compiled from, in a fixed order
+fn sources(
+    replacement_paths: &[PathBuf],
+    exclusion_path: &Path,
+    layer_path: Option<&Path>,
+) -> Vec<PathBuf> {
+    replacement_paths
+        .iter()
+        .cloned()
+        .chain(std::iter::once(exclusion_path.to_path_buf()))
+        .chain(layer_path.map(Path::to_path_buf))
+        .collect()
+}
+
+// A missing file hashes differently from an empty one
+fn sources_hash(sources: &[PathBuf]) -> u64 {
+    let contents: Vec<(&PathBuf, Option<Vec<u8>>)> = sources
+        .iter()
+        .map(|path| (path, fs::read(path).ok()))
+        .collect();
+    calculate_hash(&contents)
+}
+
+fn path_str(path: &Path) -> Result<&str, ConfigError> {
+    path.to_str()
+        .ok_or_else(|| ConfigError::InvalidPath(path.display().to_string()))
+}
+
+// The config read from its sources, with the warnings collected rather than logged
+pub fn compile_config(
+    replacement_paths: &[PathBuf],
+    exclusion_path: &Path,
+    layer_path: Option<&Path>,
+) -> Result<CompiledConfig, ConfigError> {
+    let mut warnings = Vec::new();
+    let mut replacements = Vec::new();
+    for path in replacement_paths {
+        replacements.extend(load_checked_replacements(path_str(path)?, &mut warnings)?);
+    }
+    let (exclusion_list, stage_exclusions) = load_checked_exclusions(
+        path_str(exclusion_path)?,
+        layer_path.map(path_str).transpose()?,
+        &mut warnings,
+    )?;
+    Ok(CompiledConfig {
+        rules: CompiledRules {
+            replacements,
+            exclusion_list,
+            stage_exclusions,
+        },
+        warnings,
+    })
+}
+
+// The cached config when it is up to date, otherwise the config compiled from
+// its sources with the cache rewritten. A cache that cannot be written only
+// costs the speedup.
+pub fn load_config_cached(
+    cache_dir: &Path,
+    replacement_paths: &[PathBuf],
+    exclusion_path: &Path,
+    layer_path: Option<&Path>,
+) -> Result<CompiledConfig, ConfigError> {
+    let sources = sources(replacement_paths, exclusion_path, layer_path);
+    let path = cache_path(cache_dir, &sources);
+    let sources_hash = sources_hash(&sources);
+    let config = match read_cache(&path, sources_hash) {
+        Some(config) => {
+            debug!(
+                "Loaded {} rules from {}.",
+                config.rules.replacements.len(),
+                path.display()
+            );
+            config
+        }
+        None => {
+            let config = compile_config(replacement_paths, exclusion_path, layer_path)?;
+            if let Err(e) = write_cache(&path, sources_hash, &config) {
+                warn!("Failed to write {}: {}", path.display(), e);
+            }
+            config
+        }
+    };
+    for warning in &config.warnings {
+        warn!("{}", warning);
+    }
+    Ok(config)
+}
+
+fn read_cache(path: &Path, sources_hash: u64) -> Option<CompiledConfig> {
+    let data = fs::read(path).ok()?;
+    let cache: CacheFile = bincode::deserialize(&data).ok()?;
+    if cache.version != env!("CARGO_PKG_VERSION") || cache.sources_hash != sources_hash {
+        debug!("{} is stale.", path.display());
+        return None;
+    }
+    Some(CompiledConfig {
+        rules: CompiledRules {
+            replacements: cache.rules.into_iter().map(Replacement::from).collect(),
+            exclusion_list: cache.exclusion_list,
+            stage_exclusions: cache.stage_exclusions,
+        },
+        warnings: cache.warnings,
+    })
+}
+
+// Written to a temporary file first, so a daemon starting meanwhile never
+// reads half a cache
+fn write_cache(path: &Path, sources_hash: u64, config: &CompiledConfig) -> Result<(), String> {
+    let cache = CacheFile {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        sources_hash,
+        rules: config
+            .rules
+            .replacements
+            .iter()
+            .map(CachedRule::from)
+            .collect(),
+        exclusion_list: config.rules.exclusion_list.clone(),
+        stage_exclusions: config.rules.stage_exclusions.clone(),
+        warnings: config.warnings.clone(),
+    };
+    let data = bincode::serialize(&cache).map_err(|e| e.to_string())?;
+    let dir = path.parent().ok_or("the cache path has no directory")?;
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let mut partial = tempfile::NamedTempFile::new_in(dir).map_err(|e| e.to_string())?;
+    partial.write_all(&data).map_err(|e| e.to_string())?;
+    partial.persist(path).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load_replacements;
+    use tempfile::tempdir;
+
+    const RULES: &str = r#"[
+        {"original": "foo", "replacement": "bar"},
+        {"original": "\\d+", "action": "convert_width", "max_matches": 2},
+        {"original": "!", "replacement": "！", "protect_output": true, "occurrence": "last"},
+        {"original": "same", "replacement": "same"}
+    ]"#;
+
+    struct Sources {
+        _dir: tempfile::TempDir,
+        cache_dir: PathBuf,
+        replacements: Vec<PathBuf>,
+        exclusions: PathBuf,
+    }
+
+    fn sources() -> Sources {
+        let dir = tempdir().unwrap();
+        let first = dir.path().join("replacements.json");
+        let extra = dir.path().join("extra.json");
+        let exclusions = dir.path().join("exclusions.json");
+        fs::write(&first, RULES).unwrap();
+        fs::write(&extra, r#"[{"original": "baz", "replacement": "qux"}]"#).unwrap();
+        fs::write(&exclusions, r#"{"exclude": ["Ａ"]}"#).unwrap();
+        Sources {
+            cache_dir: dir.path().join("cache"),
+            replacements: vec![first, extra],
+            exclusions,
+            _dir: dir,
+        }
+    }
+
+    fn load(sources: &Sources) -> CompiledConfig {
+        load_config_cached(
+            &sources.cache_dir,
+            &sources.replacements,
+            &sources.exclusions,
+            None,
+        )
+        .unwrap()
+    }
+
+    fn cache_file(sources: &Sources) -> PathBuf {
+        let mut all = sources.replacements.clone();
+        all.push(sources.exclusions.clone());
+        cache_path(&sources.cache_dir, &all)
+    }
+
+    #[test]
+    fn test_cache_is_written_and_matches_sources() {
+        let sources = sources();
+        let config = load(&sources);
+        let mut expected = load_replacements(sources.replacements[0].to_str().unwrap()).unwrap();
+        expected.extend(load_replacements(sources.replacements[1].to_str().unwrap()).unwrap());
+        assert_eq!(config.rules.replacements, expected);
+        assert_eq!(config.rules.exclusion_list, vec!['Ａ']);
+        assert!(cache_file(&sources).exists());
+        // Nothing is written next to the config files
+        assert!(!sources.replacements[0].with_extension("bin").exists());
+        assert_eq!(load(&sources), config);
+    }
+
+    #[test]
+    fn test_warnings_survive_the_cache() {
+        let sources = sources();
+        let config = load(&sources);
+        assert_eq!(config.warnings.len(), 1);
+        assert!(config.warnings[0].contains("changes nothing"));
+        assert_eq!(load(&sources).warnings, config.warnings);
+    }
+
+    #[test]
+    fn test_cache_is_used_until_any_source_changes() {
+        let sources = sources();
+        let config = load(&sources);
+        // A cache with other rules proves the sources are not parsed
+        let marker = CompiledConfig {
+            rules: CompiledRules {
+                replacements: vec![Replacement {
+                    original: "cached".to_string(),
+                    replacement: "rule".to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut all = sources.replacements.clone();
+        all.push(sources.exclusions.clone());
+        write_cache(&cache_file(&sources), sources_hash(&all), &marker).unwrap();
+        assert_eq!(load(&sources), marker);
+
+        // The extra file counts as much as the first
+        fs::write(
+            &sources.replacements[1],
+            r#"[{"original": "baz", "replacement": "quux"}]"#,
+        )
+        .unwrap();
+        let changed = load(&sources);
+        assert_ne!(changed, marker);
+        assert_eq!(
+            changed.rules.replacements.len(),
+            config.rules.replacements.len()
+        );
+        assert_eq!(
+            changed.rules.replacements.last().unwrap().replacement,
+            "quux"
+        );
+        // ...and so do the exclusions
+        fs::write(&sources.exclusions, r#"{"exclude": ["Ｂ"]}"#).unwrap();
+        assert_eq!(load(&sources).rules.exclusion_list, vec!['Ｂ']);
+        assert_eq!(load(&sources).rules.exclusion_list, vec!['Ｂ']);
+    }
+
+    #[test]
+    fn test_broken_cache_is_ignored() {
+        let sources = sources();
+        fs::create_dir_all(&sources.cache_dir).unwrap();
+        fs::write(cache_file(&sources), b"not bincode").unwrap();
+        assert_eq!(load(&sources).rules.replacements.len(), 4);
+
+        fs::remove_file(&sources.replacements[0]).unwrap();
+        assert!(load_config_cached(
+            &sources.cache_dir,
+            &sources.replacements,
+            &sources.exclusions,
+            None
+        )
+        .is_err());
+    }
+}
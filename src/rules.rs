@@ -0,0 +1,166 @@
+// The rules a conversion runs with. A reload builds a whole new set and
+// swaps it in at once, so a conversion that took a snapshot before the swap
+// finishes with the old rules and never sees half of each.
+use crate::config::Replacement;
+use crate::error::FormatError;
+use crate::format::{format_text_with_options, FormatOptions, StageExclusions};
+use arc_swap::ArcSwap;
+use std::sync::Arc;
+use thiserror::Error;
+
+// Converted together with each rule's `original` by the self-test
+const IDEMPOTENCY_SAMPLE: &str =
+    "ＡＢＣ ａｂｃ １２３　（全角）　ｈｔｔｐｓ：／／ｅｘａｍｐｌｅ．ｃｏｍ\r\n";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompiledRules {
+    pub replacements: Vec<Replacement>,
+    pub exclusion_list: Vec<char>,
+    // Part of the snapshot so that a reload never pairs new rules with old
+    // exclusions; handed to the conversion through FormatOptions
+    pub stage_exclusions: StageExclusions,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SharedRules(Arc<ArcSwap<CompiledRules>>);
+
+impl SharedRules {
+    pub fn new(rules: CompiledRules) -> Self {
+        SharedRules(Arc::new(ArcSwap::from_pointee(rules)))
+    }
+
+    // Stays valid, and unchanged, for as long as the caller holds it
+    pub fn snapshot(&self) -> Arc<CompiledRules> {
+        self.0.load_full()
+    }
+
+    pub fn publish(&self, rules: CompiledRules) {
+        self.0.store(Arc::new(rules));
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum IdempotencyError {
+    #[error(transparent)]
+    Format(#[from] FormatError),
+    #[error("converting '{sample}' gives '{first}', but converting that again gives '{second}'")]
+    NotIdempotent {
+        sample: String,
+        first: String,
+        second: String,
+    },
+}
+
+// What the self-test converts: every rule's `original` and a fixed sample
+pub fn idempotency_samples(rules: &CompiledRules) -> Vec<String> {
+    std::iter::once(IDEMPOTENCY_SAMPLE.to_string())
+        .chain(rules.replacements.iter().map(|rule| rule.original.clone()))
+        .collect()
+}
+
+// The daemon reads back what it wrote, so a second pass must change nothing.
+// Otherwise the clipboard is rewritten on every tick.
+pub fn check_idempotent(
+    rules: &CompiledRules,
+    options: &FormatOptions,
+    samples: &[String],
+) -> Result<(), IdempotencyError> {
+    let format = |text: &str| {
+        format_text_with_options(text, &rules.replacements, &rules.exclusion_list, options)
+    };
+    for sample in samples {
+        let first = format(sample)?;
+        let second = format(&first)?;
+        if first != second {
+            return Err(IdempotencyError::NotIdempotent {
+                sample: sample.clone(),
+                first,
+                second,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ReplacementAction;
+    use crate::format::format_text;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    fn rules(from: &str, to: &str) -> CompiledRules {
+        CompiledRules {
+            replacements: vec![Replacement {
+                original: from.to_string(),
+                replacement: to.to_string(),
+                action: ReplacementAction::Replace,
+                ..Default::default()
+            }],
+            exclusion_list: Vec::new(),
+            stage_exclusions: StageExclusions::new(),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_survives_publish() {
+        let shared = SharedRules::new(rules("foo", "bar"));
+        let before = shared.snapshot();
+        shared.publish(rules("foo", "baz"));
+        assert_eq!(*before, rules("foo", "bar"));
+        assert_eq!(*shared.snapshot(), rules("foo", "baz"));
+    }
+
+    #[test]
+    fn test_conversion_started_before_reload_uses_old_rules() {
+        let shared = SharedRules::new(rules("foo", "bar"));
+        let (started_tx, started_rx) = channel();
+        let (reloaded_tx, reloaded_rx) = channel();
+        let worker = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let rules = shared.snapshot();
+                let first = format_text("foo", &rules.replacements, &rules.exclusion_list).unwrap();
+                started_tx.send(()).unwrap();
+                reloaded_rx.recv().unwrap();
+                let second =
+                    format_text("foo", &rules.replacements, &rules.exclusion_list).unwrap();
+                (first, second)
+            })
+        };
+        started_rx.recv().unwrap();
+        shared.publish(rules("foo", "baz"));
+        reloaded_tx.send(()).unwrap();
+        assert_eq!(
+            worker.join().unwrap(),
+            ("bar".to_string(), "bar".to_string())
+        );
+        let rules = shared.snapshot();
+        assert_eq!(
+            format_text("foo", &rules.replacements, &rules.exclusion_list).unwrap(),
+            "baz"
+        );
+    }
+
+    // Test for the idempotency self-test
+    #[test]
+    fn test_idempotent_rules_pass() {
+        let rules = rules("foo", "bar");
+        let samples = idempotency_samples(&rules);
+        assert_eq!(samples.len(), 2);
+        check_idempotent(&rules, &FormatOptions::default(), &samples).unwrap();
+    }
+
+    #[test]
+    fn test_growing_rule_fails() {
+        let rules = rules("TODO", "TODO(me)");
+        let samples = idempotency_samples(&rules);
+        let error = check_idempotent(&rules, &FormatOptions::default(), &samples).unwrap_err();
+        assert!(matches!(
+            error,
+            IdempotencyError::NotIdempotent { ref sample, ref second, .. }
+                if sample == "TODO" && second == "TODO(me)(me)"
+        ));
+    }
+}
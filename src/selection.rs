@@ -0,0 +1,102 @@
+// PRIMARY selection support (X11): every mouse selection lands in PRIMARY, so
+// it is only converted while the configured modifier is held.
+
+// X11 modifier mask bits (xproto ModMask)
+const MASK_SHIFT: u16 = 0x01;
+const MASK_CONTROL: u16 = 0x04;
+const MASK_ALT: u16 = 0x08;
+const MASK_SUPER: u16 = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Modifier {
+    #[default]
+    Shift,
+    Control,
+    Alt,
+    Super,
+}
+
+impl Modifier {
+    fn mask(self) -> u16 {
+        match self {
+            Modifier::Shift => MASK_SHIFT,
+            Modifier::Control => MASK_CONTROL,
+            Modifier::Alt => MASK_ALT,
+            Modifier::Super => MASK_SUPER,
+        }
+    }
+
+    pub fn is_held(self, modifier_mask: u16) -> bool {
+        modifier_mask & self.mask() != 0
+    }
+}
+
+// `modifier_mask` is None when the modifier state could not be queried, in
+// which case the selection is left alone
+pub fn should_convert_primary(
+    enabled: bool,
+    modifier: Modifier,
+    modifier_mask: Option<u16>,
+) -> bool {
+    enabled && modifier_mask.is_some_and(|mask| modifier.is_held(mask))
+}
+
+// A copy made while the skip modifier is held is left unconverted. An unknown
+// modifier state converts as usual.
+pub fn should_skip_copy(modifier: Option<Modifier>, modifier_mask: Option<u16>) -> bool {
+    modifier
+        .zip(modifier_mask)
+        .is_some_and(|(modifier, mask)| modifier.is_held(mask))
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(feature = "primary-selection", feature = "skip-modifier")
+))]
+pub fn query_modifier_mask() -> Option<u16> {
+    crate::x11::with_connection(|conn, root| {
+        let reply = xcb::query_pointer(conn, root).get_reply().ok()?;
+        Some(reply.mask())
+    })
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(feature = "primary-selection", feature = "skip-modifier")
+)))]
+pub fn query_modifier_mask() -> Option<u16> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modifier_is_held() {
+        assert!(Modifier::Shift.is_held(MASK_SHIFT));
+        assert!(Modifier::Control.is_held(MASK_SHIFT | MASK_CONTROL));
+        assert!(!Modifier::Alt.is_held(MASK_SHIFT | MASK_CONTROL));
+        assert!(!Modifier::Super.is_held(0));
+    }
+
+    #[test]
+    fn test_should_convert_primary() {
+        let held = Some(MASK_CONTROL);
+        let not_held = Some(MASK_SHIFT);
+        assert!(should_convert_primary(true, Modifier::Control, held));
+        assert!(!should_convert_primary(true, Modifier::Control, not_held));
+        assert!(!should_convert_primary(false, Modifier::Control, held));
+        assert!(!should_convert_primary(true, Modifier::Control, None));
+    }
+
+    #[test]
+    fn test_should_skip_copy() {
+        assert!(should_skip_copy(Some(Modifier::Shift), Some(MASK_SHIFT)));
+        assert!(!should_skip_copy(Some(Modifier::Shift), Some(0)));
+        assert!(!should_skip_copy(Some(Modifier::Shift), Some(MASK_CONTROL)));
+        assert!(!should_skip_copy(None, Some(MASK_SHIFT)));
+        assert!(!should_skip_copy(Some(Modifier::Shift), None));
+    }
+}
@@ -0,0 +1,276 @@
+// `kill-zen-all setup`: asks which conversions to enable and writes the
+// exclusions and settings to match, for users who would rather not start from
+// the JSON defaults
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{
+    find_combined_config, find_config_file, serialize_exclusions, write_default_config,
+    ConfigFormat, CONFIG_VERSION, EXCLUSIONS_FILE_STEM, SETTINGS_FILE_NAME,
+};
+use crate::format::Aggressiveness;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupAnswers {
+    // ０-９ -> 0-9
+    pub digits: bool,
+    // Ａ-Ｚ, ａ-ｚ -> A-Z, a-z
+    pub letters: bool,
+    // ｶﾀｶﾅ -> カタカナ, which needs aggressiveness = high
+    pub katakana: bool,
+    // ！＃（） and the other fullwidth ASCII symbols
+    pub punctuation: bool,
+}
+
+impl Default for SetupAnswers {
+    fn default() -> Self {
+        SetupAnswers {
+            digits: true,
+            letters: true,
+            katakana: false,
+            punctuation: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedConfig {
+    pub exclude: Vec<char>,
+    pub aggressiveness: Aggressiveness,
+}
+
+pub fn generate_config(answers: &SetupAnswers) -> GeneratedConfig {
+    // The ideographic space stays, as in the default exclusions
+    let mut exclude = vec!['　'];
+    if !answers.digits {
+        exclude.extend('０'..='９');
+    }
+    if !answers.letters {
+        exclude.extend(('Ａ'..='Ｚ').chain('ａ'..='ｚ'));
+    }
+    if !answers.punctuation {
+        exclude.extend(('！'..='～').filter(|c| !c.is_alphanumeric()));
+    }
+    let aggressiveness = if answers.katakana {
+        Aggressiveness::High
+    } else {
+        Aggressiveness::Low
+    };
+    GeneratedConfig {
+        exclude,
+        aggressiveness,
+    }
+}
+
+// Asks until the answer is y/n; an empty answer or the end of input takes the default
+pub fn ask(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    question: &str,
+    default: bool,
+) -> Result<bool> {
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+    loop {
+        write!(output, "{} {} ", question, hint)?;
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            writeln!(output)?;
+            return Ok(default);
+        }
+        match line.trim().to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => writeln!(output, "Please answer y or n.")?,
+        }
+    }
+}
+
+pub fn ask_answers(input: &mut impl BufRead, output: &mut impl Write) -> Result<SetupAnswers> {
+    let defaults = SetupAnswers::default();
+    Ok(SetupAnswers {
+        digits: ask(
+            input,
+            output,
+            "Convert fullwidth digits (１２３ -> 123)?",
+            defaults.digits,
+        )?,
+        letters: ask(
+            input,
+            output,
+            "Convert fullwidth letters (ＡＢＣ -> ABC)?",
+            defaults.letters,
+        )?,
+        katakana: ask(
+            input,
+            output,
+            "Convert halfwidth katakana to fullwidth (ｶﾀｶﾅ -> カタカナ)? This also NFKC-normalizes and spaces out Japanese and Latin text",
+            defaults.katakana,
+        )?,
+        punctuation: ask(
+            input,
+            output,
+            "Convert fullwidth punctuation and symbols (！＃（） -> !#())?",
+            defaults.punctuation,
+        )?,
+    })
+}
+
+// Writes the exclusions (in the existing file's format, or `format` for a new
+// one) and sets `aggressiveness` in settings.json, keeping its other keys.
+// The default replacements are created if missing.
+pub fn write_setup(
+    config_dir: &Path,
+    config: &GeneratedConfig,
+    format: ConfigFormat,
+) -> Result<Vec<PathBuf>> {
+    let existing = find_config_file(config_dir, EXCLUSIONS_FILE_STEM);
+    let exclusion_path = if existing.exists() {
+        existing
+    } else {
+        config_dir.join(format!("{}.{}", EXCLUSIONS_FILE_STEM, format.extension()))
+    };
+    write_default_config(config_dir, format)?;
+    fs::write(
+        &exclusion_path,
+        serialize_exclusions(&exclusion_path, &config.exclude)?,
+    )
+    .with_context(|| format!("Failed to write {}", exclusion_path.display()))?;
+
+    let settings_path = config_dir.join(SETTINGS_FILE_NAME);
+    let mut settings = match fs::read_to_string(&settings_path) {
+        Ok(content) => serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", settings_path.display()))?,
+        Err(_) => serde_json::Value::Object(serde_json::Map::new()),
+    };
+    let aggressiveness = match config.aggressiveness {
+        Aggressiveness::Low => "low",
+        Aggressiveness::High => "high",
+    };
+    settings
+        .as_object_mut()
+        .with_context(|| format!("{} is not a JSON object", settings_path.display()))?
+        .insert("aggressiveness".to_string(), aggressiveness.into());
+    settings["config_version"] = CONFIG_VERSION.into();
+    fs::write(
+        &settings_path,
+        serde_json::to_string_pretty(&settings)? + "\n",
+    )
+    .with_context(|| format!("Failed to write {}", settings_path.display()))?;
+    Ok(vec![exclusion_path, settings_path])
+}
+
+pub fn run_setup(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    config_dir: &Path,
+    format: ConfigFormat,
+) -> Result<()> {
+    writeln!(
+        output,
+        "kill-zen-all rewrites fullwidth text on the clipboard as halfwidth. Choose what to convert."
+    )?;
+    if let Some(combined) = find_combined_config(config_dir) {
+        anyhow::bail!(
+            "setup writes the split exclusions and settings files, which {} takes precedence over. Edit it instead.",
+            combined.display()
+        );
+    }
+    let answers = ask_answers(input, output)?;
+    let existing = find_config_file(config_dir, EXCLUSIONS_FILE_STEM);
+    if existing.exists() {
+        let question = format!("Overwrite {}?", existing.display());
+        if !ask(input, output, &question, false)? {
+            writeln!(output, "Nothing was written.")?;
+            return Ok(());
+        }
+    }
+    let written = write_setup(config_dir, &generate_config(&answers), format)?;
+    writeln!(output, "Wrote:")?;
+    for path in &written {
+        writeln!(output, "  {}", path.display())?;
+    }
+    writeln!(
+        output,
+        "Replacement rules are in {}. Edit these files to fine-tune; a running kill-zen-all picks up changes automatically.",
+        config_dir.display()
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::{load_exclusion_list, load_settings};
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_generate_config() {
+        let config = generate_config(&SetupAnswers::default());
+        assert_eq!(config.exclude, vec!['　']);
+        assert_eq!(config.aggressiveness, Aggressiveness::Low);
+
+        let config = generate_config(&SetupAnswers {
+            digits: false,
+            letters: true,
+            katakana: true,
+            punctuation: false,
+        });
+        assert!(config.exclude.contains(&'０'));
+        assert!(config.exclude.contains(&'！'));
+        assert!(config.exclude.contains(&'～'));
+        assert!(!config.exclude.contains(&'Ａ'));
+        assert_eq!(config.aggressiveness, Aggressiveness::High);
+    }
+
+    #[test]
+    fn test_ask() {
+        let mut output = Vec::new();
+        let mut input = Cursor::new("maybe\nY\n");
+        assert!(ask(&mut input, &mut output, "Convert?", false).unwrap());
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("Please answer y or n."));
+        // Empty line and end of input fall back to the default
+        let mut input = Cursor::new("\n");
+        assert!(ask(&mut input, &mut Vec::new(), "Convert?", true).unwrap());
+        let mut input = Cursor::new("");
+        assert!(!ask(&mut input, &mut Vec::new(), "Convert?", false).unwrap());
+    }
+
+    #[test]
+    fn test_run_setup_with_scripted_answers() {
+        let temp_dir = tempdir().unwrap();
+        let config_dir = temp_dir.path();
+        fs::write(
+            config_dir.join(SETTINGS_FILE_NAME),
+            r#"{"ipc_enabled": true}"#,
+        )
+        .unwrap();
+        // Digits yes, letters no, katakana yes, punctuation (default) yes
+        let mut input = Cursor::new("y\nn\ny\n\n");
+        run_setup(&mut input, &mut Vec::new(), config_dir, ConfigFormat::Json).unwrap();
+
+        let exclusions =
+            load_exclusion_list(config_dir.join("exclusions.json").to_str().unwrap()).unwrap();
+        assert!(exclusions.contains(&'Ａ'));
+        assert!(!exclusions.contains(&'０'));
+        let settings =
+            load_settings(config_dir.join(SETTINGS_FILE_NAME).to_str().unwrap()).unwrap();
+        assert_eq!(settings.format.aggressiveness, Aggressiveness::High);
+        assert!(settings.ipc_enabled);
+        assert!(config_dir.join("replacements.json").exists());
+
+        // Declining to overwrite leaves the files alone
+        let mut input = Cursor::new("n\nn\nn\nn\nn\n");
+        run_setup(&mut input, &mut Vec::new(), config_dir, ConfigFormat::Json).unwrap();
+        let exclusions =
+            load_exclusion_list(config_dir.join("exclusions.json").to_str().unwrap()).unwrap();
+        assert!(!exclusions.contains(&'０'));
+    }
+}
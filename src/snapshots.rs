@@ -0,0 +1,167 @@
+// What the daemon wrote to the clipboard, newest first, so a conversion can be
+// listed and undone with `kill-zen-all history`. Kept in memory and, with
+// `persist_snapshots`, rewritten to snapshots.json after every conversion.
+// The file holds clipboard contents, so only its owner can read it.
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::ConfigError;
+use crate::private::write_private;
+
+pub const SNAPSHOTS_FILE_NAME: &str = "snapshots.json";
+pub const DEFAULT_SNAPSHOT_LIMIT: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub original: String,
+    pub converted: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Snapshots {
+    entries: VecDeque<Snapshot>,
+    // 0 keeps nothing
+    limit: usize,
+    path: Option<PathBuf>,
+}
+
+impl Snapshots {
+    pub fn new(limit: usize) -> Self {
+        Snapshots {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    // Starts from the snapshots of earlier runs; a missing file is none
+    pub fn persisted(path: PathBuf, limit: usize) -> Result<Self, ConfigError> {
+        let mut entries: VecDeque<Snapshot> = if path.exists() {
+            let data = fs::read_to_string(&path).map_err(|source| ConfigError::Io {
+                path: path.display().to_string(),
+                source,
+            })?;
+            serde_json::from_str(&data).map_err(|source| ConfigError::Parse {
+                path: path.display().to_string(),
+                source,
+            })?
+        } else {
+            VecDeque::new()
+        };
+        entries.truncate(limit);
+        Ok(Snapshots {
+            entries,
+            limit,
+            path: Some(path),
+        })
+    }
+
+    pub fn record(&mut self, original: &str, converted: &str) -> Result<(), ConfigError> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+        self.entries.push_front(Snapshot {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+            original: original.to_string(),
+            converted: converted.to_string(),
+        });
+        self.entries.truncate(self.limit);
+        match &self.path {
+            Some(path) => save(path, &self.entries),
+            None => Ok(()),
+        }
+    }
+
+    // Index 0 is the latest conversion
+    pub fn get(&self, index: usize) -> Option<&Snapshot> {
+        self.entries.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // One line of JSON for the `history` command, each entry with its index
+    pub fn to_json(&self) -> serde_json::Value {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(index, snapshot)| {
+                serde_json::json!({
+                    "index": index,
+                    "timestamp": snapshot.timestamp,
+                    "original": snapshot.original,
+                    "converted": snapshot.converted,
+                })
+            })
+            .collect()
+    }
+}
+
+fn save(path: &Path, entries: &VecDeque<Snapshot>) -> Result<(), ConfigError> {
+    let data = serde_json::to_string(entries).map_err(|source| ConfigError::Parse {
+        path: path.display().to_string(),
+        source,
+    })?;
+    write_private(path, data.as_bytes()).map_err(|source| ConfigError::Io {
+        path: path.display().to_string(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_snapshots_are_listed_newest_first() {
+        let mut snapshots = Snapshots::new(2);
+        snapshots.record("ａ", "a").unwrap();
+        snapshots.record("ｂ", "b").unwrap();
+        snapshots.record("ｃ", "c").unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots.get(0).unwrap().original, "ｃ");
+        assert_eq!(snapshots.get(1).unwrap().converted, "b");
+        // Out of range
+        assert!(snapshots.get(2).is_none());
+
+        let listed = snapshots.to_json();
+        assert_eq!(listed[0]["index"], 0);
+        assert_eq!(listed[0]["original"], "ｃ");
+        assert_eq!(listed[1]["converted"], "b");
+    }
+
+    #[test]
+    fn test_zero_limit_keeps_nothing() {
+        let mut snapshots = Snapshots::new(0);
+        snapshots.record("ａ", "a").unwrap();
+        assert!(snapshots.is_empty());
+    }
+
+    #[test]
+    fn test_persisted_snapshots_survive_restart() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join(SNAPSHOTS_FILE_NAME);
+        let mut snapshots = Snapshots::persisted(path.clone(), 3).unwrap();
+        snapshots.record("ａ\nｂ", "a\nb").unwrap();
+        snapshots.record("ｃ", "c").unwrap();
+
+        let reloaded = Snapshots::persisted(path.clone(), 1).unwrap();
+        assert_eq!(reloaded.len(), 1);
+        assert_eq!(reloaded.get(0), snapshots.get(0));
+
+        fs::write(&path, "not json").unwrap();
+        assert!(Snapshots::persisted(path, 3).is_err());
+    }
+}
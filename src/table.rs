@@ -0,0 +1,212 @@
+// Spreadsheet data (TSV/CSV) for `table_columns`: only the chosen columns are
+// converted, and everything else, quoting included, is kept byte for byte.
+use std::ops::Range;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TableDelimiter {
+    // Tab when the text has one, comma otherwise
+    #[default]
+    Auto,
+    Tab,
+    Comma,
+}
+
+impl TableDelimiter {
+    fn resolve(self, text: &str) -> u8 {
+        match self {
+            TableDelimiter::Tab => b'\t',
+            TableDelimiter::Comma => b',',
+            TableDelimiter::Auto if text.contains('\t') => b'\t',
+            TableDelimiter::Auto => b',',
+        }
+    }
+}
+
+struct Field {
+    // Quotes included; delimiters and line breaks excluded
+    range: Range<usize>,
+    // 1-based, like `cut -f`
+    column: usize,
+}
+
+// A field starting with `"` runs to the matching quote, with `""` for a quote
+// inside it, and may span lines. Scanning bytes is safe: the delimiters,
+// quotes and line breaks are all ASCII.
+fn fields(text: &str, delimiter: u8) -> Vec<Field> {
+    let bytes = text.as_bytes();
+    let mut fields = Vec::new();
+    let mut column = 1;
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if in_quotes {
+            if b == b'"' {
+                if bytes.get(i + 1) == Some(&b'"') {
+                    i += 2;
+                    continue;
+                }
+                in_quotes = false;
+            }
+        } else if b == b'"' && i == start {
+            in_quotes = true;
+        } else if b == delimiter || b == b'\n' {
+            let end = if b == b'\n' && i > start && bytes[i - 1] == b'\r' {
+                i - 1
+            } else {
+                i
+            };
+            fields.push(Field {
+                range: start..end,
+                column,
+            });
+            column = if b == delimiter { column + 1 } else { 1 };
+            start = i + 1;
+        }
+        i += 1;
+    }
+    if start < bytes.len() {
+        fields.push(Field {
+            range: start..bytes.len(),
+            column,
+        });
+    }
+    fields
+}
+
+// Whether the text reads as a table: every line has the same number of
+// fields, and more than one. Anything else, such as plain prose, is not.
+pub fn is_tabular(text: &str, delimiter: TableDelimiter) -> bool {
+    let mut widths: Vec<usize> = Vec::new();
+    for field in fields(text, delimiter.resolve(text)) {
+        match widths.last_mut() {
+            Some(width) if field.column > 1 => *width = field.column,
+            _ => widths.push(field.column),
+        }
+    }
+    widths.first().is_some_and(|&first| first > 1) && widths.iter().all(|&width| width == widths[0])
+}
+
+// Applies `convert` to the value of each field in `columns`
+pub fn map_columns<E>(
+    text: &str,
+    delimiter: TableDelimiter,
+    columns: &[usize],
+    mut convert: impl FnMut(&str) -> Result<String, E>,
+) -> Result<String, E> {
+    let delimiter = delimiter.resolve(text);
+    let mut converted = String::with_capacity(text.len());
+    let mut position = 0;
+    for field in fields(text, delimiter)
+        .into_iter()
+        .filter(|field| columns.contains(&field.column))
+    {
+        converted.push_str(&text[position..field.range.start]);
+        converted.push_str(&convert_field(
+            &text[field.range.clone()],
+            delimiter,
+            &mut convert,
+        )?);
+        position = field.range.end;
+    }
+    converted.push_str(&text[position..]);
+    Ok(converted)
+}
+
+// Quotes are added when the converted value needs them, e.g. when ， became a
+// comma, and kept when the field had them
+fn convert_field<E>(
+    raw: &str,
+    delimiter: u8,
+    convert: &mut impl FnMut(&str) -> Result<String, E>,
+) -> Result<String, E> {
+    let quoted = raw.len() >= 2 && raw.starts_with('"') && raw.ends_with('"');
+    let value = if quoted {
+        raw[1..raw.len() - 1].replace("\"\"", "\"")
+    } else {
+        raw.to_string()
+    };
+    let converted = convert(&value)?;
+    if converted == value {
+        return Ok(raw.to_string());
+    }
+    if quoted || converted.contains([char::from(delimiter), '"', '\n', '\r']) {
+        return Ok(format!("\"{}\"", converted.replace('"', "\"\"")));
+    }
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upper(text: &str) -> Result<String, ()> {
+        Ok(text.to_uppercase())
+    }
+
+    #[test]
+    fn test_fields_handle_quotes_and_line_breaks() {
+        let text = "a,\"b,\"\"c\"\"\r\nd\",e\r\nf,,g";
+        let fields: Vec<(&str, usize)> = fields(text, b',')
+            .into_iter()
+            .map(|field| (&text[field.range], field.column))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("a", 1),
+                ("\"b,\"\"c\"\"\r\nd\"", 2),
+                ("e", 3),
+                ("f", 1),
+                ("", 2),
+                ("g", 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_map_columns_converts_only_chosen_columns() {
+        let text = "x\ty\tz\r\nx\ty\tz\n";
+        assert_eq!(
+            map_columns(text, TableDelimiter::Auto, &[1, 3], upper).unwrap(),
+            "X\ty\tZ\r\nX\ty\tZ\n"
+        );
+        // A comma only separates columns in CSV
+        assert_eq!(
+            map_columns("a,b\tc,d", TableDelimiter::Auto, &[2], upper).unwrap(),
+            "a,b\tC,D"
+        );
+    }
+
+    #[test]
+    fn test_is_tabular() {
+        assert!(is_tabular("a\tb\nc\td\n", TableDelimiter::Auto));
+        assert!(is_tabular("a,\"b\nc\"\r\nd,e", TableDelimiter::Auto));
+        assert!(!is_tabular("ＡＢＣ", TableDelimiter::Auto));
+        assert!(!is_tabular("a,b\nc\n", TableDelimiter::Auto));
+        assert!(!is_tabular("", TableDelimiter::Auto));
+    }
+
+    #[test]
+    fn test_map_columns_quotes_when_needed() {
+        let to_comma = |text: &str| -> Result<String, ()> { Ok(text.replace('，', ",")) };
+        assert_eq!(
+            map_columns("1，000,x\n", TableDelimiter::Comma, &[1], to_comma).unwrap(),
+            "\"1,000\",x\n"
+        );
+        // Quoted fields stay quoted, with their inner quotes escaped again
+        let to_quote = |text: &str| -> Result<String, ()> { Ok(text.replace('＂', "\"")) };
+        assert_eq!(
+            map_columns(
+                "\"a \"\"b\"\" ＂c＂\",x",
+                TableDelimiter::Comma,
+                &[1],
+                to_quote
+            )
+            .unwrap(),
+            "\"a \"\"b\"\" \"\"c\"\"\",x"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+// `--tee`: every conversion is also written to stdout, for piping the daemon
+// into another tool. A writer thread does the writing, so a slow or stuck
+// reader drops results instead of holding up the clipboard.
+use log::{debug, warn};
+use std::io::Write;
+use std::sync::mpsc::{channel, sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use crate::pipe::encode_frame;
+
+// Results waiting for the reader before new ones are dropped
+const TEE_BACKLOG: usize = 64;
+// How long exiting waits for the queued results to be written
+const TEE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TeeDelimiter {
+    // One line per result, newlines escaped as in --pipe-server
+    #[default]
+    Line,
+    // The result as it is, followed by a NUL byte, like `find -print0`
+    Nul,
+}
+
+impl TeeDelimiter {
+    pub fn frame(self, text: &str) -> String {
+        match self {
+            TeeDelimiter::Line => format!("{}\n", encode_frame(text)),
+            TeeDelimiter::Nul => format!("{}\0", text),
+        }
+    }
+}
+
+pub struct Tee {
+    tx: Option<SyncSender<String>>,
+    // Disconnected once the writer thread has finished
+    writer_done: Receiver<()>,
+    delimiter: TeeDelimiter,
+    dropped: u64,
+}
+
+impl Tee {
+    pub fn spawn<W: Write + Send + 'static>(mut out: W, delimiter: TeeDelimiter) -> Self {
+        let (tx, rx) = sync_channel::<String>(TEE_BACKLOG);
+        let (done_tx, writer_done) = channel();
+        thread::spawn(move || {
+            let _done = done_tx;
+            for frame in rx {
+                if let Err(e) = out.write_all(frame.as_bytes()).and_then(|()| out.flush()) {
+                    // Usually the reader went away; the daemon goes on without it
+                    warn!("Stopped writing conversions to stdout: {}", e);
+                    return;
+                }
+            }
+        });
+        Tee {
+            tx: Some(tx),
+            writer_done,
+            delimiter,
+            dropped: 0,
+        }
+    }
+
+    pub fn send(&mut self, text: &str) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        match tx.try_send(self.delimiter.frame(text)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                debug!(
+                    "The --tee reader is behind. Dropped {} result(s) so far.",
+                    self.dropped
+                );
+            }
+            Err(TrySendError::Disconnected(_)) => self.tx = None,
+        }
+    }
+}
+
+// Waits a little for the queued results to be written, but not for a reader
+// that has stopped reading
+impl Drop for Tee {
+    fn drop(&mut self) {
+        self.tx = None;
+        let _ = self.writer_done.recv_timeout(TEE_FLUSH_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_frames() {
+        assert_eq!(TeeDelimiter::Line.frame("a\nb\\"), "a\\nb\\\\\n");
+        assert_eq!(TeeDelimiter::Nul.frame("a\nb"), "a\nb\0");
+    }
+
+    #[test]
+    fn test_tee_writes_every_result_in_order() {
+        let buffer = SharedBuffer::default();
+        let mut tee = Tee::spawn(buffer.clone(), TeeDelimiter::Line);
+        tee.send("one");
+        tee.send("two\nlines");
+        drop(tee);
+        assert_eq!(
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap(),
+            "one\ntwo\\nlines\n"
+        );
+    }
+
+    #[test]
+    fn test_closed_reader_does_not_block() {
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut tee = Tee::spawn(Closed, TeeDelimiter::Nul);
+        for _ in 0..TEE_BACKLOG * 4 {
+            tee.send("text");
+        }
+    }
+}
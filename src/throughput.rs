@@ -0,0 +1,237 @@
+// `--profile-benchmark`: how fast the loaded rule set converts, on a synthetic
+// input that mixes what the clipboard usually holds
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use crate::config::Replacement;
+use crate::error::FormatError;
+use crate::format::{format_text_with_options, FormatOptions};
+
+pub const PROFILE_INPUT_BYTES: usize = 256 * 1024;
+pub const PROFILE_DURATION: Duration = Duration::from_secs(2);
+// `--benchmark-config` runs at every start, so it measures less
+pub const GUARD_INPUT_BYTES: usize = 64 * 1024;
+pub const GUARD_DURATION: Duration = Duration::from_millis(500);
+
+// ASCII prose, fullwidth letters, digits and symbols, kana and kanji
+const SAMPLE: &str =
+    "The quick brown fox ＡＢＣ１２３！ カタカナｶﾀｶﾅ 漢字とひらがな　https://example.com/?q=１ ";
+
+pub fn synthetic_input(min_bytes: usize) -> String {
+    SAMPLE.repeat(min_bytes.div_ceil(SAMPLE.len()).max(1))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Throughput {
+    pub bytes: usize,
+    pub chars: usize,
+    pub iterations: u32,
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    pub fn mb_per_sec(&self) -> f64 {
+        let total = self.bytes as f64 * self.iterations as f64;
+        total / 1_000_000.0 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn ns_per_char(&self) -> f64 {
+        let total = self.chars as f64 * self.iterations as f64;
+        self.elapsed.as_nanos() as f64 / total.max(1.0)
+    }
+}
+
+// Converts `text` over and over until `min_duration` has passed, after one
+// warm-up run that also surfaces a broken rule as an error
+pub fn measure_throughput(
+    text: &str,
+    replacements: &[Replacement],
+    exclusion_list: &[char],
+    options: &FormatOptions,
+    min_duration: Duration,
+) -> Result<Throughput, FormatError> {
+    measure_with_clock(
+        text,
+        replacements,
+        exclusion_list,
+        options,
+        min_duration,
+        Instant::now,
+    )
+}
+
+// measure_throughput with the clock swapped out in tests
+fn measure_with_clock(
+    text: &str,
+    replacements: &[Replacement],
+    exclusion_list: &[char],
+    options: &FormatOptions,
+    min_duration: Duration,
+    mut now: impl FnMut() -> Instant,
+) -> Result<Throughput, FormatError> {
+    format_text_with_options(text, replacements, exclusion_list, options)?;
+    let mut iterations = 0;
+    let start = now();
+    loop {
+        black_box(format_text_with_options(
+            black_box(text),
+            replacements,
+            exclusion_list,
+            options,
+        )?);
+        iterations += 1;
+        let elapsed = now().saturating_duration_since(start);
+        if elapsed >= min_duration {
+            return Ok(Throughput {
+                bytes: text.len(),
+                chars: text.chars().count(),
+                iterations,
+                elapsed,
+            });
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuardOutcome {
+    Passed(Throughput),
+    TooSlow(Throughput),
+}
+
+// `--benchmark-config`: whether the rules convert the synthetic input at
+// `min_mb_per_sec` or faster
+pub fn check_min_throughput(
+    replacements: &[Replacement],
+    exclusion_list: &[char],
+    options: &FormatOptions,
+    min_mb_per_sec: f64,
+    duration: Duration,
+) -> Result<GuardOutcome, FormatError> {
+    let input = synthetic_input(GUARD_INPUT_BYTES);
+    let throughput = measure_throughput(&input, replacements, exclusion_list, options, duration)?;
+    Ok(GuardOutcome::judge(throughput, min_mb_per_sec))
+}
+
+impl GuardOutcome {
+    fn judge(throughput: Throughput, min_mb_per_sec: f64) -> Self {
+        if throughput.mb_per_sec() < min_mb_per_sec {
+            GuardOutcome::TooSlow(throughput)
+        } else {
+            GuardOutcome::Passed(throughput)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::ReplacementAction;
+
+    #[test]
+    fn test_synthetic_input_size() {
+        let input = synthetic_input(10_000);
+        assert!(input.len() >= 10_000);
+        assert!(input.len() < 10_000 + SAMPLE.len());
+        assert!(!synthetic_input(0).is_empty());
+    }
+
+    // A clock that moves on by `step` every time it is read
+    fn ticking_clock(step: Duration) -> impl FnMut() -> Instant {
+        let mut instant = Instant::now();
+        move || {
+            instant += step;
+            instant
+        }
+    }
+
+    #[test]
+    fn test_measure_runs_until_the_duration_has_passed() {
+        let text = "ＡＢＣ１２３";
+        let options = FormatOptions::default();
+        let throughput = measure_with_clock(
+            text,
+            &[],
+            &[],
+            &options,
+            Duration::from_millis(25),
+            ticking_clock(Duration::from_millis(10)),
+        )
+        .unwrap();
+        assert_eq!(
+            throughput,
+            Throughput {
+                bytes: text.len(),
+                chars: 6,
+                iterations: 3,
+                elapsed: Duration::from_millis(30),
+            }
+        );
+    }
+
+    #[test]
+    fn test_throughput_rates() {
+        let throughput = Throughput {
+            bytes: 500_000,
+            chars: 250_000,
+            iterations: 4,
+            elapsed: Duration::from_secs(2),
+        };
+        assert_eq!(throughput.mb_per_sec(), 1.0);
+        assert_eq!(throughput.ns_per_char(), 2000.0);
+    }
+
+    #[test]
+    fn test_broken_rule_fails_the_measurement() {
+        let broken = vec![Replacement {
+            original: "(".to_string(),
+            action: ReplacementAction::ConvertWidth,
+            ..Default::default()
+        }];
+        let options = FormatOptions::default();
+        assert!(measure_with_clock(
+            "x",
+            &broken,
+            &[],
+            &options,
+            Duration::from_secs(1),
+            ticking_clock(Duration::from_secs(1)),
+        )
+        .is_err());
+    }
+
+    // Test for --benchmark-config
+    #[test]
+    fn test_slow_config_trips_the_guard() {
+        // 1 MB/s
+        let throughput = Throughput {
+            bytes: 500_000,
+            chars: 500_000,
+            iterations: 2,
+            elapsed: Duration::from_secs(1),
+        };
+        assert_eq!(
+            GuardOutcome::judge(throughput, 0.5),
+            GuardOutcome::Passed(throughput)
+        );
+        assert_eq!(
+            GuardOutcome::judge(throughput, 1.0),
+            GuardOutcome::Passed(throughput)
+        );
+        assert_eq!(
+            GuardOutcome::judge(throughput, 1.5),
+            GuardOutcome::TooSlow(throughput)
+        );
+    }
+
+    #[test]
+    fn test_guard_reports_a_broken_rule() {
+        let broken = vec![Replacement {
+            original: "(".to_string(),
+            action: ReplacementAction::ConvertWidth,
+            ..Default::default()
+        }];
+        let options = FormatOptions::default();
+        assert!(check_min_throughput(&broken, &[], &options, 0.0, Duration::ZERO).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs;
+use std::path::Path;
+
+use crate::error::ConfigError;
+
+// Lifetime conversion count, kept only on this machine
+pub const USAGE_FILE_NAME: &str = "usage.json";
+
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Usage {
+    pub total_conversions: u64,
+}
+
+// Adds `conversions` to the stored total and returns the new total
+pub fn record_conversions(path: &Path, conversions: u64) -> Result<u64, ConfigError> {
+    let io_error = |source| ConfigError::Io {
+        path: path.display().to_string(),
+        source,
+    };
+    let parse_error = |source| ConfigError::Parse {
+        path: path.display().to_string(),
+        source,
+    };
+    let mut usage: Usage = if path.exists() {
+        let data = fs::read_to_string(path).map_err(io_error)?;
+        serde_json::from_str(&data).map_err(parse_error)?
+    } else {
+        Usage::default()
+    };
+    usage.total_conversions = usage.total_conversions.saturating_add(conversions);
+    let data = serde_json::to_string_pretty(&usage).map_err(parse_error)?;
+    fs::write(path, data).map_err(io_error)?;
+    Ok(usage.total_conversions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_record_conversions_round_trip() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join(USAGE_FILE_NAME);
+
+        assert_eq!(record_conversions(&path, 3).unwrap(), 3);
+        assert_eq!(record_conversions(&path, 0).unwrap(), 3);
+        assert_eq!(record_conversions(&path, 4).unwrap(), 7);
+        let saved: Usage = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved.total_conversions, 7);
+    }
+
+    #[test]
+    fn test_record_conversions_rejects_corrupt_file() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join(USAGE_FILE_NAME);
+        fs::write(&path, "not json").unwrap();
+        assert!(record_conversions(&path, 1).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not json");
+    }
+}
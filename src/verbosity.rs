@@ -0,0 +1,117 @@
+// Temporarily logging at debug level after an error, so an intermittent
+// failure leaves some context behind without running at debug all the time
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use env_logger::{Builder, Env, Logger};
+use log::{LevelFilter, Log, Metadata, Record};
+
+// Read by the logger on every message; set once per loop iteration
+static RAISED: AtomicBool = AtomicBool::new(false);
+
+// Counts down the iterations left at the raised level
+#[derive(Debug, Default)]
+pub struct VerbosityWindow {
+    length: u32,
+    remaining: u32,
+}
+
+impl VerbosityWindow {
+    // A length of 0 never raises the level
+    pub fn new(length: u32) -> Self {
+        VerbosityWindow {
+            length,
+            remaining: 0,
+        }
+    }
+
+    // An error (re)opens the window for the next `length` iterations
+    pub fn widen(&mut self) {
+        if self.length == 0 {
+            return;
+        }
+        if self.remaining == 0 {
+            log::info!(
+                "Logging at debug level for the next {} iterations.",
+                self.length
+            );
+        }
+        self.remaining = self.length;
+    }
+
+    // Called at the start of each iteration; whether it runs at the raised level
+    pub fn advance(&mut self) -> bool {
+        let raised = self.remaining > 0;
+        if raised {
+            self.remaining -= 1;
+        }
+        if RAISED.swap(raised, Ordering::Relaxed) && !raised {
+            log::info!("Back to the normal log level.");
+        }
+        raised
+    }
+}
+
+// RUST_LOG as usual, plus debug messages from this crate while a window is open
+struct AdaptiveLogger {
+    normal: Logger,
+    raised: Logger,
+}
+
+impl Log for AdaptiveLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.normal.enabled(metadata)
+            || (RAISED.load(Ordering::Relaxed) && self.raised.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.normal.matches(record) {
+            self.normal.log(record);
+        } else if RAISED.load(Ordering::Relaxed) && self.raised.matches(record) {
+            self.raised.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.normal.flush();
+    }
+}
+
+pub fn init_logger() {
+    let env = || Env::default().default_filter_or("info");
+    let normal = Builder::from_env(env()).build();
+    let raised = Builder::from_env(env())
+        .filter_module("kill_zen_all", LevelFilter::Debug)
+        .build();
+    let max_level = normal.filter().max(raised.filter());
+    if log::set_boxed_logger(Box::new(AdaptiveLogger { normal, raised })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_widens_then_narrows() {
+        let mut window = VerbosityWindow::new(3);
+        assert!(!window.advance());
+        window.widen();
+        assert!(window.advance());
+        assert!(window.advance());
+        // Another error restarts the count
+        window.widen();
+        assert!(window.advance());
+        assert!(window.advance());
+        assert!(window.advance());
+        assert!(!window.advance());
+        assert!(!window.advance());
+    }
+
+    #[test]
+    fn test_zero_length_window_never_raises() {
+        let mut window = VerbosityWindow::new(0);
+        window.widen();
+        assert!(!window.advance());
+    }
+}
@@ -0,0 +1,62 @@
+// The conversion engine without the clipboard or the filesystem, for the
+// browser playground. Rules and exclusions come in as the JSON of
+// replacements.json and exclusions.json.
+use crate::config::{parse_exclusion_list, parse_replacements};
+use crate::format::{format_text_with_options, FormatOptions};
+
+// Errors as their full chain, since JavaScript only gets the message
+pub fn format_json(text: &str, rules_json: &str, exclusions_json: &str) -> Result<String, String> {
+    let describe = |e: &dyn std::error::Error| {
+        let mut message = e.to_string();
+        let mut source = e.source();
+        while let Some(cause) = source {
+            message.push_str(&format!(": {}", cause));
+            source = cause.source();
+        }
+        message
+    };
+    let replacements = parse_replacements("rules", rules_json).map_err(|e| describe(&e))?;
+    let exclusions =
+        parse_exclusion_list("exclusions", exclusions_json).map_err(|e| describe(&e))?;
+    format_text_with_options(text, &replacements, &exclusions, &FormatOptions::default())
+        .map_err(|e| describe(&e))
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen::prelude::wasm_bindgen]
+pub fn format(
+    text: &str,
+    rules_json: &str,
+    exclusions_json: &str,
+) -> Result<String, wasm_bindgen::JsError> {
+    format_json(text, rules_json, exclusions_json).map_err(|e| wasm_bindgen::JsError::new(&e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_json() {
+        let rules = r#"[{"original": "ｆｏｏ", "replacement": "bar"}]"#;
+        let exclusions = r#"{"exclude": ["！"]}"#;
+        assert_eq!(
+            format_json("ｆｏｏ ＡＢＣ！", rules, exclusions).unwrap(),
+            "bar ABC！"
+        );
+        // Empty JSON means no rules and no exclusions
+        assert_eq!(format_json("ＡＢＣ！", "", "").unwrap(), "ABC!");
+    }
+
+    #[test]
+    fn test_format_json_errors() {
+        let error = format_json("ＡＢＣ", "[{", "").unwrap_err();
+        assert!(
+            error.starts_with("Failed to parse JSON in rules: "),
+            "{}",
+            error
+        );
+        let error = format_json("ＡＢＣ", "", r#"{"exclude": ["ab"]}"#).unwrap_err();
+        assert!(error.contains("exclusions"), "{}", error);
+    }
+}
@@ -0,0 +1,33 @@
+// One X connection for the daemon's lifetime, shared by the queries that run
+// on every check (modifier state, focused window)
+#[cfg(all(
+    target_os = "linux",
+    any(
+        feature = "primary-selection",
+        feature = "skip-modifier",
+        feature = "focus-convert"
+    )
+))]
+pub(crate) fn with_connection<T>(
+    query: impl FnOnce(&xcb::Connection, xcb::Window) -> Option<T>,
+) -> Option<T> {
+    use std::sync::Mutex;
+
+    static CONNECTION: Mutex<Option<(xcb::Connection, i32)>> = Mutex::new(None);
+    let mut connection = CONNECTION.lock().unwrap_or_else(|e| e.into_inner());
+    if connection.is_none() {
+        *connection = xcb::Connection::connect(None).ok();
+    }
+    let (conn, screen_num) = connection.as_ref()?;
+    let root = conn
+        .get_setup()
+        .roots()
+        .nth(usize::try_from(*screen_num).ok()?)?
+        .root();
+    let result = query(conn, root);
+    // A broken connection (e.g. the X server restarted) is opened again next time
+    if conn.has_error().is_err() {
+        *connection = None;
+    }
+    result
+}